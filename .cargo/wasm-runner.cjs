@@ -0,0 +1,11 @@
+#!/usr/bin/env node
+// Run a wasm32-unknown-unknown test binary with Node. The binary has no imports
+// so the test harness's output is lost, but a failing test aborts with a trap,
+// which fails the run.
+const fs = require("fs");
+
+Error.stackTraceLimit = 50;
+
+const wasm = new WebAssembly.Module(fs.readFileSync(process.argv[2]));
+const instance = new WebAssembly.Instance(wasm, {});
+process.exitCode = instance.exports.main(0, 0);
@@ -18,7 +18,7 @@ fn main() {
         }
 
         if let Some(tlv_map) = server.tlv_map {
-            if tlv_map.len() > 0 {
+            if !tlv_map.is_empty() {
                 println!("TLV responses:");
                 for val in tlv_map.values() {
                     println!("\t{:?}", val);
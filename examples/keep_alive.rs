@@ -19,8 +19,10 @@
 
 use slimproto::{
     discovery::discover,
+    name::NameKeeper,
+    proto::PlayerName,
     status::{StatusCode, StatusData},
-    Capabilities, ClientMessage, ServerMessage, FramedReader, FramedWriter,
+    Capabilities, FramedReader, FramedWriter, ServerMessage,
 };
 
 use std::time::Duration;
@@ -34,7 +36,7 @@ fn main() {
         // Prepare the server object with the capabilities and then connect
         let (mut rx, mut tx) = server.prepare(caps).connect().unwrap();
 
-        let mut client_name = String::from("BoringExample");
+        let names = NameKeeper::new(PlayerName::new("BoringExample").unwrap());
 
         // Make the status data so that we can respond to the server ticks
         let mut status = StatusData::default();
@@ -43,13 +45,11 @@ fn main() {
         while let Ok(msg) = rx.framed_read() {
             println!("{:?}", msg);
             match msg {
-                // Server wants to know our name
-                ServerMessage::Queryname => tx
-                    .framed_write(ClientMessage::Name(String::from(&client_name)))
-                    .unwrap(),
-                // Server wants to set our name
-                ServerMessage::Setname(name) => {
-                    client_name = name;
+                // Server wants to know or to set our name
+                ServerMessage::Queryname | ServerMessage::Setname(_) => {
+                    if let Some(reply) = names.handle(&msg) {
+                        tx.framed_write(reply).unwrap();
+                    }
                 }
                 // Status tick from the server, respond with updated status data
                 ServerMessage::Status(ts) => {
@@ -7,10 +7,10 @@
 use std::{
     borrow::BorrowMut,
     cell::RefCell,
-    io::Write,
-    net::{Ipv4Addr, TcpStream},
+    net::Ipv4Addr,
     rc::Rc,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use libpulse_binding as pa;
@@ -19,19 +19,20 @@ use pa::{context::Context, mainloop::threaded::Mainloop, sample::Spec, stream::S
 use slimproto::{
     buffer::SlimBuffer,
     discovery::discover,
-    proto::{PcmChannels, PcmSampleRate, Server},
+    name::NameKeeper,
+    proto::{
+        PauseKind, PcmChannels, PcmSampleRate, PlayerName, Server, ServerAddress, StreamFlags,
+        Threshold,
+    },
     status::{StatusCode, StatusData},
+    stream::{StreamConnection, StreamParams},
     Capabilities, Capability, ClientMessage, FramedReader, FramedWriter, ServerMessage,
 };
 
 use crossbeam::channel::Sender;
 use symphonia::core::{
     audio::{AsAudioBufferRef, RawSampleBuffer, Signal},
-    codecs::DecoderOptions,
-    formats::FormatOptions,
-    io::{MediaSourceStream, ReadOnlySource},
     meta::MetadataOptions,
-    probe::Hint,
 };
 
 fn main() -> anyhow::Result<()> {
@@ -44,14 +45,14 @@ fn main() -> anyhow::Result<()> {
 
     // Set up variables needed by the Slim protocol
     let mut server = Server::default();
-    let name: Arc<RwLock<String>> = Arc::new(RwLock::new("Slimproto_player".to_string()));
+    let names = NameKeeper::new(PlayerName::new("Slimproto_player")?);
     let status = Arc::new(Mutex::new(StatusData::default()));
     let (slim_tx_in, slim_tx_out) = crossbeam::channel::bounded(1);
     let (slim_rx_in, slim_rx_out) = crossbeam::channel::bounded(1);
 
     // Start the Slim protocol thread
     // Runs forever
-    let name_r = name.clone();
+    let names_r = names.clone();
     std::thread::spawn(move || {
         let mut server = match discover(None) {
             Ok(Some(server)) => server,
@@ -62,7 +63,7 @@ fn main() -> anyhow::Result<()> {
 
         slim_rx_in
             .send(ServerMessage::Serv {
-                ip_address: Ipv4Addr::from(*server.socket.ip()),
+                address: (*server.socket.ip()).into(),
                 sync_group_id: None,
             })
             .ok();
@@ -71,9 +72,7 @@ fn main() -> anyhow::Result<()> {
         // update server details when a Serv message is received
         loop {
             let mut caps = Capabilities::default();
-            if let Ok(name) = name_r.read() {
-                caps.add_name(&name);
-            }
+            caps.add_name(names_r.name().as_str());
             caps.add(Capability::Maxsamplerate(192000));
             caps.add(Capability::Pcm);
             caps.add(Capability::Mp3);
@@ -106,14 +105,17 @@ fn main() -> anyhow::Result<()> {
                 match msg {
                     // Request to change to another server
                     ServerMessage::Serv {
-                        ip_address: ip,
+                        address,
                         sync_group_id: sgid,
                     } => {
-                        server = (ip, sgid).into();
+                        server = match server.redirect_to(&address, sgid) {
+                            Ok(server) => server,
+                            Err(_) => return,
+                        };
                         // Now inform the main thread
                         slim_rx_in
                             .send(ServerMessage::Serv {
-                                ip_address: ip,
+                                address: (*server.socket.ip()).into(),
                                 sync_group_id: None,
                             })
                             .ok();
@@ -131,21 +133,16 @@ fn main() -> anyhow::Result<()> {
     while let Ok(msg) = slim_rx_out.recv() {
         // println!("{:?}", msg);
         match msg {
-            ServerMessage::Serv { ip_address, .. } => {
-                server = (ip_address, None).into();
-            }
-
-            ServerMessage::Queryname => {
-                if let Ok(name) = name.read() {
-                    slim_tx_in
-                        .send(ClientMessage::Name((*name).to_owned()))
-                        .ok();
-                }
+            ServerMessage::Serv {
+                address: ServerAddress::Ip(ip),
+                ..
+            } => {
+                server = (ip, None).into();
             }
 
-            ServerMessage::Setname(new_name) => {
-                if let Ok(mut name) = name.write() {
-                    *name = new_name;
+            ServerMessage::Queryname | ServerMessage::Setname(_) => {
+                if let Some(reply) = names.handle(&msg) {
+                    slim_tx_in.send(reply).ok();
                 }
             }
 
@@ -176,9 +173,9 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            ServerMessage::Pause(dur) => {
+            ServerMessage::Pause(when) => {
                 if let Some(ref mut sm) = stream {
-                    if dur.is_zero() {
+                    if when == PauseKind::Now {
                         let status_ref = status.clone();
                         let slim_tx_in_ref = slim_tx_in.clone();
                         (*(*sm.borrow_mut()))
@@ -195,16 +192,16 @@ fn main() -> anyhow::Result<()> {
                 }
             }
 
-            ServerMessage::Gain(l, r) => {
-                let ave_g = ((l + r) / 2.0) as f32;
+            ServerMessage::Gain { left, right, .. } => {
+                let ave_g = ((left.to_f64() + right.to_f64()) / 2.0) as f32;
                 if let Ok(mut g) = gain.lock() {
                     *g = ave_g.sqrt();
                 }
             }
 
-            ServerMessage::Unpause(dur) => {
+            ServerMessage::Unpause(when) => {
                 if let Some(ref mut sm) = stream {
-                    if dur.is_zero() {
+                    if when == PauseKind::Now {
                         let status_ref = status.clone();
                         let slim_tx_in_ref = slim_tx_in.clone();
                         (*(*sm.borrow_mut()))
@@ -224,58 +221,48 @@ fn main() -> anyhow::Result<()> {
             ServerMessage::Stream {
                 autostart,
                 format,
-                // pcmsamplesize,
+                pcmsamplesize,
                 pcmsamplerate,
                 pcmchannels,
-                // pcmendian,
+                pcmendian,
                 threshold,
                 // spdif_enable,
-                // trans_period,
-                // trans_type,
+                trans_period,
+                trans_type,
                 // flags,
                 // output_threshold,
-                // replay_gain,
+                replay_gain,
                 server_port,
                 server_ip,
-                http_headers,
+                http_headers: Some(http_headers),
                 ..
-            } => {
-                if let Some(http_headers) = http_headers {
-                    let num_crlf = http_headers.matches("\r\n").count();
-
-                    if num_crlf > 0 {
-                        if let Ok(mut status) = status.lock() {
-                            status.add_crlf(num_crlf as u8);
-                        }
-
-                        let new_stream = play_stream(
-                            slim_tx_in.clone(),
-                            status.clone(),
-                            gain.clone(),
-                            autostart,
-                            format,
-                            // pcmsamplesize,
-                            pcmsamplerate,
-                            pcmchannels,
-                            // pcmendian,
-                            threshold,
-                            //     spdif_enable,
-                            //     trans_period,
-                            //     trans_type,
-                            //     flags,
-                            //     output_threshold,
-                            //     replay_gain,
-                            server_port,
-                            server_ip,
-                            http_headers,
-                            &server,
-                            ml.clone(),
-                            cx.clone(),
-                        )?;
-
-                        stream = new_stream;
-                    }
-                }
+            } if !http_headers.is_empty() => {
+                let new_stream = play_stream(
+                    slim_tx_in.clone(),
+                    status.clone(),
+                    gain.clone(),
+                    autostart,
+                    format,
+                    pcmsamplesize,
+                    pcmsamplerate,
+                    pcmchannels,
+                    pcmendian,
+                    threshold,
+                    //     spdif_enable,
+                    trans_period,
+                    trans_type,
+                    //     flags,
+                    //     output_threshold,
+                    replay_gain,
+                    server_port,
+                    server_ip,
+                    http_headers,
+                    &server,
+                    ml.clone(),
+                    cx.clone(),
+                )?;
+
+                stream = new_stream;
             }
 
             _ => {}
@@ -284,23 +271,24 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn play_stream(
     slim_tx: Sender<ClientMessage>,
     status: Arc<Mutex<StatusData>>,
     gain: Arc<Mutex<f32>>,
     autostart: slimproto::proto::AutoStart,
     format: slimproto::proto::Format,
-    // pcmsamplesize: slimproto::proto::PcmSampleSize,
+    pcmsamplesize: slimproto::proto::PcmSampleSize,
     pcmsamplerate: slimproto::proto::PcmSampleRate,
     pcmchannels: slimproto::proto::PcmChannels,
-    // pcmendian: slimproto::proto::PcmEndian,
-    threshold: u32,
+    pcmendian: slimproto::proto::PcmEndian,
+    threshold: Threshold,
     // spdif_enable: slimproto::proto::SpdifEnable,
-    // trans_period: Duration,
-    // trans_type: slimproto::proto::TransType,
+    trans_period: Duration,
+    trans_type: slimproto::proto::TransType,
     // flags: slimproto::proto::StreamFlags,
     // output_threshold: Duration,
-    // replay_gain: f64,
+    replay_gain: slimproto::gain::Gain,
     server_port: u16,
     server_ip: Ipv4Addr,
     http_headers: String,
@@ -309,50 +297,42 @@ fn play_stream(
     cx: Rc<RefCell<Context>>,
 ) -> anyhow::Result<Option<Rc<RefCell<Stream>>>> {
     // The LMS sends an ip of 0, 0, 0, 0 when it wants us to default to it
-    let ip = if server_ip == Ipv4Addr::new(0, 0, 0, 0) {
-        *server.socket.ip()
-    } else {
-        server_ip
+    let params = StreamParams {
+        autostart,
+        format,
+        pcmsamplesize,
+        pcmsamplerate,
+        pcmchannels,
+        pcmendian,
+        threshold,
+        trans_period,
+        trans_type,
+        flags: StreamFlags::empty(),
+        replay_gain,
+        server_ip,
+        server_port,
+        http_request: http_headers.parse().ok(),
     };
-
-    let mut data_stream = TcpStream::connect((ip, server_port))?;
-    data_stream.write(http_headers.as_bytes())?;
-    data_stream.flush().ok();
+    let data_stream = StreamConnection::open(&params, server)?;
 
     if let Ok(mut status) = status.lock() {
         let msg = status.make_status_message(StatusCode::Connect);
         slim_tx.send(msg).ok();
     }
+    slim_tx
+        .send(data_stream.report_headers(&status.clone().into()))
+        .ok();
+
+    let mss = slimproto::symphonia::media_source_stream(SlimBuffer::new(
+        data_stream,
+        status.clone(),
+        threshold,
+        None,
+    ));
 
-    let mss = MediaSourceStream::new(
-        Box::new(ReadOnlySource::new(SlimBuffer::with_capacity(
-            threshold as usize * 1024,
-            data_stream,
-            status.clone(),
-            0,
-            None,
-        ))),
-        Default::default(),
-    );
-
-    // Create a hint to help the format registry guess what format reader is appropriate.
-    let mut hint = Hint::new();
-    hint.mime_type({
-        match format {
-            slimproto::proto::Format::Pcm => "audio/x-adpcm",
-            slimproto::proto::Format::Mp3 => "audio/mpeg3",
-            slimproto::proto::Format::Aac => "audio/aac",
-            slimproto::proto::Format::Ogg => "audio/ogg",
-            slimproto::proto::Format::Flac => "audio/flac",
-            _ => "",
-        }
-    });
-
-    // Use the default options for format readers other and enable gapless playback.
-    let format_opts = FormatOptions {
-        enable_gapless: true,
-        ..Default::default()
-    };
+    // Help the format registry guess what format reader is appropriate.
+    let hint = slimproto::symphonia::hint(format);
+    let format_opts = slimproto::symphonia::format_options();
 
     // Use the default options for metadata readers.
     let metadata_opts: MetadataOptions = Default::default();
@@ -459,8 +439,10 @@ fn play_stream(
     }
 
     // Create a decoder for the track.
-    let mut decoder =
-        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut decoder = symphonia::default::get_codecs().make(
+        &track.codec_params,
+        &slimproto::symphonia::decoder_options(),
+    )?;
 
     let mut audio_buf = Vec::with_capacity(8 * 1024);
 
@@ -561,7 +543,9 @@ mod pulse {
         stream::{FlagSet as SmFlagSet, Stream},
     };
 
-    pub fn setup() -> Result<(Rc<RefCell<Mainloop>>, Rc<RefCell<Context>>), PAErr> {
+    type PulseHandles = (Rc<RefCell<Mainloop>>, Rc<RefCell<Context>>);
+
+    pub fn setup() -> Result<PulseHandles, PAErr> {
         let ml = Rc::new(RefCell::new(
             Mainloop::new().ok_or(pa::error::Code::ConnectionRefused)?,
         ));
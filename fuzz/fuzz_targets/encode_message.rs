@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use slimproto::{codec::SlimCodec, ClientMessage, FramedWrite, FramedWriter};
+
+fuzz_target!(|msg: ClientMessage| {
+    let mut buf = Vec::new();
+    let _ = FramedWrite::new(&mut buf, SlimCodec::new()).framed_write(msg);
+});
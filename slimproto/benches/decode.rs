@@ -0,0 +1,97 @@
+//! The cost of decoding server messages, run with `cargo bench`.
+//!
+//! The server sends each player a `strm t` every few seconds, so with a few dozen
+//! synchronised players these status ticks are most of what a client decodes.
+
+// criterion does not build for wasm32, so there is nothing to run there
+#[cfg(target_arch = "wasm32")]
+fn main() {}
+
+#[cfg(not(target_arch = "wasm32"))]
+criterion::criterion_main!(native::benches);
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use std::hint::black_box;
+
+    use bytes::BytesMut;
+    use criterion::{criterion_group, BatchSize, Criterion, Throughput};
+    use slimproto::{codec::SlimCodec, Decoder};
+
+    fn strm(command: u8, timestamp: u32, headers: &str) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&((28 + headers.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(b"strm");
+        frame.push(command);
+        frame.extend_from_slice(b"1m????");
+        frame.extend_from_slice(&[255, 0, 0, b'0', 0, 0, 0]);
+        // The timestamp is in the replay gain field
+        frame.extend_from_slice(&timestamp.to_be_bytes());
+        frame.extend_from_slice(&[0; 6]);
+        frame.extend_from_slice(headers.as_bytes());
+        frame
+    }
+
+    fn decode_all(data: &[u8]) -> usize {
+        let mut buf = BytesMut::from(data);
+        let mut count = 0;
+        while let Some(msg) = SlimCodec::new().decode(&mut buf).unwrap() {
+            black_box(msg);
+            count += 1;
+        }
+        count
+    }
+
+    fn single(c: &mut Criterion) {
+        let tick = strm(b't', 0x1234_5678, "");
+        let stream = strm(
+            b's',
+            0,
+            "GET /stream.mp3?player=00:04:20:12:34:56 HTTP/1.0\r\n\r\n",
+        );
+
+        let mut group = c.benchmark_group("decode");
+        group.throughput(Throughput::Elements(1));
+        group.bench_function("strm t", |b| {
+            b.iter_batched_ref(
+                || BytesMut::from(&tick[..]),
+                |buf| SlimCodec::new().decode(buf).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function("strm s", |b| {
+            b.iter_batched_ref(
+                || BytesMut::from(&stream[..]),
+                |buf| SlimCodec::new().decode(buf).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+        group.finish();
+    }
+
+    fn session(c: &mut Criterion) {
+        let mut group = c.benchmark_group("session");
+        for players in [1u32, 12, 48] {
+            // A round of status ticks for each player, with the odd volume change
+            let mut data = Vec::new();
+            for tick in 0..100 {
+                for player in 0..players {
+                    data.extend(strm(b't', tick * 1000 + player, ""));
+                }
+                if tick % 20 == 0 {
+                    data.extend_from_slice(&[0, 22, b'a', b'u', b'd', b'g']);
+                    data.extend_from_slice(&[0; 10]);
+                    data.extend_from_slice(&[0, 0, 0x80, 0, 0, 0, 0x80, 0]);
+                }
+            }
+
+            group.throughput(Throughput::Elements(decode_all(&data) as u64));
+            group.bench_function(format!("{} players", players), |b| {
+                b.iter(|| decode_all(black_box(&data)))
+            });
+        }
+        group.finish();
+    }
+
+    criterion_group!(benches, single, session);
+}
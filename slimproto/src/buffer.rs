@@ -1,20 +1,192 @@
-/// Used to wrap around a reader.
-/// Keeps the associates status data updated
+//! Used to wrap around a reader.
+//! Keeps the associates status data updated
+//!
+//! Internet radio streams may interleave ICY (Shoutcast) metadata blocks in the
+//! audio data. When [SlimBuffer::with_icy] is used these blocks are removed from the
+//! data and handed to a callback as [IcyMetadata].
+//!
+//! The most recently read data is retained so that [SlimBuffer] can [Seek] backwards
+//! within it, seeking forwards reads and discards data from the stream. This is enough
+//! for decoders to probe the stream format. Seeking from the end of the stream is
+//! not supported.
+//!
+//! A stream sent with [StreamFlags::INF_LOOP], such as a test tone or an alarm,
+//! is played over and over once it ends when the buffer is made
+//! [with_flags](SlimBuffer::with_flags). The whole of such a stream is kept in
+//! memory so that it can be replayed.
+//!
+//! With the `tokio` feature `AsyncSlimBuffer` provides the same status tracking and
+//! prebuffering for an `AsyncRead` stream.
+//!
+//! With the `symphonia` feature a [SlimBuffer] can be given directly to Symphonia
+//! as a `MediaSource`. Since it is `Read + Seek + Send + Sync` it can also be used
+//! as the source of a Rodio `Decoder`, as long as the decoder only seeks near the
+//! start of the stream while probing.
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::thread::spawn;
 use std::{
-    io::{BufRead, BufReader, Read},
-    sync::{Arc, Mutex},
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
 };
 
-use crate::status::StatusData;
+use crate::{
+    metrics::SharedMetrics,
+    proto::{ClientMessage, StreamFlags, Threshold},
+    status::{SharedStatus, StatusData},
+};
 
 type MaybeCallback = Option<Box<dyn FnMut() + Send + Sync + 'static>>;
+type MetaCallback = Box<dyn FnMut(IcyMetadata) + Send + Sync + 'static>;
+type EventCallback = Box<dyn FnMut(BufferEvent) + Send + Sync + 'static>;
+
+// The amount of already read data kept for seeking backwards
+const RETAIN_LEN: usize = 255 * 1024;
+// Data beyond the retained amount is only dropped once there is this much of it,
+// so that the retained data is not moved on every chunk
+const TRIM_LEN: usize = 64 * 1024;
+const CHUNK_LEN: usize = 8 * 1024;
 
 pub struct SlimBuffer<R> {
     inner: BufReader<R>,
-    status: Arc<Mutex<StatusData>>,
-    threshold: u32,
+    status: SharedStatus,
+    threshold: Threshold,
     threshold_cb: MaybeCallback,
     prebuf: Vec<u8>,
+    prebuffered: bool,
+    icy: Option<Icy>,
+    data: Vec<u8>,
+    cursor: usize,
+    base: u64,
+    ring: Option<Arc<Ring>>,
+    events: Option<EventCallback>,
+    underrun: bool,
+    ended: bool,
+    looped: Option<Vec<u8>>,
+    metrics: SharedMetrics,
+}
+
+/// Changes in the state of a [SlimBuffer], see [SlimBuffer::with_events].
+///
+/// A player would typically send `STMo` on an underrun and `STMd` at the end
+/// of the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferEvent {
+    /// The prebuffer threshold has been reached for the first time.
+    ThresholdReached,
+    /// The buffer ran empty before the end of the stream. This is only detected for
+    /// buffers with a [fill thread](SlimBuffer::with_fill_thread), other buffers
+    /// simply block until more data arrives.
+    Underrun,
+    /// The threshold has been reached again after an underrun.
+    Refilled,
+    /// The stream has ended and all of its data has been read.
+    EndOfStream,
+}
+
+/// A block of metadata taken from an ICY stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IcyMetadata {
+    pub raw: String,
+}
+
+impl IcyMetadata {
+    /// The stream title, usually the artist and title of the current track.
+    pub fn title(&self) -> Option<&str> {
+        self.field("StreamTitle")
+    }
+
+    /// The stream URL, if any.
+    pub fn url(&self) -> Option<&str> {
+        self.field("StreamUrl")
+    }
+
+    /// The `META` message to send this metadata to the server.
+    pub fn message(&self) -> ClientMessage {
+        ClientMessage::Meta(self.raw.as_bytes().to_vec())
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        let start = self.raw.find(&format!("{}='", name))? + name.len() + 2;
+        let len = self.raw[start..].find("';")?;
+        Some(&self.raw[start..start + len])
+    }
+}
+
+/// Find the ICY metadata interval in HTTP response headers.
+pub fn icy_metaint(response_headers: &str) -> Option<u32> {
+    response_headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("icy-metaint") {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+enum IcyState {
+    Audio(usize),
+    Length,
+    Meta(usize),
+}
+
+struct Icy {
+    metaint: usize,
+    state: IcyState,
+    meta: Vec<u8>,
+    callback: MetaCallback,
+}
+
+impl Icy {
+    // Removes the metadata from `data` in place, returning the number of audio bytes left
+    fn strip(&mut self, data: &mut [u8]) -> usize {
+        let mut audio_len = 0;
+        let mut pos = 0;
+        while pos < data.len() {
+            match self.state {
+                IcyState::Audio(remaining) => {
+                    let n = remaining.min(data.len() - pos);
+                    data.copy_within(pos..pos + n, audio_len);
+                    audio_len += n;
+                    pos += n;
+                    self.state = if n == remaining {
+                        IcyState::Length
+                    } else {
+                        IcyState::Audio(remaining - n)
+                    };
+                }
+
+                IcyState::Length => {
+                    let len = data[pos] as usize * 16;
+                    pos += 1;
+                    self.meta.clear();
+                    self.state = if len == 0 {
+                        IcyState::Audio(self.metaint)
+                    } else {
+                        IcyState::Meta(len)
+                    };
+                }
+
+                IcyState::Meta(remaining) => {
+                    let n = remaining.min(data.len() - pos);
+                    self.meta.extend_from_slice(&data[pos..pos + n]);
+                    pos += n;
+                    if n == remaining {
+                        let raw = String::from_utf8_lossy(&self.meta)
+                            .trim_end_matches('\0')
+                            .to_owned();
+                        (self.callback)(IcyMetadata { raw });
+                        self.state = IcyState::Audio(self.metaint);
+                    } else {
+                        self.state = IcyState::Meta(remaining - n);
+                    }
+                }
+            }
+        }
+        audio_len
+    }
 }
 
 impl<R> SlimBuffer<R>
@@ -23,67 +195,451 @@ where
 {
     pub fn new(
         inner: R,
-        status: Arc<Mutex<StatusData>>,
-        threshold: u32,
+        status: impl Into<SharedStatus>,
+        threshold: Threshold,
         threshold_cb: MaybeCallback,
     ) -> Self {
-        let buf = BufReader::new(inner);
-        if let Ok(mut status) = status.lock() {
-            status.set_buffer_size(buf.capacity() as u32);
-        }
+        Self::from_buf(
+            BufReader::new(inner),
+            status.into(),
+            threshold,
+            threshold_cb,
+        )
+    }
+
+    pub fn with_capacity(
+        capacity: usize,
+        inner: R,
+        status: impl Into<SharedStatus>,
+        threshold: Threshold,
+        threshold_cb: MaybeCallback,
+    ) -> Self {
+        Self::from_buf(
+            BufReader::with_capacity(capacity, inner),
+            status.into(),
+            threshold,
+            threshold_cb,
+        )
+    }
+
+    fn from_buf(
+        buf: BufReader<R>,
+        status: SharedStatus,
+        threshold: Threshold,
+        threshold_cb: MaybeCallback,
+    ) -> Self {
+        status.lock().set_buffer_size(buf.capacity() as u32);
 
-        let mut this: SlimBuffer<R> = Self {
+        Self {
             inner: buf,
             status,
             threshold,
             threshold_cb,
             prebuf: Vec::with_capacity(255 * 1024),
+            prebuffered: false,
+            icy: None,
+            data: Vec::new(),
+            cursor: 0,
+            base: 0,
+            ring: None,
+            events: None,
+            underrun: false,
+            ended: false,
+            looped: None,
+            metrics: SharedMetrics::default(),
+        }
+    }
+
+    /// Remove ICY metadata from the stream, the metadata blocks are passed to `on_meta`.
+    ///
+    /// `metaint` is the number of audio bytes between metadata blocks, as given in the
+    /// `cont` message or found with [icy_metaint]. A `metaint` of zero means the stream
+    /// has no metadata. To forward the metadata to the server send the
+    /// [message](IcyMetadata::message) from the callback.
+    pub fn with_icy(
+        mut self,
+        metaint: u32,
+        on_meta: impl FnMut(IcyMetadata) + Send + Sync + 'static,
+    ) -> Self {
+        self.icy = match metaint {
+            0 => None,
+            metaint => Some(Icy {
+                metaint: metaint as usize,
+                state: IcyState::Audio(metaint as usize),
+                meta: Vec::with_capacity(255 * 16),
+                callback: Box::new(on_meta),
+            }),
         };
+        self
+    }
 
-        this.pre_buf();
-        this
+    /// Act on the flags of the stream message. With [StreamFlags::INF_LOOP] the
+    /// audio data is kept as it is read and played again from the start each
+    /// time that the stream ends, so the end of the stream is never reached. Only
+    /// a stream that fits within the prebuffer threshold is looped, a longer one
+    /// is played once as if the flag were not set. The bytes played again are not
+    /// counted as received.
+    pub fn with_flags(mut self, flags: StreamFlags) -> Self {
+        self.looped = flags.contains(StreamFlags::INF_LOOP).then(Vec::new);
+        self
     }
 
-    pub fn with_capacity(
+    /// The position in the stream of the next byte to be read.
+    pub fn position(&self) -> u64 {
+        self.base + self.cursor as u64
+    }
+
+    // Read the next chunk of audio data into the retained data, returns 0 at the end of the stream
+    fn fetch(&mut self) -> io::Result<usize> {
+        if self.prebuffered && self.is_starved() {
+            // Prebuffer up to the threshold again before handing out any more data
+            self.underrun = true;
+            self.prebuffered = false;
+            self.emit(BufferEvent::Underrun);
+            self.metrics.record(|m| m.underrun());
+        }
+        while !self.prebuffer()? {}
+
+        if self.cursor > RETAIN_LEN + TRIM_LEN {
+            let excess = self.cursor - RETAIN_LEN;
+            self.data.drain(..excess);
+            self.base += excess as u64;
+            self.cursor -= excess;
+        }
+
+        let start = self.data.len();
+        self.data.resize(start + CHUNK_LEN, 0);
+        let result = loop {
+            let bytes_read = match self.read_raw(start) {
+                Ok(n) => n,
+                Err(e) => break Err(e),
+            };
+            match &mut self.icy {
+                // A read that was all metadata must not look like the end of the stream
+                Some(icy) if bytes_read > 0 => {
+                    let audio_len = icy.strip(&mut self.data[start..start + bytes_read]);
+                    if audio_len > 0 {
+                        break Ok(audio_len);
+                    }
+                }
+                _ => break Ok(bytes_read),
+            }
+        };
+        self.data.truncate(start + *result.as_ref().unwrap_or(&0));
+        let limit = self.threshold.as_bytes() as usize;
+        let result = match (result, &mut self.looped) {
+            (Ok(0), Some(looped)) if !looped.is_empty() => {
+                // Play the stream again from the start
+                self.data.extend_from_slice(looped);
+                Ok(looped.len())
+            }
+            (Ok(n), Some(looped)) if looped.len() + n <= limit => {
+                looped.extend_from_slice(&self.data[start..]);
+                Ok(n)
+            }
+            (Ok(n), Some(_)) => {
+                // Too long to keep, the stream is played once
+                self.looped = None;
+                Ok(n)
+            }
+            (result, _) => result,
+        };
+        if matches!(result, Ok(0)) && !self.ended {
+            self.ended = true;
+            self.emit(BufferEvent::EndOfStream);
+        }
+        result
+    }
+
+    fn is_starved(&self) -> bool {
+        match &self.ring {
+            Some(ring) => {
+                self.prebuf.is_empty() && self.inner.buffer().is_empty() && ring.is_starved()
+            }
+            None => false,
+        }
+    }
+
+    fn emit(&mut self, event: BufferEvent) {
+        if let Some(callback) = &mut self.events {
+            callback(event);
+        }
+    }
+
+    fn read_raw(&mut self, start: usize) -> std::io::Result<usize> {
+        let buf = &mut self.data[start..];
+        // Bytes from the prebuffer were counted as they were prebuffered
+        let (bytes_read, received) = if !self.prebuf.is_empty() {
+            let n_bytes = (&self.prebuf[..]).read(buf)?;
+            self.prebuf.drain(..n_bytes);
+            (n_bytes, 0)
+        } else {
+            let n_bytes = self.inner.read(buf)?;
+            (n_bytes, n_bytes)
+        };
+        self.record(received);
+        Ok(bytes_read)
+    }
+
+    fn record(&mut self, received: usize) {
+        // A fill thread keeps the status up to date itself
+        if self.ring.is_none() {
+            record_read(
+                &mut self.status.lock(),
+                &self.metrics,
+                received,
+                self.prebuf.len() + self.inner.buffer().len(),
+            );
+        }
+    }
+
+    /// Report the bytes streamed, the fullness of the buffer and underruns into `metrics`.
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Self {
+        if let Some(ring) = &self.ring {
+            ring.lock().metrics = metrics.clone();
+        }
+        self.metrics = metrics;
+        self
+    }
+
+    /// Call `on_event` when the buffer runs empty, refills or reaches the end of the stream.
+    pub fn with_events(
+        mut self,
+        on_event: impl FnMut(BufferEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.events = Some(Box::new(on_event));
+        self
+    }
+
+    /// Read the next part of the stream into the prebuffer.
+    ///
+    /// Returns true once the threshold has been reached or the stream has ended,
+    /// at which point the threshold callback is called. Calling this repeatedly
+    /// lets the caller prebuffer without blocking for the whole threshold, otherwise
+    /// the first read from the buffer prebuffers before returning any data.
+    pub fn prebuffer(&mut self) -> io::Result<bool> {
+        if self.prebuffered {
+            return Ok(true);
+        }
+
+        let threshold = self.threshold.as_bytes() as usize;
+        if self.prebuf.len() < threshold {
+            let mut buf = [0u8; 1024];
+            let n = self.inner.read(&mut buf)?;
+            self.prebuf.extend_from_slice(&buf[..n]);
+            self.record(n);
+            if n > 0 && self.prebuf.len() < threshold {
+                return Ok(false);
+            }
+        }
+
+        self.prebuffered = true;
+        if self.underrun {
+            self.underrun = false;
+            self.emit(BufferEvent::Refilled);
+        } else {
+            if let Some(callback) = &mut self.threshold_cb {
+                callback();
+            }
+            self.emit(BufferEvent::ThresholdReached);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl SlimBuffer<RingReader> {
+    /// Make a buffer that is filled from `inner` by a background thread.
+    ///
+    /// The thread reads ahead into a ring buffer of `capacity` bytes so that the
+    /// network is not starved by a slow decoder, and the fullness of the ring buffer
+    /// is reported in the status data.
+    pub fn with_fill_thread<R>(
         capacity: usize,
         inner: R,
-        status: Arc<Mutex<StatusData>>,
-        threshold: u32,
+        status: impl Into<SharedStatus>,
+        threshold: Threshold,
         threshold_cb: MaybeCallback,
-    ) -> Self {
-        let buf = BufReader::with_capacity(capacity, inner);
-        if let Ok(mut status) = status.lock() {
-            status.set_buffer_size(buf.capacity() as u32);
-        }
+    ) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let status = status.into();
+        let ring = RingReader::spawn(inner, capacity, status.clone());
+        let ring_handle = ring.ring.clone();
+        let buf = BufReader::with_capacity(CHUNK_LEN, ring);
+        status.lock().set_buffer_size(capacity as u32);
 
-        let mut this: SlimBuffer<R> = Self {
+        Self {
             inner: buf,
             status,
             threshold,
             threshold_cb,
             prebuf: Vec::with_capacity(255 * 1024),
-        };
+            prebuffered: false,
+            icy: None,
+            data: Vec::new(),
+            cursor: 0,
+            base: 0,
+            ring: Some(ring_handle),
+            events: None,
+            underrun: false,
+            ended: false,
+            looped: None,
+            metrics: SharedMetrics::default(),
+        }
+    }
+}
+
+struct RingState {
+    data: VecDeque<u8>,
+    eof: bool,
+    error: Option<io::Error>,
+    closed: bool,
+    metrics: SharedMetrics,
+}
+
+struct Ring {
+    state: Mutex<RingState>,
+    cond: Condvar,
+    #[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), allow(dead_code))]
+    capacity: usize,
+}
+
+impl Ring {
+    fn lock(&self) -> MutexGuard<'_, RingState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // No data is waiting but more is expected
+    fn is_starved(&self) -> bool {
+        let state = self.lock();
+        state.data.is_empty() && !state.eof && state.error.is_none()
+    }
 
-        this.pre_buf();
-        this
+    fn wait<'a>(&self, guard: MutexGuard<'a, RingState>) -> MutexGuard<'a, RingState> {
+        self.cond.wait(guard).unwrap_or_else(|e| e.into_inner())
     }
+}
+
+/// The reading end of a bounded ring buffer filled by a background thread.
+///
+/// The thread stops once the stream ends, on a read error or when the reader
+/// is dropped.
+pub struct RingReader {
+    ring: Arc<Ring>,
+    status: SharedStatus,
+}
+
+impl RingReader {
+    /// Start a thread that fills a ring buffer of `capacity` bytes from `inner`.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn spawn<R>(mut inner: R, capacity: usize, status: SharedStatus) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let ring = Arc::new(Ring {
+            state: Mutex::new(RingState {
+                data: VecDeque::with_capacity(capacity),
+                eof: false,
+                error: None,
+                closed: false,
+                metrics: SharedMetrics::default(),
+            }),
+            cond: Condvar::new(),
+            capacity: capacity.max(1),
+        });
+
+        let fill_ring = ring.clone();
+        let fill_status = status.clone();
+        spawn(move || {
+            let mut chunk = vec![0u8; CHUNK_LEN];
+            loop {
+                let space = {
+                    let mut state = fill_ring.lock();
+                    while state.data.len() >= fill_ring.capacity && !state.closed {
+                        state = fill_ring.wait(state);
+                    }
+                    if state.closed {
+                        return;
+                    }
+                    fill_ring.capacity - state.data.len()
+                };
+
+                let result = inner.read(&mut chunk[..space.min(CHUNK_LEN)]);
+                let mut state = fill_ring.lock();
+                let bytes_read = match result {
+                    Ok(0) => {
+                        state.eof = true;
+                        0
+                    }
+                    Ok(n) => {
+                        state.data.extend(&chunk[..n]);
+                        n
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        state.error = Some(e);
+                        0
+                    }
+                };
+                // The status is updated under the ring lock so fullness is never stale
+                record_read(
+                    &mut fill_status.lock(),
+                    &state.metrics,
+                    bytes_read,
+                    state.data.len(),
+                );
+                let done = state.eof || state.error.is_some();
+                drop(state);
+                fill_ring.cond.notify_all();
 
-    fn pre_buf(&mut self) {
-        let mut buf = [0u8; 1024];
-        while self.prebuf.len() < self.threshold as usize {
-            if let Ok(n) = self.inner.read(&mut buf) {
-                if n == 0 {
-                    break;
+                if done {
+                    return;
                 }
-                self.prebuf.extend_from_slice(&buf[..n]);
-            } else {
-                break;
             }
+        });
+
+        Self { ring, status }
+    }
+
+    /// The number of bytes waiting in the ring buffer.
+    pub fn len(&self) -> usize {
+        self.ring.lock().data.len()
+    }
+
+    /// True if there are no bytes waiting in the ring buffer.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for RingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.ring.lock();
+        while state.data.is_empty() && !state.eof && state.error.is_none() {
+            state = self.ring.wait(state);
         }
 
-        if let Some(callback) = &mut self.threshold_cb {
-            callback();
+        if state.data.is_empty() {
+            return match state.error.take() {
+                Some(e) => Err(e),
+                None => Ok(0),
+            };
         }
+
+        let n = state.data.read(buf)?;
+        self.status.lock().set_fullness(state.data.len() as u32);
+        drop(state);
+        self.ring.cond.notify_all();
+        Ok(n)
+    }
+}
+
+impl Drop for RingReader {
+    fn drop(&mut self) {
+        self.ring.lock().closed = true;
+        self.ring.cond.notify_all();
     }
 }
 
@@ -92,18 +648,9 @@ where
     R: Read,
 {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let bytes_read = if self.prebuf.len() > 0 {
-            let n_bytes = (&self.prebuf[..]).read(buf)?;
-            self.prebuf.drain(..n_bytes);
-            n_bytes
-        } else {
-            self.inner.read(buf)?
-        };
-        if let Ok(mut status) = self.status.lock() {
-            status.add_bytes_received(bytes_read as u64);
-            status.set_fullness(self.inner.buffer().len() as u32);
-        }
-        Ok(bytes_read)
+        let n_bytes = self.fill_buf()?.read(buf)?;
+        self.consume(n_bytes);
+        Ok(n_bytes)
     }
 }
 
@@ -112,11 +659,200 @@ where
     R: Read,
 {
     fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
-        self.inner.fill_buf()
+        if self.cursor == self.data.len() {
+            self.fetch()?;
+        }
+        Ok(&self.data[self.cursor..])
     }
 
     fn consume(&mut self, amt: usize) {
-        self.inner.consume(amt)
+        self.cursor = (self.cursor + amt).min(self.data.len());
+    }
+}
+
+impl<R> Seek for SlimBuffer<R>
+where
+    R: Read,
+{
+    /// Seek within the stream.
+    ///
+    /// Seeking backwards is limited to the data retained in the buffer, seeking
+    /// forwards past the end of the stream leaves the position at the end.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position().checked_add_signed(offset),
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Cannot seek from the end of a stream",
+                ))
+            }
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before start"))?;
+
+        if target < self.base {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the retained data",
+            ));
+        }
+
+        while target > self.base + self.data.len() as u64 {
+            self.cursor = self.data.len();
+            if self.fetch()? == 0 {
+                break;
+            }
+        }
+
+        self.cursor = ((target - self.base) as usize).min(self.data.len());
+        Ok(self.position())
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position())
+    }
+}
+
+/// An asynchronous version of [SlimBuffer].
+///
+/// The stream is prebuffered up to the threshold on the first read, the threshold
+/// callback is then called and the status data is kept up to date as data is read.
+#[cfg(feature = "tokio")]
+pub struct AsyncSlimBuffer<R> {
+    inner: R,
+    status: SharedStatus,
+    threshold: Threshold,
+    threshold_cb: MaybeCallback,
+    prebuf: Vec<u8>,
+    prebuf_pos: usize,
+    prebuffered: bool,
+    metrics: SharedMetrics,
+}
+
+#[cfg(feature = "tokio")]
+impl<R> AsyncSlimBuffer<R>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    pub fn new(
+        inner: R,
+        status: impl Into<SharedStatus>,
+        threshold: Threshold,
+        threshold_cb: MaybeCallback,
+    ) -> Self {
+        let status = status.into();
+        status.lock().set_buffer_size(threshold.as_bytes());
+
+        Self {
+            inner,
+            status,
+            threshold,
+            threshold_cb,
+            prebuf: Vec::with_capacity(threshold.as_bytes() as usize),
+            prebuf_pos: 0,
+            prebuffered: false,
+            metrics: SharedMetrics::default(),
+        }
+    }
+
+    /// Report the bytes streamed and the fullness of the buffer into `metrics`.
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn threshold_reached(&mut self) {
+        self.prebuffered = true;
+        if let Some(callback) = &mut self.threshold_cb {
+            callback();
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R> tokio::io::AsyncRead for AsyncSlimBuffer<R>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        use std::{pin::Pin, task::ready};
+
+        let this = self.get_mut();
+        while !this.prebuffered {
+            if this.prebuf.len() >= this.threshold.as_bytes() as usize {
+                this.threshold_reached();
+                break;
+            }
+
+            let mut chunk = [0u8; 1024];
+            let mut chunk_buf = tokio::io::ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                this.threshold_reached();
+                break;
+            }
+            this.prebuf.extend_from_slice(chunk_buf.filled());
+            record_read(
+                &mut this.status.lock(),
+                &this.metrics,
+                chunk_buf.filled().len(),
+                this.prebuf.len(),
+            );
+        }
+
+        // Bytes from the prebuffer were counted as they were prebuffered
+        let mut received = 0;
+        if this.prebuf_pos < this.prebuf.len() {
+            let n = (this.prebuf.len() - this.prebuf_pos).min(buf.remaining());
+            buf.put_slice(&this.prebuf[this.prebuf_pos..this.prebuf_pos + n]);
+            this.prebuf_pos += n;
+        } else {
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            received = buf.filled().len() - start;
+        }
+
+        record_read(
+            &mut this.status.lock(),
+            &this.metrics,
+            received,
+            this.prebuf.len() - this.prebuf_pos,
+        );
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+// Update the status after reading from the stream, `fullness` is the number of bytes left buffered
+fn record_read(
+    status: &mut StatusData,
+    metrics: &SharedMetrics,
+    bytes_read: usize,
+    fullness: usize,
+) {
+    status.add_bytes_received(bytes_read as u64);
+    status.set_fullness(fullness as u32);
+    metrics.record(|m| {
+        m.bytes_streamed(bytes_read as u64);
+        m.buffer_fullness(fullness as u32);
+    });
+}
+
+#[cfg(feature = "symphonia")]
+impl<R> symphonia_core::io::MediaSource for SlimBuffer<R>
+where
+    R: Read + Send + Sync,
+{
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
     }
 }
 
@@ -124,18 +860,34 @@ where
 mod tests {
     use super::*;
 
-    use std::sync::RwLock;
+    use std::sync::{Arc, Mutex, RwLock};
 
     #[test]
     fn prebuf() {
-        const BUFLEN: usize = 1024;
+        const BUFLEN: usize = 1024 * 4;
 
         let status = Arc::new(Mutex::new(StatusData::default()));
-        let source: Vec<u8> = (0u8..255).into_iter().cycle().take(BUFLEN).collect();
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
+
+        let mut sb = SlimBuffer::new(&source[..], status.clone(), Threshold::from_kb(2), None);
+        assert!(sb.prebuf.is_empty());
 
-        let sb = SlimBuffer::new(&source[..], status, 2, None);
-        assert_eq!(sb.prebuf, source);
-        assert!(sb.prebuf.len() == source.len());
+        let mut steps = 0;
+        while !sb.prebuffer().unwrap() {
+            steps += 1;
+            // The server sees the stream arrive before playback starts, the
+            // whole of the short source is already buffered by the reader
+            assert_eq!(status.lock().unwrap().get_bytes_received(), 1024);
+            assert_eq!(status.lock().unwrap().get_fullness(), BUFLEN as u32);
+        }
+        assert_eq!(steps, 1);
+        assert_eq!(sb.prebuf, source[..1024 * 2]);
+        assert_eq!(status.lock().unwrap().get_bytes_received(), 1024 * 2);
+
+        let mut buf = Vec::new();
+        sb.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, source);
+        assert_eq!(status.lock().unwrap().get_bytes_received(), BUFLEN as u64);
     }
 
     #[test]
@@ -143,15 +895,15 @@ mod tests {
         const BUFLEN: usize = 1024 * 2;
 
         let status = Arc::new(Mutex::new(StatusData::default()));
-        let source: Vec<u8> = (0u8..255).into_iter().cycle().take(BUFLEN).collect();
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
 
-        let mut sb = SlimBuffer::new(&source[..], status, 2, None);
+        let mut sb = SlimBuffer::new(&source[..], status, Threshold::from_bytes(2), None);
 
         let mut buf = vec![0u8; BUFLEN];
         let n = sb.read(&mut buf).unwrap();
-        sb.read(&mut buf[n..]).unwrap();
+        sb.read_exact(&mut buf[n..]).unwrap();
         assert_eq!(buf, source);
-        assert!(sb.prebuf.len() == 0);
+        assert!(sb.prebuf.is_empty());
     }
 
     #[test]
@@ -159,14 +911,14 @@ mod tests {
         const BUFLEN: usize = 1024 * 2;
 
         let status = Arc::new(Mutex::new(StatusData::default()));
-        let source: Vec<u8> = (0u8..255).into_iter().cycle().take(BUFLEN).collect();
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
 
         let value = Arc::new(RwLock::new(0));
         let value_ref = value.clone();
         let mut sb = SlimBuffer::new(
             &source[..],
             status,
-            2,
+            Threshold::from_bytes(2),
             Some(Box::new(move || {
                 if let Ok(mut value) = value_ref.write() {
                     *value += 1;
@@ -176,9 +928,233 @@ mod tests {
 
         let mut buf = vec![0u8; BUFLEN];
         let n = sb.read(&mut buf).unwrap();
-        sb.read(&mut buf[n..]).unwrap();
+        sb.read_exact(&mut buf[n..]).unwrap();
 
         let val = value.read().unwrap();
         assert!(*val == 1);
     }
+
+    #[test]
+    fn inf_loop() {
+        let status = Arc::new(Mutex::new(StatusData::default()));
+        let ended = Arc::new(Mutex::new(false));
+        let mut sb = SlimBuffer::new(&b"abc"[..], status.clone(), Threshold::from_bytes(3), None)
+            .with_flags(StreamFlags::INF_LOOP)
+            .with_events({
+                let ended = ended.clone();
+                move |event| *ended.lock().unwrap() |= event == BufferEvent::EndOfStream
+            });
+
+        let mut buf = vec![0u8; 10];
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, b"abcabcabca");
+        assert_eq!(sb.position(), 10);
+        assert!(!*ended.lock().unwrap());
+        assert_eq!(status.lock().unwrap().get_bytes_received(), 3);
+
+        // A stream longer than the threshold is not kept and plays once
+        let status = Arc::new(Mutex::new(StatusData::default()));
+        let mut sb = SlimBuffer::new(&b"abcdef"[..], status, Threshold::from_bytes(4), None)
+            .with_flags(StreamFlags::INF_LOOP);
+        let mut buf = Vec::new();
+        sb.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"abcdef");
+
+        // Without the flag the stream ends
+        let status = Arc::new(Mutex::new(StatusData::default()));
+        let mut sb = SlimBuffer::new(&b"abc"[..], status, Threshold::from_bytes(2), None)
+            .with_flags(StreamFlags::OUTPUT_LEFT_ONLY);
+        let mut buf = Vec::new();
+        sb.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"abc");
+    }
+
+    #[test]
+    fn icy() {
+        let mut source = Vec::new();
+        source.extend_from_slice(b"abcd");
+        source.push(2);
+        source.extend_from_slice(b"StreamTitle='a - b';\0\0\0\0\0\0\0\0\0\0\0\0");
+        source.extend_from_slice(b"efgh");
+        source.push(0);
+        source.extend_from_slice(b"ij");
+
+        let titles = Arc::new(Mutex::new(Vec::new()));
+        let titles_ref = titles.clone();
+        let mut sb = SlimBuffer::new(
+            &source[..],
+            StatusData::default(),
+            Threshold::from_bytes(2),
+            None,
+        )
+        .with_icy(4, move |meta| {
+            titles_ref
+                .lock()
+                .unwrap()
+                .push(meta.title().unwrap_or_default().to_owned())
+        });
+
+        let mut audio = Vec::new();
+        let mut buf = [0u8; 3];
+        loop {
+            let n = sb.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            audio.extend_from_slice(&buf[..n]);
+        }
+
+        assert_eq!(audio, b"abcdefghij");
+        assert_eq!(*titles.lock().unwrap(), vec!["a - b".to_owned()]);
+    }
+
+    #[test]
+    fn seek() {
+        const BUFLEN: usize = 1024 * 4;
+
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
+        let mut sb = SlimBuffer::new(
+            &source[..],
+            StatusData::default(),
+            Threshold::from_bytes(2),
+            None,
+        );
+
+        let mut buf = [0u8; 16];
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(sb.seek(SeekFrom::Start(4)).unwrap(), 4);
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], source[4..20]);
+
+        assert_eq!(sb.seek(SeekFrom::Current(-8)).unwrap(), 12);
+        assert_eq!(sb.seek(SeekFrom::Start(3000)).unwrap(), 3000);
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], source[3000..3016]);
+
+        assert_eq!(sb.seek(SeekFrom::Start(10_000)).unwrap(), BUFLEN as u64);
+        assert!(sb.seek(SeekFrom::End(0)).is_err());
+        assert!(sb.seek(SeekFrom::Current(-(BUFLEN as i64) - 1)).is_err());
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn fill_thread() {
+        const BUFLEN: usize = 1024 * 64;
+
+        let status = SharedStatus::default();
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
+        let mut sb = SlimBuffer::with_fill_thread(
+            1024 * 4,
+            std::io::Cursor::new(source.clone()),
+            status.clone(),
+            Threshold::from_kb(1),
+            None,
+        );
+        assert_eq!(status.lock().get_buffer_size(), 1024 * 4);
+
+        let mut buf = Vec::new();
+        sb.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, source);
+        assert_eq!(status.lock().get_bytes_received(), BUFLEN as u64);
+        assert_eq!(status.lock().get_fullness(), 0);
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn events() {
+        struct Trickle(std::sync::mpsc::Receiver<Vec<u8>>);
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.recv() {
+                    Ok(data) => (&data[..]).read(buf),
+                    Err(_) => Ok(0),
+                }
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        let event_tx = Mutex::new(event_tx);
+
+        tx.send(vec![1u8; 8]).unwrap();
+        let mut sb = SlimBuffer::with_fill_thread(
+            1024,
+            Trickle(rx),
+            StatusData::default(),
+            Threshold::from_bytes(8),
+            None,
+        )
+        .with_events(move |event| {
+            event_tx.lock().unwrap().send(event).ok();
+        });
+
+        let mut buf = [0u8; 8];
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(event_rx.try_recv(), Ok(BufferEvent::ThresholdReached));
+
+        let feeder = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            tx.send(vec![2u8; 8]).unwrap();
+        });
+        sb.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2u8; 8]);
+        assert_eq!(event_rx.try_recv(), Ok(BufferEvent::Underrun));
+        assert_eq!(event_rx.try_recv(), Ok(BufferEvent::Refilled));
+
+        // The end of the stream may or may not be seen as another underrun first
+        feeder.join().unwrap();
+        assert_eq!(sb.read(&mut buf).unwrap(), 0);
+        assert_eq!(event_rx.try_iter().last(), Some(BufferEvent::EndOfStream));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_buffer() {
+        use tokio::io::AsyncReadExt;
+
+        const BUFLEN: usize = 1024 * 4;
+
+        let status = SharedStatus::default();
+        let source: Vec<u8> = (0u8..255).cycle().take(BUFLEN).collect();
+        let called = Arc::new(RwLock::new(false));
+        let called_ref = called.clone();
+
+        let mut sb = AsyncSlimBuffer::new(
+            &source[..],
+            status.clone(),
+            Threshold::from_kb(2),
+            Some(Box::new(move || *called_ref.write().unwrap() = true)),
+        );
+
+        let mut buf = Vec::new();
+        sb.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, source);
+        assert!(*called.read().unwrap());
+        assert_eq!(status.lock().get_bytes_received(), BUFLEN as u64);
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn decoder_source() {
+        fn is_source<T: Read + Seek + Send + Sync + 'static>() {}
+        is_source::<SlimBuffer<std::net::TcpStream>>();
+        is_source::<SlimBuffer<crate::stream::StreamConnection>>();
+    }
+
+    #[test]
+    fn metaint() {
+        let headers = "ICY 200 OK\r\nicy-name: Radio\r\nIcy-MetaInt: 16000\r\n\r\n";
+        assert_eq!(icy_metaint(headers), Some(16000));
+        assert_eq!(icy_metaint("HTTP/1.0 200 OK\r\n\r\n"), None);
+
+        let meta = IcyMetadata {
+            raw: "StreamTitle='Artist - Song';StreamUrl='http://x';".to_owned(),
+        };
+        assert_eq!(meta.title(), Some("Artist - Song"));
+        assert_eq!(meta.url(), Some("http://x"));
+        assert_eq!(
+            meta.message(),
+            ClientMessage::Meta(meta.raw.as_bytes().to_vec())
+        );
+    }
 }
@@ -1,9 +1,26 @@
-/// Provides the types needed to send capability data to the server.
+//! Provides the types needed to send capability data to the server.
+//!
+//! An [AudioDevice] can also decide whether a stream the server asks for can be
+//! played, see [negotiate](AudioDevice::negotiate).
 
-use std::fmt;
+use alloc::{
+    borrow::ToOwned,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{fmt, num::ParseIntError, str::FromStr};
+
+use crate::{
+    proto::{Format, PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize, SyncGroup},
+    status::StatusCode,
+    ServerMessage,
+};
 
 /// A client capability as recognised by by the server. Sent as a list of capabilities
 /// when the client announces itself to the server
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Capability {
     Wma,
     Wmap,
@@ -15,6 +32,13 @@ pub enum Capability {
     Mp3,
     Alc,
     Aac,
+    Ops,
+    Wvp,
+    Dsf,
+    Dff,
+    Dop,
+    Mp4,
+    Spdr,
     Maxsamplerate(u32),
     Model(String),
     Modelname(String),
@@ -24,6 +48,48 @@ pub enum Capability {
     Hasdigitalout,
     Haspreamp,
     Hasdisabledac,
+    Canhttps,
+    /// Any capability not otherwise listed, sent to the server as is
+    Custom(String),
+}
+
+impl Capability {
+    /// The name of the capability as it is sent to the server, without any value.
+    ///
+    /// Capabilities of the same kind replace each other in [Capabilities] so that,
+    /// for example, there is only ever one `Model` sent to the server.
+    pub fn kind(&self) -> &str {
+        match self {
+            Capability::Wma => "wma",
+            Capability::Wmap => "wmap",
+            Capability::Wmal => "wmal",
+            Capability::Ogg => "ogg",
+            Capability::Flc => "flc",
+            Capability::Pcm => "pcm",
+            Capability::Aif => "aif",
+            Capability::Mp3 => "mp3",
+            Capability::Alc => "alc",
+            Capability::Aac => "aac",
+            Capability::Ops => "ops",
+            Capability::Wvp => "wvp",
+            Capability::Dsf => "dsf",
+            Capability::Dff => "dff",
+            Capability::Dop => "dop",
+            Capability::Mp4 => "mp4",
+            Capability::Spdr => "spdr",
+            Capability::Maxsamplerate(_) => "MaxSampleRate",
+            Capability::Model(_) => "Model",
+            Capability::Modelname(_) => "Modelname",
+            Capability::Rhap => "Rhap",
+            Capability::Accurateplaypoints => "AccuratePlayPoints",
+            Capability::Syncgroupid(_) => "SyncgroupID",
+            Capability::Hasdigitalout => "HasDigitalOut",
+            Capability::Haspreamp => "HasPreAmp",
+            Capability::Hasdisabledac => "HasDisableDac",
+            Capability::Canhttps => "CanHTTPS",
+            Capability::Custom(v) => v.split('=').next().unwrap_or_default(),
+        }
+    }
 }
 
 /// When sent to the server a capability is sent as text
@@ -40,7 +106,14 @@ impl fmt::Display for Capability {
             Capability::Mp3 => write!(f, "mp3"),
             Capability::Alc => write!(f, "alc"),
             Capability::Aac => write!(f, "aac"),
-            Capability::Maxsamplerate(v) => write!(f, "MaxSampleRate={}", v.to_string()),
+            Capability::Ops => write!(f, "ops"),
+            Capability::Wvp => write!(f, "wvp"),
+            Capability::Dsf => write!(f, "dsf"),
+            Capability::Dff => write!(f, "dff"),
+            Capability::Dop => write!(f, "dop"),
+            Capability::Mp4 => write!(f, "mp4"),
+            Capability::Spdr => write!(f, "spdr"),
+            Capability::Maxsamplerate(v) => write!(f, "MaxSampleRate={}", v),
             Capability::Model(v) => write!(f, "Model={}", v),
             Capability::Modelname(v) => write!(f, "Modelname={}", v),
             Capability::Rhap => write!(f, "Rhap"),
@@ -49,36 +122,253 @@ impl fmt::Display for Capability {
             Capability::Hasdigitalout => write!(f, "HasDigitalOut=1"),
             Capability::Haspreamp => write!(f, "HasPreAmp=1"),
             Capability::Hasdisabledac => write!(f, "HasDisableDac=1"),
+            Capability::Canhttps => write!(f, "CanHTTPS=1"),
+            Capability::Custom(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Parse a capability from the text that is sent to the server, such as `flc`
+/// or `MaxSampleRate=192000`. Names are matched without regard to case and
+/// anything not recognised is kept as a [Custom](Capability::Custom) capability.
+impl FromStr for Capability {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name.to_ascii_lowercase(), Some(value)),
+            None => (s.to_ascii_lowercase(), None),
+        };
+
+        let cap = match (name.as_str(), value) {
+            ("wma", None) => Capability::Wma,
+            ("wmap", None) => Capability::Wmap,
+            ("wmal", None) => Capability::Wmal,
+            ("ogg", None) => Capability::Ogg,
+            ("flc", None) => Capability::Flc,
+            ("pcm", None) => Capability::Pcm,
+            ("aif", None) => Capability::Aif,
+            ("mp3", None) => Capability::Mp3,
+            ("alc", None) => Capability::Alc,
+            ("aac", None) => Capability::Aac,
+            ("ops", None) => Capability::Ops,
+            ("wvp", None) => Capability::Wvp,
+            ("dsf", None) => Capability::Dsf,
+            ("dff", None) => Capability::Dff,
+            ("dop", None) => Capability::Dop,
+            ("mp4", None) => Capability::Mp4,
+            ("spdr", None) => Capability::Spdr,
+            ("maxsamplerate", Some(v)) => Capability::Maxsamplerate(v.parse()?),
+            ("model", Some(v)) => Capability::Model(v.to_owned()),
+            ("modelname", Some(v)) => Capability::Modelname(v.to_owned()),
+            ("rhap", None) => Capability::Rhap,
+            ("accurateplaypoints", Some("1")) => Capability::Accurateplaypoints,
+            ("syncgroupid", Some(v)) => Capability::Syncgroupid(v.to_owned()),
+            ("hasdigitalout", Some("1")) => Capability::Hasdigitalout,
+            ("haspreamp", Some("1")) => Capability::Haspreamp,
+            ("hasdisabledac", Some("1")) => Capability::Hasdisabledac,
+            ("canhttps", Some("1")) => Capability::Canhttps,
+            _ => Capability::Custom(s.to_owned()),
+        };
+
+        Ok(cap)
+    }
+}
+
+impl From<&Format> for Capability {
+    fn from(value: &Format) -> Self {
+        match value {
+            Format::Pcm => Capability::Pcm,
+            Format::Mp3 => Capability::Mp3,
+            Format::Flac => Capability::Flc,
+            Format::Wma => Capability::Wma,
+            Format::Ogg => Capability::Ogg,
+            Format::Aac => Capability::Aac,
+            Format::Alac => Capability::Alc,
         }
     }
 }
 
+impl From<SyncGroup> for Capability {
+    fn from(value: SyncGroup) -> Self {
+        Capability::Syncgroupid(value.to_string())
+    }
+}
+
 /// A list of capabilities which is sent to the server when the client announces itself.
-/// See [SlimpProto](crate::proto::SlimProto) for more details.
+/// See [PreparedServer](crate::proto::PreparedServer) for more details.
+///
+/// The server treats the order of the format capabilities as the client's order of
+/// preference when choosing the format in which to stream a track.
+#[derive(Clone)]
 pub struct Capabilities(Vec<Capability>);
 
 impl Capabilities {
     /// Add a new capability to the list. Note that capabilities are sent to the server
     /// in the order that they are added to the list.
     ///
-    /// Normally you will not need to use this method as capabilities are usually added
-    /// using the [add_capability](crate::proto::SlimProto::add_capability) method.
+    /// If a capability of the same [kind](Capability::kind) is already in the list then
+    /// it is replaced and keeps its current position, use
+    /// [set_priority](Capabilities::set_priority) to move it.
     pub fn add(&mut self, newcap: Capability) {
         let Self(ref mut caps) = self;
-        caps.push(newcap);
+        match caps.iter_mut().find(|c| c.kind() == newcap.kind()) {
+            Some(cap) => *cap = newcap,
+            None => caps.push(newcap),
+        }
+    }
+
+    /// Insert a capability at the given position in the list, replacing any capability
+    /// of the same [kind](Capability::kind). An index past the end of the list appends
+    /// the capability.
+    pub fn insert_at(&mut self, index: usize, newcap: Capability) {
+        let Self(ref mut caps) = self;
+        caps.retain(|c| c.kind() != newcap.kind());
+        caps.insert(index.min(caps.len()), newcap);
+    }
+
+    /// Move a capability that is already in the list to the given position.
+    /// Returns `false` if the capability is not in the list.
+    pub fn set_priority(&mut self, cap: &Capability, index: usize) -> bool {
+        if self.contains(cap) {
+            self.insert_at(index, cap.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Create the default capabilities along with the format capabilities
+    /// for the decoders that the player has compiled in. PCM is always included.
+    pub fn from_build(features: BuildFeatures) -> Self {
+        let mut caps = Self::default();
+        let formats = [
+            (features.flac, Capability::Flc),
+            (features.alac, Capability::Alc),
+            (true, Capability::Pcm),
+            (features.aac, Capability::Aac),
+            (features.ogg, Capability::Ogg),
+            (features.mp3, Capability::Mp3),
+            (features.dsd, Capability::Dsf),
+            (features.dsd, Capability::Dff),
+        ];
+        caps.extend(
+            formats
+                .into_iter()
+                .filter_map(|(enabled, cap)| enabled.then_some(cap)),
+        );
+        caps
+    }
+
+    /// Create a list holding the format capabilities for the given formats in order
+    /// of preference, most preferred first.
+    ///
+    /// Extend the [default](Capabilities::default) capabilities with this list to
+    /// announce the formats to the server:
+    ///
+    /// ```
+    /// use slimproto::{proto::Format, Capabilities};
+    ///
+    /// let mut caps = Capabilities::default();
+    /// caps.extend(Capabilities::preferred_formats(&[Format::Flac, Format::Mp3]));
+    /// assert_eq!(caps.to_string(), "AccuratePlayPoints=1,Model=squeezelite,flc,mp3");
+    /// ```
+    pub fn preferred_formats(formats: &[Format]) -> Self {
+        formats.iter().map(Capability::from).collect()
     }
 
     pub fn add_name(&mut self, name: &str) {
         self.add(Capability::Modelname(name.to_owned()));
     }
+
+    /// Add the sample rate capability of an audio device.
+    ///
+    /// The server only reads `MaxSampleRate`, which is taken from the highest
+    /// supported rate. It has no capability for a list of rates or for sample
+    /// sizes, the device checks those itself when it
+    /// [negotiates](AudioDevice::negotiate) a stream.
+    pub fn add_device(&mut self, device: &AudioDevice) {
+        if let Some(max) = device.sample_rates.iter().max() {
+            self.add(Capability::Maxsamplerate(*max));
+        }
+    }
+
+    /// Returns `true` if the list contains the capability.
+    pub fn contains(&self, cap: &Capability) -> bool {
+        let Self(ref caps) = self;
+        caps.contains(cap)
+    }
+
+    /// Remove a capability from the list, returning `true` if it was present.
+    pub fn remove(&mut self, cap: &Capability) -> bool {
+        let Self(ref mut caps) = self;
+        let len = caps.len();
+        caps.retain(|c| c != cap);
+        caps.len() != len
+    }
+
+    /// Iterate over the capabilities in the order that they will be sent to the server.
+    pub fn iter(&self) -> core::slice::Iter<'_, Capability> {
+        let Self(ref caps) = self;
+        caps.iter()
+    }
+
+    /// The number of capabilities in the list.
+    pub fn len(&self) -> usize {
+        let Self(ref caps) = self;
+        caps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let Self(ref caps) = self;
+        caps.is_empty()
+    }
+}
+
+impl Extend<Capability> for Capabilities {
+    fn extend<T: IntoIterator<Item = Capability>>(&mut self, iter: T) {
+        for cap in iter {
+            self.add(cap);
+        }
+    }
+}
+
+/// Note that, unlike [default](Capabilities::default), a list collected from an
+/// iterator contains only the capabilities in the iterator.
+impl FromIterator<Capability> for Capabilities {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        let mut caps = Self(Vec::new());
+        caps.extend(iter);
+        caps
+    }
+}
+
+impl IntoIterator for Capabilities {
+    type Item = Capability;
+    type IntoIter = vec::IntoIter<Capability>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let Self(caps) = self;
+        caps.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Capabilities {
+    type Item = &'a Capability;
+    type IntoIter = core::slice::Iter<'a, Capability>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 impl Default for Capabilities {
     fn default() -> Self {
-        let mut caps = Vec::new();
-        caps.push(Capability::Accurateplaypoints);
-        caps.push(Capability::Model("squeezelite".to_owned()));
-        Self(caps)
+        Self(vec![
+            Capability::Accurateplaypoints,
+            Capability::Model("squeezelite".to_owned()),
+        ])
     }
 }
 
@@ -90,6 +380,157 @@ impl fmt::Display for Capabilities {
     }
 }
 
+/// A description of the audio output device of the player, used to derive the
+/// sample rate capability with [add_device](Capabilities::add_device).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioDevice {
+    pub sample_rates: Vec<u32>,
+    pub sample_sizes: Vec<u8>,
+}
+
+impl AudioDevice {
+    pub fn new(sample_rates: &[u32], sample_sizes: &[u8]) -> Self {
+        Self {
+            sample_rates: sample_rates.to_vec(),
+            sample_sizes: sample_sizes.to_vec(),
+        }
+    }
+
+    /// Work out the format of the audio of a `strm s` message.
+    ///
+    /// Parameters given in the message are used as they are, those that are self
+    /// describing are taken from `decoded`, the format reported by the decoder once
+    /// it has read the stream header. `Err(StatusCode::NotSupported)` is returned
+    /// if a parameter is not known or the device cannot play the sample rate or
+    /// size. A device with no listed rates or sizes is taken to play any.
+    pub fn negotiate(
+        &self,
+        msg: &ServerMessage,
+        decoded: Option<&AudioSpec>,
+    ) -> Result<AudioSpec, StatusCode> {
+        let ServerMessage::Stream {
+            pcmsamplesize,
+            pcmsamplerate,
+            pcmchannels,
+            pcmendian,
+            ..
+        } = msg
+        else {
+            return Err(StatusCode::NotSupported);
+        };
+
+        let sample_rate = match pcmsamplerate {
+            PcmSampleRate::Rate(rate) => Some(*rate),
+            PcmSampleRate::SelfDescribing => decoded.map(|d| d.sample_rate),
+        };
+        // The server sends a sample size of `Twenty` for 24 bit streams
+        let sample_size = match pcmsamplesize {
+            PcmSampleSize::Eight => Some(8),
+            PcmSampleSize::Sixteen => Some(16),
+            PcmSampleSize::Twenty => Some(24),
+            PcmSampleSize::ThirtyTwo => Some(32),
+            PcmSampleSize::SelfDescribing => decoded.map(|d| d.sample_size),
+        };
+        let channels = match pcmchannels {
+            PcmChannels::Mono => Some(1),
+            PcmChannels::Stereo => Some(2),
+            PcmChannels::SelfDescribing => decoded.map(|d| d.channels),
+        };
+        let big_endian = match pcmendian {
+            PcmEndian::Big => Some(true),
+            PcmEndian::Little => Some(false),
+            PcmEndian::SelfDescribing => decoded.map(|d| d.big_endian),
+        };
+
+        let (Some(sample_rate), Some(sample_size), Some(channels), Some(big_endian)) =
+            (sample_rate, sample_size, channels, big_endian)
+        else {
+            return Err(StatusCode::NotSupported);
+        };
+        let spec = AudioSpec {
+            sample_rate,
+            sample_size,
+            channels,
+            big_endian,
+        };
+
+        if self.plays(&spec) {
+            Ok(spec)
+        } else {
+            Err(StatusCode::NotSupported)
+        }
+    }
+
+    /// Returns `true` if the device can play audio of the given format.
+    pub fn plays(&self, spec: &AudioSpec) -> bool {
+        (self.sample_rates.is_empty() || self.sample_rates.contains(&spec.sample_rate))
+            && (self.sample_sizes.is_empty() || self.sample_sizes.contains(&spec.sample_size))
+            && spec.channels > 0
+    }
+}
+
+/// The format of decoded audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AudioSpec {
+    pub sample_rate: u32,
+    /// The sample size in bits.
+    pub sample_size: u8,
+    pub channels: u8,
+    pub big_endian: bool,
+}
+
+/// The decoders that a player has compiled in, used to create capabilities with
+/// [from_build](Capabilities::from_build).
+///
+/// This is normally created with the [build_features](crate::build_features) macro.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BuildFeatures {
+    pub mp3: bool,
+    pub flac: bool,
+    pub ogg: bool,
+    pub aac: bool,
+    pub alac: bool,
+    pub dsd: bool,
+}
+
+/// Create a [BuildFeatures](crate::capability::BuildFeatures) from the cargo features
+/// of the crate that invokes the macro.
+///
+/// Each decoder is named along with the cargo feature that enables it, decoders
+/// that are not listed are taken to be absent.
+///
+/// ```
+/// use slimproto::{build_features, Capabilities};
+///
+/// let caps = Capabilities::from_build(build_features! {
+///     mp3: "mp3",
+///     flac: "symphonia-flac",
+/// });
+/// ```
+#[macro_export]
+macro_rules! build_features {
+    ($($decoder:ident: $feature:literal),* $(,)?) => {
+        $crate::capability::BuildFeatures {
+            $($decoder: cfg!(feature = $feature),)*
+            ..::core::default::Default::default()
+        }
+    };
+}
+
+/// Parse a comma separated list of capabilities such as `flc,pcm,mp3,MaxSampleRate=192000`.
+/// Unlike [default](Capabilities::default), the list contains only the parsed capabilities.
+impl FromStr for Capabilities {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|cap| !cap.trim().is_empty())
+            .map(Capability::from_str)
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,13 +548,220 @@ mod tests {
         c.add(Capability::Mp3);
         c.add(Capability::Maxsamplerate(9600));
         c.add(Capability::Ogg);
-        assert_eq!(c.to_string(), "AccuratePlayPoints=1,Model=squeezelite,mp3,MaxSampleRate=9600,ogg");
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=squeezelite,mp3,MaxSampleRate=9600,ogg"
+        );
     }
 
     #[test]
     fn name() {
         let mut c = Capabilities::default();
         c.add_name("Testing");
-        assert_eq!(c.to_string(), "AccuratePlayPoints=1,Model=squeezelite,Modelname=Testing");
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=squeezelite,Modelname=Testing"
+        );
+    }
+
+    #[test]
+    fn sync_group() {
+        let mut c = Capabilities::default();
+        c.add(SyncGroup::new("abcd").into());
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=squeezelite,SyncgroupID=abcd"
+        );
+    }
+
+    #[test]
+    fn modern_formats() {
+        let mut c = Capabilities::default();
+        c.add(Capability::Ops);
+        c.add(Capability::Dsf);
+        c.add(Capability::Canhttps);
+        c.add(Capability::Custom("Firmware=8.0".to_owned()));
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=squeezelite,ops,dsf,CanHTTPS=1,Firmware=8.0"
+        );
+    }
+
+    #[test]
+    fn collection() {
+        let mut c: Capabilities = [Capability::Flc, Capability::Pcm, Capability::Mp3]
+            .into_iter()
+            .collect();
+        assert_eq!(c.len(), 3);
+        assert!(c.contains(&Capability::Flc));
+
+        assert!(c.remove(&Capability::Flc));
+        assert!(!c.remove(&Capability::Flc));
+        assert!(!c.contains(&Capability::Flc));
+
+        c.extend([Capability::Ogg, Capability::Maxsamplerate(192000)]);
+        assert_eq!(c.to_string(), "pcm,mp3,ogg,MaxSampleRate=192000");
+        assert_eq!(c.iter().count(), 4);
+    }
+
+    #[test]
+    fn parse() {
+        let c: Capabilities = "flc,PCM,mp3,MaxSampleRate=192000,Model=squeezelite,foo"
+            .parse()
+            .unwrap();
+        assert!(c.contains(&Capability::Pcm));
+        assert!(c.contains(&Capability::Maxsamplerate(192000)));
+        assert!(c.contains(&Capability::Custom("foo".to_owned())));
+        assert_eq!(
+            c.to_string(),
+            "flc,pcm,mp3,MaxSampleRate=192000,Model=squeezelite,foo"
+        );
+
+        assert!("MaxSampleRate=fast".parse::<Capability>().is_err());
+        assert!("".parse::<Capabilities>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut c = Capabilities::default();
+        c.add_name("Testing");
+        c.extend([
+            Capability::Hasdigitalout,
+            Capability::Syncgroupid("abc".to_owned()),
+            Capability::Canhttps,
+        ]);
+        let parsed: Capabilities = c.to_string().parse().unwrap();
+        assert_eq!(
+            parsed.iter().collect::<Vec<_>>(),
+            c.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn device() {
+        let mut c: Capabilities = std::iter::empty().collect();
+        c.add_device(&AudioDevice::new(&[96000, 44100, 48000, 44100], &[24, 16]));
+        assert_eq!(c.to_string(), "MaxSampleRate=96000");
+
+        c.add_device(&AudioDevice::new(&[], &[16]));
+        assert_eq!(c.to_string(), "MaxSampleRate=96000");
+    }
+
+    #[test]
+    fn priority() {
+        let mut c = Capabilities::preferred_formats(&[Format::Flac, Format::Pcm, Format::Mp3]);
+        c.add(Capability::Flc);
+        assert_eq!(c.to_string(), "flc,pcm,mp3");
+
+        assert!(c.set_priority(&Capability::Mp3, 0));
+        assert!(!c.set_priority(&Capability::Ogg, 0));
+        assert_eq!(c.to_string(), "mp3,flc,pcm");
+
+        c.insert_at(1, Capability::Ogg);
+        c.insert_at(10, Capability::Flc);
+        assert_eq!(c.to_string(), "mp3,ogg,pcm,flc");
+    }
+
+    #[test]
+    fn from_build() {
+        let c = Capabilities::from_build(BuildFeatures {
+            mp3: true,
+            flac: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=squeezelite,flc,pcm,mp3"
+        );
+
+        let c = Capabilities::from_build(crate::build_features! {});
+        assert_eq!(c.to_string(), "AccuratePlayPoints=1,Model=squeezelite,pcm");
+    }
+
+    #[test]
+    fn dedupe_by_kind() {
+        let mut c = Capabilities::default();
+        c.add(Capability::Model("myplayer".to_owned()));
+        c.add(Capability::Custom("Firmware=1".to_owned()));
+        c.add(Capability::Custom("Firmware=2".to_owned()));
+        assert_eq!(c.len(), 3);
+        assert_eq!(
+            c.to_string(),
+            "AccuratePlayPoints=1,Model=myplayer,Firmware=2"
+        );
+
+        assert_ne!(
+            Capability::Model("a".to_owned()),
+            Capability::Model("b".to_owned())
+        );
+        let set: std::collections::HashSet<Capability> = c.into_iter().collect();
+        assert!(set.contains(&Capability::Accurateplaypoints));
+    }
+
+    fn stream(size: PcmSampleSize, rate: PcmSampleRate, endian: PcmEndian) -> ServerMessage {
+        ServerMessage::Stream {
+            autostart: crate::proto::AutoStart::Auto,
+            format: Format::Pcm,
+            pcmsamplesize: size,
+            pcmsamplerate: rate,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: endian,
+            threshold: crate::proto::Threshold::ZERO,
+            spdif_enable: crate::proto::SpdifEnable::Auto,
+            trans_period: std::time::Duration::ZERO,
+            trans_type: crate::proto::TransType::None,
+            flags: crate::proto::StreamFlags::empty(),
+            output_threshold: std::time::Duration::ZERO,
+            replay_gain: crate::gain::Gain::MUTE,
+            server_port: 0,
+            server_ip: std::net::Ipv4Addr::UNSPECIFIED,
+            http_headers: None,
+            raw: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        let device = AudioDevice::new(&[44100, 48000], &[16, 24]);
+        let decoded = AudioSpec {
+            sample_rate: 96000,
+            sample_size: 32,
+            channels: 2,
+            big_endian: false,
+        };
+
+        let msg = stream(
+            PcmSampleSize::Twenty,
+            PcmSampleRate::Rate(48000),
+            PcmEndian::Big,
+        );
+        assert_eq!(
+            device.negotiate(&msg, Some(&decoded)),
+            Ok(AudioSpec {
+                sample_rate: 48000,
+                sample_size: 24,
+                channels: 2,
+                big_endian: true,
+            })
+        );
+        assert_eq!(device.negotiate(&msg, None), Err(StatusCode::NotSupported));
+
+        let msg = stream(
+            PcmSampleSize::SelfDescribing,
+            PcmSampleRate::SelfDescribing,
+            PcmEndian::SelfDescribing,
+        );
+        assert_eq!(
+            device.negotiate(&msg, Some(&decoded)),
+            Err(StatusCode::NotSupported)
+        );
+        assert_eq!(
+            AudioDevice::default().negotiate(&msg, Some(&decoded)),
+            Ok(decoded)
+        );
+        assert_eq!(
+            device.negotiate(&ServerMessage::Stop, Some(&decoded)),
+            Err(StatusCode::NotSupported)
+        );
     }
 }
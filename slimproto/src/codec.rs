@@ -1,388 +1,1518 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 // use bytes::{buf::BufMut, Buf, BytesMut};
 // use tokio_util::codec::{Decoder, Encoder};
-use framous::{self, Decoder, Encoder};
+#[cfg(feature = "std")]
+use framous::{self, Decoder, Encoder, FramedReader, FramedWriter};
 
 use crate::{
+    gain::{FixedGain, Gain},
+    jiffies::Jiffies,
     proto::{
-        AutoStart, Format, PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize, SpdifEnable,
-        StreamFlags, TransType,
+        AutoStart, Format, OutputEnable, PauseKind, PcmChannels, PcmEndian, PcmSampleRate,
+        PcmSampleSize, PlayerName, PlayerSetting, ServerAddress, SpdifEnable, StreamFlags,
+        SyncGroup, Threshold, TransType,
     },
     ClientMessage, ServerMessage,
 };
+#[cfg(feature = "std")]
+use crate::{metrics::SharedMetrics, status::StatusData, ServerMessages};
+
+#[cfg(feature = "chrono")]
+use crate::proto::ClockSetting;
+
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+use alloc::{
+    borrow::ToOwned,
+    format,
+    string::{String, ToString},
+};
+use core::{convert::TryInto, error::Error, fmt, net::Ipv4Addr, time::Duration};
+#[cfg(feature = "std")]
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+/// Options that control how strictly [SlimCodec] decodes messages from the server.
+///
+/// The defaults are those of a forgiving player: text that is not UTF-8 is
+/// repaired, unknown `strm` commands are [Unrecognised](ServerMessage::Unrecognised),
+/// frames of any size are accepted and gains are kept to the full precision sent.
+/// A tool that checks a server can turn these into errors instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodecOptions {
+    /// Reject text, such as the HTTP headers, player name and version, that is
+    /// not valid UTF-8 rather than replacing the bad bytes.
+    pub strict: bool,
+    /// The largest frame accepted, not counting its two byte length.
+    pub max_frame_size: usize,
+    /// Reject `strm` messages with a command that the codec does not know.
+    pub unknown_strm_is_error: bool,
+    /// The bits of the fraction of each `audg` gain that are kept, up to the 16
+    /// sent. A player whose volume control has fewer steps can round the gains
+    /// so that changes it cannot play compare equal.
+    pub gain_precision: u8,
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            max_frame_size: u16::MAX as usize,
+            unknown_strm_is_error: false,
+            gain_precision: 16,
+        }
+    }
+}
+
+impl CodecOptions {
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    pub fn unknown_strm_is_error(mut self, is_error: bool) -> Self {
+        self.unknown_strm_is_error = is_error;
+        self
+    }
+
+    pub fn gain_precision(mut self, bits: u8) -> Self {
+        self.gain_precision = bits.min(16);
+        self
+    }
+
+    fn check_text(&self, field: &str, text: &[u8]) -> Result<(), DecodeError> {
+        if self.strict && core::str::from_utf8(text).is_err() {
+            return Err(DecodeError::InvalidText {
+                field: field.to_owned(),
+            });
+        }
+        Ok(())
+    }
 
-use std::{convert::TryInto, io, net::Ipv4Addr, time::Duration};
+    // Round a 16.16 fixed point gain to the precision kept
+    fn gain(&self, fixed: u32) -> FixedGain {
+        let drop = 16 - self.gain_precision.min(16) as u32;
+        let rounded = if drop == 0 {
+            fixed
+        } else {
+            let half = 1u32 << (drop - 1);
+            (fixed.saturating_add(half) >> drop) << drop
+        };
+        FixedGain(rounded)
+    }
+}
+
+/// The client side of the codec, it decodes [ServerMessage]s and encodes [ClientMessage]s.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct SlimCodec {
+    options: CodecOptions,
+    metrics: SharedMetrics,
+}
+
+#[cfg(feature = "std")]
+impl SlimCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A codec that decodes according to `options`.
+    pub fn with_options(options: CodecOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Report the messages sent and received into `metrics`.
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn options(&self) -> &CodecOptions {
+        &self.options
+    }
 
-pub struct SlimCodec;
+    /// Decode every whole frame in `buf`, leaving any partial frame for later.
+    ///
+    /// If a frame cannot be decoded the error is returned and the messages decoded
+    /// before it are lost.
+    pub fn decode_all(&mut self, buf: &mut BytesMut) -> io::Result<ServerMessages> {
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.decode(buf)? {
+            msgs.push(msg);
+        }
+        Ok(msgs)
+    }
+}
+
+/// Decode a single frame sent by the server, including its two byte length.
+///
+/// This does no IO so it is useful for fuzzing and for transports that deliver
+/// whole frames. An error is returned if the frame is incomplete or cannot be decoded,
+/// any bytes after the frame are ignored.
+#[cfg(feature = "std")]
+pub fn decode_frame(frame: &[u8]) -> io::Result<ServerMessage> {
+    SlimCodec::new()
+        .decode(&mut BytesMut::from(frame))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete frame"))
+}
 
+#[cfg(feature = "std")]
 impl Encoder<ClientMessage> for SlimCodec {
     type Error = io::Error;
 
     fn encode(&mut self, item: ClientMessage, dst: &mut BytesMut) -> io::Result<()> {
-        dst.extend(BytesMut::from(item));
+        let start = dst.len();
+        put_client_message(item, dst);
+        let frame = &dst[start..];
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            kind = %String::from_utf8_lossy(&frame[..4]),
+            size = frame.len(),
+            "encoded message"
+        );
+        self.metrics
+            .record(|m| m.message_sent(&String::from_utf8_lossy(&frame[..4]), frame.len()));
         Ok(())
     }
 }
 
+#[cfg(feature = "std")]
 impl Decoder for SlimCodec {
     type Item = ServerMessage;
     type Error = io::Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ServerMessage>> {
-        if buf.len() <= 2 {
+        if buf.len() < 2 {
             return Ok(None);
         };
 
         let frame_size = u16::from_be_bytes(buf[..2].try_into().unwrap()) as usize;
+        if frame_size > self.options.max_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes is larger than the {} allowed",
+                    frame_size, self.options.max_frame_size
+                ),
+            ));
+        }
 
         if buf.len() < frame_size + 2 {
-            if buf.capacity() < frame_size + 2 {
-                buf.reserve(frame_size);
-            }
+            // Make room for the rest of the frame, up to 64 KB, in one go
+            buf.reserve(frame_size + 2 - buf.len());
             return Ok(None);
         };
 
         buf.advance(2);
         let msg = buf.split_to(frame_size);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            kind = %String::from_utf8_lossy(&msg[..msg.len().min(4)]),
+            size = frame_size,
+            "decoded message"
+        );
+        self.metrics.record(|m| {
+            m.message_received(
+                &String::from_utf8_lossy(&msg[..msg.len().min(4)]),
+                frame_size,
+            )
+        });
+
+        match ServerMessage::decode(msg, &self.options) {
+            ServerMessage::Malformed {
+                command, reason, ..
+            } => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    command = %String::from_utf8_lossy(&command),
+                    %reason,
+                    size = frame_size,
+                    "malformed message from server"
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    MalformedMessage { command, reason },
+                ))
+            }
+            msg => Ok(Some(msg)),
+        }
+    }
+}
 
-        match msg.into() {
-            ServerMessage::Error => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Server data corrupted",
-            )),
-            msg @ _ => Ok(Some(msg)),
+#[cfg(feature = "std")]
+const READ_LEN: usize = 4 * 1024;
+
+/// A framed connection to the server over any transport that is both `Read` and `Write`.
+///
+/// This is returned by [connect_with](crate::proto::PreparedServer::connect_with) and allows
+/// the protocol to be run over an SSH tunnel, a TLS wrapped socket, a Unix socket or any
+/// other custom transport.
+///
+/// The read and write buffers are kept between calls so that, once they have grown
+/// to fit the messages, the regular status traffic does not allocate.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct SlimFramed<T> {
+    inner: T,
+    codec: SlimCodec,
+    buf: BytesMut,
+    wbuf: BytesMut,
+}
+
+#[cfg(feature = "std")]
+impl<T> SlimFramed<T> {
+    /// Frame messages over `inner`, which is read from, written to or both.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            codec: SlimCodec::new(),
+            buf: BytesMut::with_capacity(READ_LEN),
+            wbuf: BytesMut::with_capacity(128),
+        }
+    }
+
+    /// A reference to the underlying transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// A mutable reference to the underlying transport
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// The codec used for the messages.
+    pub fn codec(&self) -> &SlimCodec {
+        &self.codec
+    }
+
+    /// Use `codec` for the messages, such as one made with [SlimCodec::with_options].
+    pub fn with_codec(mut self, codec: SlimCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Consume the framed connection and return the underlying transport.
+    /// Any data that has been read but not yet decoded is lost, use
+    /// [into_parts](SlimFramed::into_parts) to keep it.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Consume the framed connection and return the underlying transport along
+    /// with any data that has been read from it but not yet decoded.
+    pub fn into_parts(self) -> (T, BytesMut) {
+        (self.inner, self.buf)
+    }
+
+    /// Read at least one message and then every other message that has already
+    /// been received, so that a backlog built up during a stall can be handled at
+    /// once. Only reads that are needed for the first message are made.
+    pub fn framed_read_many(&mut self) -> io::Result<ServerMessages>
+    where
+        T: Read,
+    {
+        let mut msgs = vec![self.framed_read()?];
+        msgs.append(&mut self.codec.decode_all(&mut self.buf)?);
+        Ok(msgs)
+    }
+
+    /// Read a message without blocking for a transport that gives an error of kind
+    /// `WouldBlock` when it has no data, such as a non-blocking socket.
+    ///
+    /// Returns `Ok(None)` if a whole message has not arrived yet, the data read so
+    /// far is kept for the next call.
+    pub fn poll_read(&mut self) -> io::Result<Option<ServerMessage>>
+    where
+        T: Read,
+    {
+        match self.framed_read() {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
         }
     }
 }
 
-impl From<ClientMessage> for BytesMut {
-    fn from(src: ClientMessage) -> BytesMut {
-        const FRAMESIZE: usize = 1024;
-
-        let mut msg = Vec::with_capacity(FRAMESIZE + 8);
-        let mut frame_size = Vec::with_capacity(4);
-        let mut frame = Vec::with_capacity(FRAMESIZE);
-
-        match src {
-            ClientMessage::Helo {
-                device_id,
-                revision,
-                mac,
-                uuid,
-                wlan_channel_list,
-                bytes_received,
-                language,
-                capabilities,
-            } => {
-                msg.put("HELO".as_bytes());
-                frame.put_u8(device_id);
-                frame.put_u8(revision);
-                frame.put(mac.bytes().as_ref());
-                frame.put(uuid.as_ref());
-                frame.put_u16(wlan_channel_list);
-                frame.put_u64(bytes_received);
-                frame.put(
-                    language
-                        .iter()
-                        .map(|c| *c as u8)
-                        .collect::<Vec<u8>>()
-                        .as_ref(),
-                );
-                frame.put(capabilities.as_bytes());
+#[cfg(feature = "std")]
+impl<T> SlimFramed<T> {
+    /// Split the connection into read and write halves that can be moved to
+    /// different threads, for a transport such as `TcpStream` that can be read
+    /// and written through a shared reference. Use
+    /// [reunite](SlimFramed::reunite) to put the halves back together.
+    pub fn into_split(self) -> (SlimFramed<ReadHalf<T>>, SlimFramed<WriteHalf<T>>) {
+        let inner = Arc::new(self.inner);
+        let rx = SlimFramed {
+            inner: ReadHalf(inner.clone()),
+            codec: self.codec.clone(),
+            buf: self.buf,
+            wbuf: BytesMut::new(),
+        };
+        let tx = SlimFramed {
+            inner: WriteHalf(inner),
+            codec: self.codec,
+            buf: BytesMut::new(),
+            wbuf: self.wbuf,
+        };
+        (rx, tx)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> SlimFramed<ReadHalf<T>> {
+    /// Join the halves made by [into_split](SlimFramed::into_split) to give back
+    /// the whole connection, so that the transport can be reclaimed. Any data read
+    /// but not yet decoded is kept. The halves are returned in the error if they
+    /// are not from the same connection.
+    // The error is as large as the halves it gives back
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(self, tx: SlimFramed<WriteHalf<T>>) -> Result<SlimFramed<T>, ReuniteError<T>> {
+        if !Arc::ptr_eq(&self.inner.0, &tx.inner.0) {
+            return Err(ReuniteError(self, tx));
+        }
+
+        let SlimFramed {
+            inner: WriteHalf(write_half),
+            wbuf,
+            ..
+        } = tx;
+        drop(write_half);
+        let inner = match Arc::try_unwrap(self.inner.0) {
+            Ok(inner) => inner,
+            // The halves cannot be cloned so once one is dropped the other is the only owner
+            Err(_) => unreachable!("Split transport has another owner"),
+        };
+        Ok(SlimFramed {
+            inner,
+            codec: self.codec,
+            buf: self.buf,
+            wbuf,
+        })
+    }
+}
+
+/// The read half of a transport split by [into_split](SlimFramed::into_split).
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct ReadHalf<T>(Arc<T>);
+
+/// The write half of a transport split by [into_split](SlimFramed::into_split).
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct WriteHalf<T>(Arc<T>);
+
+#[cfg(feature = "std")]
+impl<T> ReadHalf<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> WriteHalf<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Read for ReadHalf<T>
+where
+    for<'a> &'a T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Write for WriteHalf<T>
+where
+    for<'a> &'a T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+/// The halves given to [reunite](SlimFramed::reunite) were not split from the
+/// same connection.
+#[cfg(feature = "std")]
+pub struct ReuniteError<T>(pub SlimFramed<ReadHalf<T>>, pub SlimFramed<WriteHalf<T>>);
+
+#[cfg(feature = "std")]
+impl<T> fmt::Debug for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError").finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> fmt::Display for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Halves are not from the same connection")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> Error for ReuniteError<T> {}
+
+#[cfg(feature = "std")]
+impl<T> FramedReader<ServerMessage> for SlimFramed<T>
+where
+    T: Read,
+{
+    fn framed_read(&mut self) -> io::Result<ServerMessage> {
+        loop {
+            if let Some(msg) = self.codec.decode(&mut self.buf)? {
+                return Ok(msg);
             }
 
-            ClientMessage::Bye(val) => {
-                msg.put("BYE!".as_bytes());
-                frame.put_u8(val);
+            // Read straight into the buffer, space left by decoded messages is reused
+            let len = self.buf.len();
+            self.buf.resize(len + READ_LEN, 0);
+            let result = self.inner.read(&mut self.buf[len..]);
+            self.buf.truncate(len + *result.as_ref().unwrap_or(&0));
+
+            match result {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionReset,
+                        "Server connection reset",
+                    ))
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
-            ClientMessage::Stat {
-                event_code,
-                stat_data,
-            } => {
-                msg.put("STAT".as_bytes());
-                frame.put(event_code.as_bytes());
-                frame.put_u8(stat_data.crlf);
-                frame.put_u16(0);
-                frame.put_u32(stat_data.buffer_size);
-                frame.put_u32(stat_data.fullness);
-                frame.put_u64(stat_data.bytes_received);
-                frame.put_u16(stat_data.sig_strength);
-                frame.put_u32(stat_data.jiffies.as_millis().try_into().unwrap_or_default());
-                frame.put_u32(stat_data.output_buffer_size);
-                frame.put_u32(stat_data.output_buffer_fullness);
-                frame.put_u32(stat_data.elapsed_seconds);
-                frame.put_u16(stat_data.voltage);
-                frame.put_u32(stat_data.elapsed_milliseconds);
-                frame.put_u32(
-                    stat_data
-                        .timestamp
-                        .as_millis()
-                        .try_into()
-                        .unwrap_or_default(),
-                );
-                frame.put_u16(stat_data.error_code);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> FramedWriter<ClientMessage> for SlimFramed<T>
+where
+    T: Write,
+{
+    /// Write a message, and any messages that have been fed, and flush.
+    fn framed_write(&mut self, item: ClientMessage) -> io::Result<()> {
+        self.feed(item)?;
+        self.flush()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> SlimFramed<T>
+where
+    T: Write,
+{
+    /// Add a message to those waiting to be written, nothing is written until the
+    /// next [flush](SlimFramed::flush) or [framed_write](FramedWriter::framed_write).
+    pub fn feed(&mut self, item: ClientMessage) -> io::Result<()> {
+        self.codec.encode(item, &mut self.wbuf)
+    }
+
+    /// Write the messages that have been fed in one go and flush the transport.
+    /// The messages are dropped if the write fails.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.write_all(&self.wbuf);
+        self.wbuf.clear();
+        result?;
+        self.inner.flush()
+    }
+
+    /// Write several messages together, such as the status messages sent when a
+    /// stream starts, so that they can go in one TCP segment rather than one each.
+    pub fn send_batch(&mut self, items: impl IntoIterator<Item = ClientMessage>) -> io::Result<()> {
+        for item in items {
+            self.feed(item)?;
+        }
+        self.flush()
+    }
+}
+
+impl From<ClientMessage> for BytesMut {
+    fn from(src: ClientMessage) -> BytesMut {
+        let mut dst = BytesMut::new();
+        put_client_message(src, &mut dst);
+        dst
+    }
+}
+
+/// Encode a client message onto the end of `dst`. The frame is written in place
+/// so that a buffer kept between messages needs no allocation once it is big enough.
+fn put_client_message(src: ClientMessage, dst: &mut BytesMut) {
+    let start = dst.len();
+    // The opcode and length are filled in once the payload is written
+    dst.put_bytes(0, 8);
+
+    let opcode: &[u8; 4] = match src {
+        ClientMessage::Helo {
+            device_id,
+            revision,
+            mac,
+            uuid,
+            wlan_channel_list,
+            bytes_received,
+            language,
+            capabilities,
+        } => {
+            dst.put_u8(device_id);
+            dst.put_u8(revision);
+            dst.put(mac.bytes().as_ref());
+            dst.put(uuid.as_ref());
+            dst.put_u16(wlan_channel_list);
+            dst.put_u64(bytes_received);
+            for c in language {
+                dst.put_u8(c as u8);
             }
+            dst.put(capabilities.as_bytes());
+            b"HELO"
+        }
 
-            ClientMessage::Name(name) => {
-                msg.put("SETD".as_bytes());
-                frame.put_u8(0);
-                frame.put(name.as_bytes());
+        ClientMessage::Bye(val) => {
+            dst.put_u8(val);
+            b"BYE!"
+        }
+        ClientMessage::Stat {
+            event_code,
+            stat_data,
+        } => {
+            // Always four bytes, however long the code, so that the rest of the
+            // status data is where the server expects it
+            let mut code = [b' '; 4];
+            code.iter_mut()
+                .zip(event_code.bytes())
+                .for_each(|(c, b)| *c = b);
+            dst.put_slice(&code);
+            dst.put_u8(stat_data.crlf);
+            dst.put_u16(0);
+            dst.put_u32(stat_data.buffer_size);
+            dst.put_u32(stat_data.fullness);
+            dst.put_u64(stat_data.bytes_received);
+            dst.put_u16(stat_data.sig_strength);
+            dst.put_u32(stat_data.jiffies.as_millis());
+            dst.put_u32(stat_data.output_buffer_size);
+            dst.put_u32(stat_data.output_buffer_fullness);
+            dst.put_u32(stat_data.elapsed_seconds);
+            dst.put_u16(stat_data.voltage);
+            dst.put_u32(stat_data.elapsed_milliseconds);
+            dst.put_u32(stat_data.timestamp.as_millis());
+            dst.put_u16(stat_data.error_code);
+            b"STAT"
+        }
+
+        ClientMessage::Name(name) => {
+            dst.put_u8(0);
+            dst.put(name.as_str().as_bytes());
+            b"SETD"
+        }
+
+        ClientMessage::Setting(setting, value) => {
+            dst.put_u8(setting.id());
+            dst.put_u8(value);
+            b"SETD"
+        }
+
+        ClientMessage::Resp(headers) => {
+            dst.put(headers.as_bytes());
+            b"RESP"
+        }
+
+        ClientMessage::Body(body) => {
+            dst.put_u32(body.len() as u32);
+            dst.put(body.as_slice());
+            b"BODY"
+        }
+
+        ClientMessage::Meta(meta) => {
+            dst.put(meta.as_slice());
+            b"META"
+        }
+
+        ClientMessage::UpgradeRequest => b"UREQ",
+
+        ClientMessage::Ir {
+            time,
+            format,
+            bits,
+            code,
+        } => {
+            dst.put_u32(time.as_millis());
+            dst.put_u8(format);
+            dst.put_u8(bits);
+            dst.put_u32(code);
+            b"IR  "
+        }
+    };
+
+    let len = (dst.len() - start - 8) as u32;
+    dst[start..start + 4].copy_from_slice(opcode);
+    dst[start + 4..start + 8].copy_from_slice(&len.to_be_bytes());
+}
+
+// The client expects strm messages to be at least this long
+const STRM_LEN: usize = 24;
+
+/// Why a message from the server could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecodeError {
+    /// The message is `len` bytes long, including its command, but must be at
+    /// least `min` bytes.
+    TooShort { len: usize, min: usize },
+    /// A field holds a value that the protocol does not define.
+    InvalidValue { field: String, value: u8 },
+    /// A text field is not valid UTF-8.
+    InvalidText { field: String },
+}
+
+impl DecodeError {
+    // The body is what is left after the four byte command
+    fn too_short(body: &[u8], min: usize) -> Self {
+        DecodeError::TooShort {
+            len: body.len() + 4,
+            min: min + 4,
+        }
+    }
+
+    fn invalid(field: &str, value: u8) -> Self {
+        DecodeError::InvalidValue {
+            field: field.to_owned(),
+            value,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort { len, min } => {
+                write!(f, "{} bytes long but needs at least {}", len, min)
+            }
+            DecodeError::InvalidValue { field, value } => {
+                write!(f, "{} cannot be {:#04x}", field, value)
             }
+            DecodeError::InvalidText { field } => write!(f, "{} is not valid UTF-8", field),
         }
+    }
+}
 
-        frame_size.put_u32(frame.len() as u32);
-        msg.append(&mut frame_size);
-        msg.append(&mut frame);
+impl Error for DecodeError {}
+
+/// The error inside the `InvalidData` [io::Error] returned by the codec for a
+/// message that cannot be decoded, it can be had with `get_ref` and `downcast_ref`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MalformedMessage {
+    pub command: [u8; 4],
+    pub reason: DecodeError,
+}
+
+impl fmt::Display for MalformedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Malformed {} message from server: {}",
+            String::from_utf8_lossy(&self.command),
+            self.reason
+        )
+    }
+}
 
-        msg.as_slice().into()
+impl Error for MalformedMessage {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.reason)
     }
 }
 
 impl From<BytesMut> for ServerMessage {
-    fn from(mut src: BytesMut) -> ServerMessage {
-        const GAIN_FACTOR: f64 = 65536.0;
+    /// Decode the payload of a frame from the server, a payload that cannot be
+    /// decoded gives [Malformed](ServerMessage::Malformed).
+    fn from(src: BytesMut) -> ServerMessage {
+        ServerMessage::decode(src, &CodecOptions::default())
+    }
+}
 
-        let msg = String::from_utf8(src.split_to(4).to_vec()).unwrap_or_default();
-        let mut buf = src; //.split();
+impl ServerMessage {
+    fn decode(src: BytesMut, options: &CodecOptions) -> ServerMessage {
+        let frame = src.freeze();
+        decode_server_message(frame.clone(), options).unwrap_or_else(|reason| {
+            let mut command = [0; 4];
+            let len = frame.len().min(4);
+            command[..len].copy_from_slice(&frame[..len]);
+            ServerMessage::Malformed {
+                command,
+                reason,
+                raw: frame.to_vec(),
+            }
+        })
+    }
+}
 
-        match msg.as_str() {
-            "serv" => {
-                if buf.len() < 4 {
-                    return ServerMessage::Error;
-                }
+fn decode_server_message(
+    mut src: Bytes,
+    options: &CodecOptions,
+) -> Result<ServerMessage, DecodeError> {
+    if src.len() < 4 {
+        return Err(DecodeError::TooShort {
+            len: src.len(),
+            min: 4,
+        });
+    }
 
-                let ip_addr = Ipv4Addr::from(buf.split_to(4).get_u32());
-                let sync_group = if buf.len() > 0 {
-                    Some(buf.into_iter().map(|c| c as char).collect::<String>())
-                } else {
-                    None
-                };
-                ServerMessage::Serv {
-                    ip_address: ip_addr,
-                    sync_group_id: sync_group,
-                }
+    // Status ticks are the most frequent message, so read the timestamp
+    // without parsing the rest of the frame or copying it
+    if src.len() >= 4 + STRM_LEN && src.starts_with(b"strmt") {
+        // In the replay gain field, 14 bytes after the command
+        let timestamp = u32::from_be_bytes(src[18..22].try_into().unwrap());
+        return Ok(ServerMessage::Status(Jiffies::from_millis(timestamp)));
+    }
+
+    let msg = String::from_utf8(src.split_to(4).to_vec()).unwrap_or_default();
+    let mut buf = src; //.split();
+
+    Ok(match msg.as_str() {
+        "serv" => {
+            if buf.len() < 4 {
+                return Err(DecodeError::too_short(&buf, 4));
+            }
+
+            if let Some(hostname) = ServerAddress::hostname(&buf) {
+                return Ok(ServerMessage::Serv {
+                    address: ServerAddress::Hostname(hostname),
+                    sync_group_id: None,
+                });
+            }
+
+            let ip_addr = Ipv4Addr::from(buf.split_to(4).get_u32());
+            let sync_group = if !buf.is_empty() {
+                Some(SyncGroup::from(
+                    buf.into_iter().map(|c| c as char).collect::<String>(),
+                ))
+            } else {
+                None
+            };
+            ServerMessage::Serv {
+                address: ServerAddress::Ip(ip_addr),
+                sync_group_id: sync_group,
+            }
+        }
+
+        "strm" => {
+            if buf.len() < STRM_LEN {
+                return Err(DecodeError::too_short(&buf, STRM_LEN));
             }
+            let raw = buf.to_vec();
 
-            "strm" => {
-                if buf.len() < 24 {
-                    return ServerMessage::Error;
+            match buf.split_to(1)[0] as char {
+                't' => {
+                    buf.advance(13);
+                    ServerMessage::Status(Jiffies::from_millis(buf.get_u32()))
                 }
 
-                match buf.split_to(1)[0] as char {
-                    't' => {
-                        buf.advance(14);
-                        let timestamp = Duration::from_millis(buf.get_u32() as u64);
-                        ServerMessage::Status(timestamp)
+                's' => {
+                    let autostart = match buf.split_to(1)[0] as char {
+                        '0' => AutoStart::None,
+                        '1' => AutoStart::Auto,
+                        '2' => AutoStart::Direct,
+                        '3' => AutoStart::AutoDirect,
+                        value => return Err(DecodeError::invalid("autostart", value as u8)),
+                    };
+
+                    let format = match buf.split_to(1)[0] as char {
+                        'p' => Format::Pcm,
+                        'm' => Format::Mp3,
+                        'f' => Format::Flac,
+                        'w' => Format::Wma,
+                        'o' => Format::Ogg,
+                        'a' => Format::Aac,
+                        'l' => Format::Alac,
+                        value => return Err(DecodeError::invalid("format", value as u8)),
+                    };
+
+                    let pcmsamplesize = match buf.split_to(1)[0] as char {
+                        '0' => PcmSampleSize::Eight,
+                        '1' => PcmSampleSize::Sixteen,
+                        '2' => PcmSampleSize::Twenty,
+                        '3' => PcmSampleSize::ThirtyTwo,
+                        '?' => PcmSampleSize::SelfDescribing,
+                        value => return Err(DecodeError::invalid("pcmsamplesize", value as u8)),
+                    };
+
+                    let pcmsamplerate = match buf.split_to(1)[0] as char {
+                        '0' => PcmSampleRate::Rate(11_000),
+                        '1' => PcmSampleRate::Rate(22_000),
+                        '2' => PcmSampleRate::Rate(32_000),
+                        '3' => PcmSampleRate::Rate(44_100),
+                        '4' => PcmSampleRate::Rate(48_000),
+                        '5' => PcmSampleRate::Rate(8_000),
+                        '6' => PcmSampleRate::Rate(12_000),
+                        '7' => PcmSampleRate::Rate(16_000),
+                        '8' => PcmSampleRate::Rate(24_000),
+                        '9' => PcmSampleRate::Rate(96_000),
+                        '?' => PcmSampleRate::SelfDescribing,
+                        value => return Err(DecodeError::invalid("pcmsamplerate", value as u8)),
+                    };
+
+                    let pcmchannels = match buf.split_to(1)[0] as char {
+                        '1' => PcmChannels::Mono,
+                        '2' => PcmChannels::Stereo,
+                        '?' => PcmChannels::SelfDescribing,
+                        value => return Err(DecodeError::invalid("pcmchannels", value as u8)),
+                    };
+
+                    let pcmendian = match buf.split_to(1)[0] as char {
+                        '0' => PcmEndian::Big,
+                        '1' => PcmEndian::Little,
+                        '?' => PcmEndian::SelfDescribing,
+                        value => return Err(DecodeError::invalid("pcmendian", value as u8)),
+                    };
+
+                    let threshold = Threshold::from_kb(buf.split_to(1)[0] as u32);
+
+                    let spdif_enable = match buf.split_to(1)[0] {
+                        0 => SpdifEnable::Auto,
+                        1 => SpdifEnable::On,
+                        2 => SpdifEnable::Off,
+                        value => return Err(DecodeError::invalid("spdif_enable", value)),
+                    };
+
+                    let trans_period = Duration::from_secs(buf.split_to(1)[0] as u64);
+
+                    let trans_type = match buf.split_to(1)[0] as char {
+                        '0' => TransType::None,
+                        '1' => TransType::Crossfade,
+                        '2' => TransType::FadeIn,
+                        '3' => TransType::FadeOut,
+                        '4' => TransType::FadeInOut,
+                        value => return Err(DecodeError::invalid("trans_type", value as u8)),
+                    };
+
+                    let flags = StreamFlags::from_bits_retain(buf.split_to(1)[0]);
+
+                    // In tenths of a second
+                    let output_threshold = Duration::from_millis(buf.split_to(1)[0] as u64 * 100);
+
+                    buf.advance(1);
+
+                    let replay_gain = Gain::from_fixed(buf.split_to(4).get_u32());
+
+                    let server_port = buf.split_to(2).get_u16();
+
+                    let server_ip = Ipv4Addr::from(buf.split_to(4).get_u32());
+
+                    let http_headers = if !buf.is_empty() {
+                        options.check_text("http_headers", &buf)?;
+                        Some(String::from_utf8_lossy(&buf).to_string())
+                    } else {
+                        None
+                    };
+
+                    ServerMessage::Stream {
+                        autostart,
+                        format,
+                        pcmsamplesize,
+                        pcmsamplerate,
+                        pcmchannels,
+                        pcmendian,
+                        threshold,
+                        spdif_enable,
+                        trans_period,
+                        trans_type,
+                        flags,
+                        output_threshold,
+                        replay_gain,
+                        server_port,
+                        server_ip,
+                        http_headers,
+                        raw,
                     }
+                }
 
-                    's' => {
-                        let autostart = match buf.split_to(1)[0] as char {
-                            '0' => AutoStart::None,
-                            '1' => AutoStart::Auto,
-                            '2' => AutoStart::Direct,
-                            '3' => AutoStart::AutoDirect,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let format = match buf.split_to(1)[0] as char {
-                            'p' => Format::Pcm,
-                            'm' => Format::Mp3,
-                            'f' => Format::Flac,
-                            'w' => Format::Wma,
-                            'o' => Format::Ogg,
-                            'a' => Format::Aac,
-                            'l' => Format::Alac,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let pcmsamplesize = match buf.split_to(1)[0] as char {
-                            '0' => PcmSampleSize::Eight,
-                            '1' => PcmSampleSize::Sixteen,
-                            '2' => PcmSampleSize::Twenty,
-                            '3' => PcmSampleSize::ThirtyTwo,
-                            '?' => PcmSampleSize::SelfDescribing,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let pcmsamplerate = match buf.split_to(1)[0] as char {
-                            '0' => PcmSampleRate::Rate(11_000),
-                            '1' => PcmSampleRate::Rate(22_000),
-                            '2' => PcmSampleRate::Rate(32_000),
-                            '3' => PcmSampleRate::Rate(44_100),
-                            '4' => PcmSampleRate::Rate(48_000),
-                            '5' => PcmSampleRate::Rate(8_000),
-                            '6' => PcmSampleRate::Rate(12_000),
-                            '7' => PcmSampleRate::Rate(16_000),
-                            '8' => PcmSampleRate::Rate(24_000),
-                            '9' => PcmSampleRate::Rate(96_000),
-                            '?' => PcmSampleRate::SelfDescribing,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let pcmchannels = match buf.split_to(1)[0] as char {
-                            '1' => PcmChannels::Mono,
-                            '2' => PcmChannels::Stereo,
-                            '?' => PcmChannels::SelfDescribing,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let pcmendian = match buf.split_to(1)[0] as char {
-                            '0' => PcmEndian::Big,
-                            '1' => PcmEndian::Little,
-                            '?' => PcmEndian::SelfDescribing,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let threshold = buf.split_to(1)[0] as u32 * 1024u32;
-
-                        let spdif_enable = match buf.split_to(1)[0] {
-                            0 => SpdifEnable::Auto,
-                            1 => SpdifEnable::On,
-                            2 => SpdifEnable::Off,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let trans_period = Duration::from_secs(buf.split_to(1)[0] as u64);
-
-                        let trans_type = match buf.split_to(1)[0] as char {
-                            '0' => TransType::None,
-                            '1' => TransType::Crossfade,
-                            '2' => TransType::FadeIn,
-                            '3' => TransType::FadeOut,
-                            '4' => TransType::FadeInOut,
-                            _ => return ServerMessage::Error,
-                        };
-
-                        let flags = StreamFlags::from_bits(buf.split_to(1)[0]).unwrap_or_default();
-
-                        let output_threshold =
-                            Duration::from_millis(buf.split_to(1)[0] as u64 * 10);
-
-                        buf.advance(1);
-
-                        let replay_gain = buf.split_to(4).get_u32() as f64 / GAIN_FACTOR;
-
-                        let server_port = buf.split_to(2).get_u16();
-
-                        let server_ip = Ipv4Addr::from(buf.split_to(4).get_u32());
-
-                        let http_headers = if buf.len() > 0 {
-                            Some(String::from_utf8_lossy(&buf).to_string())
-                        } else {
-                            None
-                        };
-
-                        ServerMessage::Stream {
-                            autostart,
-                            format,
-                            pcmsamplesize,
-                            pcmsamplerate,
-                            pcmchannels,
-                            pcmendian,
-                            threshold,
-                            spdif_enable,
-                            trans_period,
-                            trans_type,
-                            flags,
-                            output_threshold,
-                            replay_gain,
-                            server_port,
-                            server_ip,
-                            http_headers,
-                        }
-                    }
+                'q' => ServerMessage::Stop,
 
-                    'q' => ServerMessage::Stop,
+                'f' => ServerMessage::Flush,
 
-                    'f' => ServerMessage::Flush,
+                'p' => {
+                    buf.advance(13);
+                    ServerMessage::Pause(PauseKind::from_interval(Duration::from_millis(
+                        buf.get_u32().into(),
+                    )))
+                }
 
-                    'p' => {
-                        buf.advance(13);
-                        let timestamp = buf.get_u32();
-                        ServerMessage::Pause(Duration::from_millis(timestamp as u64))
-                    }
+                'u' => {
+                    buf.advance(13);
+                    ServerMessage::Unpause(Jiffies::from_millis(buf.get_u32()).into())
+                }
 
-                    'u' => {
-                        buf.advance(13);
-                        let timestamp = buf.get_u32();
-                        ServerMessage::Unpause(Duration::from_millis(timestamp as u64))
-                    }
+                'a' => {
+                    buf.advance(13);
+                    ServerMessage::Skip(Jiffies::from_millis(buf.get_u32()))
+                }
+
+                cmd if options.unknown_strm_is_error => {
+                    return Err(DecodeError::invalid("command", cmd as u8))
+                }
+
+                cmd => {
+                    let mut msg = msg.to_owned();
+                    msg.push('_');
+                    msg.push(cmd);
+                    ServerMessage::Unrecognised(msg)
+                }
+            }
+        }
+
+        "aude" => {
+            if buf.len() < 2 {
+                return Err(DecodeError::too_short(&buf, 2));
+            }
+
+            ServerMessage::Enable(OutputEnable {
+                spdif: buf[0] != 0,
+                dac: buf[1] != 0,
+            })
+        }
+
+        "audg" => {
+            if buf.len() < 18 {
+                return Err(DecodeError::too_short(&buf, 18));
+            }
+
+            let old_left = buf.split_to(4).get_u32();
+            let old_right = buf.split_to(4).get_u32();
+            let digital_volume = buf.split_to(1)[0] != 0;
+            let preamp = buf.split_to(1)[0];
+            let left = options.gain(buf.split_to(4).get_u32());
+            let right = options.gain(buf.split_to(4).get_u32());
+            ServerMessage::Gain {
+                left,
+                right,
+                old_left,
+                old_right,
+                digital_volume,
+                preamp,
+            }
+        }
 
-                    'a' => {
-                        buf.advance(13);
-                        let timestamp = buf.get_u32();
-                        ServerMessage::Skip(Duration::from_millis(timestamp as u64))
+        "setd" => {
+            if buf.is_empty() {
+                return Err(DecodeError::too_short(&buf, 1));
+            }
+
+            match buf.split_to(1)[0] {
+                0 => {
+                    if buf.is_empty() {
+                        ServerMessage::Queryname
+                    } else {
+                        options.check_text("name", &buf)?;
+                        ServerMessage::Setname(PlayerName::from_bytes(&buf))
                     }
+                }
+
+                4 => ServerMessage::DisableDac,
+
+                v => match (PlayerSetting::from_id(v), buf.first()) {
+                    (Some(setting), None) => ServerMessage::QuerySetting(setting),
+                    (Some(setting), Some(value)) => ServerMessage::SetSetting(setting, *value),
+                    (None, _) => ServerMessage::Unrecognised(format!("This SETD is unused: {}", v)),
+                },
+            }
+        }
+
+        "cont" => {
+            if buf.len() < 5 {
+                return Err(DecodeError::too_short(&buf, 5));
+            }
+
+            let metaint = buf.split_to(4).get_u32();
+            let loop_stream = buf.split_to(1)[0] != 0;
+            ServerMessage::Cont {
+                metaint,
+                loop_stream,
+            }
+        }
+
+        "updn" => ServerMessage::UpgradeNow,
+
+        "upda" => ServerMessage::UpgradeData(buf.to_vec()),
+
+        #[cfg(feature = "chrono")]
+        "rtcs" => {
+            if buf.is_empty() {
+                return Err(DecodeError::too_short(&buf, 1));
+            }
+
+            // As the Boom is sent them, the time in binary coded decimal
+            match buf[0] {
+                1 if buf.len() >= 2 => {
+                    ServerMessage::SetClock(ClockSetting::TwelveHour(buf[1] != 0))
+                }
+                2 if buf.len() >= 4 => {
+                    let hour = from_bcd("hour", buf[1])?;
+                    let minute = from_bcd("minute", buf[2])?;
+                    let second = from_bcd("second", buf[3])?;
+                    let time = chrono::NaiveTime::from_hms_opt(hour, minute, second)
+                        .ok_or_else(|| DecodeError::invalid("time", buf[1]))?;
+                    ServerMessage::SetClock(ClockSetting::Time(time))
+                }
+                1 => return Err(DecodeError::too_short(&buf, 2)),
+                2 => return Err(DecodeError::too_short(&buf, 4)),
+                cmd => return Err(DecodeError::invalid("rtcs command", cmd)),
+            }
+        }
+
+        #[cfg(feature = "display")]
+        "grfe" => {
+            if buf.len() < 4 {
+                return Err(DecodeError::too_short(&buf, 4));
+            }
+
+            ServerMessage::Graphics(crate::display::GraphicsFrame {
+                offset: buf.get_u16(),
+                transition: buf.get_u8(),
+                param: buf.get_u8(),
+                bitmap: buf.to_vec(),
+            })
+        }
+
+        "vers" => {
+            options.check_text("version", &buf)?;
+            ServerMessage::Version(String::from_utf8_lossy(&buf).trim().to_owned())
+        }
+
+        cmd => ServerMessage::Unrecognised(cmd.to_owned()),
+    })
+}
+
+#[cfg(feature = "chrono")]
+fn from_bcd(field: &str, value: u8) -> Result<u32, DecodeError> {
+    let (tens, units) = (value >> 4, value & 0x0f);
+    if tens > 9 || units > 9 {
+        return Err(DecodeError::invalid(field, value));
+    }
+    Ok((tens * 10 + units) as u32)
+}
+
+#[cfg(all(feature = "chrono", feature = "std"))]
+fn to_bcd(value: u32) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+/// The server's side of [SlimCodec], encoding server messages and decoding client messages.
+///
+/// This is useful for mock servers in tests and for checking that messages survive
+/// a round trip through the codec. Messages that the server never sends, such as
+/// [Unrecognised](ServerMessage::Unrecognised), cannot be encoded.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServerCodec;
+
+#[cfg(feature = "std")]
+impl Encoder<ServerMessage> for ServerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ServerMessage, dst: &mut BytesMut) -> io::Result<()> {
+        let mut frame = BytesMut::new();
+        let strm = |frame: &mut BytesMut, cmd: u8, offset: usize, time: Option<Jiffies>| {
+            frame.put("strm".as_bytes());
+            frame.put_u8(cmd);
+            frame.put_bytes(0, offset);
+            if let Some(time) = time {
+                frame.put_u32(time.as_millis());
+            }
+            frame.resize(STRM_LEN + 4, 0);
+        };
 
-                    cmd @ _ => {
-                        let mut msg = msg.to_owned();
-                        msg.push('_');
-                        msg.push(cmd);
-                        ServerMessage::Unrecognised(msg)
+        match item {
+            ServerMessage::Serv {
+                address,
+                sync_group_id,
+            } => {
+                frame.put("serv".as_bytes());
+                match address {
+                    ServerAddress::Ip(ip) => frame.put_u32(ip.into()),
+                    ServerAddress::Hostname(hostname) if sync_group_id.is_none() => {
+                        frame.put(hostname.as_bytes())
+                    }
+                    ServerAddress::Hostname(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "A sync group cannot be sent with a hostname",
+                        ))
                     }
                 }
+                if let Some(sync_group) = sync_group_id {
+                    frame.put(
+                        sync_group
+                            .as_str()
+                            .chars()
+                            .map(|c| c as u8)
+                            .collect::<Vec<u8>>()
+                            .as_ref(),
+                    );
+                }
             }
 
-            "aude" => {
-                if buf.len() < 2 {
-                    return ServerMessage::Error;
+            ServerMessage::Status(timestamp) => strm(&mut frame, b't', 13, Some(timestamp)),
+            ServerMessage::Stop => strm(&mut frame, b'q', 0, None),
+            ServerMessage::Flush => strm(&mut frame, b'f', 0, None),
+            ServerMessage::Pause(when) => strm(&mut frame, b'p', 13, Some(when.jiffies())),
+            ServerMessage::Unpause(when) => strm(&mut frame, b'u', 13, Some(when.jiffies())),
+            ServerMessage::Skip(by) => strm(&mut frame, b'a', 13, Some(by)),
+
+            ServerMessage::Stream {
+                autostart,
+                format,
+                pcmsamplesize,
+                pcmsamplerate,
+                pcmchannels,
+                pcmendian,
+                threshold,
+                spdif_enable,
+                trans_period,
+                trans_type,
+                flags,
+                output_threshold,
+                replay_gain,
+                server_port,
+                server_ip,
+                http_headers,
+                ..
+            } => {
+                frame.put("strms".as_bytes());
+                frame.put_u8(match autostart {
+                    AutoStart::None => b'0',
+                    AutoStart::Auto => b'1',
+                    AutoStart::Direct => b'2',
+                    AutoStart::AutoDirect => b'3',
+                });
+                frame.put_u8(match format {
+                    Format::Pcm => b'p',
+                    Format::Mp3 => b'm',
+                    Format::Flac => b'f',
+                    Format::Wma => b'w',
+                    Format::Ogg => b'o',
+                    Format::Aac => b'a',
+                    Format::Alac => b'l',
+                });
+                frame.put_u8(match pcmsamplesize {
+                    PcmSampleSize::Eight => b'0',
+                    PcmSampleSize::Sixteen => b'1',
+                    PcmSampleSize::Twenty => b'2',
+                    PcmSampleSize::ThirtyTwo => b'3',
+                    PcmSampleSize::SelfDescribing => b'?',
+                });
+                frame.put_u8(match pcmsamplerate {
+                    PcmSampleRate::Rate(11_000) => b'0',
+                    PcmSampleRate::Rate(22_000) => b'1',
+                    PcmSampleRate::Rate(32_000) => b'2',
+                    PcmSampleRate::Rate(44_100) => b'3',
+                    PcmSampleRate::Rate(48_000) => b'4',
+                    PcmSampleRate::Rate(8_000) => b'5',
+                    PcmSampleRate::Rate(12_000) => b'6',
+                    PcmSampleRate::Rate(16_000) => b'7',
+                    PcmSampleRate::Rate(24_000) => b'8',
+                    PcmSampleRate::Rate(96_000) => b'9',
+                    PcmSampleRate::SelfDescribing => b'?',
+                    PcmSampleRate::Rate(rate) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("Sample rate {} cannot be sent", rate),
+                        ))
+                    }
+                });
+                frame.put_u8(match pcmchannels {
+                    PcmChannels::Mono => b'1',
+                    PcmChannels::Stereo => b'2',
+                    PcmChannels::SelfDescribing => b'?',
+                });
+                frame.put_u8(match pcmendian {
+                    PcmEndian::Big => b'0',
+                    PcmEndian::Little => b'1',
+                    PcmEndian::SelfDescribing => b'?',
+                });
+                frame.put_u8(threshold.as_kb().min(u8::MAX as u32) as u8);
+                frame.put_u8(match spdif_enable {
+                    SpdifEnable::Auto => 0,
+                    SpdifEnable::On => 1,
+                    SpdifEnable::Off => 2,
+                });
+                frame.put_u8(trans_period.as_secs().min(u8::MAX as u64) as u8);
+                frame.put_u8(match trans_type {
+                    TransType::None => b'0',
+                    TransType::Crossfade => b'1',
+                    TransType::FadeIn => b'2',
+                    TransType::FadeOut => b'3',
+                    TransType::FadeInOut => b'4',
+                });
+                frame.put_u8(flags.bits());
+                frame.put_u8((output_threshold.as_millis() / 100).min(u8::MAX as u128) as u8);
+                frame.put_u8(0);
+                frame.put_u32(replay_gain.as_fixed());
+                frame.put_u16(server_port);
+                frame.put_u32(server_ip.into());
+                if let Some(headers) = http_headers {
+                    frame.put(headers.as_bytes());
                 }
+            }
+
+            ServerMessage::Enable(OutputEnable { spdif, dac }) => {
+                frame.put("aude".as_bytes());
+                frame.put_u8(spdif as u8);
+                frame.put_u8(dac as u8);
+            }
+
+            ServerMessage::Gain {
+                left,
+                right,
+                old_left,
+                old_right,
+                digital_volume,
+                preamp,
+            } => {
+                frame.put("audg".as_bytes());
+                frame.put_u32(old_left);
+                frame.put_u32(old_right);
+                frame.put_u8(digital_volume as u8);
+                frame.put_u8(preamp);
+                frame.put_u32(left.0);
+                frame.put_u32(right.0);
+            }
+
+            ServerMessage::Queryname => {
+                frame.put("setd".as_bytes());
+                frame.put_u8(0);
+            }
+
+            ServerMessage::Setname(name) => {
+                frame.put("setd".as_bytes());
+                frame.put_u8(0);
+                frame.put(name.as_str().as_bytes());
+                frame.put_u8(0);
+            }
+
+            ServerMessage::DisableDac => {
+                frame.put("setd".as_bytes());
+                frame.put_u8(4);
+            }
+
+            ServerMessage::QuerySetting(setting) => {
+                frame.put("setd".as_bytes());
+                frame.put_u8(setting.id());
+            }
+
+            ServerMessage::SetSetting(setting, value) => {
+                frame.put("setd".as_bytes());
+                frame.put_u8(setting.id());
+                frame.put_u8(value);
+            }
+
+            ServerMessage::Cont {
+                metaint,
+                loop_stream,
+            } => {
+                frame.put("cont".as_bytes());
+                frame.put_u32(metaint);
+                frame.put_u8(loop_stream as u8);
+            }
+
+            ServerMessage::UpgradeNow => frame.put("updn".as_bytes()),
 
-                let (spdif, dac) = (buf[0] != 0, buf[1] != 0);
-                ServerMessage::Enable(spdif, dac)
+            ServerMessage::UpgradeData(data) => {
+                frame.put("upda".as_bytes());
+                frame.put(data.as_slice());
             }
 
-            "audg" => {
-                if buf.len() < 18 {
-                    return ServerMessage::Error;
+            ServerMessage::Version(version) => {
+                frame.put("vers".as_bytes());
+                frame.put(version.as_bytes());
+            }
+
+            #[cfg(feature = "display")]
+            ServerMessage::Graphics(graphics) => {
+                frame.put("grfe".as_bytes());
+                frame.put_u16(graphics.offset);
+                frame.put_u8(graphics.transition);
+                frame.put_u8(graphics.param);
+                frame.put(&graphics.bitmap[..]);
+            }
+
+            #[cfg(feature = "chrono")]
+            ServerMessage::SetClock(setting) => {
+                use chrono::Timelike;
+
+                frame.put("rtcs".as_bytes());
+                match setting {
+                    ClockSetting::TwelveHour(twelve_hour) => {
+                        frame.put_u8(1);
+                        frame.put_u8(twelve_hour as u8);
+                    }
+                    ClockSetting::Time(time) => {
+                        frame.put_u8(2);
+                        frame.put_u8(to_bcd(time.hour()));
+                        frame.put_u8(to_bcd(time.minute()));
+                        frame.put_u8(to_bcd(time.second()));
+                    }
                 }
+            }
+
+            msg @ (ServerMessage::Unrecognised(_) | ServerMessage::Malformed { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Cannot encode {}", msg),
+                ))
+            }
+        }
+
+        let frame_size = u16::try_from(frame.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"))?;
+        dst.put_u16(frame_size);
+        dst.extend(frame);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Decoder for ServerCodec {
+    type Item = ClientMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ClientMessage>> {
+        if buf.len() < 8 {
+            return Ok(None);
+        }
 
-                buf.advance(10);
-                let left = buf.split_to(4).get_u32() as f64 / GAIN_FACTOR;
-                let right = buf.split_to(4).get_u32() as f64 / GAIN_FACTOR;
-                ServerMessage::Gain(left, right)
+        let frame_size = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+        if buf.len() < frame_size + 8 {
+            return Ok(None);
+        }
+
+        let msg = buf.split_to(4);
+        buf.advance(4);
+        let mut frame = buf.split_to(frame_size);
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Client data corrupted");
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| invalid());
+
+        let msg = match &msg[..] {
+            b"HELO" => {
+                if frame.len() < 36 {
+                    return Err(invalid());
+                }
+                let device_id = frame.get_u8();
+                let revision = frame.get_u8();
+                let mac = crate::proto::MacAddress::new(frame.split_to(6)[..].try_into().unwrap());
+                let uuid = frame.split_to(16)[..].try_into().unwrap();
+                let wlan_channel_list = frame.get_u16();
+                let bytes_received = frame.get_u64();
+                let language = [frame.get_u8() as char, frame.get_u8() as char];
+                ClientMessage::Helo {
+                    device_id,
+                    revision,
+                    mac,
+                    uuid,
+                    wlan_channel_list,
+                    bytes_received,
+                    language,
+                    capabilities: text(&frame)?,
+                }
             }
 
-            "setd" => {
-                if buf.len() == 0 {
-                    return ServerMessage::Error;
+            b"BYE!" if !frame.is_empty() => ClientMessage::Bye(frame[0]),
+
+            b"STAT" => {
+                if frame.len() < 53 {
+                    return Err(invalid());
+                }
+                let event_code = text(&frame.split_to(4))?;
+                let crlf = frame.get_u8();
+                frame.advance(2);
+                let stat_data = StatusData {
+                    crlf,
+                    buffer_size: frame.get_u32(),
+                    fullness: frame.get_u32(),
+                    bytes_received: frame.get_u64(),
+                    sig_strength: frame.get_u16(),
+                    jiffies: Jiffies::from_millis(frame.get_u32()),
+                    output_buffer_size: frame.get_u32(),
+                    output_buffer_fullness: frame.get_u32(),
+                    elapsed_seconds: frame.get_u32(),
+                    voltage: frame.get_u16(),
+                    elapsed_milliseconds: frame.get_u32(),
+                    timestamp: Jiffies::from_millis(frame.get_u32()),
+                    error_code: frame.get_u16(),
+                    ..Default::default()
+                };
+                ClientMessage::Stat {
+                    event_code,
+                    stat_data,
                 }
+            }
 
-                match buf.split_to(1)[0] {
-                    0 => {
-                        if buf.len() == 0 {
-                            ServerMessage::Queryname
-                        } else {
-                            let name = String::from_utf8(buf[..buf.len() - 1].to_vec())
-                                .unwrap_or_default();
-                            ServerMessage::Setname(name)
-                        }
-                    }
+            b"SETD" if frame.first() == Some(&0) => {
+                ClientMessage::Name(PlayerName::from_bytes(&frame[1..]))
+            }
+
+            b"SETD" => match frame[..] {
+                [id, value] => {
+                    ClientMessage::Setting(PlayerSetting::from_id(id).ok_or_else(invalid)?, value)
+                }
+                _ => return Err(invalid()),
+            },
 
-                    4 => ServerMessage::DisableDac,
+            b"RESP" => ClientMessage::Resp(text(&frame)?),
 
-                    v @ _ => ServerMessage::Unrecognised(format!("This SETD is unused: {}", v)),
+            b"BODY" => {
+                if frame.len() < 4 || frame.get_u32() as usize != frame.len() {
+                    return Err(invalid());
                 }
+                ClientMessage::Body(frame.to_vec())
             }
 
-            cmd @ _ => ServerMessage::Unrecognised(cmd.to_owned()),
-        }
+            b"META" => ClientMessage::Meta(frame.to_vec()),
+
+            b"UREQ" => ClientMessage::UpgradeRequest,
+
+            b"IR  " if frame.len() == 10 => ClientMessage::Ir {
+                time: Jiffies::from_millis(frame.get_u32()),
+                format: frame.get_u8(),
+                bits: frame.get_u8(),
+                code: frame.get_u32(),
+            },
+
+            _ => return Err(invalid()),
+        };
+
+        Ok(Some(msg))
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
-    use std::time::Instant;
-
     use super::*;
     use crate::status::StatusData;
-    use framous::{FramedRead, FramedReader, FramedWrite, FramedWriter};
+    use framous::{FramedRead, FramedWrite};
 
-    use mac_address::MacAddress;
+    use crate::proto::MacAddress;
 
     fn do_send(mut buf: &mut [u8], frame: ClientMessage) {
-        let mut framed = FramedWrite::new(&mut buf, SlimCodec);
+        let mut framed = FramedWrite::new(&mut buf, SlimCodec::new());
         framed.framed_write(frame).unwrap();
     }
 
@@ -432,19 +1562,19 @@ mod tests {
             fullness: 5678,
             bytes_received: 9123,
             sig_strength: 45,
-            jiffies: Duration::from_millis(6789),
+            jiffies: Jiffies::from_millis(6789),
             output_buffer_size: 1234,
             output_buffer_fullness: 5678,
             elapsed_seconds: 9012,
             voltage: 3456,
             elapsed_milliseconds: 7890,
-            timestamp: Duration::from_millis(1234),
+            timestamp: Jiffies::from_millis(1234),
             error_code: 5678,
-            start: Instant::now(),
+            ..Default::default()
         };
         let stat = ClientMessage::Stat {
             event_code: "STMt".to_owned(),
-            stat_data: stat_data,
+            stat_data,
         };
 
         let mut buf = [0u8; 61];
@@ -464,11 +1594,47 @@ mod tests {
                 210, 0, 0, 4, 210, 22, 46
             ]
         );
+
+        let mut buf = BytesMut::new();
+        SlimCodec::new()
+            .encode(
+                ClientMessage::Stat {
+                    event_code: "STMé".to_owned(),
+                    stat_data: StatusData::default(),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(buf.len(), 61);
+        assert_eq!(&buf[4..12], &[0, 0, 0, 53, b'S', b'T', b'M', 0xc3]);
+    }
+
+    #[test]
+    fn send_stat_after_wrap() {
+        // A player that has been up for more than 49.7 days
+        let uptime = Duration::from_millis(u32::MAX as u64 + 1001);
+        let stat_data = StatusData {
+            jiffies: Jiffies::from(uptime),
+            timestamp: Jiffies::from_millis(u32::MAX),
+            ..Default::default()
+        };
+        let stat = ClientMessage::Stat {
+            event_code: "STMt".to_owned(),
+            stat_data,
+        };
+
+        let mut buf = [0u8; 61];
+        do_send(&mut buf, stat.clone());
+        assert_eq!(&buf[33..37], &[0, 0, 3, 232]);
+        assert_eq!(&buf[55..59], &[255, 255, 255, 255]);
+
+        let mut frame = BytesMut::from(&buf[..]);
+        assert_eq!(ServerCodec.decode(&mut frame).unwrap(), Some(stat));
     }
 
     #[test]
     fn send_name() {
-        let name = ClientMessage::Name("BadBoy".to_owned());
+        let name = ClientMessage::Name(PlayerName::new("BadBoy").unwrap());
 
         let mut buf = [0u8; 15];
         do_send(&mut buf, name);
@@ -479,45 +1645,151 @@ mod tests {
         );
     }
 
+    #[test]
+    fn send_setting() {
+        let setting = ClientMessage::Setting(PlayerSetting::PowerOffDac, 1);
+
+        let mut buf = [0u8; 10];
+        do_send(&mut buf, setting);
+
+        assert_eq!(&buf[..], &[b'S', b'E', b'T', b'D', 0, 0, 0, 2, 3, 1]);
+    }
+
+    #[test]
+    fn send_resp() {
+        let resp = ClientMessage::Resp("HTTP/1.0 200 OK\r\n\r\n".to_owned());
+
+        let mut buf = [0u8; 27];
+        do_send(&mut buf, resp);
+
+        assert_eq!(&buf[..8], &[b'R', b'E', b'S', b'P', 0, 0, 0, 19]);
+        assert_eq!(&buf[8..], b"HTTP/1.0 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn send_meta() {
+        let meta = ClientMessage::Meta(b"StreamTitle='a';".to_vec());
+
+        let mut buf = [0u8; 24];
+        do_send(&mut buf, meta);
+
+        assert_eq!(&buf[..8], &[b'M', b'E', b'T', b'A', 0, 0, 0, 16]);
+        assert_eq!(&buf[8..], b"StreamTitle='a';");
+    }
+
+    #[test]
+    fn send_body() {
+        let body = ClientMessage::Body(b"abc".to_vec());
+
+        let mut buf = [0u8; 15];
+        do_send(&mut buf, body);
+
+        assert_eq!(
+            &buf[..],
+            &[b'B', b'O', b'D', b'Y', 0, 0, 0, 7, 0, 0, 0, 3, b'a', b'b', b'c']
+        );
+    }
+
+    #[test]
+    fn send_ir() {
+        let ir = ClientMessage::Ir {
+            time: Jiffies::from_millis(0x0102_0304),
+            format: 0,
+            bits: 32,
+            code: 0x7689_10ef,
+        };
+
+        let mut buf = [0u8; 18];
+        do_send(&mut buf, ir.clone());
+
+        assert_eq!(
+            &buf[..],
+            &[b'I', b'R', b' ', b' ', 0, 0, 0, 10, 1, 2, 3, 4, 0, 32, 0x76, 0x89, 0x10, 0xef]
+        );
+        let mut frame = BytesMut::from(&buf[..]);
+        assert_eq!(ServerCodec.decode(&mut frame).unwrap(), Some(ir));
+    }
+
     #[test]
     fn recv_serv() {
         let buf = [
             0u8, 12, b's', b'e', b'r', b'v', 172, 16, 1, 2, b's', b'y', b'n', b'c',
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Serv {
-            ip_address,
+            address,
             sync_group_id,
         }) = framed.framed_read()
         {
-            assert_eq!(ip_address, Ipv4Addr::new(172, 16, 1, 2));
-            assert_eq!(sync_group_id, Some("sync".to_owned()));
+            assert_eq!(address, ServerAddress::Ip(Ipv4Addr::new(172, 16, 1, 2)));
+            assert_eq!(sync_group_id, Some(SyncGroup::new("sync")));
         } else {
             panic!("SERV message not received");
         }
     }
 
+    #[test]
+    fn serv_hostname() {
+        let buf = [
+            0u8, 19, b's', b'e', b'r', b'v', b'l', b'm', b's', b'.', b'e', b'x', b'a', b'm', b'p',
+            b'l', b'e', b'.', b'c', b'o', b'm',
+        ];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        let serv = ServerMessage::Serv {
+            address: ServerAddress::Hostname("lms.example.com".to_owned()),
+            sync_group_id: None,
+        };
+        assert_eq!(framed.framed_read().unwrap(), serv);
+
+        let mut frame = BytesMut::new();
+        ServerCodec.encode(serv, &mut frame).unwrap();
+        assert_eq!(&frame[..], &buf[..]);
+
+        let serv = ServerMessage::Serv {
+            address: ServerAddress::Hostname("lms.example.com".to_owned()),
+            sync_group_id: Some("1234".into()),
+        };
+        assert!(ServerCodec.encode(serv, &mut frame).is_err());
+    }
+
     #[test]
     fn recv_status() {
         let buf = [
             0u8, 28, b's', b't', b'r', b'm', b't', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Status(d)) = framed.framed_read() {
-            assert_eq!(d, Duration::from_millis(252711186));
+            assert_eq!(d, Jiffies::from_millis(0x0e0f_1011));
         } else {
             panic!("STRMt message not received");
         }
     }
 
+    #[test]
+    fn recv_status_from_lms() {
+        // As packed by Slim::Player::Squeezebox::stream, the timestamp is sent
+        // in the replay gain field
+        let mut buf = vec![0u8, 28];
+        buf.extend_from_slice(b"strmt0m????");
+        buf.extend_from_slice(&[0, 0, 0, b'0', 0, 0, 0]);
+        buf.extend_from_slice(&0x0012_d687u32.to_be_bytes());
+        buf.extend_from_slice(&[0; 6]);
+
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::Status(Jiffies::from_millis(1_234_567))
+        );
+    }
+
     #[test]
     fn recv_stop() {
         let buf = [
             0u8, 28, b's', b't', b'r', b'm', b'q', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Stop) = framed.framed_read() {
         } else {
             panic!("STRMq message not received");
@@ -530,9 +1802,9 @@ mod tests {
             0u8, 28, b's', b't', b'r', b'm', b'p', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Pause(p)) = framed.framed_read() {
-            assert_eq!(p, Duration::from_millis(235868177));
+            assert_eq!(p, PauseKind::For(Duration::from_millis(235868177)));
         } else {
             panic!("STRMp message not received");
         }
@@ -544,9 +1816,9 @@ mod tests {
             0u8, 28, b's', b't', b'r', b'm', b'u', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Unpause(p)) = framed.framed_read() {
-            assert_eq!(p, Duration::from_millis(235868177));
+            assert_eq!(p, PauseKind::At(Jiffies::from_millis(235868177)));
         } else {
             panic!("STRMu message not received");
         }
@@ -558,9 +1830,9 @@ mod tests {
             0u8, 28, b's', b't', b'r', b'm', b'a', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Skip(p)) = framed.framed_read() {
-            assert_eq!(p, Duration::from_millis(235868177));
+            assert_eq!(p, Jiffies::from_millis(235868177));
         } else {
             panic!("STRMa message not received");
         }
@@ -572,7 +1844,7 @@ mod tests {
             0u8, 28, b's', b't', b'r', b'm', b'x', 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
             15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Unrecognised(s)) = framed.framed_read() {
             assert_eq!(s, "strm_x".to_owned());
         } else {
@@ -583,25 +1855,255 @@ mod tests {
     #[test]
     fn recv_enable() {
         let buf = [0u8, 6, b'a', b'u', b'd', b'e', 0, 1];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
-        if let Ok(ServerMessage::Enable(a, b)) = framed.framed_read() {
-            assert_eq!((a, b), (false, true));
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        if let Ok(ServerMessage::Enable(outputs)) = framed.framed_read() {
+            assert_eq!(
+                outputs,
+                OutputEnable {
+                    spdif: false,
+                    dac: true
+                }
+            );
         } else {
             panic!("AUDE message not received");
         }
     }
 
+    #[test]
+    fn recv_cont() {
+        let buf = [0u8, 11, b'c', b'o', b'n', b't', 0, 0, 0x3e, 0x80, 0, 0, 0];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        if let Ok(ServerMessage::Cont {
+            metaint,
+            loop_stream,
+        }) = framed.framed_read()
+        {
+            assert_eq!(metaint, 16000);
+            assert!(!loop_stream);
+        } else {
+            panic!("CONT message not received");
+        }
+    }
+
+    #[test]
+    fn upgrade() {
+        let mut buf = [0u8; 8];
+        do_send(&mut buf, ClientMessage::UpgradeRequest);
+        assert_eq!(buf, [b'U', b'R', b'E', b'Q', 0, 0, 0, 0]);
+
+        let buf = [0u8, 4, b'u', b'p', b'd', b'n'];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        assert_eq!(framed.framed_read().unwrap(), ServerMessage::UpgradeNow);
+
+        let buf = [0u8, 7, b'u', b'p', b'd', b'a', 1, 2, 3];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::UpgradeData(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn recv_vers() {
+        let buf = [0u8, 9, b'v', b'e', b'r', b's', b'8', b'.', b'5', b'.', b'2'];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::Version("8.5.2".to_owned())
+        );
+    }
+
+    #[test]
+    fn server_codec() {
+        let mut buf = BytesMut::new();
+        ServerCodec
+            .encode(
+                ServerMessage::Setname(PlayerName::new("Den").unwrap()),
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(
+            &buf[..],
+            &[0, 9, b's', b'e', b't', b'd', 0, b'D', b'e', b'n', 0]
+        );
+        assert!(ServerCodec
+            .encode(ServerMessage::Unrecognised("xxxx".to_owned()), &mut buf)
+            .is_err());
+
+        // Timestamps go in the replay gain field, where LMS puts them
+        let mut buf = BytesMut::new();
+        ServerCodec
+            .encode(
+                ServerMessage::Status(Jiffies::from_millis(0x0102_0304)),
+                &mut buf,
+            )
+            .unwrap();
+        assert_eq!(&buf[2..7], b"strmt");
+        assert_eq!(&buf[20..24], &[1, 2, 3, 4]);
+
+        let mut buf = BytesMut::new();
+        SlimCodec::new()
+            .encode(ClientMessage::Bye(2), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ServerCodec.decode(&mut buf).unwrap(),
+            Some(ClientMessage::Bye(2))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn short_frames() {
+        assert!(decode_frame(&[0, 2, b's', b'e']).is_err());
+        assert!(decode_frame(&[0, 5, b'a', b'u', b'd', b'e']).is_err());
+        assert!(decode_frame(&[0, 5, b's', b't', b'r', b'm', b't']).is_err());
+        assert_eq!(
+            decode_frame(&[0, 4, b'u', b'p', b'd', b'n', 0xff]).unwrap(),
+            ServerMessage::UpgradeNow
+        );
+    }
+
+    #[test]
+    fn options() {
+        let strict = SlimCodec::with_options(
+            CodecOptions::default()
+                .strict(true)
+                .unknown_strm_is_error(true)
+                .max_frame_size(32),
+        );
+        let decode = |mut codec: SlimCodec, frame: &[u8]| {
+            codec.decode(&mut BytesMut::from(frame)).map(Option::unwrap)
+        };
+
+        let name = [0u8, 8, b's', b'e', b't', b'd', 0, b'D', 0xff, b'n'];
+        assert_eq!(
+            decode(SlimCodec::new(), &name).unwrap(),
+            ServerMessage::Setname(PlayerName::from_bytes(b"D\xffn"))
+        );
+        assert!(decode(strict.clone(), &name).is_err());
+
+        let mut strm_x = vec![0u8, 28, b's', b't', b'r', b'm', b'x'];
+        strm_x.resize(30, 0);
+        assert_eq!(
+            decode(SlimCodec::new(), &strm_x).unwrap(),
+            ServerMessage::Unrecognised("strm_x".to_owned())
+        );
+        assert!(decode(strict.clone(), &strm_x).is_err());
+
+        let vers = [&[0u8, 40][..], b"vers", &[b'7'; 36]].concat();
+        assert!(decode(SlimCodec::new(), &vers).is_ok());
+        assert_eq!(
+            decode(strict.clone(), &vers).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let audg = [
+            0u8, 22, b'a', b'u', b'd', b'g', 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0x80, 0x7f, 0, 0,
+            0x80, 0x80,
+        ];
+        let coarse = SlimCodec::with_options(CodecOptions::default().gain_precision(8));
+        if let ServerMessage::Gain { left, right, .. } = decode(coarse, &audg).unwrap() {
+            assert_eq!(left, FixedGain(0x8000));
+            assert_eq!(right, FixedGain(0x8100));
+        } else {
+            panic!("AUDG message not received");
+        }
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn set_clock() {
+        let time = chrono::NaiveTime::from_hms_opt(13, 45, 9).unwrap();
+        assert_eq!(
+            decode_frame(&[0, 8, b'r', b't', b'c', b's', 2, 0x13, 0x45, 0x09]).unwrap(),
+            ServerMessage::SetClock(ClockSetting::Time(time))
+        );
+        assert_eq!(
+            decode_frame(&[0, 6, b'r', b't', b'c', b's', 1, 1]).unwrap(),
+            ServerMessage::SetClock(ClockSetting::TwelveHour(true))
+        );
+        assert_eq!(
+            ServerMessage::SetClock(ClockSetting::Time(time)).to_string(),
+            "rtcs 13:45:09"
+        );
+
+        for setting in [ClockSetting::Time(time), ClockSetting::TwelveHour(false)] {
+            let mut buf = BytesMut::new();
+            ServerCodec
+                .encode(ServerMessage::SetClock(setting), &mut buf)
+                .unwrap();
+            assert_eq!(
+                decode_frame(&buf).unwrap(),
+                ServerMessage::SetClock(setting)
+            );
+        }
+
+        // Hours that are not decimal digits
+        assert!(decode_frame(&[0, 8, b'r', b't', b'c', b's', 2, 0x1a, 0, 0]).is_err());
+        assert!(decode_frame(&[0, 8, b'r', b't', b'c', b's', 2, 0x25, 0, 0]).is_err());
+        assert!(decode_frame(&[0, 5, b'r', b't', b'c', b's', 3]).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let payload = b"strms9m????\xff\0\0\x30\0\0\0\0\0\0\0\x23\x28\0\0\0\0";
+        assert_eq!(
+            ServerMessage::from(BytesMut::from(&payload[..])),
+            ServerMessage::Malformed {
+                command: *b"strm",
+                reason: DecodeError::InvalidValue {
+                    field: "autostart".to_owned(),
+                    value: b'9'
+                },
+                raw: payload.to_vec(),
+            }
+        );
+        assert_eq!(
+            ServerMessage::from(BytesMut::from(&b"aude"[..])),
+            ServerMessage::Malformed {
+                command: *b"aude",
+                reason: DecodeError::TooShort { len: 4, min: 6 },
+                raw: b"aude".to_vec(),
+            }
+        );
+
+        let e = decode_frame(&[0, 3, b'a', b'u', b'd']).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            "Malformed aud\0 message from server: 3 bytes long but needs at least 4"
+        );
+        let malformed = e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<MalformedMessage>())
+            .unwrap();
+        assert_eq!(malformed.command, *b"aud\0");
+        assert_eq!(malformed.reason, DecodeError::TooShort { len: 3, min: 4 });
+    }
+
     #[test]
     fn recv_gain() {
         let buf = [
-            0u8, 22, b'a', b'u', b'd', b'g', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 128, 0,
+            0u8, 22, b'a', b'u', b'd', b'g', 0, 0, 0, 80, 0, 0, 0, 40, 1, 255, 0, 1, 0, 0, 0, 0,
+            128, 0,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(msg) = framed.framed_read() {
             match msg {
-                ServerMessage::Gain(left, right) => {
-                    assert_eq!(left, 1.0);
-                    assert_eq!(right, 0.5);
+                ServerMessage::Gain {
+                    left,
+                    right,
+                    old_left,
+                    old_right,
+                    digital_volume,
+                    preamp,
+                } => {
+                    assert_eq!(left, FixedGain::UNITY);
+                    assert_eq!(right, FixedGain(0x8000));
+                    assert_eq!(right.to_f64(), 0.5);
+                    assert_eq!((old_left, old_right), (80, 40));
+                    assert!(digital_volume);
+                    assert_eq!(preamp, 255);
                 }
                 _ => panic!("GAIN message incorrect"),
             }
@@ -615,11 +2117,11 @@ mod tests {
         let buf = [
             0u8, 13, b's', b'e', b't', b'd', 0, b'n', b'e', b'w', b'n', b'a', b'm', b'e', 0,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(msg) = framed.framed_read() {
             match msg {
                 ServerMessage::Setname(name) => {
-                    assert_eq!(name, "newname".to_owned());
+                    assert_eq!(name, "newname");
                 }
                 _ => panic!("SETNAME message incorrect"),
             }
@@ -628,20 +2130,65 @@ mod tests {
         }
     }
 
+    #[test]
+    fn recv_setname_unterminated() {
+        let buf = [0u8, 8, b's', b'e', b't', b'd', 0, b'D', b'e', b'n'];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::Setname(PlayerName::new("Den").unwrap())
+        );
+    }
+
+    #[test]
+    fn player_names() {
+        assert_eq!(PlayerName::from_bytes(b"Den\0\0"), "Den");
+        assert_eq!(PlayerName::from_bytes(b"\0"), "");
+        assert_eq!(PlayerName::from_bytes(b"D\xffn"), "D\u{fffd}n");
+        // Cut short on a character boundary
+        let long = "é".repeat(40);
+        assert_eq!(PlayerName::from_bytes(long.as_bytes()).as_str().len(), 64);
+
+        assert!(PlayerName::new("Kitchen").is_ok());
+        assert!(PlayerName::new("a\0b").is_err());
+        assert!(PlayerName::new("a".repeat(PlayerName::MAX_LEN + 1)).is_err());
+    }
+
     #[test]
     fn recv_queryname() {
         let buf = [0u8, 5, b's', b'e', b't', b'd', 0];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Queryname) = framed.framed_read() {
         } else {
             panic!("SETD message not received");
         }
     }
 
+    #[test]
+    fn recv_settings() {
+        let buf = [
+            0u8, 5, b's', b'e', b't', b'd', 2, 0, 6, b's', b'e', b't', b'd', 5, 1, 0, 5, b's',
+            b'e', b't', b'd', 9,
+        ];
+        let mut framed = SlimFramed::new(&buf[..]);
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::QuerySetting(PlayerSetting::WordClockOutput)
+        );
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::SetSetting(PlayerSetting::FxloopSource, 1)
+        );
+        assert!(matches!(
+            framed.framed_read().unwrap(),
+            ServerMessage::Unrecognised(_)
+        ));
+    }
+
     #[test]
     fn recv_disabledac() {
         let buf = [0u8, 5, b's', b'e', b't', b'd', 4];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::DisableDac) = framed.framed_read() {
         } else {
             panic!("SETD message not received");
@@ -654,10 +2201,10 @@ mod tests {
             panic!("STRMs message not received");
         }
         let buf = [
-            0u8, 28, b's', b't', b'r', b'm', b's', b'1', b'm', b'2', b'3', b'?', b'0', 1, 2, 3, 4,
-            b'1', 2, 0, 0, 1, 128, 0, 35, 41, 172, 16, 1, 2,
+            0u8, 28, b's', b't', b'r', b'm', b's', b'1', b'm', b'2', b'3', b'?', b'0', 1, 2, 3,
+            b'4', 1, 2, 0, 0, 1, 128, 0, 35, 41, 172, 16, 1, 2,
         ];
-        let mut framed = FramedRead::new(&buf[..], SlimCodec);
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
         if let Ok(ServerMessage::Stream {
             autostart,
             format,
@@ -675,6 +2222,7 @@ mod tests {
             server_port,
             server_ip,
             http_headers,
+            raw,
         }) = framed.framed_read()
         {
             if let AutoStart::Auto = autostart {
@@ -702,7 +2250,7 @@ mod tests {
             } else {
                 do_panic();
             }
-            assert_eq!(threshold, 1024);
+            assert_eq!(threshold, Threshold::from_kb(1));
             if let SpdifEnable::Off = spdif_enable {
             } else {
                 do_panic();
@@ -713,11 +2261,314 @@ mod tests {
                 do_panic();
             }
             assert_eq!(flags, StreamFlags::INVERT_POLARITY_LEFT);
-            assert_eq!(output_threshold, Duration::from_millis(2));
-            assert_eq!(replay_gain, 1.5);
+            assert_eq!(output_threshold, Duration::from_millis(200));
+            assert_eq!(replay_gain.as_linear(), 1.5);
+            assert_eq!(replay_gain.as_fixed(), 0x18000);
             assert_eq!(server_port, 9001);
             assert_eq!(server_ip, Ipv4Addr::new(172, 16, 1, 2));
             assert!(http_headers.is_none());
+            assert_eq!(raw, &buf[6..]);
+        } else {
+            do_panic();
+        }
+    }
+
+    #[test]
+    fn recv_strm_flags() {
+        let buf = [
+            0u8, 28, b's', b't', b'r', b'm', b's', b'1', b'm', b'2', b'3', b'?', b'0', 1, 2, 3,
+            b'4', 0x35, 2, 0, 0, 1, 128, 0, 35, 41, 172, 16, 1, 2,
+        ];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        if let Ok(ServerMessage::Stream { flags, .. }) = framed.framed_read() {
+            assert!(flags.contains(StreamFlags::INVERT_POLARITY_LEFT));
+            assert!(flags.contains(StreamFlags::OUTPUT_LEFT_ONLY));
+            assert!(!flags.contains(StreamFlags::INF_LOOP));
+            // The undefined bits are kept
+            assert_eq!(flags.bits(), 0x35);
+        } else {
+            panic!("STRMs message not received");
+        }
+    }
+
+    // Gives its data one byte per read
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn largest_frame_byte_at_a_time() {
+        let version = "9".repeat(u16::MAX as usize - 4);
+        let mut data = vec![0xff, 0xff];
+        data.extend_from_slice(b"vers");
+        data.extend_from_slice(version.as_bytes());
+        data.extend_from_slice(&[0, 5, b's', b'e', b't', b'd', 4]);
+
+        let mut framed = SlimFramed::new(Trickle(io::Cursor::new(data)));
+        assert_eq!(
+            framed.framed_read().unwrap(),
+            ServerMessage::Version(version)
+        );
+        assert_eq!(framed.framed_read().unwrap(), ServerMessage::DisableDac);
+        assert_eq!(
+            framed.framed_read().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[test]
+    fn decode_many() {
+        let data = [
+            0u8, 5, b's', b'e', b't', b'd', 0, 0, 5, b's', b'e', b't', b'd', 4, 0, 5, b's',
+        ];
+        let mut buf = BytesMut::from(&data[..]);
+        assert_eq!(
+            SlimCodec::new().decode_all(&mut buf).unwrap(),
+            vec![ServerMessage::Queryname, ServerMessage::DisableDac]
+        );
+        assert_eq!(buf.len(), 3);
+
+        let mut framed = SlimFramed::new(&data[..]);
+        assert_eq!(framed.framed_read_many().unwrap().len(), 2);
+        assert_eq!(
+            framed.framed_read_many().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[test]
+    fn eof_within_frame() {
+        let data = vec![0, 5, b's', b'e', b't'];
+        let mut framed = SlimFramed::new(Trickle(io::Cursor::new(data)));
+        assert_eq!(
+            framed.framed_read().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn read_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let cx = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        cx.set_read_timeout(Some(std::time::Duration::from_millis(10)))
+            .unwrap();
+        let _server = listener.accept().unwrap();
+
+        let (mut rx, _tx): (crate::proto::SlimReader, _) = SlimFramed::new(cx).into_split();
+        let kind = rx.framed_read().unwrap_err().kind();
+        assert!(matches!(
+            kind,
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn split_and_reunite() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = || {
+            let cx = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (SlimFramed::new(cx), listener.accept().unwrap().0)
+        };
+        let (framed, mut server) = connect();
+        let (other, _other_server) = connect();
+
+        let (mut rx, mut tx) = framed.into_split();
+        tx = std::thread::spawn(move || {
+            tx.framed_write(ClientMessage::Bye(1)).unwrap();
+            tx
+        })
+        .join()
+        .unwrap();
+        let mut bye = [0u8; 9];
+        server.read_exact(&mut bye).unwrap();
+        assert_eq!(bye, [b'B', b'Y', b'E', b'!', 0, 0, 0, 1, 1]);
+
+        // Two messages in one write, the second is left in the read half's buffer
+        server
+            .write_all(&[
+                0, 5, b's', b'e', b't', b'd', 0, 0, 5, b's', b'e', b't', b'd', 4,
+            ])
+            .unwrap();
+        assert_eq!(rx.framed_read().unwrap(), ServerMessage::Queryname);
+
+        let (other_rx, other_tx) = other.into_split();
+        let ReuniteError(rx, other_tx) = rx.reunite(other_tx).unwrap_err();
+        assert!(other_rx.reunite(other_tx).is_ok());
+
+        tx = match rx.reunite(tx) {
+            Ok(mut framed) => {
+                assert_eq!(framed.framed_read().unwrap(), ServerMessage::DisableDac);
+                framed.into_split().1
+            }
+            Err(e) => panic!("{}", e),
+        };
+        tx.framed_write(ClientMessage::Bye(2)).unwrap();
+    }
+
+    #[test]
+    fn custom_transport() {
+        struct Transport {
+            input: io::Cursor<Vec<u8>>,
+            output: Vec<u8>,
+        }
+
+        impl Read for Transport {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.input.read(buf)
+            }
+        }
+
+        impl Write for Transport {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.output.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let transport = Transport {
+            input: io::Cursor::new(vec![
+                0u8, 5, b's', b'e', b't', b'd', 0, 0, 5, b's', b'e', b't', b'd', 4,
+            ]),
+            output: Vec::new(),
+        };
+
+        let mut framed = SlimFramed::new(transport);
+        framed.framed_write(ClientMessage::Bye(55)).unwrap();
+        assert!(matches!(framed.framed_read(), Ok(ServerMessage::Queryname)));
+        assert!(matches!(
+            framed.framed_read(),
+            Ok(ServerMessage::DisableDac)
+        ));
+        assert!(framed.framed_read().is_err());
+        assert_eq!(
+            framed.into_inner().output,
+            &[b'B', b'Y', b'E', b'!', 0, 0, 0, 1, 55]
+        );
+    }
+
+    #[test]
+    fn framed_reuses_buffers() {
+        let mut framed = SlimFramed::new(Vec::new());
+        let stat = || ClientMessage::Stat {
+            event_code: "STMt".to_owned(),
+            stat_data: StatusData::default(),
+        };
+
+        framed.framed_write(stat()).unwrap();
+        let wbuf = framed.wbuf.as_ptr();
+        for _ in 0..10 {
+            framed.framed_write(stat()).unwrap();
+        }
+        assert_eq!(framed.wbuf.as_ptr(), wbuf);
+
+        let out = framed.into_inner();
+        assert_eq!(out.len(), 11 * 61);
+        assert_eq!(&out[..8], &[b'S', b'T', b'A', b'T', 0, 0, 0, 53]);
+    }
+
+    #[test]
+    fn send_batch() {
+        #[derive(Default)]
+        struct Writes(Vec<Vec<u8>>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
         }
+
+        let mut framed = SlimFramed::new(Writes::default());
+        framed
+            .send_batch([
+                ClientMessage::Bye(1),
+                ClientMessage::Resp("HTTP/1.0 200 OK\r\n\r\n".to_owned()),
+            ])
+            .unwrap();
+        framed.feed(ClientMessage::Bye(2)).unwrap();
+        framed.framed_write(ClientMessage::Bye(3)).unwrap();
+        framed.flush().unwrap();
+
+        let writes = framed.into_inner().0;
+        // Nothing is left to write for the last flush
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].len(), 9 + 27);
+        assert_eq!(&writes[0][..9], &[b'B', b'Y', b'E', b'!', 0, 0, 0, 1, 1]);
+        assert_eq!(writes[1].len(), 18);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let buf = [
+            0u8, 12, b's', b'e', b'r', b'v', 172, 16, 1, 2, b's', b'y', b'n', b'c',
+        ];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        let msg = framed.framed_read().unwrap();
+
+        let json = serde_json::to_string(&msg).unwrap();
+        let de: ServerMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(de, msg);
+
+        let stat = StatusData::default().make_status_message(crate::status::StatusCode::Timer);
+        let json = serde_json::to_string(&stat).unwrap();
+        assert_eq!(serde_json::from_str::<ClientMessage>(&json).unwrap(), stat);
+    }
+
+    #[test]
+    fn clone_and_compare() {
+        let buf = [0u8, 6, b'a', b'u', b'd', b'e', 0, 1];
+        let mut framed = FramedRead::new(&buf[..], SlimCodec::new());
+        let msg: ServerMessage = framed.framed_read().unwrap();
+        assert_eq!(
+            msg.clone(),
+            ServerMessage::Enable(OutputEnable {
+                spdif: false,
+                dac: true
+            })
+        );
+        assert_eq!(msg.to_string(), "aude spdif=false dac=true");
+
+        let bye = ClientMessage::Bye(1);
+        assert_eq!(bye.clone(), bye);
+        assert_eq!(bye.to_string(), "BYE! 1");
+    }
+}
+
+// These need only alloc so they also run without the std feature
+#[cfg(test)]
+mod payload_tests {
+    use super::*;
+
+    #[test]
+    fn decode_payload() {
+        let payload = BytesMut::from(&b"strmq0m????0000000000000000000000"[..]);
+        assert_eq!(ServerMessage::from(payload), ServerMessage::Stop);
+
+        let payload = BytesMut::from(&b"aud"[..]);
+        assert!(matches!(
+            ServerMessage::from(payload),
+            ServerMessage::Malformed { .. }
+        ));
+    }
+
+    #[test]
+    fn encode_frame() {
+        let frame = BytesMut::from(ClientMessage::Bye(55));
+        assert_eq!(&frame[..], &[b'B', b'Y', b'E', b'!', 0, 0, 0, 1, 55]);
     }
 }
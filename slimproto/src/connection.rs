@@ -0,0 +1,170 @@
+//! The state of the player's connection to the server.
+//!
+//! A [ConnectionTracker] holds the current [ConnectionState] and sends a
+//! [StateChange] to every subscriber on each transition, so that a user interface
+//! can show what the player is doing without inferring it from the messages.
+//!
+//! Give a tracker to [PreparedServer](crate::proto::PreparedServer::with_tracker)
+//! and the connecting, handshaking and ready states are set as the player connects.
+//! Pass each message from the server to [observe](ConnectionTracker::observe) for
+//! the streaming and reconnecting states. Discovery and closing the connection
+//! are up to the player.
+//!
+//! ```
+//! use slimproto::connection::{ConnectionState, ConnectionTracker};
+//!
+//! let tracker = ConnectionTracker::new();
+//! let changes = tracker.subscribe();
+//! tracker.set(ConnectionState::Discovering);
+//! assert_eq!(changes.recv().unwrap().to, ConnectionState::Discovering);
+//! ```
+
+use std::sync::{
+    mpsc::{channel, Receiver, Sender},
+    Arc, Mutex, MutexGuard,
+};
+
+use crate::ServerMessage;
+
+/// The states of the connection to the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionState {
+    /// Looking for a server on the network.
+    Discovering,
+    /// Opening the connection to the server.
+    Connecting,
+    /// Announcing the player with `HELO`.
+    Handshaking,
+    /// Connected and waiting for a stream.
+    Ready,
+    /// Playing a stream from the server.
+    Streaming,
+    /// Moving to another server or connecting again after a failure.
+    Reconnecting,
+    /// Not connected.
+    #[default]
+    Closed,
+}
+
+/// A transition between two states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+}
+
+#[derive(Default)]
+struct Tracked {
+    state: ConnectionState,
+    subscribers: Vec<Sender<StateChange>>,
+}
+
+/// The shared state of a connection, clones track the same connection.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<Mutex<Tracked>>,
+}
+
+impl ConnectionTracker {
+    /// A tracker in the [Closed](ConnectionState::Closed) state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tracked> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.lock().state
+    }
+
+    /// Receive every transition from now on.
+    pub fn subscribe(&self) -> Receiver<StateChange> {
+        let (tx, rx) = channel();
+        self.lock().subscribers.push(tx);
+        rx
+    }
+
+    /// Move to `state`, subscribers are told only if the state changes.
+    pub fn set(&self, state: ConnectionState) {
+        let mut tracked = self.lock();
+        if tracked.state == state {
+            return;
+        }
+
+        let change = StateChange {
+            from: tracked.state,
+            to: state,
+        };
+        tracked.state = state;
+        // Dropped receivers are forgotten
+        tracked
+            .subscribers
+            .retain(|subscriber| subscriber.send(change).is_ok());
+    }
+
+    /// Update the state from a message sent by the server.
+    pub fn observe(&self, msg: &ServerMessage) {
+        match msg {
+            ServerMessage::Stream { .. } => self.set(ConnectionState::Streaming),
+            ServerMessage::Stop | ServerMessage::Flush => self.set(ConnectionState::Ready),
+            ServerMessage::Serv { .. } => self.set(ConnectionState::Reconnecting),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let tracker = ConnectionTracker::new();
+        let changes = tracker.subscribe();
+        let dropped = tracker.subscribe();
+        drop(dropped);
+
+        tracker.set(ConnectionState::Ready);
+        tracker.set(ConnectionState::Ready);
+        tracker.observe(&ServerMessage::Stop);
+        tracker.observe(&ServerMessage::Serv {
+            address: std::net::Ipv4Addr::LOCALHOST.into(),
+            sync_group_id: None,
+        });
+
+        assert_eq!(tracker.state(), ConnectionState::Reconnecting);
+        assert_eq!(
+            changes.try_iter().collect::<Vec<_>>(),
+            vec![
+                StateChange {
+                    from: ConnectionState::Closed,
+                    to: ConnectionState::Ready
+                },
+                StateChange {
+                    from: ConnectionState::Ready,
+                    to: ConnectionState::Reconnecting
+                },
+            ]
+        );
+        assert_eq!(tracker.lock().subscribers.len(), 1);
+    }
+
+    #[test]
+    fn tracked_connect() {
+        let tracker = ConnectionTracker::new();
+        let changes = tracker.subscribe();
+        crate::proto::Server::default()
+            .prepare(crate::Capabilities::default())
+            .with_tracker(tracker.clone())
+            .connect_with(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+
+        assert_eq!(
+            changes.try_iter().map(|c| c.to).collect::<Vec<_>>(),
+            vec![ConnectionState::Handshaking, ConnectionState::Ready]
+        );
+    }
+}
@@ -1,20 +1,25 @@
-/// This module provides the `discover` function which "pings" for a server
-/// on the network returning its address if it exists.
+//! This module provides the `discover` function which "pings" for a server
+//! on the network returning its address if it exists, and [Discoverer] which
+//! does the same without a thread or blocking.
 
 use crate::proto::{Server, ServerTlv, ServerTlvMap, SLIM_PORT};
 
 use std::{
     collections::HashMap,
     io,
-    net::{Ipv4Addr, SocketAddr, UdpSocket, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     thread::{sleep, spawn},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+const UDPMAXSIZE: usize = 1450; // as defined in LMS code
+const PING: &[u8] = b"eNAME\0IPAD\0JSON\0VERS"; // Also \0UUID\0JVID
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Repeatedly send discover "pings" to the server with an optional timeout.
 ///
 /// Returns:
@@ -26,8 +31,16 @@ use std::{
 /// This function will try forever if no timeout is passed in which case `Ok(None)` can never
 /// be returned.
 pub fn discover(timeout: Option<Duration>) -> io::Result<Option<Server>> {
-    const UDPMAXSIZE: usize = 1450; // as defined in LMS code
+    discover_on_port(SLIM_PORT, timeout)
+}
 
+/// As [discover] but sends the discovery "pings" to a port other than the default
+/// Slim port. The returned server uses the port from which the server responded.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "discover", level = "debug")
+)]
+pub fn discover_on_port(port: u16, timeout: Option<Duration>) -> io::Result<Option<Server>> {
     let cx = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), 0))?;
     cx.set_broadcast(true)?;
     cx.set_read_timeout(timeout)?;
@@ -36,12 +49,11 @@ pub fn discover(timeout: Option<Duration>) -> io::Result<Option<Server>> {
     let running = Arc::new(AtomicBool::new(true));
     let is_running = running.clone();
     spawn(move || {
-        let buf = b"eNAME\0IPAD\0JSON\0VERS"; // Also \0UUID\0JVID
         while is_running.load(Ordering::Relaxed) {
             cx_send
-                .send_to(buf, (Ipv4Addr::new(255, 255, 255, 255), SLIM_PORT))
+                .send_to(PING, (Ipv4Addr::new(255, 255, 255, 255), port))
                 .ok();
-            sleep(Duration::from_secs(5));
+            sleep(PING_INTERVAL);
         }
     });
 
@@ -51,31 +63,88 @@ pub fn discover(timeout: Option<Duration>) -> io::Result<Option<Server>> {
 
     response.map_or_else(
         |e| match e.kind() {
-            io::ErrorKind::WouldBlock => Ok(None),
+            io::ErrorKind::WouldBlock => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("no server found");
+                Ok(None)
+            }
             _ => Err(e),
         },
-        |(len, sock_addr)| match sock_addr {
-            SocketAddr::V4(addr) => Ok(Some(Server {
-                socket: SocketAddrV4::new(*addr.ip(), SLIM_PORT),
-                // ip_address: *addr.ip(),
-                // port: SLIM_PORT,
-                tlv_map: {
-                    if len > 0 && buf[0] == b'E' {
-                        Some(decode_tlv(&buf[1..]))
-                    } else {
-                        None
-                    }
-                },
-                sync_group_id: None,
-            })),
-            _ => Ok(None),
-        },
+        |(len, sock_addr)| Ok(server_from_response(&buf[..len], sock_addr)),
     )
 }
 
+/// Discovery that is driven by the caller rather than a background thread, for
+/// platforms without threads or for players with their own event loop.
+///
+/// Call [poll](Discoverer::poll) regularly, it sends a "ping" when one is due and
+/// returns the server once one has responded. It never blocks.
+pub struct Discoverer {
+    cx: UdpSocket,
+    port: u16,
+    last_ping: Option<Instant>,
+}
+
+impl Discoverer {
+    /// Discover a server on the default Slim port.
+    pub fn new() -> io::Result<Self> {
+        Self::on_port(SLIM_PORT)
+    }
+
+    /// Discover a server listening on a port other than the default Slim port.
+    pub fn on_port(port: u16) -> io::Result<Self> {
+        let cx = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), 0))?;
+        cx.set_broadcast(true)?;
+        cx.set_nonblocking(true)?;
+        Ok(Self {
+            cx,
+            port,
+            last_ping: None,
+        })
+    }
+
+    /// Returns `Ok(Some(Server))` if a server has responded and `Ok(None)` otherwise.
+    pub fn poll(&mut self) -> io::Result<Option<Server>> {
+        if self
+            .last_ping
+            .is_none_or(|last| last.elapsed() >= PING_INTERVAL)
+        {
+            self.cx
+                .send_to(PING, (Ipv4Addr::new(255, 255, 255, 255), self.port))?;
+            self.last_ping = Some(Instant::now());
+        }
+
+        let mut buf = [0u8; UDPMAXSIZE];
+        match self.cx.recv_from(&mut buf) {
+            Ok((len, sock_addr)) => Ok(server_from_response(&buf[..len], sock_addr)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn server_from_response(buf: &[u8], sock_addr: SocketAddr) -> Option<Server> {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(%sock_addr, "server responded");
+    match sock_addr {
+        SocketAddr::V4(addr) => Some(Server {
+            socket: addr,
+            tlv_map: {
+                if !buf.is_empty() && buf[0] == b'E' {
+                    Some(decode_tlv(&buf[1..]))
+                } else {
+                    None
+                }
+            },
+            sync_group_id: None,
+        }),
+        _ => None,
+    }
+}
+
 fn decode_tlv(buf: &[u8]) -> ServerTlvMap {
     let mut ret = HashMap::new();
-    let mut view = &buf[..];
+    let mut view = buf;
 
     while view.len() > 4 && view[0].is_ascii() {
         let token = String::from_utf8(view[..4].to_vec()).unwrap_or_default();
@@ -131,4 +200,16 @@ mod tests {
             assert!(server.tlv_map.is_some());
         }
     }
+
+    #[test]
+    fn discoverer() {
+        // Nothing listens on this port so nothing answers
+        let mut discoverer = Discoverer::on_port(3482).unwrap();
+        assert!(discoverer.poll().unwrap().is_none());
+        assert!(discoverer.last_ping.is_some());
+
+        let server =
+            server_from_response(b"ENAME\x03lms", "192.168.1.2:3483".parse().unwrap()).unwrap();
+        assert!(server.tlv_map.unwrap().contains_key("NAME"));
+    }
 }
@@ -0,0 +1,219 @@
+//! Handing messages from the server to the parts of the player that want them.
+//!
+//! A [Dispatcher] calls the handlers registered for each [MessageKind] and sends
+//! a copy of the message to each channel subscribed to that kind. A user interface
+//! can then receive only the messages it shows while the audio thread receives
+//! those that control the stream, without a match on every message.
+//!
+//! ```
+//! use slimproto::{dispatch::{Dispatcher, MessageKind}, ServerMessage};
+//!
+//! let mut dispatcher = Dispatcher::new();
+//! let control = dispatcher.subscribe(&[MessageKind::Stop, MessageKind::Flush]);
+//! dispatcher.on_stop(|_| println!("stopped"));
+//!
+//! dispatcher.dispatch(&ServerMessage::Stop);
+//! assert_eq!(control.recv().unwrap(), ServerMessage::Stop);
+//! ```
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use framous::FramedReader;
+
+use crate::ServerMessage;
+
+/// The kinds of message sent by the server, one for each [ServerMessage] variant.
+/// Like [ServerMessage] it is `#[non_exhaustive]` as some kinds depend on features.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MessageKind {
+    Serv,
+    Status,
+    Stream,
+    Gain,
+    Enable,
+    Flush,
+    Stop,
+    Pause,
+    Unpause,
+    Queryname,
+    Setname,
+    DisableDac,
+    QuerySetting,
+    SetSetting,
+    Skip,
+    Cont,
+    UpgradeNow,
+    UpgradeData,
+    Version,
+    #[cfg(feature = "chrono")]
+    SetClock,
+    #[cfg(feature = "display")]
+    Graphics,
+    Unrecognised,
+    Malformed,
+}
+
+impl From<&ServerMessage> for MessageKind {
+    fn from(msg: &ServerMessage) -> Self {
+        match msg {
+            ServerMessage::Serv { .. } => MessageKind::Serv,
+            ServerMessage::Status(_) => MessageKind::Status,
+            ServerMessage::Stream { .. } => MessageKind::Stream,
+            ServerMessage::Gain { .. } => MessageKind::Gain,
+            ServerMessage::Enable(_) => MessageKind::Enable,
+            ServerMessage::Flush => MessageKind::Flush,
+            ServerMessage::Stop => MessageKind::Stop,
+            ServerMessage::Pause(_) => MessageKind::Pause,
+            ServerMessage::Unpause(_) => MessageKind::Unpause,
+            ServerMessage::Queryname => MessageKind::Queryname,
+            ServerMessage::Setname(_) => MessageKind::Setname,
+            ServerMessage::DisableDac => MessageKind::DisableDac,
+            ServerMessage::QuerySetting(_) => MessageKind::QuerySetting,
+            ServerMessage::SetSetting(..) => MessageKind::SetSetting,
+            ServerMessage::Skip(_) => MessageKind::Skip,
+            ServerMessage::Cont { .. } => MessageKind::Cont,
+            ServerMessage::UpgradeNow => MessageKind::UpgradeNow,
+            ServerMessage::UpgradeData(_) => MessageKind::UpgradeData,
+            ServerMessage::Version(_) => MessageKind::Version,
+            #[cfg(feature = "chrono")]
+            ServerMessage::SetClock(_) => MessageKind::SetClock,
+            #[cfg(feature = "display")]
+            ServerMessage::Graphics(_) => MessageKind::Graphics,
+            ServerMessage::Unrecognised(_) => MessageKind::Unrecognised,
+            ServerMessage::Malformed { .. } => MessageKind::Malformed,
+        }
+    }
+}
+
+type Handler = Box<dyn FnMut(&ServerMessage) + Send>;
+
+/// Routes each message to the handlers and subscribers for its kind.
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<MessageKind, Vec<Handler>>,
+    subscribers: HashMap<MessageKind, Vec<Sender<ServerMessage>>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `handler` with every message of the given kind, handlers are called
+    /// in the order that they are added.
+    pub fn on(&mut self, kind: MessageKind, handler: impl FnMut(&ServerMessage) + Send + 'static) {
+        self.handlers
+            .entry(kind)
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    pub fn on_stream(&mut self, handler: impl FnMut(&ServerMessage) + Send + 'static) {
+        self.on(MessageKind::Stream, handler);
+    }
+
+    pub fn on_gain(&mut self, handler: impl FnMut(&ServerMessage) + Send + 'static) {
+        self.on(MessageKind::Gain, handler);
+    }
+
+    pub fn on_stop(&mut self, handler: impl FnMut(&ServerMessage) + Send + 'static) {
+        self.on(MessageKind::Stop, handler);
+    }
+
+    /// Receive a copy of every message of the given kinds. Dropping the receiver
+    /// ends the subscription.
+    pub fn subscribe(&mut self, kinds: &[MessageKind]) -> Receiver<ServerMessage> {
+        let (tx, rx) = channel();
+        for kind in kinds {
+            self.subscribers.entry(*kind).or_default().push(tx.clone());
+        }
+        rx
+    }
+
+    /// Pass a message to its handlers and subscribers. Returns `false` if there
+    /// are none for its kind.
+    pub fn dispatch(&mut self, msg: &ServerMessage) -> bool {
+        let kind = MessageKind::from(msg);
+        let mut handled = false;
+
+        if let Some(handlers) = self.handlers.get_mut(&kind) {
+            for handler in handlers.iter_mut() {
+                handler(msg);
+                handled = true;
+            }
+        }
+
+        if let Some(subscribers) = self.subscribers.get_mut(&kind) {
+            subscribers.retain(|subscriber| subscriber.send(msg.clone()).is_ok());
+            handled |= !subscribers.is_empty();
+        }
+
+        handled
+    }
+
+    /// Read messages from `rx` and dispatch them until a read fails, the error
+    /// is returned.
+    pub fn run(&mut self, rx: &mut impl FramedReader<ServerMessage>) -> io::Error {
+        loop {
+            match rx.framed_read() {
+                Ok(msg) => {
+                    self.dispatch(&msg);
+                }
+                Err(e) => return e,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::{jiffies::Jiffies, proto::PauseKind};
+
+    #[test]
+    fn routes_by_kind() {
+        let mut dispatcher = Dispatcher::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        dispatcher.on(MessageKind::Pause, {
+            let seen = seen.clone();
+            move |msg| seen.lock().unwrap().push(msg.to_string())
+        });
+        let ui = dispatcher.subscribe(&[MessageKind::Pause, MessageKind::Unpause]);
+        let dropped = dispatcher.subscribe(&[MessageKind::Status]);
+        drop(dropped);
+
+        assert!(dispatcher.dispatch(&ServerMessage::Pause(PauseKind::Now)));
+        assert!(dispatcher.dispatch(&ServerMessage::Unpause(PauseKind::Now)));
+        assert!(!dispatcher.dispatch(&ServerMessage::Status(Jiffies::ZERO)));
+        assert!(!dispatcher.dispatch(&ServerMessage::Flush));
+
+        assert_eq!(*seen.lock().unwrap(), vec!["strm p now"]);
+        assert_eq!(
+            ui.try_iter().collect::<Vec<_>>(),
+            vec![
+                ServerMessage::Pause(PauseKind::Now),
+                ServerMessage::Unpause(PauseKind::Now)
+            ]
+        );
+    }
+
+    #[test]
+    fn run_until_error() {
+        let data = [0u8, 5, b's', b'e', b't', b'd', 0];
+        let mut rx = crate::codec::SlimFramed::new(&data[..]);
+        let mut dispatcher = Dispatcher::new();
+        let names = dispatcher.subscribe(&[MessageKind::Queryname]);
+
+        let e = dispatcher.run(&mut rx);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(names.try_recv().unwrap(), ServerMessage::Queryname);
+    }
+}
@@ -0,0 +1,350 @@
+//! The display of the classic Squeezebox, 320 by 32 pixels.
+//!
+//! The server draws the display itself and sends it to the player in `grfe`
+//! messages, decoded as a [GraphicsFrame]. The bitmap is packed a column at a
+//! time, four bytes to each column of 32 pixels with the top pixel in the most
+//! significant bit. A [Bitmap] unpacks it into rows of pixels that can be copied
+//! to an OLED or, through its `Display`, printed to a terminal.
+//!
+//! A [Bitmap] can also be drawn on with text in a 5 by 7 pixel font, like the
+//! small font of the Squeezebox, and packed to be sent in a `grfe` message.
+//! With the `terminal` feature the display can be shown in a
+//! [terminal](terminal::TerminalDisplay).
+//!
+//! ```
+//! use slimproto::display::Bitmap;
+//!
+//! let mut screen = Bitmap::new();
+//! screen.draw_text(0, 0, "Hi");
+//! assert!(screen.get(0, 0));
+//! assert_eq!(Bitmap::from_packed(&screen.to_packed()), screen);
+//! ```
+
+use alloc::{string::String, vec, vec::Vec};
+use core::fmt;
+
+#[cfg(feature = "terminal")]
+pub mod terminal;
+
+/// The width of the classic display in pixels.
+pub const WIDTH: usize = 320;
+/// The height of the classic display in pixels.
+pub const HEIGHT: usize = 32;
+
+const COLUMN_BYTES: usize = HEIGHT / 8;
+
+/// The contents of a `grfe` message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GraphicsFrame {
+    /// The byte of the display at which the bitmap starts.
+    pub offset: u16,
+    /// The transition to show, `c` for a constant display.
+    pub transition: u8,
+    /// The distance or direction of the transition.
+    pub param: u8,
+    /// The packed bitmap.
+    pub bitmap: Vec<u8>,
+}
+
+impl GraphicsFrame {
+    /// A frame of the whole display without a transition.
+    pub fn new(bitmap: &Bitmap) -> Self {
+        Self {
+            offset: 0,
+            transition: b'c',
+            param: 0,
+            bitmap: bitmap.to_packed(),
+        }
+    }
+
+    /// Draw the frame onto `screen`, at its offset.
+    pub fn draw_on(&self, screen: &mut Bitmap) {
+        let start = self.offset as usize / COLUMN_BYTES;
+        for (x, column) in self.bitmap.chunks_exact(COLUMN_BYTES).enumerate() {
+            screen.set_column(start + x, column);
+        }
+    }
+
+    /// The frame drawn onto a blank display.
+    pub fn to_bitmap(&self) -> Bitmap {
+        let mut screen = Bitmap::new();
+        self.draw_on(&mut screen);
+        screen
+    }
+}
+
+/// A monochrome picture of the display, held a row at a time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitmap {
+    rows: Vec<Vec<bool>>,
+}
+
+impl Bitmap {
+    /// A blank bitmap the size of the display.
+    pub fn new() -> Self {
+        Self::with_width(WIDTH)
+    }
+
+    /// A blank bitmap `width` pixels wide and as high as the display.
+    pub fn with_width(width: usize) -> Self {
+        Self {
+            rows: vec![vec![false; width]; HEIGHT],
+        }
+    }
+
+    /// Unpack a bitmap sent by the server, a column of pixels to each four bytes.
+    pub fn from_packed(packed: &[u8]) -> Self {
+        let mut bitmap = Self::with_width(packed.len() / COLUMN_BYTES);
+        for (x, column) in packed.chunks_exact(COLUMN_BYTES).enumerate() {
+            bitmap.set_column(x, column);
+        }
+        bitmap
+    }
+
+    /// Pack the bitmap as it is sent to the player.
+    pub fn to_packed(&self) -> Vec<u8> {
+        let mut packed = vec![0u8; self.width() * COLUMN_BYTES];
+        for (y, row) in self.rows.iter().enumerate() {
+            for (x, _) in row.iter().enumerate().filter(|(_, on)| **on) {
+                packed[x * COLUMN_BYTES + y / 8] |= 0x80 >> (y % 8);
+            }
+        }
+        packed
+    }
+
+    pub fn width(&self) -> usize {
+        self.rows[0].len()
+    }
+
+    /// The rows of pixels from the top, `true` where a pixel is lit.
+    pub fn rows(&self) -> &[Vec<bool>] {
+        &self.rows
+    }
+
+    pub fn into_rows(self) -> Vec<Vec<bool>> {
+        self.rows
+    }
+
+    /// Whether the pixel at `x` across and `y` down is lit, `false` outside the bitmap.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.rows
+            .get(y)
+            .and_then(|row| row.get(x))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Light or clear the pixel at `x` across and `y` down, pixels outside the
+    /// bitmap are ignored.
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        if let Some(pixel) = self.rows.get_mut(y).and_then(|row| row.get_mut(x)) {
+            *pixel = on;
+        }
+    }
+
+    /// Clear every pixel.
+    pub fn clear(&mut self) {
+        self.rows.iter_mut().for_each(|row| row.fill(false));
+    }
+
+    fn set_column(&mut self, x: usize, column: &[u8]) {
+        for y in 0..HEIGHT {
+            self.set(x, y, column[y / 8] & (0x80 >> (y % 8)) != 0);
+        }
+    }
+
+    /// Draw `text` with its top left corner at `x` across and `y` down, characters
+    /// that the font does not have are drawn as `?`. Returns the position across
+    /// after the last character.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str) -> usize {
+        let mut x = x;
+        for c in text.chars() {
+            for (dx, column) in glyph(c).iter().enumerate() {
+                for dy in 0..GLYPH_HEIGHT {
+                    if column & (1 << dy) != 0 {
+                        self.set(x + dx, y + dy, true);
+                    }
+                }
+            }
+            x += GLYPH_WIDTH + 1;
+        }
+        x
+    }
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shows the bitmap with a block for each lit pixel, a line to each row.
+impl fmt::Display for Bitmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in &self.rows {
+            let line: String = row.iter().map(|on| if *on { '█' } else { ' ' }).collect();
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+
+// The printable ASCII characters, a byte to each column with the top pixel in
+// the least significant bit
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x55, 0x22, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x14, 0x08, 0x3e, 0x08, 0x14], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+    [0x00, 0x01, 0x02, 0x04, 0x00], // `
+    [0x20, 0x54, 0x54, 0x54, 0x78], // a
+    [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+    [0x38, 0x44, 0x44, 0x44, 0x20], // c
+    [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+    [0x38, 0x54, 0x54, 0x54, 0x18], // e
+    [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+    [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+    [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+    [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+    [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+    [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+    [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+    [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+    [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+    [0x38, 0x44, 0x44, 0x44, 0x38], // o
+    [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+    [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+    [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+    [0x48, 0x54, 0x54, 0x54, 0x20], // s
+    [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+    [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+    [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+    [0x44, 0x28, 0x10, 0x28, 0x44], // x
+    [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+    [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+    [0x00, 0x08, 0x36, 0x41, 0x00], // {
+    [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+    [0x00, 0x41, 0x36, 0x08, 0x00], // }
+    [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    match c {
+        ' '..='~' => &FONT[c as usize - ' ' as usize],
+        _ => &FONT['?' as usize - ' ' as usize],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn packing() {
+        let mut packed = vec![0u8; WIDTH * COLUMN_BYTES];
+        packed[0] = 0x80;
+        packed[7] = 0x01;
+        let bitmap = Bitmap::from_packed(&packed);
+        assert_eq!(bitmap.width(), WIDTH);
+        assert!(bitmap.get(0, 0));
+        assert!(bitmap.get(1, 31));
+        assert_eq!(bitmap.rows().iter().flatten().filter(|on| **on).count(), 2);
+        assert_eq!(bitmap.to_packed(), packed);
+    }
+
+    #[test]
+    fn graphics_frame() {
+        let payload = [b'g', b'r', b'f', b'e', 0, 8, b'c', 0, 0xff, 0, 0, 0];
+        let crate::ServerMessage::Graphics(frame) = BytesMut::from(&payload[..]).into() else {
+            panic!("GRFE message not received");
+        };
+        assert_eq!((frame.offset, frame.transition), (8, b'c'));
+        let bitmap = frame.to_bitmap();
+        assert!((0..8).all(|y| bitmap.get(2, y)));
+        assert!(!bitmap.get(2, 8) && !bitmap.get(0, 0));
+
+        let mut screen = Bitmap::new();
+        screen.draw_text(10, 10, "Now playing");
+        assert_eq!(GraphicsFrame::new(&screen).to_bitmap(), screen);
+        assert!(matches!(
+            BytesMut::from(&b"grfe\0\0c"[..]).into(),
+            crate::ServerMessage::Malformed { .. }
+        ));
+    }
+
+    #[test]
+    fn text() {
+        let mut bitmap = Bitmap::with_width(12);
+        assert_eq!(bitmap.draw_text(0, 0, "I\u{e9}"), 12);
+        assert_eq!(
+            bitmap.to_string().lines().take(3).collect::<Vec<_>>(),
+            vec![" ███   ███", "  █   █   █", "  █       █"]
+        );
+    }
+}
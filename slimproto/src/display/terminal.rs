@@ -0,0 +1,165 @@
+//! Showing the display in a terminal.
+//!
+//! A [TerminalDisplay] draws each [GraphicsFrame] onto its copy of the display and
+//! redraws it in place, with braille characters for four rows of pixels to a line
+//! or half blocks for two. This is enough to watch what the server shows on a
+//! player without one to hand.
+//!
+//! ```no_run
+//! use slimproto::{display::terminal::TerminalDisplay, ServerMessage};
+//!
+//! let mut display = TerminalDisplay::new(std::io::stdout());
+//! # let msg = ServerMessage::Stop;
+//! display.handle(&msg).unwrap();
+//! ```
+
+use std::io::{self, Write};
+
+use super::{Bitmap, GraphicsFrame};
+use crate::ServerMessage;
+
+/// The characters with which the pixels are drawn.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Style {
+    /// A braille character for each two by four pixels, 160 by 8 characters.
+    #[default]
+    Braille,
+    /// A half block for each one by two pixels, 320 by 16 characters.
+    HalfBlock,
+}
+
+/// Draws the display to a terminal, or anything else that understands ANSI
+/// cursor movement.
+pub struct TerminalDisplay<W> {
+    out: W,
+    style: Style,
+    screen: Bitmap,
+    lines: usize,
+}
+
+impl<W: Write> TerminalDisplay<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            style: Style::default(),
+            screen: Bitmap::new(),
+            lines: 0,
+        }
+    }
+
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// The display as last drawn.
+    pub fn screen(&self) -> &Bitmap {
+        &self.screen
+    }
+
+    /// Draw the frame of a `grfe` message, other messages are ignored.
+    pub fn handle(&mut self, msg: &ServerMessage) -> io::Result<()> {
+        match msg {
+            ServerMessage::Graphics(frame) => self.show(frame),
+            _ => Ok(()),
+        }
+    }
+
+    /// Draw `frame` onto the display and redraw it over the last.
+    pub fn show(&mut self, frame: &GraphicsFrame) -> io::Result<()> {
+        frame.draw_on(&mut self.screen);
+        let text = match self.style {
+            Style::Braille => braille(&self.screen),
+            Style::HalfBlock => half_blocks(&self.screen),
+        };
+
+        if self.lines > 0 {
+            write!(self.out, "\x1b[{}A\r", self.lines)?;
+        }
+        for line in text.lines() {
+            writeln!(self.out, "{}\x1b[K", line)?;
+        }
+        self.lines = text.lines().count();
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// The bitmap as braille characters, a line to each four rows of pixels.
+pub fn braille(bitmap: &Bitmap) -> String {
+    // The dots of a braille cell, by row then column
+    const DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+    let mut text = String::new();
+    for y in (0..bitmap.rows().len()).step_by(4) {
+        for x in (0..bitmap.width()).step_by(2) {
+            let mut cell = 0;
+            for (dy, dots) in DOTS.iter().enumerate() {
+                for (dx, dot) in dots.iter().enumerate() {
+                    if bitmap.get(x + dx, y + dy) {
+                        cell |= dot;
+                    }
+                }
+            }
+            text.push(char::from_u32(0x2800 + cell).unwrap_or(' '));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// The bitmap as half blocks, a line to each two rows of pixels.
+pub fn half_blocks(bitmap: &Bitmap) -> String {
+    let mut text = String::new();
+    for y in (0..bitmap.rows().len()).step_by(2) {
+        for x in 0..bitmap.width() {
+            text.push(match (bitmap.get(x, y), bitmap.get(x, y + 1)) {
+                (false, false) => ' ',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (true, true) => '█',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn characters() {
+        let mut bitmap = Bitmap::with_width(4);
+        bitmap.set(0, 0, true);
+        bitmap.set(1, 3, true);
+        bitmap.set(3, 1, true);
+
+        let text = braille(&bitmap);
+        assert_eq!(text.lines().count(), 8);
+        assert_eq!(text.lines().next().unwrap(), "\u{2881}\u{2810}");
+        assert_eq!(
+            half_blocks(&bitmap).lines().take(2).collect::<Vec<_>>(),
+            vec!["▀  ▄", " ▄  "]
+        );
+    }
+
+    #[test]
+    fn redraws_in_place() {
+        let mut screen = Bitmap::new();
+        screen.draw_text(0, 0, "A");
+        let mut display = TerminalDisplay::new(Vec::new());
+        display.handle(&ServerMessage::Stop).unwrap();
+        display.show(&GraphicsFrame::new(&screen)).unwrap();
+        display.show(&GraphicsFrame::new(&screen)).unwrap();
+
+        assert_eq!(display.screen(), &screen);
+        let out = String::from_utf8(display.into_inner()).unwrap();
+        assert_eq!(out.matches('\n').count(), 16);
+        assert_eq!(out.matches("\x1b[8A\r").count(), 1);
+    }
+}
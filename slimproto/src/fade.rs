@@ -0,0 +1,137 @@
+//! Gain envelopes for the transitions between tracks.
+//!
+//! The `strm s` message gives the type of transition, [TransType], and its length.
+//! A track fades in over its first `trans_period` and fades out over its last,
+//! a crossfade is both at once with the outgoing and incoming tracks mixed.
+//! [FadeEnvelope] yields the gain multiplier for each frame of a transition.
+
+use core::time::Duration;
+
+use crate::proto::TransType;
+
+/// The direction of a fade.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fade {
+    In,
+    Out,
+}
+
+/// A linear gain ramp over a number of frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FadeEnvelope {
+    fade: Fade,
+    frames: u64,
+    position: u64,
+}
+
+impl FadeEnvelope {
+    pub fn new(fade: Fade, period: Duration, sample_rate: u32) -> Self {
+        Self {
+            fade,
+            frames: crate::gain::round(period.as_secs_f64() * sample_rate as f64) as u64,
+            position: 0,
+        }
+    }
+
+    /// The envelope for the start of a track, if the transition fades the track in.
+    pub fn for_start(
+        trans_type: TransType,
+        trans_period: Duration,
+        sample_rate: u32,
+    ) -> Option<Self> {
+        match trans_type {
+            TransType::FadeIn | TransType::FadeInOut | TransType::Crossfade => {
+                Some(Self::new(Fade::In, trans_period, sample_rate))
+            }
+            TransType::None | TransType::FadeOut => None,
+        }
+    }
+
+    /// The envelope for the end of a track, if the transition fades the track out.
+    /// It should be started `trans_period` before the end of the track.
+    pub fn for_end(
+        trans_type: TransType,
+        trans_period: Duration,
+        sample_rate: u32,
+    ) -> Option<Self> {
+        match trans_type {
+            TransType::FadeOut | TransType::FadeInOut | TransType::Crossfade => {
+                Some(Self::new(Fade::Out, trans_period, sample_rate))
+            }
+            TransType::None | TransType::FadeIn => None,
+        }
+    }
+
+    /// The gain at the given frame of the envelope.
+    pub fn gain_at(&self, frame: u64) -> f32 {
+        let progress = match self.frames {
+            0 => 1.0,
+            frames => (frame.min(frames) as f64 / frames as f64) as f32,
+        };
+        match self.fade {
+            Fade::In => progress,
+            Fade::Out => 1.0 - progress,
+        }
+    }
+
+    /// The gain for the next frame, the envelope holds its final gain once finished.
+    pub fn next_gain(&mut self) -> f32 {
+        let gain = self.gain_at(self.position);
+        self.position = (self.position + 1).min(self.frames);
+        gain
+    }
+
+    /// Multiply interleaved samples with `channels` channels by the envelope.
+    pub fn apply(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels.max(1)) {
+            let gain = self.next_gain();
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.frames
+    }
+
+    /// The frames of the envelope not yet used.
+    pub fn remaining_frames(&self) -> u64 {
+        self.frames - self.position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade_in() {
+        let mut envelope = FadeEnvelope::new(Fade::In, Duration::from_millis(4), 1000);
+        let gains: Vec<f32> = (0..6).map(|_| envelope.next_gain()).collect();
+        assert_eq!(gains, vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0]);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn fade_out_stereo() {
+        let mut envelope = FadeEnvelope::new(Fade::Out, Duration::from_millis(2), 1000);
+        let mut samples = [1.0f32; 6];
+        envelope.apply(&mut samples, 2);
+        assert_eq!(samples, [1.0, 1.0, 0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn transitions() {
+        let period = Duration::from_secs(1);
+        assert!(FadeEnvelope::for_start(TransType::None, period, 44100).is_none());
+        assert!(FadeEnvelope::for_end(TransType::FadeIn, period, 44100).is_none());
+
+        let start = FadeEnvelope::for_start(TransType::Crossfade, period, 44100).unwrap();
+        let end = FadeEnvelope::for_end(TransType::Crossfade, period, 44100).unwrap();
+        assert_eq!(start.remaining_frames(), 44100);
+        // The gains of a crossfade always sum to one
+        assert_eq!(start.gain_at(11025) + end.gain_at(11025), 1.0);
+
+        let instant = FadeEnvelope::new(Fade::In, Duration::ZERO, 44100);
+        assert_eq!(instant.gain_at(0), 1.0);
+    }
+}
@@ -0,0 +1,407 @@
+//! Gain and volume calculations.
+//!
+//! The server sends gains as 16.16 fixed-point numbers, in the `audg` message for
+//! the volume and in the `strm s` message for the replay gain. A [Gain] can be
+//! viewed as the raw fixed-point value, as a linear factor or in decibels. The
+//! `audg` gains are kept as a [FixedGain] so that they compare exactly as sent.
+//! [Volume] combines the volume, replay gain and preamp into the factors by
+//! which the samples of each channel are multiplied.
+
+use core::{fmt, ops::Mul};
+
+use crate::proto::StreamFlags;
+
+const FIXED_ONE: f64 = 65536.0;
+
+// Without std there is no f64::round, the values rounded are never negative
+#[cfg(feature = "std")]
+pub(crate) fn round(value: f64) -> f64 {
+    value.round()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn round(value: f64) -> f64 {
+    let whole = value as u64 as f64;
+    if value - whole >= 0.5 {
+        whole + 1.0
+    } else {
+        whole
+    }
+}
+
+/// A gain held as a linear factor.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gain(f64);
+
+impl Gain {
+    pub const UNITY: Gain = Gain(1.0);
+    pub const MUTE: Gain = Gain(0.0);
+
+    /// A gain from a 16.16 fixed-point value as sent by the server.
+    pub fn from_fixed(raw: u32) -> Self {
+        Self(raw as f64 / FIXED_ONE)
+    }
+
+    /// A gain from a linear factor, negative factors are taken as zero.
+    pub fn from_linear(factor: f64) -> Self {
+        Self(factor.max(0.0))
+    }
+
+    /// A gain from decibels, this needs `std` for the floating point maths.
+    #[cfg(feature = "std")]
+    pub fn from_db(db: f64) -> Self {
+        Self(10f64.powf(db / 20.0))
+    }
+
+    /// The gain as a 16.16 fixed-point value, saturating at the largest value.
+    pub fn as_fixed(&self) -> u32 {
+        round(self.0 * FIXED_ONE).min(u32::MAX as f64) as u32
+    }
+
+    pub fn as_linear(&self) -> f64 {
+        self.0
+    }
+
+    /// The gain in decibels, a mute gain is negative infinity.
+    #[cfg(feature = "std")]
+    pub fn as_db(&self) -> f64 {
+        20.0 * self.0.log10()
+    }
+
+    pub fn is_mute(&self) -> bool {
+        self.0 == 0.0
+    }
+}
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self::UNITY
+    }
+}
+
+impl Mul for Gain {
+    type Output = Gain;
+
+    fn mul(self, rhs: Gain) -> Gain {
+        Gain(self.0 * rhs.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for Gain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}dB", self.as_db())
+    }
+}
+
+/// A gain exactly as sent by the server, a 16.16 fixed-point value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FixedGain(pub u32);
+
+impl FixedGain {
+    pub const UNITY: FixedGain = FixedGain(1 << 16);
+    pub const MUTE: FixedGain = FixedGain(0);
+
+    /// The gain as a linear factor.
+    pub fn to_f64(&self) -> f64 {
+        self.0 as f64 / FIXED_ONE
+    }
+
+    /// The gain in decibels, a mute gain is negative infinity.
+    #[cfg(feature = "std")]
+    pub fn to_db(&self) -> f64 {
+        20.0 * self.to_f64().log10()
+    }
+}
+
+impl From<u32> for FixedGain {
+    fn from(raw: u32) -> Self {
+        Self(raw)
+    }
+}
+
+impl From<FixedGain> for Gain {
+    fn from(gain: FixedGain) -> Self {
+        Gain::from_fixed(gain.0)
+    }
+}
+
+impl From<Gain> for FixedGain {
+    fn from(gain: Gain) -> Self {
+        Self(gain.as_fixed())
+    }
+}
+
+impl fmt::Display for FixedGain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_f64())
+    }
+}
+
+/// Split a gain between the left and right channels.
+///
+/// A `balance` of -1.0 is fully left, 0.0 is centred and 1.0 is fully right, the
+/// channel away from which the balance is moved is attenuated while the other is
+/// left at the full gain.
+pub fn balance(gain: Gain, balance: f64) -> (Gain, Gain) {
+    let balance = balance.clamp(-1.0, 1.0);
+    (
+        Gain(gain.0 * (1.0 - balance.max(0.0))),
+        Gain(gain.0 * (1.0 + balance.min(0.0))),
+    )
+}
+
+/// The overall gain to apply to each channel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Volume {
+    pub left: Gain,
+    pub right: Gain,
+    pub replay_gain: Gain,
+    pub preamp: Gain,
+    /// False when the server has digital volume control turned off, the left and
+    /// right gains are then for an external amplifier and the samples are left at
+    /// full volume
+    pub digital: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            left: Gain::UNITY,
+            right: Gain::UNITY,
+            replay_gain: Gain::UNITY,
+            preamp: Gain::UNITY,
+            digital: true,
+        }
+    }
+}
+
+impl Volume {
+    /// A volume from the left and right gains of an `audg` message.
+    pub fn new(left: Gain, right: Gain) -> Self {
+        Self {
+            left,
+            right,
+            ..Default::default()
+        }
+    }
+
+    /// Set the replay gain of the current track. The server sends a replay gain of
+    /// zero when there is none so a mute replay gain is taken as unity.
+    pub fn with_replay_gain(mut self, replay_gain: Gain) -> Self {
+        self.replay_gain = if replay_gain.is_mute() {
+            Gain::UNITY
+        } else {
+            replay_gain
+        };
+        self
+    }
+
+    pub fn with_preamp(mut self, preamp: Gain) -> Self {
+        self.preamp = preamp;
+        self
+    }
+
+    /// Set whether the volume is applied to the samples, from the digital volume
+    /// field of an `audg` message.
+    pub fn with_digital(mut self, digital: bool) -> Self {
+        self.digital = digital;
+        self
+    }
+
+    /// The factor by which samples of the left channel are multiplied.
+    pub fn left_factor(&self) -> f64 {
+        (self.sample_gain(self.left) * self.replay_gain * self.preamp).as_linear()
+    }
+
+    /// The factor by which samples of the right channel are multiplied.
+    pub fn right_factor(&self) -> f64 {
+        (self.sample_gain(self.right) * self.replay_gain * self.preamp).as_linear()
+    }
+
+    fn sample_gain(&self, gain: Gain) -> Gain {
+        if self.digital {
+            gain
+        } else {
+            Gain::UNITY
+        }
+    }
+
+    /// Apply the volume to interleaved stereo samples.
+    pub fn apply_stereo(&self, samples: &mut [f32]) {
+        let (left, right) = (self.left_factor() as f32, self.right_factor() as f32);
+        for frame in samples.chunks_exact_mut(2) {
+            frame[0] *= left;
+            frame[1] *= right;
+        }
+    }
+}
+
+/// Apply the replay gain of a `strm s` message to interleaved stereo samples.
+///
+/// A replay gain of zero means the track has none and the samples are left at
+/// their level. The channels are inverted when the polarity inversion flags are set.
+pub fn apply_replay_gain(samples: &mut [f32], replay_gain: Gain, flags: StreamFlags) {
+    let gain = if replay_gain.is_mute() {
+        1.0
+    } else {
+        replay_gain.as_linear() as f32
+    };
+    let left = if flags.contains(StreamFlags::INVERT_POLARITY_LEFT) {
+        -gain
+    } else {
+        gain
+    };
+    let right = if flags.contains(StreamFlags::INVERT_POLARITY_RIGHT) {
+        -gain
+    } else {
+        gain
+    };
+
+    for frame in samples.chunks_exact_mut(2) {
+        frame[0] *= left;
+        frame[1] *= right;
+    }
+}
+
+/// A sample whose polarity can be inverted.
+pub trait Invert: Copy {
+    /// The sample with its polarity inverted, saturating at the largest value.
+    fn invert(self) -> Self;
+}
+
+impl Invert for i16 {
+    fn invert(self) -> Self {
+        self.saturating_neg()
+    }
+}
+
+impl Invert for i32 {
+    fn invert(self) -> Self {
+        self.saturating_neg()
+    }
+}
+
+impl Invert for f32 {
+    fn invert(self) -> Self {
+        -self
+    }
+}
+
+/// Invert the polarity of the channels of interleaved stereo samples for which
+/// the polarity inversion flags of a `strm s` message are set.
+pub fn invert_polarity<S: Invert>(samples: &mut [S], flags: StreamFlags) {
+    let left = flags.contains(StreamFlags::INVERT_POLARITY_LEFT);
+    let right = flags.contains(StreamFlags::INVERT_POLARITY_RIGHT);
+    if !left && !right {
+        return;
+    }
+
+    for frame in samples.chunks_exact_mut(2) {
+        if left {
+            frame[0] = frame[0].invert();
+        }
+        if right {
+            frame[1] = frame[1].invert();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let gain = Gain::from_fixed(0x8000);
+        assert_eq!(gain.as_linear(), 0.5);
+        assert_eq!(gain.as_fixed(), 0x8000);
+        assert_eq!(Gain::from_linear(1.0 / 3.0).as_fixed(), 0x5555);
+        assert_eq!(Gain::from_linear(2.0 / 3.0).as_fixed(), 0xaaab);
+        assert_eq!(Gain::from_linear(-1.0), Gain::MUTE);
+        #[cfg(feature = "std")]
+        {
+            assert!((gain.as_db() + 6.0206).abs() < 0.001);
+            assert!((Gain::from_db(-6.0206).as_linear() - 0.5).abs() < 0.0001);
+            assert_eq!(Gain::MUTE.as_db(), f64::NEG_INFINITY);
+        }
+    }
+
+    #[test]
+    fn fixed_gain() {
+        let gain = FixedGain(0x8000);
+        assert_eq!(gain.to_f64(), 0.5);
+        assert_eq!(FixedGain::from(Gain::from(gain)), gain);
+        #[cfg(feature = "std")]
+        {
+            assert!((gain.to_db() + 6.0206).abs() < 0.001);
+            assert_eq!(FixedGain::UNITY.to_db(), 0.0);
+        }
+        assert_eq!(FixedGain(0x8000).to_string(), "0.5");
+    }
+
+    #[test]
+    fn balanced() {
+        let (l, r) = balance(Gain::UNITY, 0.0);
+        assert_eq!((l, r), (Gain::UNITY, Gain::UNITY));
+        let (l, r) = balance(Gain::UNITY, 0.5);
+        assert_eq!((l.as_linear(), r.as_linear()), (0.5, 1.0));
+        let (l, r) = balance(Gain::UNITY, -2.0);
+        assert_eq!((l.as_linear(), r.as_linear()), (1.0, 0.0));
+    }
+
+    #[test]
+    fn polarity() {
+        let mut samples = [1i16, 2, i16::MIN, 4];
+        invert_polarity(&mut samples, StreamFlags::INVERT_POLARITY_LEFT);
+        assert_eq!(samples, [-1, 2, i16::MAX, 4]);
+
+        let mut samples = [1i32, 2, 3, 4];
+        invert_polarity(
+            &mut samples,
+            StreamFlags::INVERT_POLARITY_LEFT | StreamFlags::INVERT_POLARITY_RIGHT,
+        );
+        assert_eq!(samples, [-1, -2, -3, -4]);
+
+        let mut samples = [0.5f32, 0.25, 1.0];
+        invert_polarity(&mut samples, StreamFlags::INVERT_POLARITY_RIGHT);
+        assert_eq!(samples, [0.5, -0.25, 1.0]);
+        invert_polarity(&mut samples, StreamFlags::OUTPUT_LEFT_ONLY);
+        assert_eq!(samples, [0.5, -0.25, 1.0]);
+    }
+
+    #[test]
+    fn replay_gain() {
+        let mut samples = [0.5f32, 0.5, 1.0, -1.0];
+        apply_replay_gain(&mut samples, Gain::MUTE, StreamFlags::empty());
+        assert_eq!(samples, [0.5, 0.5, 1.0, -1.0]);
+
+        apply_replay_gain(
+            &mut samples,
+            Gain::from_fixed(0x8000),
+            StreamFlags::INVERT_POLARITY_RIGHT,
+        );
+        assert_eq!(samples, [0.25, -0.25, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn volume() {
+        let volume = Volume::new(Gain::from_linear(0.5), Gain::UNITY)
+            .with_replay_gain(Gain::from_fixed(0))
+            .with_preamp(Gain::from_linear(0.5));
+        assert_eq!(volume.left_factor(), 0.25);
+        assert_eq!(volume.right_factor(), 0.5);
+
+        let mut samples = [1.0f32, 1.0, 0.5, -0.5];
+        volume.apply_stereo(&mut samples);
+        assert_eq!(samples, [0.25, 0.5, 0.125, -0.25]);
+
+        let volume = volume.with_digital(false);
+        assert_eq!(volume.left_factor(), 0.5);
+        assert_eq!(volume.right_factor(), 0.5);
+    }
+}
@@ -0,0 +1,182 @@
+//! Types for the HTTP request that the server asks the client to send when streaming.
+//!
+//! A `strm s` message carries the complete HTTP request that the client must send
+//! to the server's data port to start receiving the audio stream. [HttpRequest]
+//! parses that request so that players can inspect it without parsing it by hand.
+
+use std::{collections::HashMap, fmt, net::SocketAddrV4, str::FromStr};
+
+/// An HTTP request as sent by the server in a `strm s` message.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    /// The request headers, keyed by the lower case header name
+    pub headers: HashMap<String, String>,
+    /// The request exactly as it is to be sent
+    pub raw: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Build a GET request for `path` from the server at `host`.
+    /// See [Server::build_get](crate::proto::Server::build_get) to build one
+    /// from the server address in a Stream message.
+    pub fn get(path: &str, host: SocketAddrV4) -> Self {
+        let raw = format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host);
+        let mut headers = HashMap::new();
+        headers.insert("host".to_owned(), host.to_string());
+        Self {
+            method: "GET".to_owned(),
+            path: path.to_owned(),
+            version: "HTTP/1.0".to_owned(),
+            headers,
+            raw: raw.into_bytes(),
+        }
+    }
+
+    /// Look up a header by name without regard to case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// The bytes to send to the server.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Returns `true` if the request ends with the empty line after its headers.
+    /// A request that was cut short, for example because it did not fit in one
+    /// message from the server, does not. Lines may end in `\n` as well as `\r\n`
+    /// and whitespace after the empty line is ignored.
+    pub fn is_complete(&self) -> bool {
+        let is_blank = |line: &[u8]| line.iter().all(u8::is_ascii_whitespace);
+        let mut lines = self.raw.rsplit(|b| *b == b'\n');
+        matches!(
+            (lines.next(), lines.next()),
+            (Some(rest), Some(last)) if is_blank(rest) && is_blank(last)
+        )
+    }
+}
+
+/// The error returned when a request line cannot be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseHttpRequestError;
+
+impl fmt::Display for ParseHttpRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Malformed HTTP request line")
+    }
+}
+
+impl std::error::Error for ParseHttpRequestError {}
+
+impl FromStr for HttpRequest {
+    type Err = ParseHttpRequestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines();
+        let mut request_line = lines.next().ok_or(ParseHttpRequestError)?.split(' ');
+        let (method, path, version) = match (
+            request_line.next(),
+            request_line.next(),
+            request_line.next(),
+        ) {
+            (Some(method), Some(path), Some(version)) if !method.is_empty() => {
+                (method, path, version)
+            }
+            _ => return Err(ParseHttpRequestError),
+        };
+
+        let headers = lines
+            .take_while(|line| !line.trim().is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+            .collect();
+
+        Ok(Self {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            version: version.to_owned(),
+            headers,
+            raw: s.as_bytes().to_vec(),
+        })
+    }
+}
+
+impl fmt::Display for HttpRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.raw))
+    }
+}
+
+// The status field is one byte, so the count stops at its largest value
+pub(crate) fn count_crlf(headers: &str) -> u8 {
+    headers
+        .matches("\r\n")
+        .count()
+        .try_into()
+        .unwrap_or(u8::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let raw = "GET /stream.mp3?player=00:01:02:03:04:05 HTTP/1.0\r\nHost: 172.16.1.2:9000\r\nIcy-MetaData: 1\r\n\r\n";
+        let req: HttpRequest = raw.parse().unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/stream.mp3?player=00:01:02:03:04:05");
+        assert_eq!(req.version, "HTTP/1.0");
+        assert_eq!(req.header("icy-metadata"), Some("1"));
+        assert_eq!(req.header("HOST"), Some("172.16.1.2:9000"));
+        assert_eq!(req.as_bytes(), raw.as_bytes());
+
+        assert!("".parse::<HttpRequest>().is_err());
+    }
+
+    #[test]
+    fn complete() {
+        let req: HttpRequest = "GET /stream.mp3 HTTP/1.0\r\nHost: 172.16.1.2:9000\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert!(req.is_complete());
+
+        let req: HttpRequest = "GET /stream.mp3 HTTP/1.0\r\nHost: 172.16".parse().unwrap();
+        assert!(!req.is_complete());
+        let req: HttpRequest = "GET /stream.mp3 HTTP/1.0\r\nHost: 172.16.1.2:9000\r\n"
+            .parse()
+            .unwrap();
+        assert!(!req.is_complete());
+
+        // Bare line feeds and trailing whitespace, as sent by some servers
+        let req: HttpRequest = "GET /stream.mp3 HTTP/1.0\nHost: 172.16.1.2:9000\n\n"
+            .parse()
+            .unwrap();
+        assert!(req.is_complete());
+        assert_eq!(req.version, "HTTP/1.0");
+        assert_eq!(req.header("host"), Some("172.16.1.2:9000"));
+        let req: HttpRequest = "GET /stream.mp3 HTTP/1.0\r\nHost: 172.16.1.2:9000\r\n\r\n \t"
+            .parse()
+            .unwrap();
+        assert!(req.is_complete());
+    }
+
+    #[test]
+    fn crlf() {
+        assert_eq!(count_crlf("HTTP/1.0 200 OK\r\n\r\n"), 2);
+        assert_eq!(count_crlf(&"\r\n".repeat(300)), u8::MAX);
+    }
+
+    #[test]
+    fn build() {
+        let req = HttpRequest::get("/stream.mp3", "172.16.1.2:9000".parse().unwrap());
+        let parsed: HttpRequest = req.to_string().parse().unwrap();
+        assert_eq!(parsed, req);
+    }
+}
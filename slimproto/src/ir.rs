@@ -0,0 +1,357 @@
+//! Remote control input from Linux lirc and evdev devices.
+//!
+//! Hardware players decode the infrared remote themselves and send each key press
+//! to the server in an `IR` message, the server then decides what the key does. An
+//! [IrReader] reads key presses from the lirc daemon's socket or from an input
+//! device under `/dev/input`, and an [IrMap] gives the code that the server
+//! expects for each key. The codes are those of the server's IR map files, such
+//! as `IR/Slim_Devices_Remote.ir`, and must be supplied by the caller.
+//!
+//! ```no_run
+//! use slimproto::{ir::{IrMap, IrReader}, status::SharedStatus};
+//!
+//! let map = IrMap::parse("KEY_PLAYPAUSE = 0x768910ef").unwrap();
+//! let status = SharedStatus::default();
+//! let mut remote = IrReader::open_evdev("/dev/input/event0").unwrap();
+//! let e = remote.forward(&map, &status, |msg| println!("{}", msg));
+//! eprintln!("remote closed: {}", e);
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use crate::{status::SharedStatus, ClientMessage};
+
+/// The socket on which the lirc daemon sends decoded key presses.
+pub const LIRCD_SOCKET: &str = "/var/run/lirc/lircd";
+
+// The codes of the commonly used remote control keys from linux/input-event-codes.h
+const KEY_NAMES: &[(u16, &str)] = &[
+    (1, "KEY_ESC"),
+    (2, "KEY_1"),
+    (3, "KEY_2"),
+    (4, "KEY_3"),
+    (5, "KEY_4"),
+    (6, "KEY_5"),
+    (7, "KEY_6"),
+    (8, "KEY_7"),
+    (9, "KEY_8"),
+    (10, "KEY_9"),
+    (11, "KEY_0"),
+    (28, "KEY_ENTER"),
+    (102, "KEY_HOME"),
+    (103, "KEY_UP"),
+    (105, "KEY_LEFT"),
+    (106, "KEY_RIGHT"),
+    (108, "KEY_DOWN"),
+    (113, "KEY_MUTE"),
+    (114, "KEY_VOLUMEDOWN"),
+    (115, "KEY_VOLUMEUP"),
+    (116, "KEY_POWER"),
+    (119, "KEY_PAUSE"),
+    (139, "KEY_MENU"),
+    (158, "KEY_BACK"),
+    (163, "KEY_NEXTSONG"),
+    (164, "KEY_PLAYPAUSE"),
+    (165, "KEY_PREVIOUSSONG"),
+    (166, "KEY_STOPCD"),
+    (167, "KEY_RECORD"),
+    (168, "KEY_REWIND"),
+    (200, "KEY_PLAYCD"),
+    (201, "KEY_PAUSECD"),
+    (207, "KEY_PLAY"),
+    (208, "KEY_FASTFORWARD"),
+    (352, "KEY_OK"),
+    (358, "KEY_INFO"),
+    (365, "KEY_EPG"),
+    (370, "KEY_SUBTITLE"),
+    (385, "KEY_RADIO"),
+    (392, "KEY_AUDIO"),
+    (393, "KEY_VIDEO"),
+    (398, "KEY_RED"),
+    (399, "KEY_GREEN"),
+    (400, "KEY_YELLOW"),
+    (401, "KEY_BLUE"),
+];
+
+/// The name of an evdev key code as lirc would give it, e.g. `KEY_PLAYPAUSE`.
+/// Codes without a name are given as `KEY_` followed by the number.
+pub fn key_name(code: u16) -> String {
+    KEY_NAMES
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| (*name).to_owned())
+        .unwrap_or_else(|| format!("KEY_{}", code))
+}
+
+/// The codes sent to the server for each key name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IrMap(HashMap<String, u32>);
+
+impl IrMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a map from lines of the form `KEY_PLAY = 0x768910ef`. The code may
+    /// also be given in decimal, blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut map = Self::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid IR map line: {}", line),
+                )
+            };
+            let (name, code) = line.split_once('=').ok_or_else(invalid)?;
+            let code = code.trim();
+            let code = match code.strip_prefix("0x").or_else(|| code.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => code.parse(),
+            }
+            .map_err(|_| invalid())?;
+            map.insert(name.trim(), code);
+        }
+        Ok(map)
+    }
+
+    /// Send `code` to the server when the key `name` is pressed.
+    pub fn insert(&mut self, name: &str, code: u32) {
+        self.0.insert(name.to_owned(), code);
+    }
+
+    /// The code for the key `name`, if it is mapped.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.0.get(name).copied()
+    }
+
+    /// Add a key to the map, for building one in code.
+    pub fn with(mut self, name: &str, code: u32) -> Self {
+        self.insert(name, code);
+        self
+    }
+}
+
+enum Source<R> {
+    Lirc(BufReader<R>),
+    Evdev(R),
+}
+
+/// Reads key presses from lirc or an evdev input device.
+///
+/// A held key is reported each time the device repeats it, the server uses the
+/// time between repeats to tell a held key from separate presses.
+pub struct IrReader<R> {
+    source: Source<R>,
+}
+
+impl IrReader<UnixStream> {
+    /// Connect to the lirc daemon's socket, usually [LIRCD_SOCKET].
+    pub fn open_lirc(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::lirc(UnixStream::connect(path)?))
+    }
+}
+
+impl IrReader<File> {
+    /// Open an input device such as `/dev/input/event0`. The user must be able to
+    /// read the device, which usually means being in the `input` group.
+    pub fn open_evdev(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::evdev(File::open(path)?))
+    }
+}
+
+impl<R: Read> IrReader<R> {
+    /// Read the lines sent by the lirc daemon, e.g.
+    /// `000000037ff07bee 00 KEY_PLAYPAUSE mceusb`.
+    pub fn lirc(reader: R) -> Self {
+        Self {
+            source: Source::Lirc(BufReader::new(reader)),
+        }
+    }
+
+    /// Read the `input_event` structures of a 64 bit Linux input device.
+    pub fn evdev(reader: R) -> Self {
+        Self {
+            source: Source::Evdev(reader),
+        }
+    }
+
+    /// Wait for the next key press and return its name.
+    pub fn next_key(&mut self) -> io::Result<String> {
+        match &mut self.source {
+            Source::Lirc(reader) => next_lirc_key(reader),
+            Source::Evdev(reader) => next_evdev_key(reader),
+        }
+    }
+
+    /// Wait for the next press of a key in `map` and make the message for it,
+    /// timed by the player's jiffies clock. Other keys are ignored.
+    pub fn next_message(
+        &mut self,
+        map: &IrMap,
+        status: &SharedStatus,
+    ) -> io::Result<ClientMessage> {
+        loop {
+            let key = self.next_key()?;
+            if let Some(code) = map.get(&key) {
+                return Ok(ClientMessage::Ir {
+                    time: status.lock().jiffies_now(),
+                    format: 0,
+                    bits: 32,
+                    code,
+                });
+            }
+        }
+    }
+
+    /// Hand a message to `send` for each mapped key press until reading from the
+    /// device fails, the error is returned. `send` is usually
+    /// [StatusReporter::send_message](crate::status::StatusReporter::send_message).
+    pub fn forward(
+        &mut self,
+        map: &IrMap,
+        status: &SharedStatus,
+        mut send: impl FnMut(ClientMessage),
+    ) -> io::Error {
+        loop {
+            match self.next_message(map, status) {
+                Ok(msg) => send(msg),
+                Err(e) => return e,
+            }
+        }
+    }
+}
+
+fn next_lirc_key(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "lirc connection closed",
+            ));
+        }
+        // The daemon also sends replies to commands, which start with BEGIN
+        let mut fields = line.split_whitespace();
+        if let (Some(code), Some(_repeat), Some(name)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            if u64::from_str_radix(code, 16).is_ok() {
+                return Ok(name.to_owned());
+            }
+        }
+    }
+}
+
+// An input_event starts with a timeval of two longs, 16 bytes on 64 bit targets
+// and 8 on 32 bit ones, then the type, code and value
+#[cfg(target_pointer_width = "64")]
+const TIMEVAL_SIZE: usize = 16;
+#[cfg(target_pointer_width = "32")]
+const TIMEVAL_SIZE: usize = 8;
+const EVENT_SIZE: usize = TIMEVAL_SIZE + 8;
+const EV_KEY: u16 = 1;
+
+fn next_evdev_key(reader: &mut impl Read) -> io::Result<String> {
+    let mut event = [0u8; EVENT_SIZE];
+    loop {
+        reader.read_exact(&mut event)?;
+        // The event follows the timeval in native byte order
+        let fields = &event[TIMEVAL_SIZE..];
+        let kind = u16::from_ne_bytes([fields[0], fields[1]]);
+        let code = u16::from_ne_bytes([fields[2], fields[3]]);
+        let value = i32::from_ne_bytes([fields[4], fields[5], fields[6], fields[7]]);
+        // A value of 1 is a press and 2 a repeat, releases are not sent
+        if kind == EV_KEY && (value == 1 || value == 2) {
+            return Ok(key_name(code));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event = vec![0u8; TIMEVAL_SIZE];
+        event.extend(kind.to_ne_bytes());
+        event.extend(code.to_ne_bytes());
+        event.extend(value.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn map() {
+        let map = IrMap::parse("# Slim remote\n\nKEY_PLAY = 0x768910ef\nKEY_MUTE=1234\n").unwrap();
+        assert_eq!(map.get("KEY_PLAY"), Some(0x7689_10ef));
+        assert_eq!(map.get("KEY_MUTE"), Some(1234));
+        assert_eq!(map.get("KEY_STOP"), None);
+
+        assert!(IrMap::parse("KEY_PLAY 0x768910ef").is_err());
+        assert!(IrMap::parse("KEY_PLAY = play").is_err());
+    }
+
+    #[test]
+    fn lirc() {
+        let data = "BEGIN\nSIGHUP\nEND\n\
+            000000037ff07bee 00 KEY_PLAYPAUSE mceusb\n\
+            000000037ff07bef 01 KEY_VOLUMEUP mceusb\n";
+        let mut remote = IrReader::lirc(data.as_bytes());
+        assert_eq!(remote.next_key().unwrap(), "KEY_PLAYPAUSE");
+        assert_eq!(remote.next_key().unwrap(), "KEY_VOLUMEUP");
+        assert_eq!(
+            remote.next_key().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn evdev() {
+        let data = [
+            event(4, 4, 0x7689),
+            event(EV_KEY, 164, 1),
+            event(0, 0, 0),
+            event(EV_KEY, 164, 0),
+            event(EV_KEY, 115, 1),
+            event(EV_KEY, 115, 2),
+            event(EV_KEY, 999, 1),
+        ]
+        .concat();
+        let mut remote = IrReader::evdev(&data[..]);
+        assert_eq!(remote.next_key().unwrap(), "KEY_PLAYPAUSE");
+        assert_eq!(remote.next_key().unwrap(), "KEY_VOLUMEUP");
+        assert_eq!(remote.next_key().unwrap(), "KEY_VOLUMEUP");
+        assert_eq!(remote.next_key().unwrap(), "KEY_999");
+    }
+
+    #[test]
+    fn forward() {
+        let data = [event(EV_KEY, 113, 1), event(EV_KEY, 164, 1)].concat();
+        let map = IrMap::new().with("KEY_PLAYPAUSE", 0x7689_10ef);
+        let status = SharedStatus::default();
+        let mut sent = Vec::new();
+
+        let e = IrReader::evdev(&data[..]).forward(&map, &status, |msg| sent.push(msg));
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(
+            sent[0],
+            ClientMessage::Ir {
+                format: 0,
+                bits: 32,
+                code: 0x7689_10ef,
+                ..
+            }
+        ));
+    }
+}
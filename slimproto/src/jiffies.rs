@@ -0,0 +1,203 @@
+//! A wrap-aware millisecond clock as used by the Slim protocol.
+//!
+//! The protocol exchanges times as 32 bit millisecond counters, known as jiffies,
+//! which wrap around roughly every 49.7 days. The [Jiffies] type holds such a
+//! counter and does all its arithmetic and comparisons modulo 2^32 so that
+//! times either side of a wrap still behave sensibly.
+//!
+//! A [Clock] gives the player's current jiffies. [SystemClock] counts from when it
+//! was made, [ManualClock] only moves when it is told to so that tests and
+//! simulations can control time.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+/// A 32 bit millisecond counter that wraps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Jiffies(u32);
+
+impl Jiffies {
+    pub const ZERO: Jiffies = Jiffies(0);
+
+    pub const fn from_millis(millis: u32) -> Self {
+        Self(millis)
+    }
+
+    /// The jiffies elapsed since `start`, wrapping if necessary.
+    #[cfg(feature = "std")]
+    pub fn since(start: Instant) -> Self {
+        start.elapsed().into()
+    }
+
+    pub const fn as_millis(self) -> u32 {
+        self.0
+    }
+
+    pub fn as_duration(self) -> Duration {
+        Duration::from_millis(self.0 as u64)
+    }
+
+    pub const fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The time from `earlier` to this time, taking account of any wrap in between.
+    pub fn duration_since(self, earlier: Jiffies) -> Duration {
+        Duration::from_millis(self.0.wrapping_sub(earlier.0) as u64)
+    }
+
+    /// Returns `true` if this time is before `other`.
+    ///
+    /// Times are compared as if they are less than half the range of the counter
+    /// apart, about 24.8 days, so a time just before a wrap is before a time just after it.
+    pub fn is_before(self, other: Jiffies) -> bool {
+        (other.0.wrapping_sub(self.0) as i32) > 0
+    }
+
+    /// Returns `true` if this time is after `other`, see [is_before](Jiffies::is_before).
+    pub fn is_after(self, other: Jiffies) -> bool {
+        other.is_before(self)
+    }
+}
+
+/// Converts modulo 2^32 milliseconds.
+impl From<Duration> for Jiffies {
+    fn from(value: Duration) -> Self {
+        Self(value.as_millis() as u32)
+    }
+}
+
+impl From<u32> for Jiffies {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
+impl Add<Duration> for Jiffies {
+    type Output = Jiffies;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self(self.0.wrapping_add(Jiffies::from(rhs).0))
+    }
+}
+
+impl Sub<Duration> for Jiffies {
+    type Output = Jiffies;
+
+    fn sub(self, rhs: Duration) -> Self::Output {
+        Self(self.0.wrapping_sub(Jiffies::from(rhs).0))
+    }
+}
+
+impl fmt::Display for Jiffies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}ms", self.0)
+    }
+}
+
+/// A source of the player's jiffies.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Jiffies;
+}
+
+/// A clock that counts the jiffies since it was made. It needs `std` and a
+/// working `Instant`, which `wasm32-unknown-unknown` does not have.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+#[cfg(feature = "std")]
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Jiffies {
+        Jiffies::since(self.start)
+    }
+}
+
+/// A clock that only moves when it is set or advanced, clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock(Arc<AtomicU32>);
+
+impl ManualClock {
+    pub fn new(now: Jiffies) -> Self {
+        Self(Arc::new(AtomicU32::new(now.as_millis())))
+    }
+
+    pub fn set(&self, now: Jiffies) {
+        self.0.store(now.as_millis(), Ordering::Relaxed);
+    }
+
+    /// Move the clock on by `by`, wrapping if necessary.
+    pub fn advance(&self, by: Duration) {
+        let by = Jiffies::from(by).as_millis();
+        self.0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |now| {
+                Some(now.wrapping_add(by))
+            })
+            .ok();
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Jiffies {
+        Jiffies::from_millis(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping() {
+        let before = Jiffies::from_millis(u32::MAX - 9);
+        let after = before + Duration::from_millis(20);
+        assert_eq!(after, Jiffies::from_millis(10));
+        assert_eq!(after.duration_since(before), Duration::from_millis(20));
+        assert_eq!(after - Duration::from_millis(20), before);
+        assert!(before.is_before(after));
+        assert!(after.is_after(before));
+        assert!(!after.is_before(after));
+    }
+
+    #[test]
+    fn from_duration() {
+        let d = Duration::from_millis(u32::MAX as u64 + 1235);
+        assert_eq!(Jiffies::from(d).as_millis(), 1234);
+    }
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(Jiffies::from_millis(u32::MAX - 9));
+        let shared = clock.clone();
+        shared.advance(Duration::from_millis(20));
+        assert_eq!(clock.now(), Jiffies::from_millis(10));
+        clock.set(Jiffies::ZERO);
+        assert_eq!(shared.now(), Jiffies::ZERO);
+    }
+}
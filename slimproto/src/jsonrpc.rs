@@ -0,0 +1,481 @@
+//! A client for the server's JSON interface, known as the CLI.
+//!
+//! The server answers requests on its web port, given by the `JSON` value of the
+//! discovery response. Requests are made on behalf of a player, named by its MAC
+//! address, and give the information that the Slim protocol does not, such as the
+//! metadata of the current track.
+//!
+//! ```no_run
+//! use slimproto::{discovery::discover, jsonrpc::Cli};
+//!
+//! let server = discover(None)?.unwrap();
+//! let cli = Cli::from_server(&server, slimproto::proto::MacAddress::new([1, 2, 3, 4, 5, 6]));
+//! if let Some(track) = cli.current_track()? {
+//!     println!("{} by {}", track.title, track.artist);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! [NowPlaying] polls the server to follow the track that the player is playing
+//! and [fetch_artwork] fetches the image of a track's artwork.
+
+use std::{
+    io,
+    net::Ipv4Addr,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread::{spawn, JoinHandle},
+    time::Duration,
+};
+
+use crate::proto::MacAddress;
+use serde_json::{json, Value};
+
+use crate::proto::Server;
+
+/// The port of the server's web interface when discovery does not give one.
+pub const DEFAULT_JSON_PORT: u16 = 9000;
+
+// An agent that gives up on a server that does not connect or stops responding
+fn agent() -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .timeout_connect(Some(Duration::from_secs(5)))
+        .timeout_recv_response(Some(Duration::from_secs(10)))
+        .timeout_recv_body(Some(Duration::from_secs(10)))
+        .build()
+        .into()
+}
+
+/// A client for the JSON interface of a server.
+#[derive(Clone, Debug)]
+pub struct Cli {
+    address: Ipv4Addr,
+    port: u16,
+    player: String,
+    agent: ureq::Agent,
+}
+
+impl Cli {
+    /// A client for the server at `address` and `port` making requests for the
+    /// player with the given MAC address.
+    pub fn new(address: Ipv4Addr, port: u16, player: MacAddress) -> Self {
+        Self {
+            address,
+            port,
+            player: player.to_string().to_lowercase(),
+            agent: agent(),
+        }
+    }
+
+    /// A client for a discovered server, using the JSON port from discovery.
+    pub fn from_server(server: &Server, player: MacAddress) -> Self {
+        Self::new(
+            *server.socket.ip(),
+            server.json_port().unwrap_or(DEFAULT_JSON_PORT),
+            player,
+        )
+    }
+
+    /// The base URL of the server's web interface.
+    pub fn base_url(&self) -> String {
+        format!("http://{}:{}", self.address, self.port)
+    }
+
+    /// The player for which requests are made.
+    pub fn player(&self) -> &str {
+        &self.player
+    }
+
+    /// Send a command for the player, such as `["mixer", "volume", "50"]`, and
+    /// return the result object of the response.
+    pub fn request(&self, command: &[&str]) -> io::Result<Value> {
+        let body = json!({
+            "id": 1,
+            "method": "slim.request",
+            "params": [self.player, command],
+        });
+
+        let response = self
+            .agent
+            .post(format!("{}/jsonrpc.js", self.base_url()))
+            .header("content-type", "application/json")
+            .send(body.to_string())
+            .map_err(io::Error::other)?;
+        let mut response: Value = serde_json::from_reader(response.into_body().into_reader())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        match response.get_mut("result") {
+            Some(result) => Ok(result.take()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No result in response",
+            )),
+        }
+    }
+
+    fn status(&self) -> io::Result<Value> {
+        self.request(&["status", "-", "1", "tags:acdlKj"])
+    }
+
+    /// The metadata of the track that is playing, or `None` if the playlist is empty.
+    pub fn current_track(&self) -> io::Result<Option<TrackInfo>> {
+        Ok(TrackInfo::from_status(&self.status()?, &self.base_url()))
+    }
+
+    /// The URL of the artwork of the current track.
+    pub fn artwork_url(&self) -> io::Result<Option<String>> {
+        Ok(self.current_track()?.and_then(|track| track.artwork_url))
+    }
+
+    /// The position of the current track in the playlist.
+    pub fn playlist_position(&self) -> io::Result<Option<PlaylistPosition>> {
+        Ok(PlaylistPosition::from_status(&self.status()?))
+    }
+
+    pub fn power(&self) -> io::Result<bool> {
+        let result = self.request(&["power", "?"])?;
+        Ok(as_u64(&result["_power"]) == Some(1))
+    }
+
+    pub fn set_power(&self, on: bool) -> io::Result<()> {
+        self.request(&["power", if on { "1" } else { "0" }])
+            .map(|_| ())
+    }
+}
+
+/// The metadata of a track.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrackInfo {
+    pub id: Option<i64>,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub duration: Option<Duration>,
+    pub artwork_url: Option<String>,
+}
+
+impl TrackInfo {
+    /// The current track from the result of a `status` request. Relative artwork
+    /// URLs are made absolute with `base_url`.
+    pub(crate) fn from_status(status: &Value, base_url: &str) -> Option<Self> {
+        let track = status["playlist_loop"].get(0)?;
+        let text = |key: &str| track[key].as_str().unwrap_or_default().to_owned();
+
+        let artwork = match (track["artwork_url"].as_str(), track["coverid"].as_str()) {
+            (Some(url), _) => Some(Artwork::Url(url.to_owned())),
+            (None, Some(coverid)) => Some(Artwork::Track(coverid.to_owned())),
+            (None, None) => None,
+        };
+
+        Some(Self {
+            id: track["id"].as_i64(),
+            title: text("title"),
+            artist: text("artist"),
+            album: text("album"),
+            duration: as_f64(&track["duration"]).map(Duration::from_secs_f64),
+            artwork_url: artwork.map(|artwork| artwork.url(base_url, None)),
+        })
+    }
+}
+
+/// The position of the current track in the player's playlist, counting from zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlaylistPosition {
+    pub index: u64,
+    pub length: u64,
+}
+
+impl PlaylistPosition {
+    pub(crate) fn from_status(status: &Value) -> Option<Self> {
+        Some(Self {
+            index: as_u64(&status["playlist_cur_index"])?,
+            length: as_u64(&status["playlist_tracks"])?,
+        })
+    }
+}
+
+/// The artwork to fetch with [fetch_artwork].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Artwork {
+    /// The artwork of the track with the given id, or cover id.
+    Track(String),
+    /// An artwork URL such as [TrackInfo::artwork_url], relative URLs are taken
+    /// to be on the server.
+    Url(String),
+}
+
+impl Artwork {
+    fn url(&self, base_url: &str, size: Option<u32>) -> String {
+        match self {
+            Artwork::Track(id) => match size {
+                Some(size) => format!("{}/music/{}/cover_{}x{}.jpg", base_url, id, size, size),
+                None => format!("{}/music/{}/cover.jpg", base_url, id),
+            },
+            Artwork::Url(url) if url.starts_with("http") => url.to_owned(),
+            Artwork::Url(url) => format!("{}/{}", base_url, url.trim_start_matches('/')),
+        }
+    }
+}
+
+/// Fetch the image of some artwork from the server, the image is a JPEG for
+/// track artwork. The server scales track artwork to fit a square of `size`
+/// pixels if given.
+pub fn fetch_artwork(server: &Server, artwork: &Artwork, size: Option<u32>) -> io::Result<Vec<u8>> {
+    let base_url = format!(
+        "http://{}:{}",
+        server.socket.ip(),
+        server.json_port().unwrap_or(DEFAULT_JSON_PORT)
+    );
+
+    agent()
+        .get(artwork.url(&base_url, size))
+        .call()
+        .map_err(io::Error::other)?
+        .into_body()
+        .read_to_vec()
+        .map_err(io::Error::other)
+}
+
+/// Sent by [NowPlaying] when the current track of a player changes. The track is
+/// `None` when the playlist is emptied.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrackChanged {
+    /// The MAC address of the player, as given by [Cli::player].
+    pub player: String,
+    pub track: Option<TrackInfo>,
+}
+
+/// Follows the current track of a player by polling the server from a thread.
+///
+/// The callback is called with the first track found and then whenever the track
+/// changes. Requests that fail are retried at the next poll. Polling stops when
+/// the `NowPlaying` is dropped.
+pub struct NowPlaying {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl NowPlaying {
+    pub fn spawn<F>(cli: Cli, interval: Duration, mut cb: F) -> Self
+    where
+        F: FnMut(TrackChanged) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let handle = spawn(move || {
+            let mut current = None;
+            loop {
+                if let Ok(track) = cli.current_track() {
+                    if current.as_ref() != Some(&track) {
+                        current = Some(track.clone());
+                        cb(TrackChanged {
+                            player: cli.player().to_owned(),
+                            track,
+                        });
+                    }
+                }
+
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => break,
+                }
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    /// Stop polling and wait for the polling thread to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+impl Drop for NowPlaying {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// The server sends some numbers as strings
+fn as_u64(value: &Value) -> Option<u64> {
+    value
+        .as_u64()
+        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    value
+        .as_f64()
+        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread::spawn,
+    };
+
+    #[test]
+    fn status() {
+        let status = json!({
+            "power": 1,
+            "playlist_cur_index": "2",
+            "playlist_tracks": 10,
+            "playlist_loop": [{
+                "id": 42,
+                "title": "Blue in Green",
+                "artist": "Miles Davis",
+                "album": "Kind of Blue",
+                "duration": "337.5",
+                "coverid": "ab12",
+            }],
+        });
+
+        let track = TrackInfo::from_status(&status, "http://10.0.0.1:9000").unwrap();
+        assert_eq!(track.id, Some(42));
+        assert_eq!(track.title, "Blue in Green");
+        assert_eq!(track.duration, Some(Duration::from_secs_f64(337.5)));
+        assert_eq!(
+            track.artwork_url.as_deref(),
+            Some("http://10.0.0.1:9000/music/ab12/cover.jpg")
+        );
+        assert_eq!(
+            PlaylistPosition::from_status(&status),
+            Some(PlaylistPosition {
+                index: 2,
+                length: 10
+            })
+        );
+        assert!(TrackInfo::from_status(&json!({"playlist_tracks": 0}), "").is_none());
+    }
+
+    #[test]
+    fn request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"}") {
+                let len = cx.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            let body = r#"{"id":1,"result":{"_power":"1"}}"#;
+            write!(
+                cx,
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let cli = Cli::new(
+            Ipv4Addr::LOCALHOST,
+            port,
+            MacAddress::new([0xaa, 0xbb, 0xcc, 1, 2, 3]),
+        );
+        assert!(cli.power().unwrap());
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /jsonrpc.js"));
+        assert!(request.contains(r#""aa:bb:cc:01:02:03""#));
+    }
+
+    #[test]
+    fn now_playing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let titles = ["One", "One", "Two"];
+            for (n, stream) in listener.incoming().enumerate() {
+                let mut cx = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"}") {
+                    let len = cx.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..len]);
+                }
+                let title = titles[n.min(titles.len() - 1)];
+                let body = json!({"result": {"playlist_loop": [{"title": title}]}}).to_string();
+                write!(
+                    cx,
+                    "HTTP/1.0 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let cli = Cli::new(
+            Ipv4Addr::LOCALHOST,
+            port,
+            MacAddress::new([1, 2, 3, 4, 5, 6]),
+        );
+        let now_playing = NowPlaying::spawn(cli, Duration::from_millis(10), move |event| {
+            tx.send(event).ok();
+        });
+
+        let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        now_playing.stop();
+
+        assert_eq!(first.player, "01:02:03:04:05:06");
+        assert_eq!(first.track.unwrap().title, "One");
+        assert_eq!(second.track.unwrap().title, "Two");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn artwork() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let len = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\njpg")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+
+        let mut lms = Server::new(Ipv4Addr::LOCALHOST);
+        lms.tlv_map = Some(
+            [("JSON".to_owned(), crate::proto::ServerTlv::Port(port))]
+                .into_iter()
+                .collect(),
+        );
+
+        let image = fetch_artwork(&lms, &Artwork::Track("42".to_owned()), Some(100)).unwrap();
+        assert_eq!(image, b"jpg");
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /music/42/cover_100x100.jpg"));
+
+        assert_eq!(
+            Artwork::Url("/imageproxy/a/image.png".to_owned()).url("http://h:9000", None),
+            "http://h:9000/imageproxy/a/image.png"
+        );
+    }
+}
@@ -4,7 +4,7 @@
 //!
 //! Primarily, communicating with the server is done by instantiating a `Server` object, connecting to
 //! the LMS server and then reading from and writing to supplied connection objects.
-//! See [SlimProto][slimproto].
+//! See the [proto] module.
 //!
 //! This library also provides a [discover][discover] function to enable auto-discovery of LMS
 //! servers on the network and a [StatusData][statusdata] struct to simplify the creation of the
@@ -13,20 +13,87 @@
 //! In order to use this library it's a good idea to have studied the [Slim TCP Protocol][slimtcp] first
 //! so that this library makes sense.
 //!
+//! Messages are framed by [SlimFramed](crate::codec::SlimFramed), which both the
+//! connections returned by [PreparedServer](crate::proto::PreparedServer) use. It
+//! implements the [FramedReader] and [FramedWriter] traits, re-exported from the
+//! `framous` crate along with the [Decoder] and [Encoder] traits implemented by
+//! [SlimCodec](crate::codec::SlimCodec) for use with other framing.
+//!
+//! Everything that needs a socket, a thread or the host's network interfaces is
+//! behind the default `std` feature. On `wasm32` targets only the protocol layer
+//! is built, the messages, codec, status and capabilities, so that tools in the
+//! browser can parse and build messages. Without the `std` feature the crate is
+//! `no_std` and needs only `alloc`. The payload of each frame from the server,
+//! the bytes after its two byte length, is decoded with `ServerMessage::from`
+//! and a whole frame for the server is encoded with `BytesMut::from`, the
+//! framing over `Read` and `Write` needs `std`.
+//!
 //! [lms]: https://en.wikipedia.org/wiki/Logitech_Media_Server
-//! [slimproto]: crate::proto::SlimProto
 //! [discover]: crate::discovery::discover
 //! [statusdata]: crate::status::StatusData
 //! [slimtcp]: https://wiki.slimdevices.com/index.php/SlimProto_TCP_protocol
 
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod buffer;
 pub mod capability;
 pub mod codec;
+#[cfg(feature = "std")]
+pub mod connection;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
 pub mod discovery;
+#[cfg(feature = "std")]
+pub mod dispatch;
+#[cfg(feature = "display")]
+pub mod display;
+pub mod fade;
+pub mod gain;
+#[cfg(feature = "std")]
+pub mod http;
+#[cfg(all(feature = "ir", target_os = "linux"))]
+pub mod ir;
+pub mod jiffies;
+#[cfg(all(feature = "jsonrpc", feature = "std", not(target_arch = "wasm32")))]
+pub mod jsonrpc;
+#[cfg(any(not(feature = "std"), target_arch = "wasm32", test))]
+mod mac;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "embedded-nal")]
+pub mod nal;
+#[cfg(feature = "std")]
+pub mod name;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod player;
 pub mod proto;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod proxy;
+#[cfg(feature = "std")]
+pub mod queue;
+#[cfg(all(feature = "rodio", feature = "std", not(target_arch = "wasm32")))]
+pub mod rodio_player;
+#[cfg(feature = "std")]
+pub mod sensors;
+#[cfg(feature = "std")]
+pub mod settings;
 pub mod status;
-pub mod buffer;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod stream;
+#[cfg(feature = "symphonia")]
+pub mod symphonia;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod version;
 
 pub use capability::{Capabilities, Capability};
-pub use proto::{ClientMessage, ServerMessage};
-pub use framous::*;
+/// The framing traits and types, messages are read and written with [FramedReader]
+/// and [FramedWriter]. These are the only parts of `framous` that are re-exported.
+#[cfg(feature = "std")]
+pub use framous::{Decoder, Encoder, Framed, FramedRead, FramedReader, FramedWrite, FramedWriter};
+pub use proto::{ClientMessage, ServerMessage, ServerMessages};
 // pub use status::{StatusCode, StatusData};
@@ -0,0 +1,96 @@
+//! A MAC address for `wasm32` and `no_std` where the `mac_address` crate cannot
+//! be built.
+//!
+//! This has the same interface as `mac_address::MacAddress` that the rest of
+//! the crate uses so that messages can be parsed and built in the browser. There
+//! is no host MAC address to look up.
+
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{io, str::FromStr};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress {
+    bytes: [u8; 6],
+}
+
+impl MacAddress {
+    pub fn new(bytes: [u8; 6]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn bytes(self) -> [u8; 6] {
+        self.bytes
+    }
+
+    #[cfg(any(feature = "std", feature = "serde", test))]
+    fn parse(s: &str) -> Option<Self> {
+        let mut bytes = [0u8; 6];
+        let mut octets = s.split([':', '-']);
+        for byte in bytes.iter_mut() {
+            *byte = u8::from_str_radix(octets.next()?, 16).ok()?;
+        }
+        match octets.next() {
+            Some(_) => None,
+            None => Some(Self::new(bytes)),
+        }
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.bytes;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
+/// Parse an address such as `AA:BB:CC:01:02:03`, `-` may also be used to
+/// separate the octets.
+#[cfg(feature = "std")]
+impl FromStr for MacAddress {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        Self::parse(s)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid MAC address"))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for MacAddress {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MacAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <alloc::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Self::parse(&s).ok_or_else(|| serde::de::Error::custom("Invalid MAC address"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The stand-in must format as the `mac_address` one does as the server sees it
+    #[test]
+    fn same_as_mac_address() {
+        let bytes = [0xaa, 0xbb, 0xcc, 1, 2, 3];
+        let mac = MacAddress::new(bytes);
+        assert_eq!(mac.bytes(), bytes);
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        assert_eq!(
+            mac.to_string(),
+            mac_address::MacAddress::new(bytes).to_string()
+        );
+        assert_eq!(MacAddress::parse(&mac.to_string()), Some(mac));
+        assert_eq!(MacAddress::parse("aa-bb-cc-01-02-03"), Some(mac));
+        assert!(MacAddress::parse("aa:bb:cc:01:02").is_none());
+        assert!(MacAddress::parse("aa:bb:cc:01:02:03:04").is_none());
+        #[cfg(feature = "std")]
+        assert!("aa:bb:cc:01:02".parse::<MacAddress>().is_err());
+    }
+}
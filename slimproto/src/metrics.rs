@@ -0,0 +1,117 @@
+//! Hooks for collecting metrics from the library.
+//!
+//! Implement [Metrics] and attach it, in a [SharedMetrics], to the parts of a
+//! player that report into it: the [codec](crate::codec::SlimCodec::with_metrics),
+//! the [connection](crate::proto::PreparedServer::with_metrics) and the
+//! [SlimBuffer](crate::buffer::SlimBuffer::with_metrics). Players in one process
+//! can each have their own. All methods do nothing by default so only those of
+//! interest need implementing.
+//!
+//! ```
+//! use std::sync::atomic::{AtomicU64, Ordering};
+//! use slimproto::{codec::SlimCodec, metrics::{Metrics, SharedMetrics}};
+//!
+//! #[derive(Default)]
+//! struct Streamed(AtomicU64);
+//!
+//! impl Metrics for Streamed {
+//!     fn bytes_streamed(&self, bytes: u64) {
+//!         self.0.fetch_add(bytes, Ordering::Relaxed);
+//!     }
+//! }
+//!
+//! let metrics = SharedMetrics::new(Streamed::default());
+//! let codec = SlimCodec::new().with_metrics(metrics.clone());
+//! ```
+
+use std::{fmt, sync::Arc};
+
+/// Receives the metrics of the library, the methods may be called from any thread.
+pub trait Metrics: Send + Sync {
+    /// A message was sent to the server, `kind` is its four letter code such as `STAT`
+    /// and `size` is the size of the frame in bytes.
+    fn message_sent(&self, _kind: &str, _size: usize) {}
+
+    /// A message was received from the server, `kind` is its four letter code such
+    /// as `strm` and `size` is the size of the frame in bytes.
+    fn message_received(&self, _kind: &str, _size: usize) {}
+
+    /// A connection to the server was made and the player announced, each call after
+    /// the first is a reconnection.
+    fn connected(&self) {}
+
+    /// Bytes of an audio stream were read.
+    fn bytes_streamed(&self, _bytes: u64) {}
+
+    /// The number of bytes waiting in the stream buffer changed.
+    fn buffer_fullness(&self, _bytes: u32) {}
+
+    /// A stream buffer ran empty while playing.
+    fn underrun(&self) {}
+}
+
+/// A [Metrics] that can be shared between the parts of a player. The default
+/// records nothing.
+#[derive(Clone, Default)]
+pub struct SharedMetrics(Option<Arc<dyn Metrics>>);
+
+impl SharedMetrics {
+    pub fn new(metrics: impl Metrics + 'static) -> Self {
+        Self(Some(Arc::new(metrics)))
+    }
+
+    /// Report into the [Metrics], if there is one.
+    pub(crate) fn record(&self, report: impl FnOnce(&dyn Metrics)) {
+        if let Some(metrics) = &self.0 {
+            report(metrics.as_ref());
+        }
+    }
+}
+
+impl fmt::Debug for SharedMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => write!(f, "SharedMetrics(..)"),
+            None => write!(f, "SharedMetrics(None)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::{codec::SlimCodec, ClientMessage};
+    use framous::{FramedWrite, FramedWriter};
+
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Metrics for Counter {
+        fn message_sent(&self, _kind: &str, _size: usize) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn per_codec() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let metrics = SharedMetrics::new(Counter(sent.clone()));
+
+        let mut buf = [0u8; 1008];
+        FramedWrite::new(&mut buf[..], SlimCodec::new().with_metrics(metrics))
+            .framed_write(ClientMessage::Meta(vec![0; 1000]))
+            .unwrap();
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+
+        // A codec without metrics reports nowhere
+        FramedWrite::new(&mut buf[..], SlimCodec::new())
+            .framed_write(ClientMessage::Meta(vec![0; 1000]))
+            .unwrap();
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,161 @@
+//! A transport over an `embedded-nal` TCP stack for players without `std::net`.
+//!
+//! [NalStream] makes a connected socket of any [TcpClientStack] into a `Read` and
+//! `Write` transport that can be given to
+//! [connect_with](crate::proto::PreparedServer::connect_with). The stack is not
+//! polled here so a socket that has no data gives an error of kind `WouldBlock`,
+//! use [poll_read](crate::codec::SlimFramed::poll_read) to read messages as they
+//! arrive without blocking.
+//!
+//! ```ignore
+//! let mut socket = stack.socket()?;
+//! nb::block!(stack.connect(&mut socket, server_addr))?;
+//! let mut framed = prepared.connect_with(NalStream::new(&mut stack, socket))?;
+//! loop {
+//!     interface.poll(now, &mut device, &mut sockets);
+//!     if let Some(msg) = framed.poll_read()? {
+//!         // ...
+//!     }
+//! }
+//! ```
+
+use std::io::{self, Read, Write};
+
+use embedded_nal::{nb, TcpClientStack, TcpError, TcpErrorKind};
+
+/// A connected socket of an `embedded-nal` TCP stack.
+pub struct NalStream<'a, S>
+where
+    S: TcpClientStack,
+{
+    stack: &'a mut S,
+    socket: S::TcpSocket,
+}
+
+impl<'a, S> NalStream<'a, S>
+where
+    S: TcpClientStack,
+{
+    /// The socket must already be connected to the server.
+    pub fn new(stack: &'a mut S, socket: S::TcpSocket) -> Self {
+        Self { stack, socket }
+    }
+
+    /// Close the socket.
+    pub fn close(self) -> io::Result<()> {
+        self.stack.close(self.socket).map_err(nal_error)
+    }
+}
+
+fn nal_error(e: impl TcpError) -> io::Error {
+    match e.kind() {
+        TcpErrorKind::PipeClosed => io::Error::new(io::ErrorKind::BrokenPipe, format!("{:?}", e)),
+        _ => io::Error::other(format!("{:?}", e)),
+    }
+}
+
+impl<S> Read for NalStream<'_, S>
+where
+    S: TcpClientStack,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stack.receive(&mut self.socket, buf) {
+            Ok(n) => Ok(n),
+            Err(nb::Error::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            // The server has closed the connection
+            Err(nb::Error::Other(e)) if e.kind() == TcpErrorKind::PipeClosed => Ok(0),
+            Err(nb::Error::Other(e)) => Err(nal_error(e)),
+        }
+    }
+}
+
+impl<S> Write for NalStream<'_, S>
+where
+    S: TcpClientStack,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stack.send(&mut self.socket, buf) {
+            Ok(n) => Ok(n),
+            Err(nb::Error::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(nb::Error::Other(e)) => Err(nal_error(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{collections::VecDeque, net::SocketAddr};
+
+    use crate::{codec::SlimFramed, ServerMessage};
+
+    #[derive(Debug)]
+    struct Closed;
+
+    impl TcpError for Closed {
+        fn kind(&self) -> TcpErrorKind {
+            TcpErrorKind::PipeClosed
+        }
+    }
+
+    // Each receive gives the next chunk, an empty chunk is no data yet
+    #[derive(Default)]
+    struct Stack {
+        chunks: VecDeque<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl TcpClientStack for Stack {
+        type TcpSocket = ();
+        type Error = Closed;
+
+        fn socket(&mut self) -> Result<(), Closed> {
+            Ok(())
+        }
+
+        fn connect(&mut self, _: &mut (), _: SocketAddr) -> nb::Result<(), Closed> {
+            Ok(())
+        }
+
+        fn send(&mut self, _: &mut (), buffer: &[u8]) -> nb::Result<usize, Closed> {
+            self.sent.extend_from_slice(buffer);
+            Ok(buffer.len())
+        }
+
+        fn receive(&mut self, _: &mut (), buffer: &mut [u8]) -> nb::Result<usize, Closed> {
+            match self.chunks.pop_front() {
+                Some(chunk) if chunk.is_empty() => Err(nb::Error::WouldBlock),
+                Some(chunk) => {
+                    buffer[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => Err(nb::Error::Other(Closed)),
+            }
+        }
+
+        fn close(&mut self, _: ()) -> Result<(), Closed> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poll_messages() {
+        let mut stack = Stack {
+            chunks: vec![vec![0, 5, b's'], vec![], vec![b'e', b't', b'd', 0]].into(),
+            ..Default::default()
+        };
+
+        let mut framed = SlimFramed::new(NalStream::new(&mut stack, ()));
+        assert_eq!(framed.poll_read().unwrap(), None);
+        assert_eq!(framed.poll_read().unwrap(), Some(ServerMessage::Queryname));
+        assert_eq!(
+            framed.poll_read().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -0,0 +1,98 @@
+//! Keeping the player's name for the server.
+//!
+//! The server asks for the player's name with `Queryname` and changes it with
+//! `Setname`. A [NameKeeper] holds the name, answers the first and applies the
+//! second, calling back so that a new name can be saved for the next start.
+//!
+//! ```
+//! use slimproto::{name::NameKeeper, proto::PlayerName, ClientMessage, ServerMessage};
+//!
+//! let names = NameKeeper::new(PlayerName::new("Kitchen").unwrap())
+//!     .with_persist(|name| println!("save {}", name));
+//!
+//! names.handle(&ServerMessage::Setname(PlayerName::new("Lounge").unwrap()));
+//! assert_eq!(
+//!     names.handle(&ServerMessage::Queryname),
+//!     Some(ClientMessage::Name(PlayerName::new("Lounge").unwrap()))
+//! );
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use crate::{proto::PlayerName, ClientMessage, ServerMessage};
+
+type PersistFn = Arc<dyn Fn(&PlayerName) + Send + Sync>;
+
+/// The player's name, clones share the same name.
+#[derive(Clone)]
+pub struct NameKeeper {
+    name: Arc<RwLock<PlayerName>>,
+    persist: Option<PersistFn>,
+}
+
+impl NameKeeper {
+    pub fn new(name: PlayerName) -> Self {
+        Self {
+            name: Arc::new(RwLock::new(name)),
+            persist: None,
+        }
+    }
+
+    /// Call `persist` with the new name each time the server sets it.
+    pub fn with_persist(mut self, persist: impl Fn(&PlayerName) + Send + Sync + 'static) -> Self {
+        self.persist = Some(Arc::new(persist));
+        self
+    }
+
+    pub fn name(&self) -> PlayerName {
+        self.name.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Change the name without calling back.
+    pub fn set_name(&self, name: PlayerName) {
+        *self.name.write().unwrap_or_else(|e| e.into_inner()) = name;
+    }
+
+    /// Act on `Queryname` and `Setname`, other messages are ignored. Returns the
+    /// reply to send to the server, if there is one.
+    pub fn handle(&self, msg: &ServerMessage) -> Option<ClientMessage> {
+        match msg {
+            ServerMessage::Queryname => Some(ClientMessage::Name(self.name())),
+            ServerMessage::Setname(name) => {
+                self.set_name(name.clone());
+                if let Some(persist) = &self.persist {
+                    persist(name);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn persist() {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let names = NameKeeper::new(PlayerName::new("Kitchen").unwrap()).with_persist({
+            let saved = saved.clone();
+            move |name| saved.lock().unwrap().push(name.to_string())
+        });
+        let shared = names.clone();
+
+        assert_eq!(names.handle(&ServerMessage::Stop), None);
+        names.set_name(PlayerName::new("Hall").unwrap());
+        assert_eq!(
+            names.handle(&ServerMessage::Setname(PlayerName::new("Lounge").unwrap())),
+            None
+        );
+
+        assert_eq!(shared.name(), "Lounge");
+        assert_eq!(*saved.lock().unwrap(), vec!["Lounge"]);
+    }
+}
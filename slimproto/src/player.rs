@@ -0,0 +1,1006 @@
+//! A trait for audio backends and a driver that speaks the protocol for them.
+//!
+//! An audio backend implements [Player], the [PlayerDriver] then turns each
+//! [ServerMessage] into calls on the player and sends the status messages the
+//! server expects in reply. Opening the audio stream, sending its response
+//! headers to the server and the timer status messages are all handled by the
+//! driver so a backend only needs to decode and play.
+//!
+//! A stream sent with [StreamFlags::NO_RESTART_DECODER] follows on from the one
+//! before it without a gap, as Squeezelite does. It is given to a player that
+//! [supports_gapless](Player::supports_gapless) with
+//! [continue_stream](Player::continue_stream) so that the running decoder can carry
+//! on with it, and the backend tells the driver when the output reaches the new
+//! track with [track_started](PlayerDriver::track_started). Any other stream starts
+//! to play when it is given to the player or, when the server does not ask for it
+//! to start by itself, when the server unpauses the player.
+
+use std::io;
+
+use crate::{
+    gain::Volume,
+    jiffies::Jiffies,
+    name::NameKeeper,
+    proto::{
+        ConnectOptions, OutputEnable, PauseKind, PcmChannels, PcmSampleRate, PcmSampleSize,
+        PlayerName, Server, SpdifEnable, StreamFlags,
+    },
+    settings::Settings,
+    status::{StatusCode, StatusReporter},
+    stream::{StreamConnection, StreamParams},
+    sync::{ScheduledAction, SyncScheduler},
+    Capabilities, Capability, ServerMessage,
+};
+
+/// The layout of the audio that a backend outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub sample_rate: u32,
+    pub bytes_per_sample: u8,
+    pub channels: u8,
+}
+
+impl OutputFormat {
+    /// The format given by the PCM fields of a `strm s` message, `None` if any
+    /// of them are self describing. A sample size of `Twenty` is sent for 24 bit
+    /// streams so is taken as three bytes.
+    pub fn from_pcm(
+        size: PcmSampleSize,
+        rate: PcmSampleRate,
+        channels: PcmChannels,
+    ) -> Option<Self> {
+        let bytes_per_sample = match size {
+            PcmSampleSize::Eight => 1,
+            PcmSampleSize::Sixteen => 2,
+            PcmSampleSize::Twenty => 3,
+            PcmSampleSize::ThirtyTwo => 4,
+            PcmSampleSize::SelfDescribing => return None,
+        };
+        let PcmSampleRate::Rate(sample_rate) = rate else {
+            return None;
+        };
+        let channels = match channels {
+            PcmChannels::Mono => 1,
+            PcmChannels::Stereo => 2,
+            PcmChannels::SelfDescribing => return None,
+        };
+        Some(Self {
+            sample_rate,
+            bytes_per_sample,
+            channels,
+        })
+    }
+
+    /// The size of one sample for every channel.
+    pub fn frame_bytes(self) -> usize {
+        self.bytes_per_sample as usize * self.channels as usize
+    }
+
+    /// The number of bytes played in the time `by`, in whole frames.
+    pub fn bytes_for(self, by: Jiffies) -> usize {
+        let frames = by.as_millis() as u64 * self.sample_rate as u64 / 1000;
+        frames as usize * self.frame_bytes()
+    }
+}
+
+/// The operations of an audio backend.
+///
+/// Times given as [Jiffies] are on the status data's clock.
+pub trait Player {
+    /// Decode and play a new stream. The response headers have already been read
+    /// from the stream. An error tells the server that the stream is not supported.
+    ///
+    /// Unless [StreamParams::is_autostart] is true the output should wait for
+    /// [unpause_at](Player::unpause_at) before it plays the stream.
+    fn start_stream(&mut self, params: StreamParams, stream: StreamConnection) -> io::Result<()>;
+
+    /// True if the backend can carry on from one stream to the next without a
+    /// gap. Only then are streams given to [continue_stream](Player::continue_stream),
+    /// other backends are given every stream with [start_stream](Player::start_stream).
+    fn supports_gapless(&self) -> bool {
+        false
+    }
+
+    /// Feed a new stream into the running decoder without resetting it, for a
+    /// stream sent with [StreamFlags::NO_RESTART_DECODER] to a backend that
+    /// [supports_gapless](Player::supports_gapless). The audio of the previous
+    /// stream is still playing, `STMs` is not sent until the backend calls
+    /// [PlayerDriver::track_started]. By default the stream is started as any other.
+    fn continue_stream(
+        &mut self,
+        params: StreamParams,
+        stream: StreamConnection,
+    ) -> io::Result<()> {
+        self.start_stream(params, stream)
+    }
+
+    /// Pause now, at the given time or, for [PauseKind::For], now and then play
+    /// on once the interval has passed.
+    fn pause_at(&mut self, when: PauseKind);
+
+    /// Unpause now or at the given time. The driver sends `STMr` when the time
+    /// comes.
+    fn unpause_at(&mut self, when: PauseKind);
+
+    /// Stop playing and discard the stream.
+    fn stop(&mut self);
+
+    /// Discard the stream and anything buffered for output.
+    fn flush(&mut self);
+
+    fn set_gain(&mut self, volume: Volume);
+
+    /// Turn the outputs on or off. The driver only calls this when the outputs
+    /// that the player can switch change, see [with_capabilities](PlayerDriver::with_capabilities).
+    fn enable_output(&mut self, outputs: OutputEnable);
+
+    /// Skip ahead in the output by the given time. This is called for a skip only
+    /// when the output format is not known, otherwise [drop_output](Player::drop_output) is.
+    fn skip(&mut self, _by: Jiffies) {}
+
+    /// Discard the given number of bytes of output to regain sync with the other
+    /// players. The count is always whole frames of the [output_format](Player::output_format).
+    fn drop_output(&mut self, _bytes: usize) {}
+
+    /// The format of the audio being played. When this is `None` the format from
+    /// the PCM fields of the stream message is used, if there is one.
+    fn output_format(&self) -> Option<OutputFormat> {
+        None
+    }
+}
+
+/// Control of S/PDIF hardware, such as a HiFiBerry Digi, by the driver.
+///
+/// Both methods do nothing by default so that a device implements only what
+/// its hardware can do.
+pub trait DigitalOutput {
+    /// Set the S/PDIF output as asked for by a `strm s` message, before the
+    /// stream is started.
+    fn set_spdif(&mut self, _enable: SpdifEnable) {}
+
+    /// Turn the outputs on or off as the server sent them in an `aude` message.
+    fn enable_outputs(&mut self, _outputs: OutputEnable) {}
+}
+
+/// Drives a [Player] from the messages sent by the server.
+pub struct PlayerDriver<P> {
+    player: P,
+    server: Server,
+    stream_options: ConnectOptions,
+    reporter: StatusReporter,
+    scheduler: SyncScheduler,
+    resume: Option<ScheduledAction>,
+    names: Option<NameKeeper>,
+    settings: Option<Box<dyn Settings + Send>>,
+    digital_output: Option<Box<dyn DigitalOutput + Send>>,
+    stream_format: Option<OutputFormat>,
+    capabilities: Option<Capabilities>,
+    outputs: Option<OutputEnable>,
+    server_outputs: Option<OutputEnable>,
+    // A stream waiting for the server to unpause it before it plays
+    start_on_unpause: bool,
+}
+
+impl<P> PlayerDriver<P>
+where
+    P: Player,
+{
+    /// Status messages are sent through the `reporter` and streams are fetched
+    /// from `server` when the stream message does not give another address.
+    pub fn new(player: P, server: Server, reporter: StatusReporter) -> Self {
+        Self {
+            player,
+            server,
+            stream_options: StreamConnection::default_options(),
+            scheduler: SyncScheduler::new(reporter.status().clone()),
+            reporter,
+            resume: None,
+            names: None,
+            settings: None,
+            digital_output: None,
+            stream_format: None,
+            capabilities: None,
+            outputs: None,
+            server_outputs: None,
+            start_on_unpause: false,
+        }
+    }
+
+    /// The name to give when the server asks for it.
+    pub fn with_name(mut self, name: PlayerName) -> Self {
+        self.names = Some(NameKeeper::new(name));
+        self
+    }
+
+    /// Keep the player's name in `names`, which can be shared with the rest of
+    /// the player and can save the name when the server changes it.
+    pub fn with_names(mut self, names: NameKeeper) -> Self {
+        self.names = Some(names);
+        self
+    }
+
+    /// Save the name, volume and sync group in `settings` when the server changes them.
+    ///
+    /// Any that were saved before are restored, the name is given when the server
+    /// asks for it, the volume is set on the player and the sync group is added to
+    /// the [server](PlayerDriver::server) so that it is announced in the HELO.
+    pub fn with_settings(mut self, settings: impl Settings + Send + 'static) -> Self {
+        if let Some(name) = settings.name() {
+            match &self.names {
+                Some(names) => names.set_name(name),
+                None => self.names = Some(NameKeeper::new(name)),
+            }
+        }
+        if let Some(sync_group) = settings.sync_group() {
+            self.server = self.server.clone().with_sync_group(sync_group);
+        }
+        if let Some(volume) = settings.volume() {
+            self.player.set_gain(volume);
+        }
+        self.settings = Some(Box::new(settings));
+        self
+    }
+
+    /// Connect to streams with the timeouts and socket options in `options`
+    /// rather than [StreamConnection::default_options].
+    pub fn with_stream_options(mut self, options: ConnectOptions) -> Self {
+        self.stream_options = options;
+        self
+    }
+
+    /// Send the `STMr` of a timed unpause from `scheduler`, normally the one that
+    /// the backend unpauses with, rather than from one of the driver's own.
+    pub fn with_scheduler(mut self, scheduler: SyncScheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Pass the S/PDIF settings of each stream and the outputs of each `aude`
+    /// message to `output`.
+    pub fn with_digital_output(mut self, output: impl DigitalOutput + Send + 'static) -> Self {
+        self.digital_output = Some(Box::new(output));
+        self
+    }
+
+    // The player carries on if a setting cannot be saved
+    fn save(&self, save: impl FnOnce(&dyn Settings) -> io::Result<()>) {
+        if let Some(settings) = &self.settings {
+            if let Err(_e) = save(settings.as_ref()) {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(error = %_e, "failed to save setting");
+            }
+        }
+    }
+
+    /// The capabilities that the player announced. S/PDIF is then only turned on
+    /// for a player with `HasDigitalOut` and the DAC only turned off for a player
+    /// with `HasDisableDac`. Without them the outputs are passed on as the server
+    /// sends them.
+    pub fn with_capabilities(mut self, caps: &Capabilities) -> Self {
+        self.capabilities = Some(
+            caps.iter()
+                .filter(|cap| matches!(cap, Capability::Hasdigitalout | Capability::Hasdisabledac))
+                .cloned()
+                .collect(),
+        );
+        self
+    }
+
+    pub fn player(&mut self) -> &mut P {
+        &mut self.player
+    }
+
+    pub fn reporter(&self) -> &StatusReporter {
+        &self.reporter
+    }
+
+    /// The server that streams are fetched from, with any sync group restored
+    /// from the [settings](PlayerDriver::with_settings). Prepare the connection
+    /// to the server from this so that the player rejoins its sync group.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// The outputs as they were last set on the player, `None` until the server
+    /// sends `aude`.
+    pub fn outputs(&self) -> Option<OutputEnable> {
+        self.outputs
+    }
+
+    /// Set the outputs on the player and the digital output again after the player
+    /// reconnects to the server. The server expects the player to remember them
+    /// and does not send them again.
+    pub fn reconnected(&mut self) {
+        if let (Some(output), Some(outputs)) = (&mut self.digital_output, self.server_outputs) {
+            output.enable_outputs(outputs);
+        }
+        if let Some(outputs) = self.outputs {
+            self.player.enable_output(outputs);
+        }
+    }
+
+    /// The number of bytes of output in the time `by`, if the output format is known.
+    pub fn skip_bytes(&self, by: Jiffies) -> Option<usize> {
+        self.player
+            .output_format()
+            .or(self.stream_format)
+            .map(|format| format.bytes_for(by))
+    }
+
+    /// Send `STMd` once the decoder has read the whole stream so that the server
+    /// sends the next track. The decoder should be kept until the next stream
+    /// arrives, it is then either continued or restarted.
+    pub fn decoder_finished(&self) {
+        self.reporter.decoder_ready();
+    }
+
+    /// Restart the elapsed time and send `STMs` when the output reaches the start
+    /// of a track given to [continue_stream](Player::continue_stream). The driver
+    /// does this itself for other streams.
+    pub fn track_started(&self) {
+        self.reporter.status().lock().start_track();
+        self.reporter.track_started();
+    }
+
+    /// Stop the elapsed time and send `STMu` when the output has played all that
+    /// was decoded.
+    pub fn output_finished(&self) {
+        self.reporter.status().lock().stop_track();
+        self.reporter.underrun();
+    }
+
+    /// Act on a message from the server.
+    ///
+    /// Only errors that leave the driver unable to continue are returned, a stream
+    /// that cannot be opened or played is reported to the server with `STMn`.
+    pub fn handle(&mut self, msg: ServerMessage) -> io::Result<()> {
+        match msg {
+            ServerMessage::Status(timestamp) => self.reporter.timer(timestamp),
+
+            ServerMessage::Stream {
+                pcmsamplesize,
+                pcmsamplerate,
+                pcmchannels,
+                spdif_enable,
+                ..
+            } => {
+                if let Some(output) = &mut self.digital_output {
+                    output.set_spdif(spdif_enable);
+                }
+                self.stream_format =
+                    OutputFormat::from_pcm(pcmsamplesize, pcmsamplerate, pcmchannels);
+                let started =
+                    StreamParams::try_from(&msg).and_then(|params| self.start_stream(params));
+                if started.is_err() {
+                    self.reporter.send(StatusCode::NotSupported);
+                }
+            }
+
+            ServerMessage::Pause(when) => {
+                self.player.pause_at(when);
+                if when == PauseKind::Now {
+                    self.reporter.send(StatusCode::Pause);
+                }
+            }
+
+            ServerMessage::Unpause(when) => {
+                self.player.unpause_at(when);
+                self.cancel_resume();
+                let start = std::mem::take(&mut self.start_on_unpause);
+                let resumed = self.reporter.deferred(StatusCode::Resume);
+                let started = start.then(|| {
+                    let status = self.reporter.status().clone();
+                    let track_started = self.reporter.deferred(StatusCode::TrackStarted);
+                    move || {
+                        status.lock().start_track();
+                        track_started();
+                    }
+                });
+                let resume = move || {
+                    resumed();
+                    if let Some(started) = started {
+                        started();
+                    }
+                };
+                match when {
+                    PauseKind::Now => resume(),
+                    PauseKind::At(at) => self.resume = Some(self.scheduler.schedule(at, resume)),
+                    PauseKind::For(interval) => {
+                        self.resume = Some(self.scheduler.schedule_after(interval, resume))
+                    }
+                }
+            }
+
+            ServerMessage::Stop => {
+                self.start_on_unpause = false;
+                self.cancel_resume();
+                self.player.stop();
+                self.reporter.status().lock().stop_track();
+                self.reporter.send(StatusCode::Flushed);
+            }
+
+            ServerMessage::Flush => {
+                self.start_on_unpause = false;
+                self.cancel_resume();
+                self.player.flush();
+                self.reporter.status().lock().stop_track();
+                self.reporter.send(StatusCode::Flushed);
+            }
+
+            ServerMessage::Gain {
+                left,
+                right,
+                digital_volume,
+                ..
+            } => {
+                let volume = Volume::new(left.into(), right.into()).with_digital(digital_volume);
+                self.save(|settings| settings.save_volume(&volume));
+                self.player.set_gain(volume);
+            }
+
+            ServerMessage::Serv {
+                sync_group_id: Some(ref sync_group),
+                ..
+            } => self.save(|settings| settings.save_sync_group(sync_group)),
+
+            ServerMessage::Enable(outputs) => {
+                self.server_outputs = Some(outputs);
+                if let Some(output) = &mut self.digital_output {
+                    output.enable_outputs(outputs);
+                }
+                let outputs = match &self.capabilities {
+                    Some(caps) => outputs.supported_by(caps),
+                    None => outputs,
+                };
+                if self.outputs != Some(outputs) {
+                    self.outputs = Some(outputs);
+                    self.player.enable_output(outputs);
+                }
+            }
+
+            ServerMessage::Skip(by) => match self.skip_bytes(by) {
+                Some(bytes) => self.player.drop_output(bytes),
+                None => self.player.skip(by),
+            },
+
+            ServerMessage::Queryname | ServerMessage::Setname(_) => {
+                if let ServerMessage::Setname(name) = &msg {
+                    self.save(|settings| settings.save_name(name));
+                }
+                match &self.names {
+                    Some(names) => {
+                        if let Some(reply) = names.handle(&msg) {
+                            self.reporter.send_message(reply);
+                        }
+                    }
+                    None => {
+                        if let ServerMessage::Setname(name) = msg {
+                            self.names = Some(NameKeeper::new(name));
+                        }
+                    }
+                }
+            }
+
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // A timed unpause that has not yet happened is dropped along with the stream
+    fn cancel_resume(&mut self) {
+        if let Some(resume) = self.resume.take() {
+            resume.cancel();
+        }
+    }
+
+    fn start_stream(&mut self, params: StreamParams) -> io::Result<()> {
+        let stream =
+            StreamConnection::start(&params, &self.server, &self.reporter, &self.stream_options)?;
+        if params.flags.contains(StreamFlags::NO_RESTART_DECODER) && self.player.supports_gapless()
+        {
+            return self.player.continue_stream(params, stream);
+        }
+
+        let autostart = params.is_autostart();
+        self.player.start_stream(params, stream)?;
+        self.start_on_unpause = !autostart;
+        if autostart {
+            self.track_started();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener},
+        sync::{mpsc, Mutex},
+        thread::spawn,
+    };
+
+    use crate::{
+        gain::{FixedGain, Gain},
+        proto::{AutoStart, Format, Threshold},
+        status::StatusData,
+        ClientMessage,
+    };
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<String>,
+        format: Option<OutputFormat>,
+        gapless: bool,
+    }
+
+    impl Player for Recorder {
+        fn supports_gapless(&self) -> bool {
+            self.gapless
+        }
+
+        fn start_stream(
+            &mut self,
+            params: StreamParams,
+            mut stream: StreamConnection,
+        ) -> io::Result<()> {
+            let mut body = String::new();
+            stream.read_to_string(&mut body)?;
+            self.calls
+                .push(format!("start {:?} {}", params.format, body));
+            Ok(())
+        }
+
+        fn continue_stream(
+            &mut self,
+            params: StreamParams,
+            mut stream: StreamConnection,
+        ) -> io::Result<()> {
+            let mut body = String::new();
+            stream.read_to_string(&mut body)?;
+            self.calls
+                .push(format!("continue {:?} {}", params.format, body));
+            Ok(())
+        }
+
+        fn pause_at(&mut self, when: PauseKind) {
+            self.calls.push(format!("pause {}", when));
+        }
+
+        fn unpause_at(&mut self, when: PauseKind) {
+            self.calls.push(format!("unpause {}", when));
+        }
+
+        fn stop(&mut self) {
+            self.calls.push("stop".to_owned());
+        }
+
+        fn flush(&mut self) {
+            self.calls.push("flush".to_owned());
+        }
+
+        fn set_gain(&mut self, volume: Volume) {
+            self.calls.push(format!("gain {}", volume.left_factor()));
+        }
+
+        fn enable_output(&mut self, outputs: OutputEnable) {
+            self.calls
+                .push(format!("enable {} {}", outputs.spdif, outputs.dac));
+        }
+
+        fn skip(&mut self, by: Jiffies) {
+            self.calls.push(format!("skip {}", by));
+        }
+
+        fn drop_output(&mut self, bytes: usize) {
+            self.calls.push(format!("drop {}", bytes));
+        }
+
+        fn output_format(&self) -> Option<OutputFormat> {
+            self.format
+        }
+    }
+
+    fn driver() -> (PlayerDriver<Recorder>, mpsc::Receiver<ClientMessage>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let reporter = StatusReporter::new(StatusData::default(), move |msg| {
+            tx.lock().unwrap().send(msg).ok();
+        });
+        let driver = PlayerDriver::new(
+            Recorder::default(),
+            Server::new(Ipv4Addr::LOCALHOST),
+            reporter,
+        )
+        .with_name(PlayerName::new("Kitchen").unwrap());
+        (driver, rx)
+    }
+
+    fn event_codes(rx: &mpsc::Receiver<ClientMessage>) -> Vec<String> {
+        rx.try_iter()
+            .map(|msg| match msg {
+                ClientMessage::Stat { event_code, .. } => event_code,
+                other => other.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn control() {
+        let (mut driver, rx) = driver();
+
+        driver.handle(ServerMessage::Pause(PauseKind::Now)).unwrap();
+        driver
+            .handle(ServerMessage::Pause(PauseKind::For(
+                std::time::Duration::from_millis(5),
+            )))
+            .unwrap();
+        driver.handle(ServerMessage::Flush).unwrap();
+        driver
+            .handle(ServerMessage::Enable(OutputEnable {
+                spdif: false,
+                dac: true,
+            }))
+            .unwrap();
+        driver.handle(ServerMessage::Queryname).unwrap();
+        driver
+            .handle(ServerMessage::Status(Jiffies::from_millis(7)))
+            .unwrap();
+
+        assert_eq!(
+            driver.player().calls,
+            vec!["pause now", "pause for 5ms", "flush", "enable false true"]
+        );
+        assert_eq!(
+            event_codes(&rx),
+            vec!["STMp", "STMf", "SETD name=Kitchen", "STMt"]
+        );
+    }
+
+    #[test]
+    fn timed_unpause() {
+        let (mut driver, rx) = driver();
+        let now = driver.reporter().status().lock().jiffies_now();
+        driver
+            .handle(ServerMessage::Unpause(PauseKind::At(
+                now + std::time::Duration::from_millis(20),
+            )))
+            .unwrap();
+        assert!(event_codes(&rx).is_empty());
+
+        let resumed = rx.recv_timeout(std::time::Duration::from_secs(1)).unwrap();
+        assert!(matches!(resumed, ClientMessage::Stat { event_code, .. } if event_code == "STMr"));
+
+        // A flush drops an unpause that is still to come
+        driver
+            .handle(ServerMessage::Unpause(PauseKind::At(
+                now + std::time::Duration::from_millis(50),
+            )))
+            .unwrap();
+        driver.handle(ServerMessage::Flush).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(event_codes(&rx), vec!["STMf"]);
+        assert_eq!(
+            driver.player().calls,
+            vec![
+                format!("unpause at {}", now + std::time::Duration::from_millis(20)),
+                format!("unpause at {}", now + std::time::Duration::from_millis(50)),
+                "flush".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn gain() {
+        let (mut driver, _rx) = driver();
+        for digital_volume in [true, false] {
+            driver
+                .handle(ServerMessage::Gain {
+                    left: FixedGain(0x8000),
+                    right: FixedGain(0x8000),
+                    old_left: 0,
+                    old_right: 0,
+                    digital_volume,
+                    preamp: 0,
+                })
+                .unwrap();
+        }
+        assert_eq!(driver.player().calls, vec!["gain 0.5", "gain 1"]);
+    }
+
+    #[derive(Clone, Default)]
+    struct Saved(std::sync::Arc<Mutex<Vec<String>>>);
+
+    impl Settings for Saved {
+        fn load(&self, key: &str) -> Option<String> {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .find_map(|saved| saved.strip_prefix(&format!("{}=", key)).map(str::to_owned))
+        }
+
+        fn save(&self, key: &str, value: &str) -> io::Result<()> {
+            self.0.lock().unwrap().push(format!("{}={}", key, value));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let saved = Saved::default();
+        let (driver, _rx) = driver();
+        let mut driver = driver.with_settings(saved.clone());
+
+        driver
+            .handle(ServerMessage::Setname(PlayerName::new("Lounge").unwrap()))
+            .unwrap();
+        driver
+            .handle(ServerMessage::Gain {
+                left: FixedGain(0x8000),
+                right: FixedGain::UNITY,
+                old_left: 0,
+                old_right: 0,
+                digital_volume: true,
+                preamp: 0,
+            })
+            .unwrap();
+        driver
+            .handle(ServerMessage::Serv {
+                address: Ipv4Addr::LOCALHOST.into(),
+                sync_group_id: Some("kitchen".into()),
+            })
+            .unwrap();
+
+        assert_eq!(
+            *saved.0.lock().unwrap(),
+            vec!["name=Lounge", "volume=32768 65536", "sync_group=kitchen"]
+        );
+    }
+
+    #[test]
+    fn restore_settings() {
+        let saved = Saved::default();
+        saved.0.lock().unwrap().extend([
+            "name=Lounge".to_owned(),
+            "volume=32768 32768".to_owned(),
+            "sync_group=kitchen".to_owned(),
+        ]);
+        let (driver, rx) = driver();
+        let mut driver = driver.with_settings(saved);
+
+        assert_eq!(driver.player().calls, vec!["gain 0.5"]);
+        assert_eq!(
+            driver.server().sync_group_id,
+            Some(crate::proto::SyncGroup::new("kitchen"))
+        );
+        driver.handle(ServerMessage::Queryname).unwrap();
+        assert_eq!(event_codes(&rx), vec!["SETD name=Lounge"]);
+    }
+
+    #[test]
+    fn outputs() {
+        let (driver, _rx) = driver();
+        let mut caps = Capabilities::default();
+        caps.add(Capability::Hasdisabledac);
+        let mut driver = driver.with_capabilities(&caps);
+
+        for (spdif, dac) in [(true, false), (false, false), (true, true)] {
+            driver
+                .handle(ServerMessage::Enable(OutputEnable { spdif, dac }))
+                .unwrap();
+        }
+
+        // Without a digital output S/PDIF is never turned on
+        assert_eq!(
+            driver.player().calls,
+            vec!["enable false false", "enable false true"]
+        );
+        assert_eq!(
+            driver.outputs(),
+            Some(OutputEnable {
+                spdif: false,
+                dac: true
+            })
+        );
+
+        driver.reconnected();
+        assert_eq!(driver.player().calls.last().unwrap(), "enable false true");
+    }
+
+    #[derive(Clone, Default)]
+    struct Digi(std::sync::Arc<Mutex<Vec<String>>>);
+
+    impl DigitalOutput for Digi {
+        fn set_spdif(&mut self, enable: SpdifEnable) {
+            self.0.lock().unwrap().push(format!("spdif {:?}", enable));
+        }
+
+        fn enable_outputs(&mut self, outputs: OutputEnable) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("enable {} {}", outputs.spdif, outputs.dac));
+        }
+    }
+
+    #[test]
+    fn digital_output() {
+        let digi = Digi::default();
+        let (driver, _rx) = driver();
+        let mut driver = driver
+            .with_capabilities(&Capabilities::default())
+            .with_digital_output(digi.clone());
+
+        driver
+            .handle(ServerMessage::Enable(OutputEnable {
+                spdif: true,
+                dac: false,
+            }))
+            .unwrap();
+        driver
+            .handle(stream_message(serve_stream(), StreamFlags::empty()))
+            .unwrap();
+
+        // The hardware is given the outputs as the server sent them
+        assert_eq!(
+            *digi.0.lock().unwrap(),
+            vec!["enable true false", "spdif Auto"]
+        );
+        assert_eq!(
+            driver.player().calls,
+            vec!["enable false true", "start Flac abc"]
+        );
+
+        driver.reconnected();
+        assert_eq!(digi.0.lock().unwrap().last().unwrap(), "enable true false");
+    }
+
+    #[test]
+    fn skip() {
+        let (mut driver, _rx) = driver();
+        driver
+            .handle(ServerMessage::Skip(Jiffies::from_millis(10)))
+            .unwrap();
+
+        driver.player().format = OutputFormat::from_pcm(
+            PcmSampleSize::Sixteen,
+            PcmSampleRate::Rate(44_100),
+            PcmChannels::Stereo,
+        );
+        driver
+            .handle(ServerMessage::Skip(Jiffies::from_millis(10)))
+            .unwrap();
+
+        // 441 frames of four bytes
+        assert_eq!(driver.player().calls, vec!["skip 10ms", "drop 1764"]);
+    }
+
+    // Serve one stream and give back its port
+    fn serve_stream() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\n\r\nabc").unwrap();
+        });
+        port
+    }
+
+    fn stream_message(port: u16, flags: StreamFlags) -> ServerMessage {
+        ServerMessage::Stream {
+            autostart: AutoStart::Auto,
+            format: Format::Flac,
+            pcmsamplesize: crate::proto::PcmSampleSize::SelfDescribing,
+            pcmsamplerate: crate::proto::PcmSampleRate::SelfDescribing,
+            pcmchannels: crate::proto::PcmChannels::SelfDescribing,
+            pcmendian: crate::proto::PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            spdif_enable: crate::proto::SpdifEnable::Auto,
+            trans_period: std::time::Duration::ZERO,
+            trans_type: crate::proto::TransType::None,
+            flags,
+            output_threshold: std::time::Duration::ZERO,
+            replay_gain: Gain::MUTE,
+            server_port: port,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            http_headers: Some("GET /a.flac HTTP/1.0\r\n\r\n".to_owned()),
+            raw: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let (mut driver, rx) = driver();
+        driver
+            .handle(stream_message(serve_stream(), StreamFlags::empty()))
+            .unwrap();
+
+        assert_eq!(driver.player().calls, vec!["start Flac abc"]);
+        assert_eq!(event_codes(&rx), vec!["STMc", "STMe", "STMh", "STMs"]);
+        assert!(driver.reporter().status().lock().track_elapsed().is_some());
+    }
+
+    #[test]
+    fn start_on_unpause() {
+        let (mut driver, rx) = driver();
+        let mut msg = stream_message(serve_stream(), StreamFlags::empty());
+        if let ServerMessage::Stream { autostart, .. } = &mut msg {
+            *autostart = AutoStart::None;
+        }
+        driver.handle(msg).unwrap();
+        assert_eq!(event_codes(&rx), vec!["STMc", "STMe", "STMh"]);
+
+        driver
+            .handle(ServerMessage::Unpause(PauseKind::Now))
+            .unwrap();
+        assert_eq!(event_codes(&rx), vec!["STMr", "STMs"]);
+
+        // Only the first unpause starts the track
+        driver
+            .handle(ServerMessage::Unpause(PauseKind::Now))
+            .unwrap();
+        assert_eq!(event_codes(&rx), vec!["STMr"]);
+    }
+
+    #[test]
+    fn gapless() {
+        let (mut driver, rx) = driver();
+        driver.player().gapless = true;
+        driver
+            .handle(stream_message(serve_stream(), StreamFlags::empty()))
+            .unwrap();
+        driver.decoder_finished();
+        driver
+            .handle(stream_message(
+                serve_stream(),
+                StreamFlags::NO_RESTART_DECODER,
+            ))
+            .unwrap();
+        assert_eq!(
+            event_codes(&rx),
+            vec!["STMc", "STMe", "STMh", "STMs", "STMd", "STMc", "STMe", "STMh"]
+        );
+
+        // The second track starts when the output reaches it
+        driver.track_started();
+        assert!(driver.reporter().status().lock().track_elapsed().is_some());
+        driver.decoder_finished();
+        driver.output_finished();
+        assert!(driver.reporter().status().lock().track_elapsed().is_none());
+        assert_eq!(event_codes(&rx), vec!["STMs", "STMd", "STMu"]);
+        assert_eq!(
+            driver.player().calls,
+            vec!["start Flac abc", "continue Flac abc"]
+        );
+    }
+
+    #[test]
+    fn gapless_unsupported() {
+        let (mut driver, rx) = driver();
+        driver
+            .handle(stream_message(serve_stream(), StreamFlags::empty()))
+            .unwrap();
+        driver
+            .handle(stream_message(
+                serve_stream(),
+                StreamFlags::NO_RESTART_DECODER,
+            ))
+            .unwrap();
+
+        // Each stream is started anew and sends its own STMs
+        assert_eq!(
+            event_codes(&rx),
+            vec!["STMc", "STMe", "STMh", "STMs", "STMc", "STMe", "STMh", "STMs"]
+        );
+        assert_eq!(
+            driver.player().calls,
+            vec!["start Flac abc", "start Flac abc"]
+        );
+    }
+}
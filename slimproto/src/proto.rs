@@ -1,24 +1,59 @@
-/// Contains the Server type with which we connect to the server.
-///
-/// This module also holds the `ClientMessage` and `ServerMessage` types that
-/// are sent to and received from the server.
+//! Contains the Server type with which we connect to the server.
+//!
+//! This module also holds the `ClientMessage` and `ServerMessage` types that
+//! are sent to and received from the server.
+
 use bitflags::bitflags;
-use framous::{FramedRead, FramedWrite, FramedWriter};
-use mac_address::{get_mac_address, MacAddress};
+#[cfg(feature = "std")]
+use framous::FramedWriter;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use socket2::{SockRef, TcpKeepalive};
+
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+pub use crate::mac::MacAddress;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub use mac_address::MacAddress;
+
 pub const SLIM_PORT: u16 = 3483;
 
-use crate::{codec::SlimCodec, status::StatusData, Capabilities, Capability};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use crate::codec::{ReadHalf, WriteHalf};
+use crate::{
+    codec::DecodeError,
+    gain::{FixedGain, Gain},
+    jiffies::Jiffies,
+    status::StatusData,
+    Capabilities, Capability,
+};
+#[cfg(feature = "std")]
+use crate::{
+    codec::{SlimCodec, SlimFramed},
+    connection::{ConnectionState, ConnectionTracker},
+    http::HttpRequest,
+    metrics::SharedMetrics,
+    version::ServerVersion,
+};
 
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use alloc::format;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+#[cfg(feature = "std")]
+use core::net::SocketAddrV4;
+use core::{fmt, net::Ipv4Addr, str::FromStr, time::Duration};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::net::TcpStream;
+#[cfg(feature = "std")]
 use std::{
     collections::HashMap,
-    io::{self, BufReader, BufWriter},
-    net::{Ipv4Addr, SocketAddrV4, TcpStream},
-    time::Duration,
+    io::{self, Read, Write},
 };
 
 /// An enum which describes the various [TLV](https://en.wikipedia.org/wiki/Type%E2%80%93length%E2%80%93value)
 /// values with which the server can respond.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ServerTlv {
     Name(String),
     Version(String),
@@ -26,31 +61,156 @@ pub enum ServerTlv {
     Port(u16),
 }
 
+/// The id of a group of players that play in synchronisation.
+///
+/// The server hands out the sync group in a Serv message when it moves a player
+/// into a group and the player must announce it back as the `SyncgroupID`
+/// capability when it next connects. This is done automatically when a [Server]
+/// with a sync group is [prepared](Server::prepare).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyncGroup(String);
+
+impl SyncGroup {
+    pub fn new(id: &str) -> Self {
+        Self(id.to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SyncGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<String> for SyncGroup {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for SyncGroup {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+/// The server to which a Serv message moves the player.
+///
+/// The server normally sends an IPv4 address, followed by the sync group when it
+/// moves the player into one. A server elsewhere may instead be named by its
+/// hostname, which is sent on its own and must be looked up before the player can
+/// connect to it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ServerAddress {
+    Ip(Ipv4Addr),
+    Hostname(String),
+}
+
+impl ServerAddress {
+    // The payload of a Serv message is taken to be a hostname when it is longer
+    // than an address, has only the characters allowed in a hostname and ends in a
+    // top level domain of letters. An address followed by a sync group, which the
+    // server makes from digits, never does.
+    pub(crate) fn hostname(payload: &[u8]) -> Option<String> {
+        let hostname = core::str::from_utf8(payload).ok()?;
+        let (_, tld) = hostname.rsplit_once('.')?;
+        let valid = payload.len() > 4
+            && hostname
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+            && !tld.is_empty()
+            && tld.bytes().all(|b| b.is_ascii_alphabetic());
+        valid.then(|| hostname.to_owned())
+    }
+
+    /// The IPv4 address of the server, looking up a hostname if necessary.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn resolve(&self) -> io::Result<Ipv4Addr> {
+        use std::net::{SocketAddr, ToSocketAddrs};
+
+        match self {
+            ServerAddress::Ip(ip) => Ok(*ip),
+            ServerAddress::Hostname(hostname) => (hostname.as_str(), SLIM_PORT)
+                .to_socket_addrs()?
+                .find_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(*addr.ip()),
+                    SocketAddr::V6(_) => None,
+                })
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No IPv4 address for {}", hostname),
+                    )
+                }),
+        }
+    }
+}
+
+impl From<Ipv4Addr> for ServerAddress {
+    fn from(value: Ipv4Addr) -> Self {
+        ServerAddress::Ip(value)
+    }
+}
+
+impl fmt::Display for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerAddress::Ip(ip) => write!(f, "{}", ip),
+            ServerAddress::Hostname(hostname) => write!(f, "{}", hostname),
+        }
+    }
+}
+
 /// A hashmap to hold all TLVs from the server
+#[cfg(feature = "std")]
 pub(crate) type ServerTlvMap = HashMap<String, ServerTlv>;
 
-/// A Server struct to hold the connection details
+/// A Server struct to hold the connection details.
+///
+/// A `Server` is normally obtained from [discover](crate::discovery::discover) but it
+/// can also be built by hand:
+///
+/// ```
+/// use slimproto::proto::Server;
+/// use std::net::Ipv4Addr;
+///
+/// let server = Server::new(Ipv4Addr::new(192, 168, 1, 10))
+///     .with_port(3484)
+///     .with_sync_group("group1");
+/// assert_eq!(server.socket.port(), 3484);
+/// ```
+#[cfg(feature = "std")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Server {
     pub socket: SocketAddrV4,
     pub tlv_map: Option<ServerTlvMap>,
-    pub sync_group_id: Option<String>,
+    pub sync_group_id: Option<SyncGroup>,
 }
 
 /// Allow to clone the server.
 /// We'll lose the TLV map but it's not needed for connecting to the server
+#[cfg(feature = "std")]
 impl Clone for Server {
     fn clone(&self) -> Self {
         Self {
             socket: self.socket,
             tlv_map: None,
-            sync_group_id: self.sync_group_id.as_ref().map(String::from),
+            sync_group_id: self.sync_group_id.clone(),
         }
     }
 }
 
-/// Useful for conversions from a Serv message
-impl From<(Ipv4Addr, Option<String>)> for Server {
-    fn from(value: (Ipv4Addr, Option<String>)) -> Self {
+/// Useful for conversions from a Serv message.
+/// The default Slim port is used, see [redirect](Server::redirect) to keep the current port.
+#[cfg(feature = "std")]
+impl From<(Ipv4Addr, Option<SyncGroup>)> for Server {
+    fn from(value: (Ipv4Addr, Option<SyncGroup>)) -> Self {
         Self {
             socket: SocketAddrV4::new(value.0, SLIM_PORT),
             tlv_map: None,
@@ -59,6 +219,18 @@ impl From<(Ipv4Addr, Option<String>)> for Server {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<(SocketAddrV4, Option<SyncGroup>)> for Server {
+    fn from(value: (SocketAddrV4, Option<SyncGroup>)) -> Self {
+        Self {
+            socket: value.0,
+            tlv_map: None,
+            sync_group_id: value.1,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<SocketAddrV4> for Server {
     fn from(value: SocketAddrV4) -> Self {
         Self {
@@ -69,6 +241,7 @@ impl From<SocketAddrV4> for Server {
     }
 }
 
+#[cfg(feature = "std")]
 impl Default for Server {
     fn default() -> Self {
         Self {
@@ -79,68 +252,529 @@ impl Default for Server {
     }
 }
 
+/// Options that control the TCP connection to the server.
+///
+/// The defaults are those that have always been used: no connect timeout,
+/// 30 second read and write timeouts, no TCP keepalive and `TCP_NODELAY` set.
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub keepalive: Option<Duration>,
+    pub nodelay: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: None,
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            keepalive: None,
+            nodelay: true,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Bound the time taken to establish the connection.
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Bound the time a read on the connection can block. `None` blocks forever.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Bound the time a write on the connection can block. `None` blocks forever.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Enable TCP keepalive probes after the connection has been idle for `idle`.
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.keepalive = idle;
+        self
+    }
+
+    /// Set or clear `TCP_NODELAY` on the connection.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub(crate) fn open(&self, addr: SocketAddrV4) -> io::Result<TcpStream> {
+        let cx = match self.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr.into(), timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        cx.set_nodelay(self.nodelay)?;
+        cx.set_read_timeout(self.read_timeout)?;
+        cx.set_write_timeout(self.write_timeout)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(&cx).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(cx)
+    }
+}
+
 /// A prepared server struct is one that has capabilities and is ready
-/// for connection to the Slim server
+/// for connection to the Slim server.
+///
+/// A `Server` cannot be connected to directly, it must first be prepared with the
+/// client's capabilities using [prepare](Server::prepare) or [with_caps](Server::with_caps).
+/// The resulting `PreparedServer` can then be given the identity of the player before
+/// calling [connect](PreparedServer::connect), which opens the connection and announces
+/// the player to the server with a HELO message.
+///
+#[cfg_attr(all(feature = "std", not(target_arch = "wasm32")), doc = "```no_run")]
+#[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), doc = "```ignore")]
+/// use slimproto::{proto::{PlayerName, Server}, Capabilities};
+/// use std::net::Ipv4Addr;
+///
+/// let (rx, tx) = Server::new(Ipv4Addr::new(192, 168, 1, 10))
+///     .prepare(Capabilities::default())
+///     .with_name(PlayerName::new("Kitchen").unwrap())
+///     .with_device_id(12)
+///     .connect()
+///     .unwrap();
+/// ```
+#[cfg(feature = "std")]
+#[derive(Clone)]
 pub struct PreparedServer {
+    #[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), allow(dead_code))]
     server: Server,
     caps: Capabilities,
+    bytes_received: u64,
+    language: Language,
+    name: Option<PlayerName>,
+    mac: MacSource,
+    uuid: [u8; 16],
+    device_id: u8,
+    options: ConnectOptions,
+    tracker: Option<ConnectionTracker>,
+    metrics: SharedMetrics,
+    reconnecting: bool,
 }
 
+#[cfg(feature = "std")]
 impl Server {
-    pub fn prepare(&self, mut caps: Capabilities) -> PreparedServer {
+    /// Create a new server at the given address using the default Slim port.
+    pub fn new(ip_address: Ipv4Addr) -> Self {
+        SocketAddrV4::new(ip_address, SLIM_PORT).into()
+    }
+
+    /// Use a port other than the default Slim port.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.socket.set_port(port);
+        self
+    }
+
+    /// The port on which the server is listening for Slim protocol connections.
+    pub fn port(&self) -> u16 {
+        self.socket.port()
+    }
+
+    /// The port of the server's JSON interface, if given in the discovery response.
+    pub fn json_port(&self) -> Option<u16> {
+        match self.tlv_map.as_ref()?.get("JSON")? {
+            ServerTlv::Port(port) => Some(*port),
+            _ => None,
+        }
+    }
+
+    /// The version of the server, if given in the discovery response.
+    pub fn version(&self) -> Option<ServerVersion> {
+        match self.tlv_map.as_ref()?.get("VERS")? {
+            ServerTlv::Version(version) => version.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Create the server to which a Serv message redirects us.
+    /// The port of this server is kept so that port-forwarded or containerised
+    /// servers continue to work.
+    pub fn redirect(&self, ip_address: Ipv4Addr, sync_group_id: Option<SyncGroup>) -> Self {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(from = %self.socket, to = %ip_address, "redirected to server");
+        (SocketAddrV4::new(ip_address, self.port()), sync_group_id).into()
+    }
+
+    /// Create the server to which a Serv message redirects us, looking up the
+    /// server's hostname if it sent one. See [redirect](Server::redirect).
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn redirect_to(
+        &self,
+        address: &ServerAddress,
+        sync_group_id: Option<SyncGroup>,
+    ) -> io::Result<Self> {
+        Ok(self.redirect(address.resolve()?, sync_group_id))
+    }
+
+    /// The address from which to fetch the audio stream of a Stream message.
+    /// The server sends an address of 0.0.0.0 when the stream is to be fetched
+    /// from the server itself.
+    pub fn stream_address(&self, server_ip: Ipv4Addr, server_port: u16) -> SocketAddrV4 {
+        if server_ip.is_unspecified() {
+            SocketAddrV4::new(*self.socket.ip(), server_port)
+        } else {
+            SocketAddrV4::new(server_ip, server_port)
+        }
+    }
+
+    /// Build a GET request for `path` from the address in a Stream message,
+    /// resolving an address of 0.0.0.0 to this server as
+    /// [stream_address](Server::stream_address) does.
+    pub fn build_get(&self, server_ip: Ipv4Addr, port: u16, path: &str) -> HttpRequest {
+        HttpRequest::get(path, self.stream_address(server_ip, port))
+    }
+
+    /// Set the sync group that this client will announce to the server.
+    pub fn with_sync_group(mut self, sync_group_id: impl Into<SyncGroup>) -> Self {
+        self.sync_group_id = Some(sync_group_id.into());
+        self
+    }
+
+    /// Add capabilities to the server ready for connection.
+    /// This is the consuming equivalent of [prepare](Server::prepare).
+    pub fn with_caps(self, caps: Capabilities) -> PreparedServer {
+        self.prepare(caps)
+    }
+
+    /// Prepare the server with the given capabilities and announce the player
+    /// over a caller-supplied transport. See [PreparedServer::connect_with].
+    pub fn connect_with<T>(&self, caps: Capabilities, transport: T) -> io::Result<SlimFramed<T>>
+    where
+        T: Read + Write,
+    {
+        self.prepare(caps).connect_with(transport)
+    }
+
+    fn caps_with_sync_group(&self, mut caps: Capabilities) -> Capabilities {
         if let Some(sgid) = &self.sync_group_id {
-            caps.add(Capability::Syncgroupid(sgid.to_owned()));
+            caps.add(sgid.clone().into());
         }
+        caps
+    }
+
+    /// Add capabilities to the server ready for connection.
+    pub fn prepare(&self, caps: Capabilities) -> PreparedServer {
         PreparedServer {
             server: self.clone(),
-            caps,
+            caps: self.caps_with_sync_group(caps),
+            bytes_received: 0,
+            language: Language::default(),
+            name: None,
+            mac: MacSource::default(),
+            uuid: [0u8; 16],
+            device_id: 12,
+            options: ConnectOptions::default(),
+            tracker: None,
+            metrics: SharedMetrics::default(),
+            reconnecting: false,
         }
     }
 }
 
+/// The read half of a connection to the server.
+///
+/// A read gives an error, rather than retrying, when the connection's read timeout
+/// expires and when the server closes the connection.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub type SlimReader = SlimFramed<ReadHalf<TcpStream>>;
+
+/// The write half of a connection to the server. Each message is written in one
+/// go and flushed.
+///
+/// The halves can be joined again with [reunite](SlimFramed::reunite) to reclaim
+/// the socket.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub type SlimWriter = SlimFramed<WriteHalf<TcpStream>>;
+
+#[cfg(feature = "std")]
 impl PreparedServer {
-    pub fn connect(
-        self,
-    ) -> io::Result<(
-        FramedRead<BufReader<TcpStream>, SlimCodec>,
-        FramedWrite<BufWriter<TcpStream>, SlimCodec>,
-    )> {
-        let cx = TcpStream::connect(self.server.socket)?;
-        cx.set_nodelay(true)?;
-        cx.set_read_timeout(Some(Duration::from_secs(30)))?;
-        cx.set_write_timeout(Some(Duration::from_secs(30)))?;
+    /// Set the name of the player. The name is sent to the server
+    /// immediately after the HELO message.
+    pub fn with_name(mut self, name: PlayerName) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Set the MAC address that identifies the player to the server.
+    /// If this is not set then the MAC address of the host is used.
+    pub fn with_mac(mut self, mac: MacAddress) -> Self {
+        self.mac = MacSource::Fixed(mac);
+        self
+    }
+
+    /// Set where the MAC address that identifies the player comes from.
+    pub fn with_mac_source(mut self, source: MacSource) -> Self {
+        self.mac = source;
+        self
+    }
+
+    /// Set the language sent to the server, the default is English.
+    pub fn with_language(mut self, language: Language) -> Self {
+        self.language = language;
+        self
+    }
+
+    /// Set the UUID of the player.
+    pub fn with_uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Set the device id of the player, the default is 12 which
+    /// identifies the player as a squeezeplay device.
+    pub fn with_device_id(mut self, device_id: u8) -> Self {
+        self.device_id = device_id;
+        self
+    }
 
+    /// Track the state of the connection as the player connects and announces itself.
+    pub fn with_tracker(mut self, tracker: ConnectionTracker) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Report connections, and the messages sent and received over them, into `metrics`.
+    pub fn with_metrics(mut self, metrics: SharedMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    fn codec(&self) -> SlimCodec {
+        SlimCodec::new().with_metrics(self.metrics.clone())
+    }
+
+    fn track(&self, state: ConnectionState) {
+        if let Some(tracker) = &self.tracker {
+            tracker.set(state);
+        }
+    }
+
+    /// Replace the capabilities that are announced to the server, everything else
+    /// about the player is kept. The server only reads the capabilities from the
+    /// HELO message so the player must then [reconnect](PreparedServer::reconnect)
+    /// for them to take effect, for example after a decoder is turned on or off.
+    pub fn update_capabilities(&mut self, caps: Capabilities) {
+        self.caps = self.server.caps_with_sync_group(caps);
+    }
+
+    /// Connect to the server again and announce the player with the same MAC
+    /// address and name, so that the server treats it as the same player.
+    ///
+    /// `bytes_received` is the number of stream bytes received so far, which is
+    /// sent in the HELO along with the [HELO_RECONNECT] bit so that the server can
+    /// carry on with the current stream.
+    /// The server drops the old connection once the player has announced itself
+    /// on the new one, the old reader and writer should then be dropped.
+    ///
+    /// ```no_run
+    /// use slimproto::{proto::{PlayerName, Server}, Capabilities, Capability};
+    /// use std::net::Ipv4Addr;
+    ///
+    /// let mut prepared = Server::new(Ipv4Addr::new(192, 168, 1, 10))
+    ///     .prepare(Capabilities::default())
+    ///     .with_name(PlayerName::new("Kitchen").unwrap());
+    /// let (rx, tx) = prepared.clone().connect().unwrap();
+    ///
+    /// let mut caps = Capabilities::default();
+    /// caps.add(Capability::Flc);
+    /// prepared.update_capabilities(caps);
+    /// let (rx, tx) = prepared.reconnect(0).unwrap();
+    /// ```
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn reconnect(&self, bytes_received: u64) -> io::Result<(SlimReader, SlimWriter)> {
+        let mut prepared = self.clone();
+        prepared.bytes_received = bytes_received;
+        prepared.reconnecting = true;
+        prepared.connect()
+    }
+
+    /// Set the options used for the TCP connection to the server.
+    pub fn with_options(mut self, options: ConnectOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Connect to the server and announce the player with a HELO message.
+    ///
+    /// Returns a reader and a writer for receiving messages from, and sending
+    /// messages to, the server.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "connect", skip_all, fields(server = %self.server.socket))
+    )]
+    pub fn connect(self) -> io::Result<(SlimReader, SlimWriter)> {
+        self.track(ConnectionState::Connecting);
+        let cx = self
+            .options
+            .open(self.server.socket)
+            .inspect_err(|_| self.track(ConnectionState::Closed))?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!("connected");
+
+        let (rx, mut tx) = SlimFramed::new(cx).with_codec(self.codec()).into_split();
+
+        self.announce(&mut tx)?;
+        Ok((rx, tx))
+    }
+
+    /// Announce the player with a HELO message over a caller-supplied transport
+    /// rather than a direct TCP connection to the server.
+    ///
+    /// The transport must already be connected to the server, the socket address of the
+    /// server and the connection options are not used.
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "connect", skip_all))]
+    pub fn connect_with<T>(self, transport: T) -> io::Result<SlimFramed<T>>
+    where
+        T: Read + Write,
+    {
+        let mut framed = SlimFramed::new(transport).with_codec(self.codec());
+        self.announce(&mut framed)?;
+        Ok(framed)
+    }
+
+    fn announce(self, tx: &mut impl FramedWriter<ClientMessage>) -> io::Result<()> {
+        self.track(ConnectionState::Handshaking);
+        let tracker = self.tracker.clone();
+        let result = self.send_helo(tx);
+        if let Some(tracker) = tracker {
+            tracker.set(match result {
+                Ok(_) => ConnectionState::Ready,
+                Err(_) => ConnectionState::Closed,
+            });
+        }
+        result
+    }
+
+    fn send_helo(self, tx: &mut impl FramedWriter<ClientMessage>) -> io::Result<()> {
         let helo = ClientMessage::Helo {
-            device_id: 12,
+            device_id: self.device_id,
             revision: 0,
-            mac: match get_mac_address() {
-                Ok(Some(mac)) => mac,
-                _ => MacAddress::new([1, 2, 3, 4, 5, 6]),
-            },
-            uuid: [0u8; 16],
-            wlan_channel_list: 0,
-            bytes_received: 0,
-            language: ['e', 'n'],
+            mac: self.mac.resolve(&self.uuid)?,
+            uuid: self.uuid,
+            wlan_channel_list: if self.reconnecting { HELO_RECONNECT } else { 0 },
+            bytes_received: self.bytes_received,
+            language: self.language.chars(),
             capabilities: self.caps.to_string(),
         };
 
-        let rx = FramedRead::new(BufReader::new(cx.try_clone()?), SlimCodec);
-        let mut tx = FramedWrite::new(BufWriter::new(cx), SlimCodec);
-
+        #[cfg(feature = "tracing")]
+        tracing::debug!(%helo, "announcing player");
         tx.framed_write(helo)?;
-        Ok((rx, tx))
+        if let Some(name) = self.name {
+            tx.framed_write(ClientMessage::Name(name))?;
+        }
+        self.metrics.record(|m| m.connected());
+        Ok(())
+    }
+}
+
+/// Where the MAC address that identifies the player to the server comes from.
+///
+/// The server tells players apart by their MAC address so each player on the
+/// network needs a different one, several players on one host should each be
+/// given a [Fixed](MacSource::Fixed) address or a different UUID.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MacSource {
+    /// The address of the host's first network interface. If the host has none,
+    /// as on wasm32, one derived from the UUID or, when no UUID has been set,
+    /// the [FALLBACK_MAC].
+    #[default]
+    Host,
+    /// The address of the named network interface.
+    SystemInterface(String),
+    /// The given address, as set by [with_mac](PreparedServer::with_mac).
+    Fixed(MacAddress),
+    /// A locally administered address made from the first six bytes of the UUID.
+    DerivedFromUuid,
+}
+
+#[cfg(feature = "std")]
+impl MacSource {
+    /// The MAC address for a player with the given UUID. An error of kind
+    /// `NotFound` is returned if a named interface has no address and one of kind
+    /// `InvalidInput` if the address is to be derived from a UUID that has not
+    /// been set, as every such player would share the same address.
+    pub fn resolve(&self, uuid: &[u8; 16]) -> io::Result<MacAddress> {
+        match self {
+            MacSource::Host => {
+                #[cfg(not(target_arch = "wasm32"))]
+                if let Ok(Some(mac)) = mac_address::get_mac_address() {
+                    return Ok(mac);
+                }
+                mac_from_uuid(uuid).or(Ok(MacAddress::new(FALLBACK_MAC)))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            MacSource::SystemInterface(name) => mac_address::mac_address_by_name(name)
+                .ok()
+                .flatten()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No MAC address for interface {}", name),
+                    )
+                }),
+            #[cfg(target_arch = "wasm32")]
+            MacSource::SystemInterface(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "No network interfaces on wasm32",
+            )),
+            MacSource::Fixed(mac) => Ok(*mac),
+            MacSource::DerivedFromUuid => mac_from_uuid(uuid),
+        }
+    }
+}
+
+/// The MAC address of a player on a host with no network interface of its own
+/// and no UUID set. It is locally administered so it cannot clash with a real
+/// interface, but only one such player can be connected to a server at a time.
+pub const FALLBACK_MAC: [u8; 6] = [0x02, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+// Set the locally administered bit and clear the multicast bit so that the
+// address cannot clash with a real interface
+#[cfg(feature = "std")]
+fn mac_from_uuid(uuid: &[u8; 16]) -> io::Result<MacAddress> {
+    if *uuid == [0u8; 16] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No UUID to derive a MAC address from",
+        ));
     }
+
+    let mut bytes = [0u8; 6];
+    bytes.copy_from_slice(&uuid[..6]);
+    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+    Ok(MacAddress::new(bytes))
 }
 
+/// The bit set in the wlan channel list of a HELO sent by a player that is
+/// reconnecting, the server then carries on with the current stream rather than
+/// starting the player afresh.
+pub const HELO_RECONNECT: u16 = 0x4000;
+
 /// A type that describes all messages that are sent from the client to
 /// the server.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClientMessage {
     Helo {
         device_id: u8,
         revision: u8,
         mac: MacAddress,
         uuid: [u8; 16],
+        /// Has the [HELO_RECONNECT] bit set when the player reconnects.
         wlan_channel_list: u16,
         bytes_received: u64,
         language: [char; 2],
@@ -151,10 +785,92 @@ pub enum ClientMessage {
         stat_data: StatusData,
     },
     Bye(u8),
-    Name(String),
+    Name(PlayerName),
+    /// The value of a player setting, in reply to [ServerMessage::QuerySetting].
+    Setting(PlayerSetting, u8),
+    /// The HTTP response headers received when a stream is opened
+    Resp(String),
+    /// The body of an HTTP response fetched by the client, used with direct streaming
+    Body(Vec<u8>),
+    /// Metadata taken from an ICY (Shoutcast) stream
+    Meta(Vec<u8>),
+    /// Ask the server for a firmware image, sent by a hardware player that has
+    /// restarted to be upgraded.
+    UpgradeRequest,
+    /// A key pressed on the remote control. The `time` is the player's jiffies
+    /// when the key was received, the server uses it to tell a held key from
+    /// repeated presses. The `code` is in the server's `format`, 0 for the 32 bit
+    /// codes of the Slim Devices remote.
+    Ir {
+        time: Jiffies,
+        format: u8,
+        bits: u8,
+        code: u32,
+    },
 }
 
-#[derive(Debug, PartialEq)]
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for ClientMessage {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=9)? {
+            0 => ClientMessage::Helo {
+                device_id: u.arbitrary()?,
+                revision: u.arbitrary()?,
+                mac: MacAddress::new(u.arbitrary()?),
+                uuid: u.arbitrary()?,
+                wlan_channel_list: u.arbitrary()?,
+                bytes_received: u.arbitrary()?,
+                language: u.arbitrary()?,
+                capabilities: u.arbitrary()?,
+            },
+            1 => {
+                let stat_data = StatusData {
+                    crlf: u.arbitrary()?,
+                    buffer_size: u.arbitrary()?,
+                    fullness: u.arbitrary()?,
+                    bytes_received: u.arbitrary()?,
+                    jiffies: Jiffies::from_millis(u.arbitrary()?),
+                    output_buffer_size: u.arbitrary()?,
+                    output_buffer_fullness: u.arbitrary()?,
+                    elapsed_seconds: u.arbitrary()?,
+                    elapsed_milliseconds: u.arbitrary()?,
+                    timestamp: Jiffies::from_millis(u.arbitrary()?),
+                    error_code: u.arbitrary()?,
+                    ..Default::default()
+                };
+                ClientMessage::Stat {
+                    event_code: u.arbitrary()?,
+                    stat_data,
+                }
+            }
+            2 => ClientMessage::Bye(u.arbitrary()?),
+            3 => ClientMessage::Name(PlayerName::from_bytes(u.arbitrary()?)),
+            4 => ClientMessage::Resp(u.arbitrary()?),
+            5 => ClientMessage::Body(u.arbitrary()?),
+            6 => ClientMessage::Meta(u.arbitrary()?),
+            7 => ClientMessage::Setting(
+                *u.choose(&[
+                    PlayerSetting::DigitalOutputEncoding,
+                    PlayerSetting::WordClockOutput,
+                    PlayerSetting::PowerOffDac,
+                    PlayerSetting::FxloopSource,
+                    PlayerSetting::FxloopClock,
+                ])?,
+                u.arbitrary()?,
+            ),
+            8 => ClientMessage::Ir {
+                time: Jiffies::from_millis(u.arbitrary()?),
+                format: u.arbitrary()?,
+                bits: u.arbitrary()?,
+                code: u.arbitrary()?,
+            },
+            _ => ClientMessage::UpgradeRequest,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AutoStart {
     None,
     Auto,
@@ -162,7 +878,8 @@ pub enum AutoStart {
     AutoDirect,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Format {
     Pcm,
     Mp3,
@@ -172,7 +889,8 @@ pub enum Format {
     Aac,
     Alac,
 }
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PcmSampleSize {
     Eight,
     Sixteen,
@@ -181,34 +899,39 @@ pub enum PcmSampleSize {
     SelfDescribing,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PcmSampleRate {
     Rate(u32),
     SelfDescribing,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PcmChannels {
     Mono,
     Stereo,
     SelfDescribing,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PcmEndian {
     Big,
     Little,
     SelfDescribing,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SpdifEnable {
     Auto,
     On,
     Off,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TransType {
     None,
     Crossfade,
@@ -217,25 +940,358 @@ pub enum TransType {
     FadeInOut,
 }
 
+/// The amount of data to buffer before playback starts.
+///
+/// The server gives the threshold in kilobytes, this type makes the unit explicit
+/// so that it is not mistaken for a number of bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Threshold(u32);
+
+impl Threshold {
+    pub const ZERO: Threshold = Threshold(0);
+
+    /// A threshold of `kb` kilobytes, limited to the largest number of bytes that fits.
+    pub fn from_kb(kb: u32) -> Self {
+        Self(kb.saturating_mul(1024))
+    }
+
+    pub fn from_bytes(bytes: u32) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> u32 {
+        self.0
+    }
+
+    pub fn as_kb(&self) -> u32 {
+        self.0 / 1024
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}KB", self.as_kb())
+    }
+}
+
 bitflags! {
-    #[derive(Debug, Default, PartialEq)]
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct StreamFlags: u8 {
+        /// Play the stream in an infinite loop
         const INF_LOOP = 0b1000_0000;
+        /// Stream the new track without restarting the decoder
         const NO_RESTART_DECODER = 0b0100_0000;
+        /// Output only the left channel, to both outputs
+        const OUTPUT_LEFT_ONLY = 0b0000_0100;
+        /// Output only the right channel, to both outputs
+        const OUTPUT_RIGHT_ONLY = 0b0000_1000;
         const INVERT_POLARITY_LEFT = 0b0000_0001;
         const INVERT_POLARITY_RIGHT = 0b0000_0010;
+
+        // Bits not defined here are kept so that they are not lost
+        const _ = !0;
+    }
+}
+
+/// A value that cannot be sent to the server, such as a player name that is too
+/// long. Under `std` it converts into an `io::Error` of kind `InvalidInput`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidValue(&'static str);
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl core::error::Error for InvalidValue {}
+
+#[cfg(feature = "std")]
+impl From<InvalidValue> for io::Error {
+    fn from(e: InvalidValue) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// The two letter language code sent in the HELO message, such as `en` or `de`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Language([char; 2]);
+
+impl Language {
+    pub const ENGLISH: Language = Language(['e', 'n']);
+
+    /// Anything other than two ASCII letters is an error, the code is kept in
+    /// lower case.
+    pub fn new(code: &str) -> Result<Self, InvalidValue> {
+        let mut letters = code.chars().map(|c| c.to_ascii_lowercase());
+        match (letters.next(), letters.next(), letters.next()) {
+            (Some(a), Some(b), None) if a.is_ascii_lowercase() && b.is_ascii_lowercase() => {
+                Ok(Self([a, b]))
+            }
+            _ => Err(InvalidValue("Language must be two letters")),
+        }
+    }
+
+    pub fn chars(self) -> [char; 2] {
+        self.0
+    }
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Self::ENGLISH
+    }
+}
+
+impl FromStr for Language {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, InvalidValue> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.0[0], self.0[1])
+    }
+}
+
+/// The name of a player as sent in `setd` and `SETD` messages.
+///
+/// A name is at most [MAX_LEN](PlayerName::MAX_LEN) bytes of UTF-8 and cannot
+/// contain a NUL, which terminates the name on the wire.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct PlayerName(String);
+
+impl PlayerName {
+    /// The longest name in bytes, as held by squeezelite and the hardware players.
+    pub const MAX_LEN: usize = 64;
+
+    /// A name that is too long or that contains a NUL is an error.
+    pub fn new(name: impl Into<String>) -> Result<Self, InvalidValue> {
+        let name = name.into();
+        if name.len() > Self::MAX_LEN || name.contains('\0') {
+            return Err(InvalidValue("Invalid player name"));
+        }
+        Ok(Self(name))
+    }
+
+    /// Make a name from the bytes of a message. The name ends at the first NUL,
+    /// if there is one, invalid UTF-8 is replaced and a name that is too long is
+    /// cut short.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        let mut name = String::from_utf8_lossy(&bytes[..end]).into_owned();
+        let mut len = name.len().min(Self::MAX_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        name.truncate(len);
+        Self(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for PlayerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for PlayerName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<PlayerName> for String {
+    fn from(name: PlayerName) -> Self {
+        name.0
+    }
+}
+
+impl PartialEq<&str> for PlayerName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+/// A player setting that the server queries and sets with a `setd` message.
+///
+/// The player name and disabling the DAC have messages of their own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PlayerSetting {
+    DigitalOutputEncoding,
+    WordClockOutput,
+    PowerOffDac,
+    FxloopSource,
+    FxloopClock,
+}
+
+impl PlayerSetting {
+    /// The id of the setting in the `setd` message.
+    pub fn id(&self) -> u8 {
+        match self {
+            PlayerSetting::DigitalOutputEncoding => 1,
+            PlayerSetting::WordClockOutput => 2,
+            PlayerSetting::PowerOffDac => 3,
+            PlayerSetting::FxloopSource => 5,
+            PlayerSetting::FxloopClock => 6,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(PlayerSetting::DigitalOutputEncoding),
+            2 => Some(PlayerSetting::WordClockOutput),
+            3 => Some(PlayerSetting::PowerOffDac),
+            5 => Some(PlayerSetting::FxloopSource),
+            6 => Some(PlayerSetting::FxloopClock),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PlayerSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerSetting::DigitalOutputEncoding => write!(f, "digital output encoding"),
+            PlayerSetting::WordClockOutput => write!(f, "word clock output"),
+            PlayerSetting::PowerOffDac => write!(f, "power off dac"),
+            PlayerSetting::FxloopSource => write!(f, "effects loop source"),
+            PlayerSetting::FxloopClock => write!(f, "effects loop clock"),
+        }
+    }
+}
+
+/// When a pause or unpause takes effect.
+///
+/// The server sends a zero time to pause or unpause now, the player then
+/// acknowledges with `STMp` or `STMr`. Any other time in an unpause is the
+/// jiffies at which to unpause, `STMr` is then sent when the player unpauses.
+/// Any other time in a pause is an interval, the player pauses for that long and
+/// then plays on without acknowledging either.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PauseKind {
+    #[default]
+    Now,
+    At(Jiffies),
+    /// Pause for this long, then play on.
+    For(Duration),
+}
+
+impl PauseKind {
+    /// The pause sent with the given interval, which is zero for now.
+    pub fn from_interval(interval: Duration) -> Self {
+        if interval.is_zero() {
+            PauseKind::Now
+        } else {
+            PauseKind::For(interval)
+        }
+    }
+
+    /// The time as sent by the server, zero for now.
+    pub fn jiffies(self) -> Jiffies {
+        match self {
+            PauseKind::Now => Jiffies::ZERO,
+            PauseKind::At(at) => at,
+            PauseKind::For(interval) => Jiffies::from_millis(interval.as_millis() as u32),
+        }
+    }
+}
+
+impl From<Jiffies> for PauseKind {
+    fn from(at: Jiffies) -> Self {
+        if at.is_zero() {
+            PauseKind::Now
+        } else {
+            PauseKind::At(at)
+        }
+    }
+}
+
+impl fmt::Display for PauseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PauseKind::Now => write!(f, "now"),
+            PauseKind::At(at) => write!(f, "at {}", at),
+            PauseKind::For(interval) => write!(f, "for {}ms", interval.as_millis()),
+        }
+    }
+}
+
+/// The audio outputs that the server asks to be turned on with `aude`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OutputEnable {
+    pub spdif: bool,
+    pub dac: bool,
+}
+
+impl OutputEnable {
+    /// The outputs as a player with the capabilities `caps` can set them.
+    ///
+    /// S/PDIF stays off unless the player announces `HasDigitalOut` and the DAC
+    /// stays on unless it announces `HasDisableDac`.
+    pub fn supported_by(self, caps: &Capabilities) -> Self {
+        Self {
+            spdif: self.spdif && caps.contains(&Capability::Hasdigitalout),
+            dac: self.dac || !caps.contains(&Capability::Hasdisabledac),
+        }
+    }
+}
+
+/// A setting for the front panel clock of a player such as a Boom, sent by the
+/// server in an `rtcs` message. Only the time of day is sent, the clock has no date.
+#[cfg(feature = "chrono")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClockSetting {
+    /// Show the time in 12 hour rather than 24 hour format.
+    TwelveHour(bool),
+    /// Set the clock to this local time.
+    Time(chrono::NaiveTime),
+}
+
+#[cfg(feature = "chrono")]
+impl fmt::Display for ClockSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockSetting::TwelveHour(true) => write!(f, "12h"),
+            ClockSetting::TwelveHour(false) => write!(f, "24h"),
+            ClockSetting::Time(time) => write!(f, "{}", time),
+        }
     }
 }
 
 /// A type that describes all messages that are sent from the server to
 /// the client.
-#[derive(Debug)]
+///
+/// Some variants are only decoded with an optional feature, the enum is
+/// `#[non_exhaustive]` so that enabling one does not break a `match` elsewhere
+/// in the build.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
 pub enum ServerMessage {
     Serv {
-        ip_address: Ipv4Addr,
-        sync_group_id: Option<String>,
+        address: ServerAddress,
+        sync_group_id: Option<SyncGroup>,
     },
-    Status(Duration),
+    /// A request for a status message. The server's timestamp must be
+    /// returned in the status message.
+    Status(Jiffies),
     Stream {
         autostart: AutoStart,
         format: Format,
@@ -243,27 +1299,341 @@ pub enum ServerMessage {
         pcmsamplerate: PcmSampleRate,
         pcmchannels: PcmChannels,
         pcmendian: PcmEndian,
-        threshold: u32,
+        /// The amount of the stream to buffer before playback starts, sent in
+        /// kilobytes
+        threshold: Threshold,
         spdif_enable: SpdifEnable,
+        /// The length of the transition, sent in seconds
         trans_period: Duration,
         trans_type: TransType,
         flags: StreamFlags,
+        /// The amount of output to buffer before playback starts, sent in tenths
+        /// of a second
         output_threshold: Duration,
-        replay_gain: f64,
+        /// The replay gain of the track, zero when there is none
+        replay_gain: Gain,
         server_port: u16,
         server_ip: Ipv4Addr,
+        /// The HTTP request to send for the stream, `None` when there is none. A
+        /// frame holds less than 64 KB so a longer request arrives cut short,
+        /// [StreamParams](crate::stream::StreamParams) rejects a request that does
+        /// not end with an empty line.
         http_headers: Option<String>,
+        /// The whole payload of the message, starting with the command byte, for
+        /// fields that are not decoded such as the reserved byte
+        raw: Vec<u8>,
     },
-    Gain(f64, f64),
-    Enable(bool, bool),
+    /// Set the volume. `left` and `right` are the gains as sent, the old-style
+    /// gains are for older players. When `digital_volume` is false the player
+    /// should leave the volume at full and let the amplifier control it. The
+    /// `preamp` is for players that have a preamp.
+    Gain {
+        left: FixedGain,
+        right: FixedGain,
+        old_left: u32,
+        old_right: u32,
+        digital_volume: bool,
+        preamp: u8,
+    },
+    /// Turn the S/PDIF and analogue outputs on or off.
+    Enable(OutputEnable),
     Flush,
     Stop,
-    Pause(Duration),
-    Unpause(Duration),
+    /// Pause playback now or at the given jiffies time.
+    Pause(PauseKind),
+    /// Resume playback now or at the given jiffies time.
+    Unpause(PauseKind),
     Queryname,
-    Setname(String),
+    Setname(PlayerName),
     DisableDac,
-    Skip(Duration),
+    /// Ask for the value of a player setting, reply with [ClientMessage::Setting].
+    QuerySetting(PlayerSetting),
+    SetSetting(PlayerSetting, u8),
+    /// Skip ahead in the output by the given number of milliseconds.
+    Skip(Jiffies),
+    /// Continue with the stream, if `metaint` is not zero the stream has ICY metadata
+    /// every `metaint` bytes. If `loop_stream` is true the stream is to be replayed
+    /// from the buffer when it ends.
+    Cont {
+        metaint: u32,
+        loop_stream: bool,
+    },
+    /// Restart ready for a firmware upgrade, sent to hardware players when the
+    /// server has newer firmware for them.
+    UpgradeNow,
+    /// A chunk of a firmware image, sent in order after an [UpgradeRequest](ClientMessage::UpgradeRequest).
+    UpgradeData(Vec<u8>),
+    /// The version of the server, see [ServerVersion].
+    Version(String),
+    /// Set the real time clock of a player that has one. Only decoded with the
+    /// `chrono` feature, without it an `rtcs` message is [Unrecognised](ServerMessage::Unrecognised).
+    #[cfg(feature = "chrono")]
+    SetClock(ClockSetting),
+    /// A bitmap for the display of a classic Squeezebox. Only decoded with the
+    /// `display` feature, without it a `grfe` message is [Unrecognised](ServerMessage::Unrecognised).
+    #[cfg(feature = "display")]
+    Graphics(crate::display::GraphicsFrame),
     Unrecognised(String),
-    Error,
+    /// A message that could not be decoded, with the reason and the whole payload
+    /// so that it can be reported. The codec returns an `InvalidData` error for
+    /// these, decode the payload with `ServerMessage::from` to get the details.
+    Malformed {
+        command: [u8; 4],
+        reason: DecodeError,
+        raw: Vec<u8>,
+    },
+}
+
+/// A batch of messages from the server, in the order in which they were sent.
+pub type ServerMessages = Vec<ServerMessage>;
+
+/// A concise, single line description of the message for logging
+impl fmt::Display for ClientMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientMessage::Helo {
+                device_id,
+                mac,
+                capabilities,
+                ..
+            } => write!(
+                f,
+                "HELO device={} mac={} caps={}",
+                device_id, mac, capabilities
+            ),
+            ClientMessage::Stat {
+                event_code,
+                stat_data,
+            } => write!(
+                f,
+                "STAT {} jiffies={} elapsed={}ms",
+                event_code,
+                stat_data.get_jiffies(),
+                stat_data.get_elapsed_milli_seconds()
+            ),
+            ClientMessage::Bye(val) => write!(f, "BYE! {}", val),
+            ClientMessage::Name(name) => write!(f, "SETD name={}", name),
+            ClientMessage::Setting(setting, value) => write!(f, "SETD {}={}", setting, value),
+            ClientMessage::Resp(headers) => write!(f, "RESP {} bytes", headers.len()),
+            ClientMessage::Body(body) => write!(f, "BODY {} bytes", body.len()),
+            ClientMessage::Meta(meta) => write!(f, "META {} bytes", meta.len()),
+            ClientMessage::UpgradeRequest => write!(f, "UREQ"),
+            ClientMessage::Ir { time, code, .. } => write!(f, "IR {:#010x} at {}", code, time),
+        }
+    }
+}
+
+/// A concise, single line description of the message for logging
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerMessage::Serv {
+                address,
+                sync_group_id,
+            } => match sync_group_id {
+                Some(sgid) => write!(f, "serv {} sync_group={}", address, sgid),
+                None => write!(f, "serv {}", address),
+            },
+            ServerMessage::Status(ts) => write!(f, "strm t {}", ts),
+            ServerMessage::Stream {
+                autostart,
+                format,
+                server_ip,
+                server_port,
+                ..
+            } => write!(
+                f,
+                "strm s {:?} {:?} {}:{}",
+                format, autostart, server_ip, server_port
+            ),
+            ServerMessage::Gain { left, right, .. } => write!(f, "audg {} {}", left, right),
+            ServerMessage::Enable(OutputEnable { spdif, dac }) => {
+                write!(f, "aude spdif={} dac={}", spdif, dac)
+            }
+            ServerMessage::Flush => write!(f, "strm f"),
+            ServerMessage::Stop => write!(f, "strm q"),
+            ServerMessage::Pause(when) => write!(f, "strm p {}", when),
+            ServerMessage::Unpause(when) => write!(f, "strm u {}", when),
+            ServerMessage::Queryname => write!(f, "setd query name"),
+            ServerMessage::Setname(name) => write!(f, "setd name={}", name),
+            ServerMessage::DisableDac => write!(f, "setd disable dac"),
+            ServerMessage::QuerySetting(setting) => write!(f, "setd query {}", setting),
+            ServerMessage::SetSetting(setting, value) => {
+                write!(f, "setd {}={}", setting, value)
+            }
+            ServerMessage::Skip(by) => write!(f, "strm a {}", by),
+            ServerMessage::Cont {
+                metaint,
+                loop_stream,
+            } => write!(f, "cont metaint={} loop={}", metaint, loop_stream),
+            ServerMessage::UpgradeNow => write!(f, "updn"),
+            ServerMessage::UpgradeData(data) => write!(f, "upda {} bytes", data.len()),
+            ServerMessage::Version(version) => write!(f, "vers {}", version),
+            #[cfg(feature = "chrono")]
+            ServerMessage::SetClock(setting) => write!(f, "rtcs {}", setting),
+            #[cfg(feature = "display")]
+            ServerMessage::Graphics(frame) => write!(
+                f,
+                "grfe offset={} {} bytes",
+                frame.offset,
+                frame.bitmap.len()
+            ),
+            ServerMessage::Unrecognised(cmd) => write!(f, "unrecognised {}", cmd),
+            ServerMessage::Malformed {
+                command, reason, ..
+            } => write!(
+                f,
+                "malformed {}: {}",
+                String::from_utf8_lossy(command),
+                reason
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn server_address() {
+        assert_eq!(
+            ServerAddress::hostname(b"lms.example.com"),
+            Some("lms.example.com".to_owned())
+        );
+        assert_eq!(ServerAddress::hostname(b"a.bc"), None);
+        assert_eq!(ServerAddress::hostname(b"abcd1234567890"), None);
+        assert_eq!(ServerAddress::hostname(b"lms.example.c0m"), None);
+        assert_eq!(ServerAddress::hostname(&[172, 16, 1, 2]), None);
+
+        let server = Server::new(Ipv4Addr::new(172, 16, 1, 2)).with_port(9483);
+        let moved = server
+            .redirect_to(&ServerAddress::Hostname("localhost".to_owned()), None)
+            .unwrap();
+        assert_eq!(moved.socket, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9483));
+        let moved = server
+            .redirect_to(&Ipv4Addr::new(10, 0, 0, 1).into(), Some("1234".into()))
+            .unwrap();
+        assert_eq!(*moved.socket.ip(), Ipv4Addr::new(10, 0, 0, 1));
+    }
+
+    #[test]
+    fn threshold() {
+        assert_eq!(Threshold::from_kb(2).as_bytes(), 2048);
+        assert_eq!(Threshold::from_kb(u32::MAX).as_bytes(), u32::MAX);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn build_get() {
+        let server = Server::new(Ipv4Addr::new(172, 16, 1, 2));
+        let req = server.build_get(Ipv4Addr::UNSPECIFIED, 9000, "/stream.mp3");
+        assert_eq!(req.header("host"), Some("172.16.1.2:9000"));
+        let req = server.build_get(Ipv4Addr::new(10, 0, 0, 1), 8000, "/radio.mp3");
+        assert_eq!(req.path, "/radio.mp3");
+        assert_eq!(req.header("host"), Some("10.0.0.1:8000"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn mac_source() {
+        let uuid = [
+            0xff, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let fixed = MacAddress::new([1, 2, 3, 4, 5, 6]);
+        assert_eq!(MacSource::Fixed(fixed).resolve(&uuid).unwrap(), fixed);
+        assert_eq!(
+            MacSource::DerivedFromUuid.resolve(&uuid).unwrap(),
+            MacAddress::new([0xfe, 0x11, 0x22, 0x33, 0x44, 0x55])
+        );
+        assert_eq!(
+            MacSource::DerivedFromUuid
+                .resolve(&[0u8; 16])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        #[cfg(target_arch = "wasm32")]
+        {
+            assert_eq!(
+                MacSource::Host.resolve(&[0u8; 16]).unwrap(),
+                MacAddress::new(FALLBACK_MAC)
+            );
+            assert_eq!(
+                MacSource::Host.resolve(&uuid).unwrap(),
+                MacAddress::new([0xfe, 0x11, 0x22, 0x33, 0x44, 0x55])
+            );
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        assert!(MacSource::Host.resolve(&[0u8; 16]).is_ok());
+        #[cfg(not(target_arch = "wasm32"))]
+        assert_eq!(
+            MacSource::SystemInterface("no-such-interface".to_owned())
+                .resolve(&uuid)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn language() {
+        assert_eq!(Language::new("DE").unwrap().chars(), ['d', 'e']);
+        assert_eq!("fr".parse::<Language>().unwrap().to_string(), "fr");
+        for bad in ["", "e", "eng", "e1", "é!"] {
+            assert!(Language::new(bad).is_err());
+        }
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn reconnect() {
+        use crate::codec::ServerCodec;
+        use framous::FramedReader;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+
+        let mut prepared = Server::from(addr)
+            .with_sync_group("kitchen")
+            .prepare(Capabilities::default())
+            .with_name(PlayerName::new("Kitchen").unwrap())
+            .with_mac(MacAddress::new([1, 2, 3, 4, 5, 6]));
+        let mut caps = Capabilities::default();
+        caps.add(Capability::Flc);
+        prepared.update_capabilities(caps);
+        let _first = prepared.clone().connect().unwrap();
+        let (cx, _) = listener.accept().unwrap();
+        let mut rx = framous::FramedRead::new(cx, ServerCodec);
+        match rx.framed_read().unwrap() {
+            ClientMessage::Helo {
+                wlan_channel_list, ..
+            } => assert_eq!(wlan_channel_list & HELO_RECONNECT, 0),
+            msg => panic!("Expected HELO, got {}", msg),
+        }
+
+        let _connection = prepared.reconnect(1234).unwrap();
+
+        let (cx, _) = listener.accept().unwrap();
+        let mut rx = framous::FramedRead::new(cx, ServerCodec);
+        match rx.framed_read().unwrap() {
+            ClientMessage::Helo {
+                mac,
+                wlan_channel_list,
+                bytes_received,
+                capabilities,
+                ..
+            } => {
+                assert_eq!(mac, MacAddress::new([1, 2, 3, 4, 5, 6]));
+                assert_eq!(wlan_channel_list, HELO_RECONNECT);
+                assert_eq!(bytes_received, 1234);
+                assert!(capabilities.ends_with(",flc,SyncgroupID=kitchen"));
+            }
+            msg => panic!("Expected HELO, got {}", msg),
+        }
+    }
 }
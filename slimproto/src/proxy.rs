@@ -0,0 +1,270 @@
+//! A proxy that sits between a player and the server and relays their messages.
+//!
+//! Each frame is decoded so that it can be inspected, and changed or dropped,
+//! by a callback before it is sent on. Frames are passed on byte for byte unless
+//! they are replaced, so a message that the codec does not fully understand
+//! still reaches the other side as it was sent. This is useful for finding out
+//! why a player and a server do not get on.
+//!
+//! ```no_run
+//! use slimproto::proxy::{Action, Proxy};
+//! use std::net::TcpListener;
+//!
+//! let listener = TcpListener::bind("0.0.0.0:3483").unwrap();
+//! let (player, _) = listener.accept().unwrap();
+//! Proxy::new("192.168.1.10:3483".parse().unwrap())
+//!     .on_client_message(|msg| {
+//!         println!("player: {}", msg);
+//!         Action::Forward
+//!     })
+//!     .on_server_message(|msg| {
+//!         println!("server: {}", msg);
+//!         Action::Forward
+//!     })
+//!     .serve(player)
+//!     .unwrap();
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    thread::spawn,
+};
+
+use bytes::BytesMut;
+use framous::{Decoder, Encoder};
+
+use crate::{
+    codec::{ServerCodec, SlimCodec},
+    ClientMessage, ServerMessage,
+};
+
+/// What to do with a message passing through the proxy.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action<M> {
+    /// Send the message on unchanged.
+    Forward,
+    /// Send this message in its place.
+    Replace(M),
+    /// Do not send the message.
+    Drop,
+}
+
+type Callback<M> = Box<dyn FnMut(&M) -> Action<M> + Send>;
+
+/// Relays messages between one player and the server.
+pub struct Proxy {
+    upstream: SocketAddr,
+    on_client: Callback<ClientMessage>,
+    on_server: Callback<ServerMessage>,
+}
+
+impl Proxy {
+    /// A proxy to the server at `upstream` that forwards every message.
+    pub fn new(upstream: SocketAddr) -> Self {
+        Self {
+            upstream,
+            on_client: Box::new(|_| Action::Forward),
+            on_server: Box::new(|_| Action::Forward),
+        }
+    }
+
+    /// Call `callback` with each message from the player to the server.
+    pub fn on_client_message(
+        mut self,
+        callback: impl FnMut(&ClientMessage) -> Action<ClientMessage> + Send + 'static,
+    ) -> Self {
+        self.on_client = Box::new(callback);
+        self
+    }
+
+    /// Call `callback` with each message from the server to the player.
+    pub fn on_server_message(
+        mut self,
+        callback: impl FnMut(&ServerMessage) -> Action<ServerMessage> + Send + 'static,
+    ) -> Self {
+        self.on_server = Box::new(callback);
+        self
+    }
+
+    /// Connect to the server and relay messages to and from the connected
+    /// `player` until either side closes its connection or an error occurs,
+    /// both connections are then shut down.
+    pub fn serve(self, player: TcpStream) -> io::Result<()> {
+        let server = TcpStream::connect(self.upstream)?;
+        let Self {
+            mut on_client,
+            mut on_server,
+            ..
+        } = self;
+
+        let (player_rx, server_tx) = (player.try_clone()?, server.try_clone()?);
+        let upstream = spawn(move || {
+            let result = relay(&player_rx, &server_tx, client_frame_len, |frame| {
+                relay_frame(frame, ServerCodec, SlimCodec::new(), &mut on_client)
+            });
+            // Either side closing ends both directions
+            shutdown(&player_rx, &server_tx);
+            result
+        });
+
+        let result = relay(&server, &player, server_frame_len, |frame| {
+            relay_frame(frame, SlimCodec::new(), ServerCodec, &mut on_server)
+        });
+        shutdown(&player, &server);
+        let upstream = upstream
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Proxy thread panicked")));
+        result.and(upstream)
+    }
+}
+
+// A frame from the player has a four byte code then a four byte length
+fn client_frame_len(buf: &[u8]) -> Option<usize> {
+    let len = u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?) as usize;
+    Some(len + 8)
+}
+
+// A frame from the server has a two byte length
+fn server_frame_len(buf: &[u8]) -> Option<usize> {
+    let len = u16::from_be_bytes(buf.get(0..2)?.try_into().ok()?) as usize;
+    Some(len + 2)
+}
+
+fn shutdown(player: &TcpStream, server: &TcpStream) {
+    let _ = player.shutdown(Shutdown::Both);
+    let _ = server.shutdown(Shutdown::Both);
+}
+
+// Relay whole frames from `rx` to `tx` until `rx` is closed. A connection that is
+// closed or reset is the normal end of a session rather than an error.
+fn relay(
+    mut rx: &TcpStream,
+    mut tx: &TcpStream,
+    frame_len: fn(&[u8]) -> Option<usize>,
+    mut on_frame: impl FnMut(BytesMut) -> io::Result<Option<BytesMut>>,
+) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        while let Some(len) = frame_len(&buf).filter(|len| buf.len() >= *len) {
+            if let Some(frame) = on_frame(buf.split_to(len))? {
+                if let Err(e) = tx.write_all(&frame) {
+                    return closed(e);
+                }
+            }
+        }
+
+        match rx.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return closed(e),
+        }
+    }
+}
+
+fn closed(e: io::Error) -> io::Result<()> {
+    match e.kind() {
+        io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected => Ok(()),
+        _ => Err(e),
+    }
+}
+
+// Decode a whole frame for the callback and give back the bytes to send on
+fn relay_frame<M, D, E>(
+    frame: BytesMut,
+    mut decoder: D,
+    mut encoder: E,
+    callback: &mut Callback<M>,
+) -> io::Result<Option<BytesMut>>
+where
+    D: Decoder<Item = M, Error = io::Error>,
+    E: Encoder<M, Error = io::Error>,
+{
+    let msg = match decoder.decode(&mut frame.clone()) {
+        Ok(Some(msg)) => msg,
+        // Pass on what cannot be decoded, the other side may understand it
+        _ => return Ok(Some(frame)),
+    };
+
+    match callback(&msg) {
+        Action::Forward => Ok(Some(frame)),
+        Action::Replace(msg) => {
+            let mut replacement = BytesMut::new();
+            encoder.encode(msg, &mut replacement)?;
+            Ok(Some(replacement))
+        }
+        Action::Drop => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use framous::{FramedRead, FramedReader, FramedWriter};
+
+    use crate::codec::SlimFramed;
+
+    #[test]
+    fn relays_and_replaces() {
+        let lms = TcpListener::bind("127.0.0.1:0").unwrap();
+        let front = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = lms.local_addr().unwrap();
+        let front_addr = front.local_addr().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        let proxy = spawn({
+            let seen = seen.clone();
+            move || {
+                let (player, _) = front.accept().unwrap();
+                Proxy::new(upstream)
+                    .on_client_message(move |msg| {
+                        seen.lock().unwrap().push(msg.to_string());
+                        match msg {
+                            ClientMessage::Bye(_) => Action::Drop,
+                            _ => Action::Forward,
+                        }
+                    })
+                    .on_server_message(|msg| match msg {
+                        ServerMessage::Queryname => Action::Replace(ServerMessage::DisableDac),
+                        _ => Action::Forward,
+                    })
+                    .serve(player)
+            }
+        });
+
+        let mut player = SlimFramed::new(TcpStream::connect(front_addr).unwrap());
+        let (server, _) = lms.accept().unwrap();
+
+        player.framed_write(ClientMessage::Bye(1)).unwrap();
+        player.framed_write(ClientMessage::Bye(2)).unwrap();
+        player
+            .framed_write(ClientMessage::Resp("HTTP/1.0 200 OK".to_owned()))
+            .unwrap();
+        let mut from_player = FramedRead::new(server.try_clone().unwrap(), ServerCodec);
+        assert_eq!(
+            from_player.framed_read().unwrap(),
+            ClientMessage::Resp("HTTP/1.0 200 OK".to_owned())
+        );
+
+        let mut to_player = framous::FramedWrite::new(server, ServerCodec);
+        to_player.framed_write(ServerMessage::Queryname).unwrap();
+        to_player.framed_write(ServerMessage::Stop).unwrap();
+        assert_eq!(player.framed_read().unwrap(), ServerMessage::DisableDac);
+        assert_eq!(player.framed_read().unwrap(), ServerMessage::Stop);
+
+        drop(player);
+        proxy.join().unwrap().unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+}
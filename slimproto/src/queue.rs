@@ -0,0 +1,437 @@
+//! A bounded queue for messages waiting to be sent to the server.
+//!
+//! Messages are sent in order of [Priority], status messages first, then metadata
+//! and lastly stream data, so heartbeats are never held up behind large payloads.
+//! Messages of the same priority are sent in the order they were queued, `RESP`
+//! goes with the status messages so that it stays between the `STMc` and `STMh`
+//! around it.
+//!
+//! The queue holds a fixed number of bytes, if the connection stalls further
+//! metadata and stream data is refused, or waits for room with
+//! [OutgoingQueue::push_wait], rather than pushing out data already queued.
+//! Status messages are always taken. A timer status, `STMt`, takes the place of
+//! one still waiting since the server only needs the latest, no other status is
+//! dropped or replaced.
+//!
+#![cfg_attr(all(feature = "std", not(target_arch = "wasm32")), doc = "```no_run")]
+#![cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), doc = "```ignore")]
+//! use slimproto::{proto::Server, queue::OutgoingQueue, status::{StatusData, StatusReporter}};
+//! use slimproto::Capabilities;
+//! use std::net::Ipv4Addr;
+//!
+//! let (rx, tx) = Server::new(Ipv4Addr::new(192, 168, 1, 10))
+//!     .prepare(Capabilities::default())
+//!     .connect()
+//!     .unwrap();
+//!
+//! let queue = OutgoingQueue::new(64 * 1024);
+//! let writer = queue.spawn_writer(tx);
+//! let reporter = StatusReporter::new(StatusData::default(), {
+//!     let queue = queue.clone();
+//!     move |msg| {
+//!         queue.push(msg).ok();
+//!     }
+//! });
+//! ```
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::thread::{spawn, JoinHandle};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+};
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use framous::FramedWriter;
+
+use crate::ClientMessage;
+
+/// The order in which queued messages are sent, highest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Stream data, `BODY`.
+    Bulk,
+    /// Metadata, `META`.
+    Metadata,
+    /// Status and control messages such as `STAT`, `RESP`, `HELO` and `BYE!`.
+    Status,
+}
+
+impl From<&ClientMessage> for Priority {
+    fn from(msg: &ClientMessage) -> Self {
+        match msg {
+            ClientMessage::Body(_) => Priority::Bulk,
+            ClientMessage::Meta(_) => Priority::Metadata,
+            _ => Priority::Status,
+        }
+    }
+}
+
+// The bytes counted for a message with no payload, a STAT is the largest at 61
+const CONTROL_SIZE: usize = 64;
+
+// The bytes a message takes in the queue, roughly its size on the wire
+fn size(msg: &ClientMessage) -> usize {
+    match msg {
+        ClientMessage::Body(data) | ClientMessage::Meta(data) => data.len(),
+        ClientMessage::Resp(headers) => headers.len(),
+        ClientMessage::Helo { capabilities, .. } => CONTROL_SIZE + capabilities.len(),
+        _ => CONTROL_SIZE,
+    }
+}
+
+fn is_timer_status(msg: &ClientMessage) -> bool {
+    matches!(msg, ClientMessage::Stat { event_code, .. } if event_code == "STMt")
+}
+
+#[derive(Default)]
+struct QueueState {
+    // Indexed by priority, lowest first
+    queues: [VecDeque<ClientMessage>; 3],
+    bytes: usize,
+    closed: bool,
+}
+
+impl QueueState {
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    // Signalled when a message is queued
+    ready: Condvar,
+    // Signalled when a message is taken
+    space: Condvar,
+    capacity: usize,
+}
+
+/// A bounded, prioritised queue of messages for the server.
+///
+/// Clones share the same queue so that one can be given to each part of the
+/// player that sends messages.
+#[derive(Clone)]
+pub struct OutgoingQueue {
+    shared: Arc<Shared>,
+}
+
+impl OutgoingQueue {
+    /// Make a queue that holds at most `capacity` bytes of messages, status
+    /// messages are taken even when it is full. A message larger than this is
+    /// still taken when the queue is empty.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(QueueState::default()),
+                ready: Condvar::new(),
+                space: Condvar::new(),
+                capacity: capacity.max(1),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.shared.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn closed_error() -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, "Outgoing queue closed")
+    }
+
+    /// Queue a message without blocking.
+    ///
+    /// A status message is always queued, a timer status replacing one that is
+    /// still waiting to be sent. Metadata or stream data that does not fit gives
+    /// an error of kind `WouldBlock`, and any message gives `BrokenPipe` once the
+    /// queue is closed.
+    pub fn push(&self, msg: ClientMessage) -> io::Result<()> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(Self::closed_error());
+        }
+        if !self.has_room(&state, &msg) {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Outgoing queue full",
+            ));
+        }
+
+        Self::insert(&mut state, msg);
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Queue a message, waiting for room if the queue is full. An error of kind
+    /// `BrokenPipe` is returned if the queue is closed.
+    pub fn push_wait(&self, msg: ClientMessage) -> io::Result<()> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(Self::closed_error());
+            }
+            if self.has_room(&state, &msg) {
+                break;
+            }
+            state = self
+                .shared
+                .space
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+
+        Self::insert(&mut state, msg);
+        drop(state);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    fn has_room(&self, state: &QueueState, msg: &ClientMessage) -> bool {
+        Priority::from(msg) == Priority::Status
+            || state.len() == 0
+            || state.bytes + size(msg) <= self.shared.capacity
+    }
+
+    fn insert(state: &mut QueueState, msg: ClientMessage) {
+        let queue = &mut state.queues[Priority::from(&msg) as usize];
+        if is_timer_status(&msg) {
+            if let Some(waiting) = queue.iter_mut().find(|m| is_timer_status(m)) {
+                *waiting = msg;
+                return;
+            }
+        }
+        state.bytes += size(&msg);
+        queue.push_back(msg);
+    }
+
+    /// Take the next message to send, waiting for one if the queue is empty.
+    /// Returns `None` once the queue is closed.
+    pub fn pop(&self) -> Option<ClientMessage> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return None;
+            }
+            if let Some(msg) = self.take(&mut state) {
+                return Some(msg);
+            }
+            state = self
+                .shared
+                .ready
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Take the next message to send if there is one.
+    pub fn try_pop(&self) -> Option<ClientMessage> {
+        self.take(&mut self.lock())
+    }
+
+    fn take(&self, state: &mut QueueState) -> Option<ClientMessage> {
+        let msg = state
+            .queues
+            .iter_mut()
+            .rev()
+            .find_map(VecDeque::pop_front)?;
+        state.bytes -= size(&msg);
+        self.shared.space.notify_all();
+        Some(msg)
+    }
+
+    /// The number of messages waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// The number of bytes waiting to be sent.
+    pub fn bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    /// True if no messages are waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close the queue, any waiting messages are dropped and further pushes fail.
+    pub fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.queues.iter_mut().for_each(VecDeque::clear);
+        state.bytes = 0;
+        drop(state);
+        self.shared.ready.notify_all();
+        self.shared.space.notify_all();
+    }
+
+    /// Start a thread that writes the queued messages to `tx` in priority order.
+    ///
+    /// The thread ends when the queue is closed or a write fails, in which case the
+    /// queue is closed and the error returned from the thread.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn spawn_writer<W>(&self, mut tx: W) -> JoinHandle<io::Result<()>>
+    where
+        W: FramedWriter<ClientMessage> + Send + 'static,
+    {
+        let queue = self.clone();
+        spawn(move || {
+            while let Some(msg) = queue.pop() {
+                if let Err(e) = tx.framed_write(msg) {
+                    queue.close();
+                    return Err(e);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::status::{StatusCode, StatusData};
+
+    fn stat(code: StatusCode) -> ClientMessage {
+        StatusData::default().make_status_message(code)
+    }
+
+    fn event_code(msg: Option<ClientMessage>) -> String {
+        match msg {
+            Some(ClientMessage::Stat { event_code, .. }) => event_code,
+            other => panic!("Expected a STAT, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn priority() {
+        let queue = OutgoingQueue::new(1024);
+        queue.push(ClientMessage::Body(vec![0; 100])).unwrap();
+        queue.push(stat(StatusCode::Connect)).unwrap();
+        queue.push(ClientMessage::Meta(vec![1])).unwrap();
+        queue
+            .push(ClientMessage::Resp("HTTP/1.0 200 OK".to_owned()))
+            .unwrap();
+        queue.push(stat(StatusCode::HeadersReceived)).unwrap();
+        queue.push(ClientMessage::Body(vec![2])).unwrap();
+
+        assert_eq!(queue.len(), 6);
+        assert_eq!(queue.bytes(), 2 * CONTROL_SIZE + 15 + 100 + 1 + 1);
+        assert_eq!(event_code(queue.pop()), "STMc");
+        assert!(matches!(queue.pop(), Some(ClientMessage::Resp(_))));
+        assert_eq!(event_code(queue.pop()), "STMh");
+        assert_eq!(queue.pop(), Some(ClientMessage::Meta(vec![1])));
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![0; 100])));
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![2])));
+        assert!(queue.try_pop().is_none());
+        assert_eq!(queue.bytes(), 0);
+    }
+
+    #[test]
+    fn timer_status_replaced() {
+        let queue = OutgoingQueue::new(1024);
+        queue.push(stat(StatusCode::Timer)).unwrap();
+        queue.push(ClientMessage::Body(vec![1])).unwrap();
+
+        let mut latest = StatusData::default();
+        latest.set_bytes_received(10);
+        queue
+            .push(latest.make_status_message(StatusCode::Timer))
+            .unwrap();
+
+        assert_eq!(queue.len(), 2);
+        match queue.pop() {
+            Some(ClientMessage::Stat { stat_data, .. }) => {
+                assert_eq!(stat_data.get_bytes_received(), 10)
+            }
+            other => panic!("Expected a STAT, got {:?}", other),
+        }
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![1])));
+    }
+
+    #[test]
+    fn bounded() {
+        let queue = OutgoingQueue::new(100);
+        queue.push(ClientMessage::Body(vec![1; 60])).unwrap();
+        assert_eq!(
+            queue
+                .push(ClientMessage::Body(vec![2; 60]))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            queue
+                .push(ClientMessage::Meta(vec![2; 60]))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Status messages are never refused and nothing queued is pushed out
+        queue.push(stat(StatusCode::Flushed)).unwrap();
+        queue.push(stat(StatusCode::DecoderReady)).unwrap();
+        assert_eq!(event_code(queue.pop()), "STMf");
+        assert_eq!(event_code(queue.pop()), "STMd");
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![1; 60])));
+
+        // A message larger than the queue is taken when it is empty
+        queue.push(ClientMessage::Body(vec![3; 200])).unwrap();
+        assert_eq!(queue.bytes(), 200);
+
+        queue.close();
+        assert!(queue.pop().is_none());
+        assert_eq!(
+            queue.push(stat(StatusCode::Timer)).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn push_wait() {
+        let queue = OutgoingQueue::new(100);
+        queue.push(ClientMessage::Body(vec![1; 60])).unwrap();
+
+        let pusher = std::thread::spawn({
+            let queue = queue.clone();
+            move || queue.push_wait(ClientMessage::Body(vec![2; 60]))
+        });
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!pusher.is_finished());
+
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![1; 60])));
+        pusher.join().unwrap().unwrap();
+        assert_eq!(queue.pop(), Some(ClientMessage::Body(vec![2; 60])));
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn writer() {
+        use framous::FramedWriter;
+
+        struct Sent(Arc<Mutex<Vec<ClientMessage>>>);
+
+        impl FramedWriter<ClientMessage> for Sent {
+            fn framed_write(&mut self, item: ClientMessage) -> io::Result<()> {
+                self.0.lock().unwrap().push(item);
+                Ok(())
+            }
+        }
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let queue = OutgoingQueue::new(1024);
+        let writer = queue.spawn_writer(Sent(sent.clone()));
+
+        queue.push(ClientMessage::Bye(0)).unwrap();
+        while !queue.is_empty() {
+            std::thread::yield_now();
+        }
+        queue.close();
+        writer.join().unwrap().unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![ClientMessage::Bye(0)]);
+    }
+}
@@ -0,0 +1,501 @@
+//! A [Player] that plays through a Rodio `Sink`.
+//!
+//! The sink is made by the caller so that the output device can be chosen,
+//! which needs Rodio's `playback` feature, and the decoders that Rodio is built
+//! with decide the formats that can be played. Each stream is wrapped in a
+//! [SlimBuffer] so that the status data is kept up to date.
+//!
+//! PCM streams whose format is given in the stream message have no header, so
+//! they are played by this module rather than by a Rodio decoder.
+//!
+//! ```ignore
+//! let output = rodio::OutputStreamBuilder::open_default_stream()?;
+//! let sink = rodio::Sink::connect_new(output.mixer());
+//! let player = RodioPlayer::new(sink, status.clone());
+//! let mut driver = PlayerDriver::new(player, server, reporter);
+//! ```
+
+use std::{
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use rodio::{Decoder, Sink, Source};
+
+use crate::{
+    buffer::SlimBuffer,
+    gain::Volume,
+    player::{OutputFormat, Player},
+    proto::{AutoStart, Format, OutputEnable, PauseKind, PcmEndian},
+    status::SharedStatus,
+    stream::{StreamConnection, StreamParams},
+    sync::SyncScheduler,
+};
+
+pub struct RodioPlayer {
+    sink: Arc<Sink>,
+    status: SharedStatus,
+    scheduler: SyncScheduler,
+    gains: ChannelGains,
+    // Paused because the server turned the DAC off
+    dac_off: bool,
+}
+
+impl RodioPlayer {
+    pub fn new(sink: Sink, status: impl Into<SharedStatus>) -> Self {
+        let status = status.into();
+        Self {
+            sink: Arc::new(sink),
+            scheduler: SyncScheduler::new(status.clone()),
+            status,
+            gains: ChannelGains::default(),
+            dac_off: false,
+        }
+    }
+
+    pub fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    fn play(&self) {
+        self.sink.play();
+        let mut status = self.status.lock();
+        match status.track_elapsed() {
+            Some(_) => status.resume(),
+            None => status.start_track(),
+        }
+    }
+
+    fn pause(&self) {
+        self.sink.pause();
+        self.status.lock().pause();
+    }
+}
+
+/// The file extension that hints at the format of the stream.
+fn format_hint(format: Format) -> &'static str {
+    match format {
+        Format::Pcm => "wav",
+        Format::Mp3 => "mp3",
+        Format::Flac => "flac",
+        Format::Wma => "wma",
+        Format::Ogg => "ogg",
+        Format::Aac => "aac",
+        Format::Alac => "m4a",
+    }
+}
+
+// The gains of the left and right channels as the bits of an f32, shared with
+// the sources on the sink so that a change is heard straight away
+#[derive(Clone)]
+struct ChannelGains(Arc<[AtomicU32; 2]>);
+
+impl Default for ChannelGains {
+    fn default() -> Self {
+        let unity = 1.0f32.to_bits();
+        Self(Arc::new([AtomicU32::new(unity), AtomicU32::new(unity)]))
+    }
+}
+
+impl ChannelGains {
+    fn set(&self, left: f32, right: f32) {
+        self.0[0].store(left.to_bits(), Ordering::Relaxed);
+        self.0[1].store(right.to_bits(), Ordering::Relaxed);
+    }
+
+    fn get(&self, channel: usize) -> f32 {
+        f32::from_bits(self.0[channel].load(Ordering::Relaxed))
+    }
+}
+
+// Applies the gain of each channel to a source. A mono source, or a channel
+// after the first two, gets the mean of the two gains.
+struct WithGains<S> {
+    source: S,
+    gains: ChannelGains,
+    channel: u16,
+}
+
+impl<S: Source> Iterator for WithGains<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next()?;
+        let channels = self.source.channels().max(1);
+        let gain = match (channels, self.channel) {
+            (1, _) | (_, 2..) => (self.gains.get(0) + self.gains.get(1)) / 2.0,
+            (_, channel) => self.gains.get(channel as usize),
+        };
+        self.channel = (self.channel + 1) % channels;
+        Some(sample * gain)
+    }
+}
+
+impl<S: Source> Source for WithGains<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        self.source.current_span_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+// Headerless PCM in the format given by the fields of the stream message.
+// Eight bit samples are unsigned, as in WAV files.
+struct PcmSource<R> {
+    reader: R,
+    format: OutputFormat,
+    big_endian: bool,
+}
+
+// The format of a PCM stream and whether it is big endian, `None` when the
+// stream has a header that describes it
+fn pcm_format(params: &StreamParams) -> Option<(OutputFormat, bool)> {
+    if !matches!(params.format, Format::Pcm) {
+        return None;
+    }
+    let format = OutputFormat::from_pcm(
+        params.pcmsamplesize,
+        params.pcmsamplerate,
+        params.pcmchannels,
+    )?;
+    let big_endian = match params.pcmendian {
+        PcmEndian::Big => true,
+        PcmEndian::Little => false,
+        PcmEndian::SelfDescribing if format.bytes_per_sample == 1 => false,
+        PcmEndian::SelfDescribing => return None,
+    };
+    Some((format, big_endian))
+}
+
+impl<R: Read> Iterator for PcmSource<R> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let size = self.format.bytes_per_sample as usize;
+        let mut bytes = [0u8; 4];
+        self.reader.read_exact(&mut bytes[..size]).ok()?;
+        if size == 1 {
+            bytes[0] ^= 0x80;
+        } else if !self.big_endian {
+            bytes[..size].reverse();
+        }
+        // The sample is now in the top bytes of a big endian i32
+        Some(i32::from_be_bytes(bytes) as f32 / -(i32::MIN as f32))
+    }
+}
+
+impl<R: Read> Source for PcmSource<R> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Player for RodioPlayer {
+    fn start_stream(&mut self, params: StreamParams, stream: StreamConnection) -> io::Result<()> {
+        self.sink.clear();
+        self.status.lock().stop_track();
+
+        let buffer = SlimBuffer::new(stream, self.status.clone(), params.threshold, None)
+            .with_flags(params.flags);
+        let source: Box<dyn Source + Send> = match pcm_format(&params) {
+            Some((format, big_endian)) => Box::new(PcmSource {
+                reader: buffer,
+                format,
+                big_endian,
+            }),
+            None => Box::new(
+                Decoder::builder()
+                    .with_data(buffer)
+                    .with_hint(format_hint(params.format))
+                    .build()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            ),
+        };
+        self.sink.append(WithGains {
+            source,
+            gains: self.gains.clone(),
+            channel: 0,
+        });
+
+        match params.autostart {
+            AutoStart::Auto | AutoStart::AutoDirect => self.play(),
+            AutoStart::None | AutoStart::Direct => {}
+        }
+        Ok(())
+    }
+
+    fn pause_at(&mut self, when: PauseKind) {
+        match when {
+            PauseKind::Now => self.pause(),
+            PauseKind::At(at) => {
+                let sink = self.sink.clone();
+                let status = self.status.clone();
+                self.scheduler.schedule(at, move || {
+                    sink.pause();
+                    status.lock().pause();
+                });
+            }
+            PauseKind::For(interval) => {
+                self.pause();
+                self.unpause_at(PauseKind::For(interval));
+            }
+        }
+    }
+
+    fn unpause_at(&mut self, when: PauseKind) {
+        match when {
+            PauseKind::Now => self.play(),
+            PauseKind::At(at) => {
+                let sink = self.sink.clone();
+                let status = self.status.clone();
+                self.scheduler.schedule(at, move || {
+                    sink.play();
+                    status.lock().resume();
+                });
+            }
+            PauseKind::For(interval) => {
+                let sink = self.sink.clone();
+                let status = self.status.clone();
+                self.scheduler.schedule_after(interval, move || {
+                    sink.play();
+                    status.lock().resume();
+                });
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.sink.clear();
+    }
+
+    fn flush(&mut self) {
+        self.sink.clear();
+    }
+
+    fn set_gain(&mut self, volume: Volume) {
+        self.gains
+            .set(volume.left_factor() as f32, volume.right_factor() as f32);
+    }
+
+    fn enable_output(&mut self, outputs: OutputEnable) {
+        if !outputs.dac && !self.sink.is_paused() {
+            self.dac_off = true;
+            self.pause();
+        } else if outputs.dac && self.dac_off {
+            self.dac_off = false;
+            self.play();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener},
+        thread::spawn,
+        time::Duration,
+    };
+
+    use crate::{
+        gain::Gain,
+        proto::{
+            PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize, Server, StreamFlags, Threshold,
+            TransType,
+        },
+        status::StatusData,
+    };
+
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let data_len = samples.len() as u32 * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        samples
+            .iter()
+            .for_each(|s| wav.extend_from_slice(&s.to_le_bytes()));
+        wav
+    }
+
+    fn params(port: u16) -> StreamParams {
+        StreamParams {
+            autostart: AutoStart::Auto,
+            format: Format::Pcm,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::ZERO,
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::LOCALHOST,
+            server_port: port,
+            http_request: Some("GET /a.wav HTTP/1.0\r\n\r\n".parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn pcm() {
+        let mut params = params(0);
+        assert!(pcm_format(&params).is_none());
+
+        params.pcmsamplesize = PcmSampleSize::Sixteen;
+        params.pcmsamplerate = PcmSampleRate::Rate(8000);
+        params.pcmchannels = PcmChannels::Stereo;
+        params.pcmendian = PcmEndian::Little;
+        let (format, big_endian) = pcm_format(&params).unwrap();
+        let source = PcmSource {
+            reader: &[0x00, 0x40, 0x00, 0xc0, 0x00][..],
+            format,
+            big_endian,
+        };
+        assert_eq!((source.channels(), source.sample_rate()), (2, 8000));
+        assert_eq!(source.collect::<Vec<_>>(), vec![0.5, -0.5]);
+
+        params.pcmsamplesize = PcmSampleSize::Twenty;
+        params.pcmendian = PcmEndian::Big;
+        let (format, big_endian) = pcm_format(&params).unwrap();
+        let source = PcmSource {
+            reader: &[0xc0, 0x00, 0x00][..],
+            format,
+            big_endian,
+        };
+        assert_eq!(source.collect::<Vec<_>>(), vec![-0.5]);
+
+        params.pcmsamplesize = PcmSampleSize::Eight;
+        params.pcmendian = PcmEndian::SelfDescribing;
+        let (format, big_endian) = pcm_format(&params).unwrap();
+        let source = PcmSource {
+            reader: &[0x80, 0xc0][..],
+            format,
+            big_endian,
+        };
+        assert_eq!(source.collect::<Vec<_>>(), vec![0.0, 0.5]);
+    }
+
+    #[test]
+    fn gains() {
+        let gains = ChannelGains::default();
+        gains.set(0.5, 0.25);
+        let stereo = WithGains {
+            source: rodio::buffer::SamplesBuffer::new(2, 8000, vec![1.0; 4]),
+            gains: gains.clone(),
+            channel: 0,
+        };
+        assert_eq!(stereo.collect::<Vec<_>>(), vec![0.5, 0.25, 0.5, 0.25]);
+
+        let mono = WithGains {
+            source: rodio::buffer::SamplesBuffer::new(1, 8000, vec![1.0; 2]),
+            gains,
+            channel: 0,
+        };
+        assert_eq!(mono.collect::<Vec<_>>(), vec![0.375, 0.375]);
+    }
+
+    #[test]
+    fn play_raw_pcm() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            cx.write_all(&[0, 0, 100, 0, 156, 255, 0, 0]).unwrap();
+        });
+
+        let params = StreamParams {
+            pcmsamplesize: PcmSampleSize::Sixteen,
+            pcmsamplerate: PcmSampleRate::Rate(8000),
+            pcmchannels: PcmChannels::Mono,
+            pcmendian: PcmEndian::Little,
+            http_request: Some("GET /a.pcm HTTP/1.0\r\n\r\n".parse().unwrap()),
+            ..params(port)
+        };
+        let stream = StreamConnection::open(&params, &Server::new(Ipv4Addr::LOCALHOST)).unwrap();
+
+        let (sink, _output) = Sink::new();
+        let mut player = RodioPlayer::new(sink, StatusData::default());
+        player.start_stream(params, stream).unwrap();
+        assert_eq!(player.sink().len(), 1);
+
+        player.enable_output(OutputEnable {
+            spdif: false,
+            dac: false,
+        });
+        assert!(player.sink().is_paused());
+        player.enable_output(OutputEnable {
+            spdif: false,
+            dac: true,
+        });
+        assert!(!player.sink().is_paused());
+    }
+
+    #[test]
+    fn play_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+            cx.write_all(&wav(&[0, 100, -100, 0])).unwrap();
+        });
+
+        let params = params(port);
+        let stream = StreamConnection::open(&params, &Server::new(Ipv4Addr::LOCALHOST)).unwrap();
+
+        let (sink, _output) = Sink::new();
+        let status = SharedStatus::from(StatusData::default());
+        let mut player = RodioPlayer::new(sink, status.clone());
+        player.start_stream(params, stream).unwrap();
+
+        assert_eq!(player.sink().len(), 1);
+        assert!(!player.sink().is_paused());
+        assert!(status.lock().track_elapsed().is_some());
+
+        player.pause_at(PauseKind::Now);
+        assert!(player.sink().is_paused());
+    }
+}
@@ -0,0 +1,93 @@
+//! Providers of the voltage and wireless signal strength reported to the server.
+//!
+//! Battery powered and wireless players, like the Squeezebox Radio, report their
+//! supply voltage and wireless signal strength in every status message. Install a
+//! [Sensors] provider on a [StatusReporter](crate::status::StatusReporter) to have
+//! these filled in before each status message is sent.
+
+/// A source of device sensor readings.
+///
+/// Return `None` from a method to leave the corresponding value in the status
+/// data unchanged.
+pub trait Sensors {
+    /// The supply voltage in millivolts
+    fn voltage(&self) -> Option<u16> {
+        None
+    }
+
+    /// The wireless signal strength as a percentage
+    fn signal_strength(&self) -> Option<u16> {
+        None
+    }
+}
+
+/// Reads the sensors of a Linux host from sysfs and procfs.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Debug)]
+pub struct LinuxSensors {
+    interface: Option<String>,
+    battery: Option<std::path::PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl LinuxSensors {
+    /// Read the signal strength of the named wireless interface, e.g. `wlan0`,
+    /// from `/proc/net/wireless` and the voltage of the named power supply, e.g. `BAT0`,
+    /// from `/sys/class/power_supply`.
+    pub fn new(interface: Option<&str>, battery: Option<&str>) -> Self {
+        Self {
+            interface: interface.map(str::to_owned),
+            battery: battery.map(|name| std::path::Path::new("/sys/class/power_supply").join(name)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Sensors for LinuxSensors {
+    fn voltage(&self) -> Option<u16> {
+        let path = self.battery.as_ref()?.join("voltage_now");
+        let microvolts: u64 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+        u16::try_from(microvolts / 1000).ok()
+    }
+
+    fn signal_strength(&self) -> Option<u16> {
+        let wireless = std::fs::read_to_string("/proc/net/wireless").ok()?;
+        link_quality(&wireless, self.interface.as_ref()?)
+    }
+}
+
+/// Find the link quality of an interface in the contents of `/proc/net/wireless`.
+/// The quality is out of 70, as used by most drivers, and is scaled to a percentage.
+#[cfg(target_os = "linux")]
+fn link_quality(wireless: &str, interface: &str) -> Option<u16> {
+    const MAX_QUALITY: f32 = 70.0;
+
+    wireless.lines().find_map(|line| {
+        let (name, values) = line.trim().split_once(':')?;
+        if name != interface {
+            return None;
+        }
+        let quality: f32 = values
+            .split_whitespace()
+            .nth(1)?
+            .trim_end_matches('.')
+            .parse()
+            .ok()?;
+        Some((quality / MAX_QUALITY * 100.0).clamp(0.0, 100.0) as u16)
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wireless() {
+        let wireless =
+            "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE
+ face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22
+ wlan0: 0000   56.  -54.  -256        0      0      0      0     12        0";
+        assert_eq!(link_quality(wireless, "wlan0"), Some(80));
+        assert_eq!(link_quality(wireless, "wlan1"), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Keeping the player's settings across restarts.
+//!
+//! Hardware players keep their name, volume and sync group in NVRAM so that they
+//! come back as they were after a power cut. A [Settings] store does the same for
+//! a software player, [FileSettings] keeps them in a file. Give a store to the
+//! [PlayerDriver](crate::player::PlayerDriver::with_settings) to save the settings
+//! as the server changes them and read them back when the player starts.
+//!
+//! ```no_run
+//! use slimproto::settings::{FileSettings, Settings};
+//!
+//! let settings = FileSettings::open("player.conf").unwrap();
+//! let name = settings.name();
+//! let volume = settings.volume();
+//! ```
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::thread::{sleep, spawn};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    gain::{Gain, Volume},
+    proto::{PlayerName, SyncGroup},
+};
+
+pub const NAME: &str = "name";
+pub const VOLUME: &str = "volume";
+pub const SYNC_GROUP: &str = "sync_group";
+
+/// A key-value store for the player's settings.
+pub trait Settings {
+    fn load(&self, key: &str) -> Option<String>;
+
+    fn save(&self, key: &str, value: &str) -> io::Result<()>;
+
+    fn name(&self) -> Option<PlayerName> {
+        self.load(NAME).and_then(|name| PlayerName::new(name).ok())
+    }
+
+    /// The left and right gains of the last volume set by the server.
+    fn volume(&self) -> Option<Volume> {
+        let value = self.load(VOLUME)?;
+        let (left, right) = value.split_once(' ')?;
+        Some(Volume::new(
+            Gain::from_fixed(left.parse().ok()?),
+            Gain::from_fixed(right.parse().ok()?),
+        ))
+    }
+
+    fn sync_group(&self) -> Option<SyncGroup> {
+        self.load(SYNC_GROUP).map(SyncGroup::from)
+    }
+
+    fn save_name(&self, name: &PlayerName) -> io::Result<()> {
+        self.save(NAME, name.as_str())
+    }
+
+    /// Save the left and right gains, as fixed point so that they are read back
+    /// exactly.
+    fn save_volume(&self, volume: &Volume) -> io::Result<()> {
+        self.save(
+            VOLUME,
+            &format!("{} {}", volume.left.as_fixed(), volume.right.as_fixed()),
+        )
+    }
+
+    fn save_sync_group(&self, sync_group: &SyncGroup) -> io::Result<()> {
+        self.save(SYNC_GROUP, sync_group.as_str())
+    }
+}
+
+/// Settings kept in a file of `key=value` lines.
+///
+/// The whole file is written again, through a temporary file so that a crash
+/// while saving leaves the old settings in place. The server can send a volume
+/// many times a second as a knob is turned, so the file is written in the
+/// background a short [delay](FileSettings::with_delay) after a change rather
+/// than on each save. Any change still waiting is written when the settings are
+/// dropped.
+pub struct FileSettings {
+    shared: Arc<FileShared>,
+    #[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), allow(dead_code))]
+    delay: Duration,
+}
+
+struct FileShared {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+#[derive(Default)]
+struct FileState {
+    values: BTreeMap<String, String>,
+    dirty: bool,
+    #[cfg_attr(any(not(feature = "std"), target_arch = "wasm32"), allow(dead_code))]
+    writing: bool,
+}
+
+impl FileShared {
+    fn lock(&self) -> MutexGuard<'_, FileState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Write the settings if they have changed since they were last written
+    fn write(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        if !state.dirty {
+            return Ok(());
+        }
+        state.dirty = false;
+        let text: String = state
+            .values
+            .iter()
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl FileSettings {
+    /// The time after a change that the file is written by default.
+    pub const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+
+    /// Read the settings from `path`, a file that does not exist yet has no settings.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let values = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            shared: Arc::new(FileShared {
+                path,
+                state: Mutex::new(FileState {
+                    values,
+                    ..Default::default()
+                }),
+            }),
+            delay: Self::DEFAULT_DELAY,
+        })
+    }
+
+    /// Write the file this long after a change, changes made in the meantime are
+    /// written with it. A zero delay writes the file on each save, as is always
+    /// done on `wasm32`.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Write any changes that are waiting now.
+    pub fn flush(&self) -> io::Result<()> {
+        self.shared.write()
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    fn write_later(&self, mut state: MutexGuard<'_, FileState>) -> io::Result<()> {
+        if self.delay.is_zero() {
+            drop(state);
+            return self.shared.write();
+        }
+        if !state.writing {
+            state.writing = true;
+            let shared = self.shared.clone();
+            let delay = self.delay;
+            spawn(move || {
+                sleep(delay);
+                shared.lock().writing = false;
+                if let Err(_e) = shared.write() {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(error = %_e, "failed to write settings");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+    fn write_later(&self, state: MutexGuard<'_, FileState>) -> io::Result<()> {
+        drop(state);
+        self.shared.write()
+    }
+}
+
+impl Drop for FileSettings {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Settings for FileSettings {
+    fn load(&self, key: &str) -> Option<String> {
+        self.shared.lock().values.get(key).cloned()
+    }
+
+    fn save(&self, key: &str, value: &str) -> io::Result<()> {
+        if key.contains(['=', '\n']) || value.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Setting cannot be written as a line",
+            ));
+        }
+
+        let mut state = self.shared.lock();
+        if state.values.get(key).map(String::as_str) == Some(value) {
+            return Ok(());
+        }
+        state.values.insert(key.to_owned(), value.to_owned());
+        state.dirty = true;
+        self.write_later(state)
+    }
+}
+
+// The settings file is not written on wasm32
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_settings() {
+        let path = std::env::temp_dir().join(format!("slimproto-settings-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let settings = FileSettings::open(&path).unwrap();
+        assert!(settings.name().is_none());
+        settings
+            .save_name(&PlayerName::new("Kitchen").unwrap())
+            .unwrap();
+        let volume = Volume::new(Gain::from_fixed(0x8000), Gain::UNITY);
+        settings.save_volume(&volume).unwrap();
+        settings.save_sync_group(&SyncGroup::new("a=b")).unwrap();
+        assert!(settings.save("bad=key", "1").is_err());
+
+        drop(settings);
+
+        let settings = FileSettings::open(&path).unwrap();
+        assert_eq!(settings.name().unwrap(), "Kitchen");
+        assert_eq!(settings.volume(), Some(volume));
+        assert_eq!(settings.sync_group(), Some(SyncGroup::new("a=b")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delayed_write() {
+        let path = std::env::temp_dir().join(format!("slimproto-delayed-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let settings = FileSettings::open(&path)
+            .unwrap()
+            .with_delay(Duration::from_millis(50));
+        for left in 1..10 {
+            let volume = Volume::new(Gain::from_fixed(left), Gain::UNITY);
+            settings.save_volume(&volume).unwrap();
+        }
+        assert!(!path.exists());
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "volume=9 65536\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,41 +1,193 @@
-/// A convenience module for working with client status data.
-///
-/// The Logitech Media Server requires regular status messages from
-/// the client. This module provides convenience types for this.
-use std::{
+//! A convenience module for working with client status data.
+//!
+//! The Logitech Media Server requires regular status messages from
+//! the client. This module provides convenience types for this.
+//!
+//! The status data also keeps a [Latency] estimate of the round trip to the
+//! server and the offset of the server's clock, from the times at which status
+//! messages are sent and `strm t` messages arrive.
+
+use alloc::{string::ToString, sync::Arc};
+use core::{
     fmt,
-    time::{Duration, Instant},
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use std::thread::{sleep, spawn};
+#[cfg(feature = "std")]
+use std::{
+    sync::{atomic::AtomicBool, Mutex, MutexGuard},
+    time::Instant,
 };
 
-use crate::ClientMessage;
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+use crate::jiffies::ManualClock;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use crate::jiffies::SystemClock;
+#[cfg(feature = "std")]
+use crate::sensors::Sensors;
+use crate::{
+    jiffies::{Clock, Jiffies},
+    ClientMessage,
+};
 
 /// A struct to hold the status data as required by the server
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StatusData {
     pub(crate) crlf: u8,
     pub(crate) buffer_size: u32,
     pub(crate) fullness: u32,
     pub(crate) bytes_received: u64,
     pub(crate) sig_strength: u16,
-    pub(crate) jiffies: Duration,
+    pub(crate) jiffies: Jiffies,
     pub(crate) output_buffer_size: u32,
     pub(crate) output_buffer_fullness: u32,
     pub(crate) elapsed_seconds: u32,
     pub(crate) voltage: u16,
     pub(crate) elapsed_milliseconds: u32,
-    pub(crate) timestamp: Duration,
+    pub(crate) timestamp: Jiffies,
     pub(crate) error_code: u16,
     // -- Items below are not sent
-    pub(crate) start: Instant,
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_clock"))]
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) track_elapsed: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) playing_since: Option<Jiffies>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) output_level: Option<OutputBufferLevel>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) latency: LatencyEstimator,
+}
+
+/// The round trip time to the server and the offset of its clock from the
+/// player's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Latency {
+    /// The shortest time seen from sending a status message to receiving the
+    /// `strm t` that follows it.
+    pub rtt: Duration,
+    /// The server's clock less the player's jiffies, in milliseconds, taken when
+    /// the shortest round trip was seen.
+    pub skew: i64,
+    /// The number of round trips measured.
+    pub samples: u32,
+}
+
+impl Latency {
+    /// The one way network latency, half the round trip time.
+    pub fn one_way(&self) -> Duration {
+        self.rtt / 2
+    }
+}
+
+/// Pairs each status message sent with the next `strm t` from the server.
+///
+/// The server's timestamp in the `strm t` is taken at the middle of the round
+/// trip. A round trip can be stretched by the server waiting before it asks
+/// for the status again, so only the shortest is kept, as NTP does.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LatencyEstimator {
+    sent: Option<Jiffies>,
+    best: Option<Latency>,
+}
+
+impl LatencyEstimator {
+    pub(crate) fn stat_sent(&mut self, at: Jiffies) {
+        self.sent = Some(at);
+    }
+
+    pub(crate) fn strm_t_received(&mut self, at: Jiffies, server_timestamp: Jiffies) {
+        let Some(sent) = self.sent.take() else {
+            return;
+        };
+        let rtt = at.duration_since(sent);
+        let midpoint = sent + rtt / 2;
+        let skew = server_timestamp
+            .as_millis()
+            .wrapping_sub(midpoint.as_millis()) as i32 as i64;
+        let samples = self.best.map_or(0, |best| best.samples) + 1;
+
+        self.best = match self.best {
+            Some(best) if best.rtt <= rtt => Some(Latency { samples, ..best }),
+            _ => Some(Latency { rtt, skew, samples }),
+        };
+    }
+
+    pub(crate) fn latency(&self) -> Option<Latency> {
+        self.best
+    }
+}
+
+/// The size and fullness of the audio output's buffer.
+///
+/// The audio output updates these as it plays and the values are copied into
+/// the status data each time a status message is made. Cloning gives another
+/// handle to the same values.
+#[derive(Clone, Debug, Default)]
+pub struct OutputBufferLevel {
+    size: Arc<AtomicU32>,
+    fullness: Arc<AtomicU32>,
+}
+
+impl OutputBufferLevel {
+    pub fn set_size(&self, size: u32) {
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn set_fullness(&self, fullness: u32) {
+        self.fullness.store(fullness, Ordering::Relaxed);
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub fn fullness(&self) -> u32 {
+        self.fullness.load(Ordering::Relaxed)
+    }
 }
 
 impl StatusData {
-    // pub fn new(buffer_size: u32, output_buffer_size: u32) -> Self {
-    //     let mut stat = StatusData::default();
-    //     stat.buffer_size = buffer_size;
-    //     stat.output_buffer_size = output_buffer_size;
-    //     stat
-    // }
+    pub fn new(buffer_size: u32, output_buffer_size: u32) -> Self {
+        Self {
+            buffer_size,
+            output_buffer_size,
+            ..Default::default()
+        }
+    }
+
+    /// Make status data that take the jiffies sent to the server, and the elapsed
+    /// time of the track, from `clock` rather than from the time that the status
+    /// data were made. The system clock is never read, which is needed on `wasm32`
+    /// where the jiffies otherwise stay at zero.
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        Self::from_clock(Arc::new(clock))
+    }
+
+    fn from_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            crlf: 0,
+            buffer_size: 0,
+            fullness: 0,
+            bytes_received: 0,
+            sig_strength: 0,
+            jiffies: Jiffies::ZERO,
+            output_buffer_size: 0,
+            output_buffer_fullness: 0,
+            elapsed_seconds: 0,
+            voltage: 0,
+            elapsed_milliseconds: 0,
+            timestamp: Jiffies::ZERO,
+            error_code: 0,
+            clock,
+            track_elapsed: None,
+            playing_since: None,
+            output_level: None,
+            latency: LatencyEstimator::default(),
+        }
+    }
 
     pub fn add_crlf(&mut self, num_crlf: u8) {
         self.crlf = self.crlf.wrapping_add(num_crlf);
@@ -49,7 +201,13 @@ impl StatusData {
         self.bytes_received = self.bytes_received.wrapping_add(bytes_received);
     }
 
-    pub fn set_jiffies(&mut self, jiffies: Duration) {
+    /// Set the count of bytes received outright, for players that read the
+    /// stream themselves rather than through a [SlimBuffer](crate::buffer::SlimBuffer).
+    pub fn set_bytes_received(&mut self, bytes_received: u64) {
+        self.bytes_received = bytes_received;
+    }
+
+    pub fn set_jiffies(&mut self, jiffies: Jiffies) {
         self.jiffies = jiffies;
     }
 
@@ -73,22 +231,160 @@ impl StatusData {
         self.buffer_size = size;
     }
 
-    pub fn set_timestamp(&mut self, timestamp: Duration) {
+    pub fn set_timestamp(&mut self, timestamp: Jiffies) {
         self.timestamp = timestamp;
     }
 
-    // pub fn set_error_code<'a>(&'a mut self, error_code: u16) -> &'a mut Self {
-    //     self.error_code = error_code;
-    //     self
-    // }
+    pub fn set_sig_strength(&mut self, sig_strength: u16) {
+        self.sig_strength = sig_strength;
+    }
 
-    pub fn get_jiffies(&self) -> Duration {
+    pub fn set_voltage(&mut self, voltage: u16) {
+        self.voltage = voltage;
+    }
+
+    pub fn set_error_code(&mut self, error_code: u16) {
+        self.error_code = error_code;
+    }
+
+    pub fn get_crlf(&self) -> u8 {
+        self.crlf
+    }
+
+    pub fn get_buffer_size(&self) -> u32 {
+        self.buffer_size
+    }
+
+    pub fn get_fullness(&self) -> u32 {
+        self.fullness
+    }
+
+    pub fn get_bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn get_sig_strength(&self) -> u16 {
+        self.sig_strength
+    }
+
+    pub fn get_jiffies(&self) -> Jiffies {
         self.jiffies
     }
 
+    pub fn get_output_buffer_size(&self) -> u32 {
+        self.output_buffer_size
+    }
+
+    pub fn get_output_buffer_fullness(&self) -> u32 {
+        self.output_buffer_fullness
+    }
+
+    pub fn get_elapsed_seconds(&self) -> u32 {
+        self.elapsed_seconds
+    }
+
+    pub fn get_voltage(&self) -> u16 {
+        self.voltage
+    }
+
+    pub fn get_elapsed_milli_seconds(&self) -> u32 {
+        self.elapsed_milliseconds
+    }
+
+    pub fn get_timestamp(&self) -> Jiffies {
+        self.timestamp
+    }
+
+    pub fn get_error_code(&self) -> u16 {
+        self.error_code
+    }
+
+    /// The current time on the player's jiffies clock, as sent in status messages.
+    pub fn jiffies_now(&self) -> Jiffies {
+        self.clock.now()
+    }
+
+    /// Start tracking the elapsed time of a new track.
+    ///
+    /// Once a track is started the elapsed seconds and milliseconds are
+    /// calculated for you when a status message is made, taking account of any
+    /// time spent paused.
+    pub fn start_track(&mut self) {
+        self.track_elapsed = Some(Duration::ZERO);
+        self.playing_since = Some(self.clock.now());
+    }
+
+    /// Stop the elapsed time of the track from advancing.
+    pub fn pause(&mut self) {
+        if let (Some(elapsed), Some(since)) = (self.track_elapsed, self.playing_since.take()) {
+            self.track_elapsed = Some(elapsed + self.clock.now().duration_since(since));
+        }
+    }
+
+    /// Allow the elapsed time of the track to advance again after a pause.
+    pub fn resume(&mut self) {
+        if self.track_elapsed.is_some() && self.playing_since.is_none() {
+            self.playing_since = Some(self.clock.now());
+        }
+    }
+
+    /// Stop tracking the elapsed time of the track.
+    pub fn stop_track(&mut self) {
+        self.track_elapsed = None;
+        self.playing_since = None;
+    }
+
+    /// The elapsed time of the current track, if one has been started
+    /// with [start_track](StatusData::start_track).
+    pub fn track_elapsed(&self) -> Option<Duration> {
+        self.track_elapsed.map(|elapsed| {
+            elapsed
+                + self
+                    .playing_since
+                    .map(|since| self.clock.now().duration_since(since))
+                    .unwrap_or_default()
+        })
+    }
+
+    /// Take the output buffer size and fullness from the returned handle whenever
+    /// a status message is made. The handle is given to the audio output so that it
+    /// can keep the values up to date.
+    pub fn track_output_buffer(&mut self) -> OutputBufferLevel {
+        self.output_level
+            .get_or_insert_with(|| {
+                let level = OutputBufferLevel::default();
+                level.set_size(self.output_buffer_size);
+                level.set_fullness(self.output_buffer_fullness);
+                level
+            })
+            .clone()
+    }
+
+    /// Note the arrival of a `strm t` carrying the server's `timestamp`, to be
+    /// paired with the last status message sent for the [latency](StatusData::latency).
+    pub fn server_timestamp_received(&mut self, timestamp: Jiffies) {
+        let now = self.jiffies_now();
+        self.latency.strm_t_received(now, timestamp);
+    }
+
+    /// The estimated latency to the server, once a status message has been
+    /// followed by a `strm t`.
+    pub fn latency(&self) -> Option<Latency> {
+        self.latency.latency()
+    }
+
     /// Create a status message for sending to the server
     pub fn make_status_message(&mut self, msgtype: StatusCode) -> ClientMessage {
-        self.set_jiffies(Instant::now() - self.start);
+        self.set_jiffies(self.jiffies_now());
+        self.latency.stat_sent(self.jiffies);
+        if let Some(level) = &self.output_level {
+            self.output_buffer_size = level.size();
+            self.output_buffer_fullness = level.fullness();
+        }
+        if let Some(elapsed) = self.track_elapsed() {
+            self.set_elapsed_seconds(elapsed.as_secs() as u32);
+            self.set_elapsed_milli_seconds(elapsed.as_millis() as u32);
+        }
         let stat_data = self.clone();
         ClientMessage::Stat {
             event_code: msgtype.to_string(),
@@ -97,28 +393,287 @@ impl StatusData {
     }
 }
 
+/// Status data are equal when the values sent to the server are equal
+impl PartialEq for StatusData {
+    fn eq(&self, other: &Self) -> bool {
+        self.crlf == other.crlf
+            && self.buffer_size == other.buffer_size
+            && self.fullness == other.fullness
+            && self.bytes_received == other.bytes_received
+            && self.sig_strength == other.sig_strength
+            && self.jiffies == other.jiffies
+            && self.output_buffer_size == other.output_buffer_size
+            && self.output_buffer_fullness == other.output_buffer_fullness
+            && self.elapsed_seconds == other.elapsed_seconds
+            && self.voltage == other.voltage
+            && self.elapsed_milliseconds == other.elapsed_milliseconds
+            && self.timestamp == other.timestamp
+            && self.error_code == other.error_code
+    }
+}
+
+// There is no working Instant on wasm32-unknown-unknown or without std, so there
+// the clock stands still at zero unless the status data are made `with_clock`
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock::new())
+}
+
+#[cfg(any(not(feature = "std"), target_arch = "wasm32"))]
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(ManualClock::default())
+}
+
 impl Default for StatusData {
     fn default() -> Self {
+        Self::from_clock(default_clock())
+    }
+}
+
+/// A thread-safe handle to status data that can be cheaply cloned and shared
+/// between the network buffer, the audio output and the code that sends status
+/// messages to the server.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default)]
+pub struct SharedStatus(Arc<Mutex<StatusData>>);
+
+#[cfg(feature = "std")]
+impl SharedStatus {
+    pub fn new(status: StatusData) -> Self {
+        Self(Arc::new(Mutex::new(status)))
+    }
+
+    /// Lock the status data for reading or writing.
+    ///
+    /// A panic in another thread while the lock was held does not poison the
+    /// status data, it is always safe to carry on using it.
+    pub fn lock(&self) -> MutexGuard<'_, StatusData> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run a closure with the status data locked.
+    pub fn with<T>(&self, f: impl FnOnce(&mut StatusData) -> T) -> T {
+        f(&mut self.lock())
+    }
+
+    /// Create a status message for sending to the server.
+    pub fn make_status_message(&self, msgtype: StatusCode) -> ClientMessage {
+        self.lock().make_status_message(msgtype)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<StatusData> for SharedStatus {
+    fn from(value: StatusData) -> Self {
+        Self::new(value)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Arc<Mutex<StatusData>>> for SharedStatus {
+    fn from(value: Arc<Mutex<StatusData>>) -> Self {
+        Self(value)
+    }
+}
+
+#[cfg(feature = "std")]
+type SendFn = Arc<dyn Fn(ClientMessage) + Send + Sync + 'static>;
+#[cfg(feature = "std")]
+type SensorsRef = Arc<dyn Sensors + Send + Sync + 'static>;
+
+/// Sends status messages to the server on behalf of the player.
+///
+/// The server deems a player to be dead when it stops receiving status messages
+/// so, once [started](StatusReporter::start), the reporter sends a timer status
+/// message whenever no other status message has been sent for the given interval,
+/// even if the server's status request is missed.
+///
+/// Messages are handed to the closure given to [new](StatusReporter::new), usually
+/// the sending half of a channel feeding the connection's writer.
+#[cfg(feature = "std")]
+pub struct StatusReporter {
+    status: SharedStatus,
+    send: SendFn,
+    sensors: Option<SensorsRef>,
+    last_sent: Arc<Mutex<Option<Instant>>>,
+    running: Arc<AtomicBool>,
+    generation: Arc<AtomicU32>,
+}
+
+#[cfg(feature = "std")]
+impl StatusReporter {
+    pub fn new(
+        status: impl Into<SharedStatus>,
+        send: impl Fn(ClientMessage) + Send + Sync + 'static,
+    ) -> Self {
         Self {
-            crlf: 0,
-            buffer_size: 0,
-            fullness: 0,
-            bytes_received: 0,
-            sig_strength: 0,
-            jiffies: Duration::default(),
-            output_buffer_size: 0,
-            output_buffer_fullness: 0,
-            elapsed_seconds: 0,
-            voltage: 0,
-            elapsed_milliseconds: 0,
-            timestamp: Duration::default(),
-            error_code: 0,
-            start: Instant::now(),
+            status: status.into(),
+            send: Arc::new(send),
+            sensors: None,
+            last_sent: Arc::new(Mutex::new(None)),
+            running: Arc::new(AtomicBool::new(false)),
+            generation: Arc::new(AtomicU32::new(0)),
         }
     }
+
+    /// Poll the given sensors for the voltage and signal strength before each
+    /// status message is sent. This must be called before [start](StatusReporter::start).
+    pub fn with_sensors(mut self, sensors: impl Sensors + Send + Sync + 'static) -> Self {
+        self.sensors = Some(Arc::new(sensors));
+        self
+    }
+
+    /// The status data from which messages are made.
+    pub fn status(&self) -> &SharedStatus {
+        &self.status
+    }
+
+    /// Start sending timer status messages at the given interval in a background thread.
+    /// Calling this while the reporter is already running has no effect.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn start(&self, interval: Duration) {
+        if self.running.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        // A thread from before the last stop may still be asleep, it exits when
+        // it wakes to find that it is no longer the current generation
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let current = self.generation.clone();
+        let status = self.status.clone();
+        let send = self.send.clone();
+        let sensors = self.sensors.clone();
+        let last_sent = self.last_sent.clone();
+        if let Ok(mut last_sent) = last_sent.lock() {
+            last_sent.get_or_insert_with(Instant::now);
+        }
+        spawn(move || {
+            while current.load(Ordering::SeqCst) == generation {
+                let since = last_sent
+                    .lock()
+                    .ok()
+                    .and_then(|t| t.map(|t| t.elapsed()))
+                    .unwrap_or_default();
+                if since >= interval {
+                    send_status(&status, &send, &sensors, &last_sent, StatusCode::Timer);
+                    sleep(interval);
+                } else {
+                    sleep(interval - since);
+                }
+            }
+        });
+    }
+
+    /// Stop sending timer status messages.
+    pub fn stop(&self) {
+        if self.running.swap(false, Ordering::Relaxed) {
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Send a status message with the given code now.
+    pub fn send(&self, code: StatusCode) {
+        send_status(
+            &self.status,
+            &self.send,
+            &self.sensors,
+            &self.last_sent,
+            code,
+        );
+    }
+
+    /// Send any other message through the same channel as the status messages.
+    pub fn send_message(&self, msg: ClientMessage) {
+        (self.send)(msg);
+    }
+
+    /// Make a function that sends the status message `code` when called, for
+    /// messages that are due after the caller no longer holds the reporter.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub(crate) fn deferred(&self, code: StatusCode) -> impl FnOnce() + Send + Sync + 'static {
+        let status = self.status.clone();
+        let send = self.send.clone();
+        let sensors = self.sensors.clone();
+        let last_sent = self.last_sent.clone();
+        move || send_status(&status, &send, &sensors, &last_sent, code)
+    }
+
+    /// Send `STMt` in response to a status request from the server.
+    pub fn timer(&self, timestamp: Jiffies) {
+        self.status.with(|status| {
+            status.server_timestamp_received(timestamp);
+            status.set_timestamp(timestamp);
+        });
+        self.send(StatusCode::Timer);
+    }
+
+    /// Send `STMh` once the response headers of the stream have been read, the
+    /// `\r\n` sequences in the headers are added to the `crlf` count first.
+    pub fn headers_received(&self, headers: &str) {
+        self.status
+            .lock()
+            .add_crlf(crate::http::count_crlf(headers));
+        self.send(StatusCode::HeadersReceived);
+    }
+
+    /// Send `STMs`, playback of a new track has started.
+    pub fn track_started(&self) {
+        self.send(StatusCode::TrackStarted);
+    }
+
+    /// Send `STMd`, the decoder is ready for the next track.
+    pub fn decoder_ready(&self) {
+        self.send(StatusCode::DecoderReady);
+    }
+
+    /// Send `STMu`, the output buffer has run out of data at the end of a track.
+    pub fn underrun(&self) {
+        self.send(StatusCode::Underrun);
+    }
+
+    /// Send `STMo`, the output buffer has run out of data during playback.
+    pub fn output_underrun(&self) {
+        self.send(StatusCode::OutputUnderrun);
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for StatusReporter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(feature = "std")]
+fn send_status(
+    status: &SharedStatus,
+    send: &SendFn,
+    sensors: &Option<SensorsRef>,
+    #[cfg_attr(target_arch = "wasm32", allow(unused_variables))] last_sent: &Mutex<Option<Instant>>,
+    code: StatusCode,
+) {
+    let msg = {
+        let mut status = status.lock();
+        if let Some(sensors) = sensors {
+            if let Some(voltage) = sensors.voltage() {
+                status.set_voltage(voltage);
+            }
+            if let Some(sig_strength) = sensors.signal_strength() {
+                status.set_sig_strength(sig_strength);
+            }
+        }
+        status.make_status_message(code)
+    };
+    // Only the timer thread reads this, and wasm32 has neither it nor a working Instant
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Ok(mut last_sent) = last_sent.lock() {
+        *last_sent = Some(Instant::now());
+    }
+    send(msg);
 }
 
 /// Status code to send as part of the status message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StatusCode {
     Connect,
     DecoderReady,
@@ -133,6 +688,30 @@ pub enum StatusCode {
     TrackStarted,
     Timer,
     Underrun,
+    /// Any other four character event code, such as a firmware specific code
+    Other(EventCode),
+}
+
+/// A four character status event code made only of ASCII characters, so that it
+/// always takes the four bytes set aside for it in a status message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventCode([u8; 4]);
+
+impl EventCode {
+    /// Returns `None` if any of the characters is not ASCII.
+    pub fn new(code: [u8; 4]) -> Option<Self> {
+        code.is_ascii().then_some(Self(code))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 4] {
+        &self.0
+    }
+}
+
+impl fmt::Display for EventCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|c| write!(f, "{}", *c as char))
+    }
 }
 
 impl fmt::Display for StatusCode {
@@ -151,6 +730,210 @@ impl fmt::Display for StatusCode {
             StatusCode::TrackStarted => write!(f, "STMs"),
             StatusCode::Timer => write!(f, "STMt"),
             StatusCode::Underrun => write!(f, "STMu"),
+            StatusCode::Other(code) => write!(f, "{}", code),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn shared() {
+        let status = SharedStatus::default();
+        let status_ref = status.clone();
+        std::thread::spawn(move || status_ref.with(|s| s.add_bytes_received(10)))
+            .join()
+            .unwrap();
+        assert_eq!(status.lock().get_bytes_received(), 10);
+    }
+
+    #[test]
+    fn clock() {
+        let clock = crate::jiffies::ManualClock::new(Jiffies::from_millis(1000));
+        let mut status = StatusData::with_clock(clock.clone());
+        clock.advance(Duration::from_millis(500));
+        if let ClientMessage::Stat { stat_data, .. } = status.make_status_message(StatusCode::Timer)
+        {
+            assert_eq!(stat_data.get_jiffies(), Jiffies::from_millis(1500));
+        } else {
+            panic!("STAT message not made");
+        }
+    }
+
+    #[test]
+    fn latency() {
+        let clock = crate::jiffies::ManualClock::new(Jiffies::from_millis(1000));
+        let mut status = StatusData::with_clock(clock.clone());
+        status.server_timestamp_received(Jiffies::from_millis(5000));
+        assert!(status.latency().is_none());
+
+        status.make_status_message(StatusCode::Timer);
+        clock.advance(Duration::from_millis(40));
+        status.server_timestamp_received(Jiffies::from_millis(5020));
+        let latency = status.latency().unwrap();
+        assert_eq!(latency.rtt, Duration::from_millis(40));
+        assert_eq!(latency.one_way(), Duration::from_millis(20));
+        assert_eq!(latency.skew, 4000);
+
+        // A slow round trip counts but does not replace the estimate
+        status.make_status_message(StatusCode::Timer);
+        clock.advance(Duration::from_millis(5000));
+        status.server_timestamp_received(Jiffies::from_millis(12_000));
+        let latency = status.latency().unwrap();
+        assert_eq!(
+            (latency.rtt, latency.skew, latency.samples),
+            (Duration::from_millis(40), 4000, 2)
+        );
+
+        // The server's clock can be behind the player's
+        status.make_status_message(StatusCode::Timer);
+        clock.advance(Duration::from_millis(10));
+        status.server_timestamp_received(Jiffies::from_millis(45));
+        let latency = status.latency().unwrap();
+        assert_eq!(
+            (latency.rtt, latency.skew),
+            (Duration::from_millis(10), -6000)
+        );
+    }
+
+    #[test]
+    fn set_bytes_received() {
+        let mut status = StatusData::default();
+        status.add_bytes_received(10);
+        status.set_bytes_received(4096);
+        status.set_fullness(1024);
+        assert_eq!(status.get_bytes_received(), 4096);
+        assert_eq!(status.get_fullness(), 1024);
+    }
+
+    #[test]
+    fn other_code() {
+        let mut status = StatusData::default();
+        if let ClientMessage::Stat { event_code, .. } =
+            status.make_status_message(StatusCode::Other(EventCode::new(*b"STMa").unwrap()))
+        {
+            assert_eq!(event_code, "STMa");
+        } else {
+            panic!("STAT message not made");
+        }
+        assert!(EventCode::new([b'S', b'T', b'M', 0xe9]).is_none());
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn reporter() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let reporter = StatusReporter::new(StatusData::default(), move |msg| {
+            tx.lock().unwrap().send(msg).ok();
+        });
+
+        reporter.track_started();
+        assert!(matches!(
+            rx.recv(),
+            Ok(ClientMessage::Stat { event_code, .. }) if event_code == "STMs"
+        ));
+
+        reporter.headers_received("HTTP/1.0 200 OK\r\n\r\n");
+        assert!(matches!(
+            rx.recv(),
+            Ok(ClientMessage::Stat { event_code, stat_data }) if event_code == "STMh" && stat_data.get_crlf() == 2
+        ));
+
+        reporter.start(Duration::from_millis(10));
+        assert!(matches!(
+            rx.recv_timeout(Duration::from_secs(1)),
+            Ok(ClientMessage::Stat { event_code, .. }) if event_code == "STMt"
+        ));
+        reporter.stop();
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[test]
+    fn restart() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let reporter = StatusReporter::new(StatusData::default(), move |msg| {
+            tx.lock().unwrap().send(msg).ok();
+        });
+
+        // Only the thread started last sends, at its own interval
+        reporter.start(Duration::from_millis(10));
+        reporter.stop();
+        reporter.start(Duration::from_secs(3600));
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        reporter.stop();
+        reporter.start(Duration::from_millis(10));
+        assert!(rx.recv_timeout(Duration::from_secs(1)).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn sensors() {
+        struct Battery;
+        impl Sensors for Battery {
+            fn voltage(&self) -> Option<u16> {
+                Some(3700)
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let reporter = StatusReporter::new(StatusData::default(), move |msg| {
+            tx.lock().unwrap().send(msg).ok();
+        })
+        .with_sensors(Battery);
+
+        reporter.decoder_ready();
+        if let Ok(ClientMessage::Stat { stat_data, .. }) = rx.recv() {
+            assert_eq!(stat_data.get_voltage(), 3700);
+            assert_eq!(stat_data.get_sig_strength(), 0);
+        } else {
+            panic!("STAT message not sent");
+        }
+    }
+
+    #[test]
+    fn output_level() {
+        let mut status = StatusData::new(0, 1024);
+        let level = status.track_output_buffer();
+        assert_eq!(level.size(), 1024);
+
+        let audio_side = level.clone();
+        audio_side.set_fullness(512);
+        if let ClientMessage::Stat { stat_data, .. } = status.make_status_message(StatusCode::Timer)
+        {
+            assert_eq!(stat_data.get_output_buffer_size(), 1024);
+            assert_eq!(stat_data.get_output_buffer_fullness(), 512);
+        } else {
+            panic!("STAT message not made");
+        }
+    }
+
+    #[test]
+    fn track_timing() {
+        let clock = crate::jiffies::ManualClock::new(Jiffies::from_millis(1000));
+        let mut status = StatusData::with_clock(clock.clone());
+        assert!(status.track_elapsed().is_none());
+
+        status.start_track();
+        clock.advance(Duration::from_millis(20));
+        status.pause();
+        assert_eq!(status.track_elapsed(), Some(Duration::from_millis(20)));
+
+        clock.advance(Duration::from_millis(20));
+        assert_eq!(status.track_elapsed(), Some(Duration::from_millis(20)));
+
+        status.resume();
+        clock.advance(Duration::from_millis(30));
+        status.make_status_message(StatusCode::Timer);
+        assert_eq!(status.get_elapsed_milli_seconds(), 50);
+
+        status.stop_track();
+        assert!(status.track_elapsed().is_none());
+    }
+}
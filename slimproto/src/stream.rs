@@ -0,0 +1,772 @@
+//! Helpers for opening the audio stream requested by a `strm s` message.
+//!
+//! Starting a stream means connecting to the data port given in the message,
+//! sending the HTTP request that the server supplied and reading the response
+//! headers before the audio data. [StreamConnection] does all of this and the
+//! result can be read directly or wrapped in a [SlimBuffer](crate::buffer::SlimBuffer).
+//!
+//! When the server asks for direct streaming (`AutoStart::Direct` or `AutoStart::AutoDirect`)
+//! the client fetches the URL itself, this needs the `direct` feature which brings in an
+//! HTTP(S) client.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+use crate::{
+    gain::Gain,
+    http::{count_crlf, HttpRequest},
+    proto::{
+        AutoStart, ConnectOptions, Format, PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize,
+        Server, StreamFlags, Threshold, TransType,
+    },
+    status::{SharedStatus, StatusCode, StatusReporter},
+    ClientMessage, ServerMessage,
+};
+
+// The most response header bytes that are read before giving up on the stream
+const MAX_HEADER_LEN: u64 = 16 * 1024;
+
+// Long enough for a slow server, short enough that the player is not left
+// waiting on a stream that will never connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of a `strm s` message needed to open and play a stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamParams {
+    pub autostart: AutoStart,
+    pub format: Format,
+    pub pcmsamplesize: PcmSampleSize,
+    pub pcmsamplerate: PcmSampleRate,
+    pub pcmchannels: PcmChannels,
+    pub pcmendian: PcmEndian,
+    pub threshold: Threshold,
+    /// The length of the transition to the stream
+    pub trans_period: Duration,
+    pub trans_type: TransType,
+    pub flags: StreamFlags,
+    /// The replay gain of the track, zero when there is none
+    pub replay_gain: Gain,
+    pub server_ip: Ipv4Addr,
+    pub server_port: u16,
+    pub http_request: Option<HttpRequest>,
+}
+
+impl StreamParams {
+    /// True if the client is expected to fetch the stream itself.
+    pub fn is_direct(&self) -> bool {
+        matches!(self.autostart, AutoStart::Direct | AutoStart::AutoDirect)
+    }
+
+    /// True if playback starts once the stream is buffered, otherwise the player
+    /// waits for the server to unpause it.
+    pub fn is_autostart(&self) -> bool {
+        matches!(self.autostart, AutoStart::Auto | AutoStart::AutoDirect)
+    }
+}
+
+impl TryFrom<&ServerMessage> for StreamParams {
+    type Error = io::Error;
+
+    fn try_from(value: &ServerMessage) -> io::Result<Self> {
+        match value {
+            ServerMessage::Stream {
+                autostart,
+                format,
+                pcmsamplesize,
+                pcmsamplerate,
+                pcmchannels,
+                pcmendian,
+                threshold,
+                trans_period,
+                trans_type,
+                flags,
+                replay_gain,
+                server_ip,
+                server_port,
+                http_headers,
+                ..
+            } => Ok(Self {
+                autostart: *autostart,
+                format: *format,
+                pcmsamplesize: *pcmsamplesize,
+                pcmsamplerate: *pcmsamplerate,
+                pcmchannels: *pcmchannels,
+                pcmendian: *pcmendian,
+                threshold: *threshold,
+                trans_period: *trans_period,
+                trans_type: *trans_type,
+                flags: *flags,
+                replay_gain: *replay_gain,
+                server_ip: *server_ip,
+                server_port: *server_port,
+                http_request: http_request(http_headers.as_deref())?,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a stream message",
+            )),
+        }
+    }
+}
+
+// A stream message may have no request, a player waiting for a `cont` has no use
+// for one, but a request that is there must be whole to be sent
+fn http_request(headers: Option<&str>) -> io::Result<Option<HttpRequest>> {
+    let headers = match headers.map(|h| h.trim_end_matches('\0')) {
+        Some(headers) if !headers.is_empty() => headers,
+        _ => return Ok(None),
+    };
+
+    let request: HttpRequest = headers.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed HTTP request in stream message",
+        )
+    })?;
+    if !request.is_complete() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "HTTP request in stream message is cut short",
+        ));
+    }
+    Ok(Some(request))
+}
+
+/// An open connection to the audio stream.
+///
+/// The response headers have already been read from the connection so
+/// reading from it yields only the audio data.
+pub struct StreamConnection {
+    inner: BufReader<Box<dyn Read + Send + Sync>>,
+    response_headers: String,
+}
+
+impl StreamConnection {
+    /// Connect to the stream described by `params` and send the HTTP request.
+    ///
+    /// The `server` is the one to which the control connection is made, it is used
+    /// when the stream message asks for the stream to be fetched from the server itself.
+    pub fn open(params: &StreamParams, server: &Server) -> io::Result<Self> {
+        Self::open_with(params, server, &Self::default_options())
+    }
+
+    /// Open the stream as with [open](StreamConnection::open), connecting with the
+    /// timeouts and socket options in `options`.
+    pub fn open_with(
+        params: &StreamParams,
+        server: &Server,
+        options: &ConnectOptions,
+    ) -> io::Result<Self> {
+        Self::connect(params, server, options, || {})
+    }
+
+    /// The options used by [open](StreamConnection::open), those of [ConnectOptions]
+    /// with a ten second connect timeout so that a stream that cannot be reached
+    /// fails rather than blocking the player.
+    pub fn default_options() -> ConnectOptions {
+        ConnectOptions::default().connect_timeout(Some(CONNECT_TIMEOUT))
+    }
+
+    /// Open the stream as with [open](StreamConnection::open) or, for direct streams,
+    /// `open_direct`, which needs the `direct` feature, and send the status messages
+    /// that report its progress to the server in the order that it expects.
+    ///
+    /// `STMc` is sent before connecting, `STMe` once connected and `STMh` once the
+    /// response headers have been read, direct streams also send the headers in a
+    /// `RESP` before `STMh`. `STMs` is left to the caller, as only the output
+    /// knows when the track starts to play.
+    ///
+    /// The connection is made with the timeouts and socket options in `options`,
+    /// normally [default_options](StreamConnection::default_options).
+    pub fn start(
+        params: &StreamParams,
+        server: &Server,
+        reporter: &StatusReporter,
+        options: &ConnectOptions,
+    ) -> io::Result<Self> {
+        reporter.send(StatusCode::Connect);
+        let stream = if params.is_direct() {
+            #[cfg(feature = "direct")]
+            {
+                let stream = Self::open_direct_with(params, server, options)?;
+                reporter.send(StatusCode::StreamEstablished);
+                reporter.send_message(stream.resp_message());
+                stream
+            }
+            #[cfg(not(feature = "direct"))]
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Direct streaming needs the direct feature",
+                ));
+            }
+        } else {
+            Self::connect(params, server, options, || {
+                reporter.send(StatusCode::StreamEstablished)
+            })?
+        };
+
+        reporter.headers_received(stream.response_headers());
+        Ok(stream)
+    }
+
+    fn connect(
+        params: &StreamParams,
+        server: &Server,
+        options: &ConnectOptions,
+        established: impl FnOnce(),
+    ) -> io::Result<Self> {
+        let request = params.http_request.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "No HTTP request to send")
+        })?;
+
+        let mut cx = options.open(server.stream_address(params.server_ip, params.server_port))?;
+        cx.write_all(request.as_bytes())?;
+        cx.flush()?;
+        established();
+
+        let mut inner = BufReader::new(Box::new(cx) as Box<dyn Read + Send + Sync>);
+        let mut response_headers = String::new();
+        loop {
+            let remaining = MAX_HEADER_LEN.saturating_sub(response_headers.len() as u64);
+            let n = (&mut inner)
+                .take(remaining)
+                .read_line(&mut response_headers)?;
+            if n == 0 && remaining == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP response headers too long",
+                ));
+            }
+            if n == 0 || response_headers.ends_with("\r\n\r\n") || response_headers == "\r\n" {
+                break;
+            }
+        }
+
+        Ok(Self {
+            inner,
+            response_headers,
+        })
+    }
+
+    /// Fetch the stream directly from the URL in the request of `params`.
+    ///
+    /// This is used when [StreamParams::is_direct] is true. The request path may be
+    /// a full URL, possibly to a different host, otherwise the stream address is used
+    /// as with [StreamConnection::open]. Redirects are followed and if the response is
+    /// a playlist then the first entry in the playlist is fetched instead.
+    ///
+    /// The response headers should be sent to the server with [StreamConnection::resp_message].
+    #[cfg(feature = "direct")]
+    pub fn open_direct(params: &StreamParams, server: &Server) -> io::Result<Self> {
+        Self::open_direct_with(params, server, &Self::default_options())
+    }
+
+    /// Fetch the stream as with [open_direct](StreamConnection::open_direct), giving
+    /// up if the connection or the response takes longer than the connect or read
+    /// timeout in `options`.
+    #[cfg(feature = "direct")]
+    pub fn open_direct_with(
+        params: &StreamParams,
+        server: &Server,
+        options: &ConnectOptions,
+    ) -> io::Result<Self> {
+        const MAX_PLAYLIST_DEPTH: usize = 3;
+        const MAX_PLAYLIST_LEN: u64 = 64 * 1024;
+
+        let request = params.http_request.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "No HTTP request to send")
+        })?;
+
+        // The body is not given a timeout as it is read for as long as the track plays
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_connect(options.connect_timeout)
+            .timeout_recv_response(options.read_timeout)
+            .build()
+            .into();
+
+        let mut url = direct_url(
+            &request.path,
+            server.stream_address(params.server_ip, params.server_port),
+        );
+
+        for _ in 0..=MAX_PLAYLIST_DEPTH {
+            let mut builder = agent.get(&url);
+            for (name, value) in request
+                .headers
+                .iter()
+                .filter(|(name, _)| !matches!(name.as_str(), "host" | "connection"))
+            {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+
+            let response = builder.call().map_err(io::Error::other)?;
+            let response_headers = format_response_headers(&response);
+            let content_type = response
+                .headers()
+                .get("content-type")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_owned();
+            let mut reader = response.into_body().into_reader();
+
+            if is_playlist(&content_type, &url) {
+                let mut body = String::new();
+                (&mut reader)
+                    .take(MAX_PLAYLIST_LEN)
+                    .read_to_string(&mut body)?;
+                url = playlist_entry(&body)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty playlist"))?;
+                continue;
+            }
+
+            return Ok(Self {
+                inner: BufReader::new(Box::new(reader)),
+                response_headers,
+            });
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Too many nested playlists",
+        ))
+    }
+
+    /// The response headers exactly as received, including the final empty line.
+    pub fn response_headers(&self) -> &str {
+        &self.response_headers
+    }
+
+    /// Account for the response headers in the status data and make the `STMh` status
+    /// message that tells the server the headers have been received.
+    ///
+    /// The number of `\r\n` sequences in the headers is added to the status data's
+    /// `crlf` count.
+    pub fn report_headers(&self, status: &SharedStatus) -> ClientMessage {
+        let mut status = status.lock();
+        status.add_crlf(count_crlf(&self.response_headers));
+        status.make_status_message(StatusCode::HeadersReceived)
+    }
+
+    /// The `RESP` message that reports the response headers to the server.
+    pub fn resp_message(&self) -> ClientMessage {
+        ClientMessage::Resp(self.response_headers.clone())
+    }
+
+    /// Consume the connection returning the underlying reader, positioned at the
+    /// start of the audio data.
+    pub fn into_inner(self) -> BufReader<Box<dyn Read + Send + Sync>> {
+        self.inner
+    }
+}
+
+#[cfg(feature = "direct")]
+fn direct_url(path: &str, addr: std::net::SocketAddrV4) -> String {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        path.to_owned()
+    } else {
+        format!("http://{}{}", addr, path)
+    }
+}
+
+#[cfg(feature = "direct")]
+fn format_response_headers(response: &ureq::http::Response<ureq::Body>) -> String {
+    let status = response.status();
+    let mut headers = format!(
+        "{:?} {} {}\r\n",
+        response.version(),
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in response.headers() {
+        headers.push_str(&format!(
+            "{}: {}\r\n",
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        ));
+    }
+    headers.push_str("\r\n");
+    headers
+}
+
+#[cfg(feature = "direct")]
+fn is_playlist(content_type: &str, url: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    content_type.contains("mpegurl")
+        || content_type.contains("scpls")
+        || path.ends_with(".m3u")
+        || path.ends_with(".m3u8")
+        || path.ends_with(".pls")
+}
+
+/// Find the first URL in an M3U or PLS playlist.
+#[cfg(feature = "direct")]
+fn playlist_entry(body: &str) -> Option<String> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if key.starts_with("File") => value.trim(),
+            _ => line,
+        })
+        .find(|line| line.starts_with("http://") || line.starts_with("https://"))
+        .map(str::to_owned)
+}
+
+impl Read for StreamConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl BufRead for StreamConnection {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener, thread::spawn};
+
+    #[test]
+    fn request_in_message() {
+        assert_eq!(http_request(None).unwrap(), None);
+        assert_eq!(http_request(Some("")).unwrap(), None);
+        assert_eq!(http_request(Some("\0")).unwrap(), None);
+
+        let request = http_request(Some("GET /a.mp3 HTTP/1.0\r\n\r\n\0")).unwrap();
+        assert_eq!(request.unwrap().path, "/a.mp3");
+
+        let e = http_request(Some("GET /a.mp3 HTTP/1.0\r\nHost: 172.1")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(http_request(Some("\r\n\r\n")).is_err());
+    }
+
+    #[test]
+    fn open() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_thread = spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let n = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\n\r\nabcdef")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Auto,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            server_port: port,
+            http_request: Some("GET /stream.mp3 HTTP/1.0\r\n\r\n".parse().unwrap()),
+        };
+
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        let mut stream = StreamConnection::open(&params, &server).unwrap();
+        assert_eq!(
+            stream.response_headers(),
+            "HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\n\r\n"
+        );
+
+        let status = SharedStatus::default();
+        let msg = stream.report_headers(&status);
+        assert!(matches!(msg, ClientMessage::Stat { event_code, .. } if event_code == "STMh"));
+        assert_eq!(status.lock().get_crlf(), 3);
+
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcdef");
+        assert_eq!(
+            server_thread.join().unwrap(),
+            "GET /stream.mp3 HTTP/1.0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn start() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf).unwrap();
+            cx.write_all(b"HTTP/1.0 200 OK\r\n\r\nabc").unwrap();
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Auto,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            server_port: port,
+            http_request: Some("GET /stream.mp3 HTTP/1.0\r\n\r\n".parse().unwrap()),
+        };
+
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reporter = StatusReporter::new(crate::status::StatusData::default(), {
+            let sent = sent.clone();
+            move |msg| {
+                if let ClientMessage::Stat { event_code, .. } = msg {
+                    sent.lock().unwrap().push(event_code);
+                }
+            }
+        });
+
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        let mut stream = StreamConnection::start(
+            &params,
+            &server,
+            &reporter,
+            &StreamConnection::default_options(),
+        )
+        .unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec!["STMc", "STMe", "STMh"]);
+
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abc");
+        assert_eq!(*sent.lock().unwrap(), vec!["STMc", "STMe", "STMh"]);
+    }
+
+    #[test]
+    fn long_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf).unwrap();
+            let _ = cx.write_all(b"HTTP/1.0 200 OK\r\nX-Padding: ");
+            let _ = cx.write_all(&[b'a'; MAX_HEADER_LEN as usize]);
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Auto,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            server_port: port,
+            http_request: Some("GET /stream.mp3 HTTP/1.0\r\n\r\n".parse().unwrap()),
+        };
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        let e = StreamConnection::open(&params, &server).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn silent_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_thread = spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf);
+            // Hold the connection open without sending a response
+            let _ = cx.read(&mut buf);
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Auto,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            server_port: port,
+            http_request: Some("GET /stream.mp3 HTTP/1.0\r\n\r\n".parse().unwrap()),
+        };
+        let options =
+            StreamConnection::default_options().read_timeout(Some(Duration::from_millis(100)));
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        let e = StreamConnection::open_with(&params, &server, &options)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        server_thread.join().unwrap();
+    }
+
+    #[cfg(feature = "direct")]
+    #[test]
+    fn playlists() {
+        let m3u = "#EXTM3U\n#EXTINF:-1,Radio\nhttp://radio.example/stream\n";
+        assert_eq!(
+            playlist_entry(m3u).as_deref(),
+            Some("http://radio.example/stream")
+        );
+
+        let pls = "[playlist]\nNumberOfEntries=1\nFile1=https://radio.example/aac\n";
+        assert_eq!(
+            playlist_entry(pls).as_deref(),
+            Some("https://radio.example/aac")
+        );
+
+        assert!(is_playlist(
+            "audio/x-mpegurl",
+            "http://radio.example/listen"
+        ));
+        assert!(is_playlist(
+            "text/plain",
+            "http://radio.example/listen.pls?id=1"
+        ));
+        assert!(!is_playlist("audio/mpeg", "http://radio.example/stream"));
+    }
+
+    #[cfg(feature = "direct")]
+    #[test]
+    fn open_direct() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_thread = spawn(move || {
+            let mut requests = Vec::new();
+            let mut buf = [0u8; 1024];
+
+            let (mut cx, _) = listener.accept().unwrap();
+            let n = cx.read(&mut buf).unwrap();
+            requests.push(String::from_utf8_lossy(&buf[..n]).to_string());
+            let playlist = format!("#EXTM3U\nhttp://127.0.0.1:{}/real.mp3\n", port);
+            cx.write_all(
+                format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: audio/x-mpegurl\r\nContent-Length: {}\r\n\r\n{}",
+                    playlist.len(),
+                    playlist
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+            drop(cx);
+
+            let (mut cx, _) = listener.accept().unwrap();
+            let n = cx.read(&mut buf).unwrap();
+            requests.push(String::from_utf8_lossy(&buf[..n]).to_string());
+            cx.write_all(b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\n\r\nabcdef")
+                .unwrap();
+            requests
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Direct,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::LOCALHOST,
+            server_port: port,
+            http_request: Some(
+                "GET /listen.m3u HTTP/1.0\r\nIcy-MetaData: 1\r\n\r\n"
+                    .parse()
+                    .unwrap(),
+            ),
+        };
+        assert!(params.is_direct());
+
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        let mut stream = StreamConnection::open_direct(&params, &server).unwrap();
+        assert!(stream
+            .response_headers()
+            .contains("content-type: audio/mpeg\r\n"));
+        assert!(stream.response_headers().ends_with("\r\n\r\n"));
+
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abcdef");
+
+        let requests = server_thread.join().unwrap();
+        assert!(requests[0].starts_with("GET /listen.m3u "));
+        assert!(requests[0].to_lowercase().contains("icy-metadata: 1"));
+        assert!(requests[1].starts_with("GET /real.mp3 "));
+    }
+
+    #[cfg(feature = "direct")]
+    #[test]
+    fn open_direct_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_thread = spawn(move || {
+            let (mut cx, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = cx.read(&mut buf);
+            // Hold the connection open without sending a response
+            let _ = cx.read(&mut buf);
+        });
+
+        let params = StreamParams {
+            autostart: AutoStart::Direct,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(1),
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            replay_gain: Gain::MUTE,
+            server_ip: Ipv4Addr::LOCALHOST,
+            server_port: port,
+            http_request: Some("GET /stream.mp3 HTTP/1.0\r\n\r\n".parse().unwrap()),
+        };
+        let options =
+            StreamConnection::default_options().read_timeout(Some(Duration::from_millis(100)));
+        let server = Server::new(Ipv4Addr::LOCALHOST);
+        assert!(StreamConnection::open_direct_with(&params, &server, &options).is_err());
+        server_thread.join().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//! Helpers for decoding streams with Symphonia.
+//!
+//! A [SlimBuffer] is a Symphonia `MediaSource`, [media_source_stream] wraps one
+//! ready for probing. The [hint] for a stream is made from the format in the
+//! `strm s` message. Raw PCM streams carry no header to probe so when the server
+//! gives all of the PCM parameters [pcm_codec_params] describes the stream to a
+//! Symphonia PCM decoder instead.
+
+use std::io::Read;
+
+use symphonia_core::{
+    audio::Channels,
+    codecs::{
+        CodecParameters, CodecType, DecoderOptions, CODEC_TYPE_PCM_S16BE, CODEC_TYPE_PCM_S16LE,
+        CODEC_TYPE_PCM_S24BE, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_PCM_S32BE, CODEC_TYPE_PCM_S32LE,
+        CODEC_TYPE_PCM_U8,
+    },
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    probe::Hint,
+};
+
+use crate::{
+    buffer::SlimBuffer,
+    proto::{Format, PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize},
+};
+
+/// The file extension and mime type of each stream format.
+fn format_names(format: Format) -> (&'static str, &'static str) {
+    match format {
+        Format::Pcm => ("wav", "audio/wav"),
+        Format::Mp3 => ("mp3", "audio/mpeg"),
+        Format::Flac => ("flac", "audio/flac"),
+        Format::Wma => ("wma", "audio/x-ms-wma"),
+        Format::Ogg => ("ogg", "audio/ogg"),
+        Format::Aac => ("aac", "audio/aac"),
+        Format::Alac => ("m4a", "audio/mp4"),
+    }
+}
+
+/// A hint to help Symphonia's probe find the format reader for a stream.
+pub fn hint(format: Format) -> Hint {
+    let (extension, mime_type) = format_names(format);
+    let mut hint = Hint::new();
+    hint.with_extension(extension).mime_type(mime_type);
+    hint
+}
+
+/// Format reader options for playing a stream, with gapless playback enabled.
+pub fn format_options() -> FormatOptions {
+    FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    }
+}
+
+pub fn decoder_options() -> DecoderOptions {
+    DecoderOptions::default()
+}
+
+/// Wrap a buffer in a stream that Symphonia can probe.
+pub fn media_source_stream<R>(buffer: SlimBuffer<R>) -> MediaSourceStream
+where
+    R: Read + Send + Sync + 'static,
+{
+    MediaSourceStream::new(Box::new(buffer), Default::default())
+}
+
+/// The codec parameters of a raw PCM stream.
+///
+/// `None` is returned if any of the parameters are self describing, the stream
+/// then has a header and should be probed. A sample size of `Twenty` is taken as
+/// 24 bit samples as the server sends it for 24 bit streams.
+pub fn pcm_codec_params(
+    size: PcmSampleSize,
+    rate: PcmSampleRate,
+    channels: PcmChannels,
+    endian: PcmEndian,
+) -> Option<CodecParameters> {
+    let codec = pcm_codec_type(size, endian)?;
+    let bits = match size {
+        PcmSampleSize::Eight => 8,
+        PcmSampleSize::Sixteen => 16,
+        PcmSampleSize::Twenty => 24,
+        PcmSampleSize::ThirtyTwo => 32,
+        PcmSampleSize::SelfDescribing => return None,
+    };
+    let PcmSampleRate::Rate(rate) = rate else {
+        return None;
+    };
+    let channels = match channels {
+        PcmChannels::Mono => Channels::FRONT_CENTRE,
+        PcmChannels::Stereo => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        PcmChannels::SelfDescribing => return None,
+    };
+
+    let mut params = CodecParameters::new();
+    params
+        .for_codec(codec)
+        .with_sample_rate(rate)
+        .with_bits_per_sample(bits)
+        .with_bits_per_coded_sample(bits)
+        .with_channels(channels)
+        .with_max_frames_per_packet(4096);
+    Some(params)
+}
+
+fn pcm_codec_type(size: PcmSampleSize, endian: PcmEndian) -> Option<CodecType> {
+    match (size, endian) {
+        (PcmSampleSize::Eight, _) => Some(CODEC_TYPE_PCM_U8),
+        (_, PcmEndian::SelfDescribing) => None,
+        (PcmSampleSize::Sixteen, PcmEndian::Little) => Some(CODEC_TYPE_PCM_S16LE),
+        (PcmSampleSize::Sixteen, PcmEndian::Big) => Some(CODEC_TYPE_PCM_S16BE),
+        (PcmSampleSize::Twenty, PcmEndian::Little) => Some(CODEC_TYPE_PCM_S24LE),
+        (PcmSampleSize::Twenty, PcmEndian::Big) => Some(CODEC_TYPE_PCM_S24BE),
+        (PcmSampleSize::ThirtyTwo, PcmEndian::Little) => Some(CODEC_TYPE_PCM_S32LE),
+        (PcmSampleSize::ThirtyTwo, PcmEndian::Big) => Some(CODEC_TYPE_PCM_S32BE),
+        (PcmSampleSize::SelfDescribing, _) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcm_params() {
+        let params = pcm_codec_params(
+            PcmSampleSize::Twenty,
+            PcmSampleRate::Rate(48000),
+            PcmChannels::Stereo,
+            PcmEndian::Big,
+        )
+        .unwrap();
+        assert_eq!(params.codec, CODEC_TYPE_PCM_S24BE);
+        assert_eq!(params.sample_rate, Some(48000));
+        assert_eq!(params.bits_per_sample, Some(24));
+        assert_eq!(params.channels.map(|c| c.count()), Some(2));
+
+        assert!(pcm_codec_params(
+            PcmSampleSize::Sixteen,
+            PcmSampleRate::SelfDescribing,
+            PcmChannels::Stereo,
+            PcmEndian::Little,
+        )
+        .is_none());
+        assert_eq!(
+            pcm_codec_type(PcmSampleSize::Eight, PcmEndian::SelfDescribing),
+            Some(CODEC_TYPE_PCM_U8)
+        );
+    }
+
+    #[test]
+    fn hints() {
+        assert_eq!(format_names(Format::Pcm), ("wav", "audio/wav"));
+        assert!(format!("{:?}", hint(Format::Flac)).contains("flac"));
+        assert!(format_options().enable_gapless);
+    }
+}
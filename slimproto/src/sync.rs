@@ -0,0 +1,317 @@
+//! Helpers for synchronised playback across multiple players.
+//!
+//! When players are synchronised the server sends `strm u` and `strm p` messages
+//! carrying the jiffies time, on the player's own clock, at which the player must
+//! resume or pause. The [SyncScheduler] turns those times into local deadlines and
+//! runs an action when the deadline arrives.
+//!
+//! Other commands, such as `strm a`, carry an interval that starts when the server
+//! sends the message. The message has already been on its way for half the round
+//! trip to the server when it arrives, so [schedule_after](SyncScheduler::schedule_after)
+//! takes that off the interval. The round trip is the [latency](crate::status::StatusData::latency)
+//! measured by the status data unless one is given.
+
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+use crate::{jiffies::Jiffies, status::SharedStatus};
+
+type Action = Box<dyn FnOnce() + Send>;
+
+struct Pending {
+    id: u64,
+    deadline: Instant,
+    action: Action,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: Vec<Pending>,
+    next_id: u64,
+    started: bool,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Closes the queue when the last clone of the scheduler is dropped
+struct Owner(Arc<Shared>);
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        self.0.lock().closed = true;
+        self.0.wake.notify_one();
+    }
+}
+
+/// Schedules actions at jiffies times on the player's clock.
+///
+/// The player's clock is the one used for the jiffies in its status messages,
+/// so the scheduler is created from the same status data that is sent to the server.
+///
+/// All the actions of a scheduler, and of its clones, run in turn on one
+/// background thread. Dropping the last clone cancels any that have not yet run.
+#[derive(Clone)]
+pub struct SyncScheduler {
+    status: SharedStatus,
+    latency: Duration,
+    round_trip: Option<Duration>,
+    owner: Arc<Owner>,
+}
+
+/// A handle to an action scheduled by a [SyncScheduler].
+/// Dropping the handle does not cancel the action.
+pub struct ScheduledAction {
+    id: u64,
+    deadline: Instant,
+    shared: Weak<Shared>,
+}
+
+impl ScheduledAction {
+    /// Prevent the action from running if it has not yet run.
+    pub fn cancel(&self) {
+        if let Some(shared) = self.shared.upgrade() {
+            shared.lock().pending.retain(|p| p.id != self.id);
+            shared.wake.notify_one();
+        }
+    }
+
+    /// The local time at which the action runs.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl SyncScheduler {
+    pub fn new(status: impl Into<SharedStatus>) -> Self {
+        Self {
+            status: status.into(),
+            latency: Duration::ZERO,
+            round_trip: None,
+            owner: Arc::new(Owner(Arc::default())),
+        }
+    }
+
+    /// Run actions early by the given latency, normally the latency of the
+    /// audio output, so that the audio is heard at the requested time.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The round trip time of the network to the server. Half of it is taken
+    /// off intervals given to [schedule_after](SyncScheduler::schedule_after).
+    /// Without it the round trip measured by the status data is used.
+    pub fn with_round_trip(mut self, round_trip: Duration) -> Self {
+        self.round_trip = Some(round_trip);
+        self
+    }
+
+    /// The round trip time in use, zero until one has been given or measured.
+    pub fn round_trip(&self) -> Duration {
+        self.round_trip
+            .or_else(|| self.status.lock().latency().map(|latency| latency.rtt))
+            .unwrap_or_default()
+    }
+
+    /// The local deadline for a jiffies time on the player's clock.
+    ///
+    /// A zero time, which the server uses to mean "now", or a time that has
+    /// already passed gives a deadline of now.
+    pub fn deadline(&self, at: Jiffies) -> Instant {
+        let now = Instant::now();
+        if at.is_zero() {
+            return now;
+        }
+
+        let current = self.status.lock().jiffies_now();
+        if at.is_after(current) {
+            let wait = at.duration_since(current).saturating_sub(self.latency);
+            now + wait
+        } else {
+            now
+        }
+    }
+
+    /// The local deadline for an interval that the server started when it sent
+    /// the message that has just arrived.
+    pub fn deadline_after(&self, interval: Duration) -> Instant {
+        Instant::now()
+            + interval
+                .saturating_sub(self.round_trip() / 2)
+                .saturating_sub(self.latency)
+    }
+
+    /// Run `action` in the background at the given jiffies time.
+    pub fn schedule(&self, at: Jiffies, action: impl FnOnce() + Send + 'static) -> ScheduledAction {
+        self.schedule_at(self.deadline(at), Box::new(action))
+    }
+
+    /// Run `action` in the background once the interval sent by the server has passed.
+    pub fn schedule_after(
+        &self,
+        interval: Duration,
+        action: impl FnOnce() + Send + 'static,
+    ) -> ScheduledAction {
+        self.schedule_at(self.deadline_after(interval), Box::new(action))
+    }
+
+    fn schedule_at(&self, deadline: Instant, action: Action) -> ScheduledAction {
+        let shared = &self.owner.0;
+        let mut queue = shared.lock();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.pending.push(Pending {
+            id,
+            deadline,
+            action,
+        });
+        if !queue.started {
+            queue.started = true;
+            let shared = shared.clone();
+            spawn(move || run(&shared));
+        }
+        drop(queue);
+        shared.wake.notify_one();
+
+        ScheduledAction {
+            id,
+            deadline,
+            shared: Arc::downgrade(shared),
+        }
+    }
+}
+
+// Run each action when its deadline arrives, waking early when an action is
+// added or cancelled
+fn run(shared: &Shared) {
+    let mut queue = shared.lock();
+    while !queue.closed {
+        let next = queue
+            .pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, p)| p.deadline)
+            .map(|(i, p)| (i, p.deadline));
+
+        queue = match next {
+            None => shared.wake.wait(queue).unwrap_or_else(|e| e.into_inner()),
+            Some((i, deadline)) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    let Pending { action, .. } = queue.pending.swap_remove(i);
+                    drop(queue);
+                    action();
+                    shared.lock()
+                } else {
+                    shared
+                        .wake
+                        .wait_timeout(queue, deadline - now)
+                        .map(|(queue, _)| queue)
+                        .unwrap_or_else(|e| e.into_inner().0)
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::StatusData;
+
+    use std::sync::mpsc;
+
+    #[test]
+    fn schedule() {
+        let status = SharedStatus::new(StatusData::default());
+        let scheduler = SyncScheduler::new(status.clone());
+        let at = status.lock().jiffies_now() + Duration::from_millis(50);
+
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        scheduler.schedule(at, move || tx.send(Instant::now()).unwrap());
+        let ran = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(ran.duration_since(start) >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn now_and_cancel() {
+        let scheduler = SyncScheduler::new(StatusData::default());
+        let now = Instant::now();
+        assert!(scheduler.deadline(Jiffies::ZERO) <= Instant::now());
+        assert!(scheduler.deadline(Jiffies::from_millis(1)) >= now);
+
+        let (tx, rx) = mpsc::channel();
+        let action = scheduler.schedule(Jiffies::from_millis(u32::MAX / 4), {
+            let tx = tx.clone();
+            move || tx.send("late").unwrap()
+        });
+        action.cancel();
+        assert!(rx.recv_timeout(Duration::from_millis(20)).is_err());
+
+        // A cancelled action does not hold up the ones after it
+        scheduler.schedule(Jiffies::ZERO, move || tx.send("now").unwrap());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(1)), Ok("now"));
+    }
+
+    #[test]
+    fn order_and_round_trip() {
+        let scheduler =
+            SyncScheduler::new(StatusData::default()).with_round_trip(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(
+            scheduler.deadline_after(Duration::from_millis(30)) <= start + Duration::from_millis(5)
+        );
+        assert!(
+            scheduler.deadline_after(Duration::from_secs(1)) >= start + Duration::from_millis(950)
+        );
+
+        let (tx, rx) = mpsc::channel();
+        for (after, name) in [(150, "second"), (100, "first"), (200, "third")] {
+            let tx = tx.clone();
+            scheduler.schedule_after(Duration::from_millis(after), move || tx.send(name).unwrap());
+        }
+        let ran: Vec<_> = rx.iter().take(3).collect();
+        assert_eq!(ran, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn measured_round_trip() {
+        let clock = crate::jiffies::ManualClock::new(Jiffies::from_millis(1000));
+        let status = SharedStatus::new(StatusData::with_clock(clock.clone()));
+        let scheduler = SyncScheduler::new(status.clone());
+        assert_eq!(scheduler.round_trip(), Duration::ZERO);
+
+        status
+            .lock()
+            .make_status_message(crate::status::StatusCode::Timer);
+        clock.advance(Duration::from_millis(60));
+        status
+            .lock()
+            .server_timestamp_received(Jiffies::from_millis(9000));
+        assert_eq!(scheduler.round_trip(), Duration::from_millis(60));
+
+        let start = Instant::now();
+        assert!(
+            scheduler.deadline_after(Duration::from_millis(20)) <= start + Duration::from_millis(5)
+        );
+
+        // One given by hand takes the place of the measured one
+        let scheduler = scheduler.with_round_trip(Duration::from_millis(10));
+        assert_eq!(scheduler.round_trip(), Duration::from_millis(10));
+    }
+}
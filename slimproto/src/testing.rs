@@ -0,0 +1,354 @@
+//! Property testing of the codec.
+//!
+//! [client_message] and [server_message] are `proptest` strategies for messages
+//! that the codec can encode, [roundtrip_client] and [roundtrip_server] send a
+//! message through both sides of the codec. Together they check that every
+//! message comes back unchanged, which is useful for anyone changing the codec.
+//!
+//! ```
+//! use proptest::prelude::*;
+//! use slimproto::testing::{client_message, roundtrip_client};
+//!
+//! proptest!(|(msg in client_message())| {
+//!     prop_assert_eq!(roundtrip_client(msg.clone()).unwrap(), msg);
+//! });
+//! ```
+//!
+//! The [corpus] holds frames as the server sends them, with the messages that
+//! they should decode to.
+
+pub mod corpus;
+
+use std::{io, net::Ipv4Addr, time::Duration};
+
+use bytes::BytesMut;
+use framous::{Decoder, Encoder};
+use proptest::{collection::vec, option, prelude::*};
+
+use crate::{
+    codec::{ServerCodec, SlimCodec},
+    gain::{FixedGain, Gain},
+    jiffies::Jiffies,
+    proto::{
+        AutoStart, Format, MacAddress, OutputEnable, PauseKind, PcmChannels, PcmEndian,
+        PcmSampleRate, PcmSampleSize, PlayerName, PlayerSetting, ServerAddress, SpdifEnable,
+        StreamFlags, SyncGroup, Threshold, TransType,
+    },
+    status::StatusData,
+    ClientMessage, ServerMessage,
+};
+
+/// Encode a client message as the client does and decode it as the server does.
+pub fn roundtrip_client(msg: ClientMessage) -> io::Result<ClientMessage> {
+    let mut buf = BytesMut::new();
+    SlimCodec::new().encode(msg, &mut buf)?;
+    ServerCodec
+        .decode(&mut buf)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete frame"))
+}
+
+/// Encode a server message as the server does and decode it as the client does.
+pub fn roundtrip_server(msg: ServerMessage) -> io::Result<ServerMessage> {
+    let mut buf = BytesMut::new();
+    ServerCodec.encode(msg, &mut buf)?;
+    SlimCodec::new()
+        .decode(&mut buf)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete frame"))
+}
+
+fn jiffies() -> impl Strategy<Value = Jiffies> {
+    any::<u32>().prop_map(Jiffies::from_millis)
+}
+
+fn pause_kind() -> impl Strategy<Value = PauseKind> {
+    any::<u32>().prop_map(|ms| PauseKind::from_interval(Duration::from_millis(ms.into())))
+}
+
+fn unpause_kind() -> impl Strategy<Value = PauseKind> {
+    jiffies().prop_map(PauseKind::from)
+}
+
+fn player_name() -> impl Strategy<Value = PlayerName> {
+    "[^\0]{0,16}".prop_map(|name| PlayerName::new(name).unwrap())
+}
+
+fn player_setting() -> impl Strategy<Value = PlayerSetting> {
+    prop_oneof![
+        Just(PlayerSetting::DigitalOutputEncoding),
+        Just(PlayerSetting::WordClockOutput),
+        Just(PlayerSetting::PowerOffDac),
+        Just(PlayerSetting::FxloopSource),
+        Just(PlayerSetting::FxloopClock),
+    ]
+}
+
+// The codec sends gains as 16.16 fixed point numbers
+fn fixed_gain() -> impl Strategy<Value = FixedGain> {
+    any::<u32>().prop_map(FixedGain)
+}
+
+fn status_data() -> impl Strategy<Value = StatusData> {
+    (
+        (any::<u8>(), any::<u32>(), any::<u32>(), any::<u64>()),
+        (any::<u16>(), jiffies(), any::<u32>(), any::<u32>()),
+        (any::<u32>(), any::<u16>(), any::<u32>(), jiffies()),
+        any::<u16>(),
+    )
+        .prop_map(
+            |(
+                (crlf, buffer_size, fullness, bytes_received),
+                (sig_strength, jiffies, output_buffer_size, output_buffer_fullness),
+                (elapsed_seconds, voltage, elapsed_milliseconds, timestamp),
+                error_code,
+            )| StatusData {
+                crlf,
+                buffer_size,
+                fullness,
+                bytes_received,
+                sig_strength,
+                jiffies,
+                output_buffer_size,
+                output_buffer_fullness,
+                elapsed_seconds,
+                voltage,
+                elapsed_milliseconds,
+                timestamp,
+                error_code,
+                ..Default::default()
+            },
+        )
+}
+
+/// Client messages that survive a round trip through the codec.
+pub fn client_message() -> impl Strategy<Value = ClientMessage> {
+    prop_oneof![
+        (
+            (
+                any::<u8>(),
+                any::<u8>(),
+                any::<[u8; 6]>(),
+                any::<[u8; 16]>()
+            ),
+            (any::<u16>(), any::<u64>(), "[a-z]{2}", "[ -~]{0,100}"),
+        )
+            .prop_map(
+                |(
+                    (device_id, revision, mac, uuid),
+                    (wlan_channel_list, bytes_received, language, capabilities),
+                )| {
+                    let mut language = language.chars();
+                    ClientMessage::Helo {
+                        device_id,
+                        revision,
+                        mac: MacAddress::new(mac),
+                        uuid,
+                        wlan_channel_list,
+                        bytes_received,
+                        language: [language.next().unwrap(), language.next().unwrap()],
+                        capabilities,
+                    }
+                }
+            ),
+        ("STM[a-z]", status_data()).prop_map(|(event_code, stat_data)| ClientMessage::Stat {
+            event_code,
+            stat_data
+        }),
+        any::<u8>().prop_map(ClientMessage::Bye),
+        player_name().prop_map(ClientMessage::Name),
+        (player_setting(), any::<u8>())
+            .prop_map(|(setting, value)| ClientMessage::Setting(setting, value)),
+        ".{0,200}".prop_map(ClientMessage::Resp),
+        vec(any::<u8>(), 0..256).prop_map(ClientMessage::Body),
+        vec(any::<u8>(), 0..256).prop_map(ClientMessage::Meta),
+        Just(ClientMessage::UpgradeRequest),
+        (any::<u32>(), any::<u8>(), any::<u8>(), any::<u32>()).prop_map(
+            |(time, format, bits, code)| ClientMessage::Ir {
+                time: Jiffies::from_millis(time),
+                format,
+                bits,
+                code,
+            }
+        ),
+    ]
+}
+
+fn stream_message() -> impl Strategy<Value = ServerMessage> {
+    (
+        (
+            prop_oneof![
+                Just(AutoStart::None),
+                Just(AutoStart::Auto),
+                Just(AutoStart::Direct),
+                Just(AutoStart::AutoDirect),
+            ],
+            prop_oneof![
+                Just(Format::Pcm),
+                Just(Format::Mp3),
+                Just(Format::Flac),
+                Just(Format::Wma),
+                Just(Format::Ogg),
+                Just(Format::Aac),
+                Just(Format::Alac),
+            ],
+            prop_oneof![
+                Just(PcmSampleSize::Eight),
+                Just(PcmSampleSize::Sixteen),
+                Just(PcmSampleSize::Twenty),
+                Just(PcmSampleSize::ThirtyTwo),
+                Just(PcmSampleSize::SelfDescribing),
+            ],
+            prop_oneof![
+                Just(PcmSampleRate::SelfDescribing),
+                proptest::sample::select(vec![
+                    8_000, 11_000, 12_000, 16_000, 22_000, 24_000, 32_000, 44_100, 48_000, 96_000,
+                ])
+                .prop_map(PcmSampleRate::Rate),
+            ],
+            prop_oneof![
+                Just(PcmChannels::Mono),
+                Just(PcmChannels::Stereo),
+                Just(PcmChannels::SelfDescribing),
+            ],
+            prop_oneof![
+                Just(PcmEndian::Big),
+                Just(PcmEndian::Little),
+                Just(PcmEndian::SelfDescribing),
+            ],
+        ),
+        (
+            any::<u8>(),
+            prop_oneof![
+                Just(SpdifEnable::Auto),
+                Just(SpdifEnable::On),
+                Just(SpdifEnable::Off),
+            ],
+            any::<u8>(),
+            prop_oneof![
+                Just(TransType::None),
+                Just(TransType::Crossfade),
+                Just(TransType::FadeIn),
+                Just(TransType::FadeOut),
+                Just(TransType::FadeInOut),
+            ],
+            any::<u8>(),
+            any::<u8>(),
+        ),
+        (
+            any::<u32>(),
+            any::<u16>(),
+            any::<u32>(),
+            option::of("[ -~]{1,200}"),
+        ),
+    )
+        .prop_map(
+            |(
+                (autostart, format, pcmsamplesize, pcmsamplerate, pcmchannels, pcmendian),
+                (threshold, spdif_enable, trans_period, trans_type, flags, output_threshold),
+                (replay_gain, server_port, server_ip, http_headers),
+            )| {
+                let msg = ServerMessage::Stream {
+                    autostart,
+                    format,
+                    pcmsamplesize,
+                    pcmsamplerate,
+                    pcmchannels,
+                    pcmendian,
+                    threshold: Threshold::from_kb(threshold as u32),
+                    spdif_enable,
+                    trans_period: Duration::from_secs(trans_period as u64),
+                    trans_type,
+                    flags: StreamFlags::from_bits_retain(flags),
+                    output_threshold: Duration::from_millis(output_threshold as u64 * 100),
+                    replay_gain: Gain::from_fixed(replay_gain),
+                    server_port,
+                    server_ip: Ipv4Addr::from(server_ip),
+                    http_headers,
+                    raw: Vec::new(),
+                };
+                with_raw(msg)
+            },
+        )
+}
+
+// The client keeps the payload of a stream message, the payload follows the
+// frame length and the four letter code
+fn with_raw(mut msg: ServerMessage) -> ServerMessage {
+    let mut buf = BytesMut::new();
+    ServerCodec.encode(msg.clone(), &mut buf).unwrap();
+    if let ServerMessage::Stream { raw, .. } = &mut msg {
+        *raw = buf[6..].to_vec();
+    }
+    msg
+}
+
+/// Server messages that survive a round trip through the codec.
+pub fn server_message() -> impl Strategy<Value = ServerMessage> {
+    prop_oneof![
+        // The server makes sync groups from digits
+        (any::<u32>(), option::of("[0-9]{1,10}")).prop_map(|(ip, sync_group)| {
+            ServerMessage::Serv {
+                address: ServerAddress::Ip(Ipv4Addr::from(ip)),
+                sync_group_id: sync_group.map(SyncGroup::from),
+            }
+        }),
+        "[a-z][a-z0-9-]{1,10}\\.[a-z]{2,6}".prop_map(|hostname| ServerMessage::Serv {
+            address: ServerAddress::Hostname(hostname),
+            sync_group_id: None,
+        }),
+        jiffies().prop_map(ServerMessage::Status),
+        stream_message(),
+        Just(ServerMessage::Stop),
+        Just(ServerMessage::Flush),
+        pause_kind().prop_map(ServerMessage::Pause),
+        unpause_kind().prop_map(ServerMessage::Unpause),
+        jiffies().prop_map(ServerMessage::Skip),
+        (any::<bool>(), any::<bool>())
+            .prop_map(|(spdif, dac)| ServerMessage::Enable(OutputEnable { spdif, dac })),
+        (
+            (fixed_gain(), fixed_gain()),
+            (any::<u32>(), any::<u32>(), any::<bool>(), any::<u8>()),
+        )
+            .prop_map(
+                |((left, right), (old_left, old_right, digital_volume, preamp))| {
+                    ServerMessage::Gain {
+                        left,
+                        right,
+                        old_left,
+                        old_right,
+                        digital_volume,
+                        preamp,
+                    }
+                }
+            ),
+        Just(ServerMessage::Queryname),
+        player_name().prop_map(ServerMessage::Setname),
+        Just(ServerMessage::DisableDac),
+        player_setting().prop_map(ServerMessage::QuerySetting),
+        (player_setting(), any::<u8>())
+            .prop_map(|(setting, value)| ServerMessage::SetSetting(setting, value)),
+        (any::<u32>(), any::<bool>()).prop_map(|(metaint, loop_stream)| ServerMessage::Cont {
+            metaint,
+            loop_stream
+        }),
+        Just(ServerMessage::UpgradeNow),
+        vec(any::<u8>(), 0..256).prop_map(ServerMessage::UpgradeData),
+        "[0-9][0-9.]{0,8}".prop_map(ServerMessage::Version),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn client(msg in client_message()) {
+            prop_assert_eq!(roundtrip_client(msg.clone()).unwrap(), msg);
+        }
+
+        #[test]
+        fn server(msg in server_message()) {
+            prop_assert_eq!(roundtrip_server(msg.clone()).unwrap(), msg);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! Server frames laid out as Logitech Media Server sends them.
+//!
+//! Each [Fixture] holds a whole frame, length included, and the message that the
+//! client should decode from it. The frames follow the layouts that the server
+//! packs rather than what [ServerCodec](crate::codec::ServerCodec) writes, so they
+//! catch a change to the codec that still round trips but no longer reads what
+//! the server sends.
+//!
+//! ```
+//! use slimproto::testing::corpus::server_frames;
+//!
+//! for fixture in server_frames() {
+//!     assert_eq!(fixture.decode().unwrap(), fixture.expected, "{}", fixture.name);
+//! }
+//! ```
+
+use std::{io, net::Ipv4Addr, time::Duration};
+
+use bytes::BytesMut;
+use framous::Decoder;
+
+use crate::{
+    codec::SlimCodec,
+    gain::{FixedGain, Gain},
+    jiffies::Jiffies,
+    proto::{
+        AutoStart, Format, OutputEnable, PauseKind, PcmChannels, PcmEndian, PcmSampleRate,
+        PcmSampleSize, PlayerName, ServerAddress, SpdifEnable, StreamFlags, SyncGroup, Threshold,
+        TransType,
+    },
+    ServerMessage,
+};
+
+/// A frame from the server and the message it holds.
+#[derive(Clone, Debug)]
+pub struct Fixture {
+    pub name: &'static str,
+    pub frame: Vec<u8>,
+    pub expected: ServerMessage,
+}
+
+impl Fixture {
+    /// Decode the frame as the client does.
+    pub fn decode(&self) -> io::Result<ServerMessage> {
+        SlimCodec::new()
+            .decode(&mut BytesMut::from(&self.frame[..]))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete frame"))
+    }
+}
+
+// Join the parts of a frame and put the length in front
+fn frame(parts: &[&[u8]]) -> Vec<u8> {
+    let payload = parts.concat();
+    let mut frame = (payload.len() as u16).to_be_bytes().to_vec();
+    frame.extend(payload);
+    frame
+}
+
+// The 24 byte strm header has the timestamp or interval of most commands in the
+// replay gain field
+fn strm(command: u8, replay_gain: u32) -> Vec<u8> {
+    frame(&[
+        b"strm",
+        &[
+            command, b'0', b'm', b'?', b'?', b'?', b'?', 0, 0, 0, b'0', 0, 0, 0,
+        ],
+        &replay_gain.to_be_bytes(),
+        &[0; 6],
+    ])
+}
+
+struct Stream {
+    command: &'static [u8; 7],
+    params: [u8; 7],
+    replay_gain: u32,
+    server_port: u16,
+    server_ip: [u8; 4],
+    http_headers: &'static str,
+}
+
+fn stream(stream: Stream) -> Vec<u8> {
+    frame(&[
+        b"strm",
+        stream.command,
+        &stream.params,
+        &stream.replay_gain.to_be_bytes(),
+        &stream.server_port.to_be_bytes(),
+        &stream.server_ip,
+        stream.http_headers.as_bytes(),
+    ])
+}
+
+// The client keeps the strm payload as it was sent
+fn raw(frame: &[u8]) -> Vec<u8> {
+    frame[6..].to_vec()
+}
+
+fn mp3_stream() -> Fixture {
+    let frame = stream(Stream {
+        command: b"s1m????",
+        params: [255, 0, 0, b'0', 0, 0, 0],
+        replay_gain: 0,
+        server_port: 9000,
+        server_ip: [0; 4],
+        http_headers: "GET /stream.mp3?player=00:04:20:12:34:56 HTTP/1.0\r\n\r\n",
+    });
+    Fixture {
+        name: "strm s mp3 from the server",
+        expected: ServerMessage::Stream {
+            autostart: AutoStart::Auto,
+            format: Format::Mp3,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(255),
+            spdif_enable: SpdifEnable::Auto,
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            output_threshold: Duration::ZERO,
+            replay_gain: Gain::from_fixed(0),
+            server_port: 9000,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            http_headers: Some(
+                "GET /stream.mp3?player=00:04:20:12:34:56 HTTP/1.0\r\n\r\n".to_owned(),
+            ),
+            raw: raw(&frame),
+        },
+        frame,
+    }
+}
+
+fn pcm_stream() -> Fixture {
+    let frame = stream(Stream {
+        command: b"s1p1321",
+        params: [64, 0, 10, b'1', 0, 1, 0],
+        replay_gain: 0x0000_b0a1,
+        server_port: 9000,
+        server_ip: [0; 4],
+        http_headers: "GET /stream.mp3?player=00:04:20:12:34:56 HTTP/1.0\r\n\r\n",
+    });
+    Fixture {
+        name: "strm s pcm with crossfade and replay gain",
+        expected: ServerMessage::Stream {
+            autostart: AutoStart::Auto,
+            format: Format::Pcm,
+            pcmsamplesize: PcmSampleSize::Sixteen,
+            pcmsamplerate: PcmSampleRate::Rate(44_100),
+            pcmchannels: PcmChannels::Stereo,
+            pcmendian: PcmEndian::Little,
+            threshold: Threshold::from_kb(64),
+            spdif_enable: SpdifEnable::Auto,
+            trans_period: Duration::from_secs(10),
+            trans_type: TransType::Crossfade,
+            flags: StreamFlags::empty(),
+            output_threshold: Duration::from_millis(100),
+            replay_gain: Gain::from_fixed(0x0000_b0a1),
+            server_port: 9000,
+            server_ip: Ipv4Addr::UNSPECIFIED,
+            http_headers: Some(
+                "GET /stream.mp3?player=00:04:20:12:34:56 HTTP/1.0\r\n\r\n".to_owned(),
+            ),
+            raw: raw(&frame),
+        },
+        frame,
+    }
+}
+
+fn direct_stream() -> Fixture {
+    let frame = stream(Stream {
+        command: b"s3f????",
+        params: [255, 0, 0, b'0', 0, 0, 0],
+        replay_gain: 0,
+        server_port: 80,
+        server_ip: [93, 184, 216, 34],
+        http_headers:
+            "GET /radio.flac HTTP/1.0\r\nHost: radio.example.com\r\nIcy-MetaData: 1\r\n\r\n",
+    });
+    Fixture {
+        name: "strm s flac direct from a radio station",
+        expected: ServerMessage::Stream {
+            autostart: AutoStart::AutoDirect,
+            format: Format::Flac,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: Threshold::from_kb(255),
+            spdif_enable: SpdifEnable::Auto,
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            output_threshold: Duration::ZERO,
+            replay_gain: Gain::from_fixed(0),
+            server_port: 80,
+            server_ip: Ipv4Addr::new(93, 184, 216, 34),
+            http_headers: Some(
+                "GET /radio.flac HTTP/1.0\r\nHost: radio.example.com\r\nIcy-MetaData: 1\r\n\r\n"
+                    .to_owned(),
+            ),
+            raw: raw(&frame),
+        },
+        frame,
+    }
+}
+
+/// Every fixture in the corpus.
+pub fn server_frames() -> Vec<Fixture> {
+    vec![
+        mp3_stream(),
+        pcm_stream(),
+        direct_stream(),
+        Fixture {
+            name: "strm t status request",
+            frame: strm(b't', 1_234_567),
+            expected: ServerMessage::Status(Jiffies::from_millis(1_234_567)),
+        },
+        Fixture {
+            name: "strm q",
+            frame: strm(b'q', 0),
+            expected: ServerMessage::Stop,
+        },
+        Fixture {
+            name: "strm f",
+            frame: strm(b'f', 0),
+            expected: ServerMessage::Flush,
+        },
+        Fixture {
+            name: "strm p now",
+            frame: strm(b'p', 0),
+            expected: ServerMessage::Pause(PauseKind::Now),
+        },
+        Fixture {
+            name: "strm p for an interval",
+            frame: strm(b'p', 2000),
+            expected: ServerMessage::Pause(PauseKind::For(Duration::from_millis(2000))),
+        },
+        Fixture {
+            name: "strm u at a time",
+            frame: strm(b'u', 123_456),
+            expected: ServerMessage::Unpause(PauseKind::At(Jiffies::from_millis(123_456))),
+        },
+        Fixture {
+            name: "strm a",
+            frame: strm(b'a', 500),
+            expected: ServerMessage::Skip(Jiffies::from_millis(500)),
+        },
+        Fixture {
+            name: "audg at half volume",
+            frame: frame(&[
+                b"audg",
+                &64u32.to_be_bytes(),
+                &64u32.to_be_bytes(),
+                &[1, 255],
+                &0x8000u32.to_be_bytes(),
+                &0x8000u32.to_be_bytes(),
+            ]),
+            expected: ServerMessage::Gain {
+                left: FixedGain(0x8000),
+                right: FixedGain(0x8000),
+                old_left: 64,
+                old_right: 64,
+                digital_volume: true,
+                preamp: 255,
+            },
+        },
+        Fixture {
+            name: "aude",
+            frame: frame(&[b"aude", &[1, 1]]),
+            expected: ServerMessage::Enable(OutputEnable {
+                spdif: true,
+                dac: true,
+            }),
+        },
+        Fixture {
+            name: "serv with a sync group",
+            frame: frame(&[b"serv", &[192, 168, 1, 10], b"1234567890"]),
+            expected: ServerMessage::Serv {
+                address: Ipv4Addr::new(192, 168, 1, 10).into(),
+                sync_group_id: Some(SyncGroup::new("1234567890")),
+            },
+        },
+        Fixture {
+            name: "serv without a sync group",
+            frame: frame(&[b"serv", &[192, 168, 1, 10]]),
+            expected: ServerMessage::Serv {
+                address: Ipv4Addr::new(192, 168, 1, 10).into(),
+                sync_group_id: None,
+            },
+        },
+        Fixture {
+            name: "serv with a hostname",
+            frame: frame(&[b"serv", b"lms.example.com"]),
+            expected: ServerMessage::Serv {
+                address: ServerAddress::Hostname("lms.example.com".to_owned()),
+                sync_group_id: None,
+            },
+        },
+        Fixture {
+            name: "setd query name",
+            frame: frame(&[b"setd", &[0]]),
+            expected: ServerMessage::Queryname,
+        },
+        Fixture {
+            name: "setd set name",
+            frame: frame(&[b"setd", &[0], b"Kitchen\0"]),
+            expected: ServerMessage::Setname(PlayerName::new("Kitchen").unwrap()),
+        },
+        Fixture {
+            name: "setd disable dac",
+            frame: frame(&[b"setd", &[4]]),
+            expected: ServerMessage::DisableDac,
+        },
+        Fixture {
+            name: "vers",
+            frame: frame(&[b"vers", b"8.3.1"]),
+            expected: ServerMessage::Version("8.3.1".to_owned()),
+        },
+        Fixture {
+            // A whole screen for a Squeezebox2, only decoded with the display feature
+            name: "grfe",
+            frame: frame(&[b"grfe", &0u16.to_be_bytes(), b"c", &[0], &[0x55; 1280]]),
+            #[cfg(not(feature = "display"))]
+            expected: ServerMessage::Unrecognised("grfe".to_owned()),
+            #[cfg(feature = "display")]
+            expected: ServerMessage::Graphics(crate::display::GraphicsFrame {
+                offset: 0,
+                transition: b'c',
+                param: 0,
+                bitmap: vec![0x55; 1280],
+            }),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use framous::FramedReader;
+
+    use crate::codec::SlimFramed;
+
+    #[test]
+    fn decodes_every_frame() {
+        for fixture in server_frames() {
+            assert_eq!(
+                fixture.decode().unwrap(),
+                fixture.expected,
+                "{}",
+                fixture.name
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_a_session() {
+        let fixtures = server_frames();
+        let data: Vec<u8> = fixtures.iter().flat_map(|f| f.frame.clone()).collect();
+        let mut framed = SlimFramed::new(&data[..]);
+        for fixture in fixtures {
+            assert_eq!(
+                framed.framed_read().unwrap(),
+                fixture.expected,
+                "{}",
+                fixture.name
+            );
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! The version of the server.
+//!
+//! The server gives its version in the `vers` message and in the discovery
+//! response. A [ServerVersion] can be compared with others so that a client can
+//! work around older servers, for example by leaving out capabilities that the
+//! server does not know.
+
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{io, str::FromStr};
+
+/// A server version such as `8.5.2`, missing minor and patch numbers are zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl ServerVersion {
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Returns `true` if the server can stream Ogg FLAC, the `ogf` capability.
+    pub fn supports_ogg_flac(&self) -> bool {
+        *self >= Self::new(8, 3, 0)
+    }
+
+    /// Returns `true` if the server can ask the client to stream from HTTPS
+    /// URLs directly.
+    pub fn supports_https_streaming(&self) -> bool {
+        *self >= Self::new(8, 0, 0)
+    }
+}
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Parse a version such as `7.9.1` or `9.0.0 - 1712345678`, anything after the
+/// numbers is ignored.
+#[cfg(feature = "std")]
+impl FromStr for ServerVersion {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let numbers = s
+            .trim()
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .next()
+            .unwrap_or_default();
+        let mut parts = numbers.split('.').filter(|p| !p.is_empty()).map(str::parse);
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid server version");
+        let major = parts.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+        let minor = parts.next().transpose().map_err(|_| invalid())?;
+        let patch = parts.next().transpose().map_err(|_| invalid())?;
+
+        Ok(Self::new(major, minor.unwrap_or(0), patch.unwrap_or(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "8.5.2".parse::<ServerVersion>().unwrap(),
+            ServerVersion::new(8, 5, 2)
+        );
+        assert_eq!(
+            "9.0 - 1712345678".parse::<ServerVersion>().unwrap(),
+            ServerVersion::new(9, 0, 0)
+        );
+        assert!("".parse::<ServerVersion>().is_err());
+        assert!("x7".parse::<ServerVersion>().is_err());
+    }
+
+    #[test]
+    fn features() {
+        let old = ServerVersion::new(7, 9, 1);
+        let new = ServerVersion::new(8, 5, 0);
+        assert!(old < new);
+        assert!(!old.supports_https_streaming());
+        assert!(new.supports_https_streaming());
+        assert!(!ServerVersion::new(8, 2, 9).supports_ogg_flac());
+        assert!(new.supports_ogg_flac());
+        assert_eq!(new.to_string(), "8.5.0");
+    }
+}